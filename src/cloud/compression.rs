@@ -0,0 +1,127 @@
+//! Compression applied to archives between [`Archive::read`](crate::cpio::Archive::read)
+//! and [`CloudProvider::upload`](super::CloudProvider::upload).
+
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use async_compression::Level;
+use snafu::Snafu;
+use tokio::io::{AsyncRead, BufReader};
+
+/// Magic number that every zstd frame starts with.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Codec that was used to compress the archive. It is stored in the database next to each archive,
+/// so it is enough to decompress it back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Archive is stored as is.
+    None,
+    Zstd,
+}
+
+/// Compression that should be applied to new archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Zstd with given level, from 1 (fastest) to 22 (smallest).
+    Zstd {
+        level: u32,
+    },
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(display("Unknown codec: {}", name))]
+pub struct UnknownCodec {
+    name: String,
+}
+
+impl Codec {
+    /// Name of the codec, as it is stored in the database.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// Detects codec by first bytes of compressed data.
+    ///
+    /// ```
+    /// # use colbak_lib::cloud::compression::Codec;
+    /// assert_eq!(Codec::detect(b"\x28\xB5\x2F\xFD\x00"), Codec::Zstd);
+    /// assert_eq!(Codec::detect(b"\xC7\x71"), Codec::None);
+    /// ```
+    #[must_use]
+    pub fn detect(data: &[u8]) -> Codec {
+        if data.starts_with(&ZSTD_MAGIC) {
+            Codec::Zstd
+        } else {
+            Codec::None
+        }
+    }
+
+    /// Wraps reader of compressed data into reader of original data.
+    pub fn decompress<'a, R: AsyncRead + 'a>(self, reader: R) -> Pin<Box<dyn AsyncRead + 'a>> {
+        match self {
+            Codec::None => Box::pin(reader),
+            Codec::Zstd => {
+                let mut decoder = ZstdDecoder::new(BufReader::new(reader));
+                // Some tools split large archives into several frames.
+                decoder.multiple_members(true);
+                Box::pin(decoder)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = UnknownCodec;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(UnknownCodec {
+                name: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl Compression {
+    /// Codec that should be used to decompress the data.
+    #[must_use]
+    pub fn codec(self) -> Codec {
+        match self {
+            Compression::None => Codec::None,
+            Compression::Zstd { .. } => Codec::Zstd,
+        }
+    }
+
+    /// Wraps reader of original data into reader of compressed data.
+    pub fn compress<'a, R: AsyncRead + 'a>(self, reader: R) -> Pin<Box<dyn AsyncRead + 'a>> {
+        match self {
+            Compression::None => Box::pin(reader),
+            Compression::Zstd { level } => Box::pin(ZstdEncoder::with_quality(
+                BufReader::new(reader),
+                Level::Precise(level.clamp(1, 22)),
+            )),
+        }
+    }
+}
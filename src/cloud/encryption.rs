@@ -0,0 +1,725 @@
+//! Streaming authenticated encryption of archives in [age](https://age-encryption.org/v1) format,
+//! so they can be decrypted with `age -d` too.
+//!
+//! Archive can be encrypted either to X25519 public keys, so the backup host never needs the secret,
+//! or with a passphrase.
+//!
+//! Plaintext is split into 64KB chunks, each one is encrypted by ChaCha20-Poly1305
+//! with a counter as a nonce. Last chunk is marked, so truncated archives are detected as well.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use bech32::{FromBase32, ToBase32, Variant};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key as AeadKey, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use pin_project_lite::pin_project;
+use sha2::Sha256;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, ReadBuf, Take};
+
+/// First line of every encrypted archive.
+const MAGIC: &[u8] = b"age-encryption.org/v1";
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const FILE_KEY_SIZE: usize = 16;
+const NONCE_SIZE: usize = 16;
+/// Size of HMAC-SHA256 of the header.
+const MAC_SIZE: usize = 32;
+/// Headers are tiny, anything larger is definitely not an archive header.
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+/// Body of stanza is encoded in lines of this length, last one is always shorter.
+const STANZA_LINE: usize = 64;
+
+const X25519_TYPE: &str = "X25519";
+const X25519_INFO: &[u8] = b"age-encryption.org/v1/X25519";
+const SCRYPT_TYPE: &str = "scrypt";
+const SCRYPT_SALT: &[u8] = b"age-encryption.org/v1/scrypt";
+/// Size of the random part of scrypt salt, stored in the stanza.
+const SCRYPT_SALT_SIZE: usize = 16;
+/// Default scrypt work factor, takes about a second on modern machines.
+pub const DEFAULT_WORK_FACTOR: u8 = 18;
+/// Larger work factors are rejected when decrypting, since they may take hours.
+const MAX_WORK_FACTOR: u8 = 22;
+
+const PUBLIC_KEY_HRP: &str = "age";
+const SECRET_KEY_HRP: &str = "age-secret-key-";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    IoFailed {
+        source: io::Error,
+        backtrace: snafu::Backtrace,
+    },
+    /// Data is not an encrypted archive, or it is corrupted.
+    InvalidHeader,
+    #[snafu(display("Invalid key: {}", reason))]
+    InvalidKey { reason: &'static str },
+    /// None of the given identities can decrypt the archive.
+    NoMatchingIdentity,
+    /// Header was modified after encryption.
+    HeaderMacMismatch,
+    #[snafu(display("Work factor {} is too large", log_n))]
+    WorkFactorTooLarge { log_n: u8 },
+    /// Passphrase can't be mixed with other recipients.
+    PassphraseNotAlone,
+    /// Archive should have at least one recipient.
+    NoRecipients,
+}
+
+/// X25519 public key, encoded as `age1...` string.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PublicKey(x25519_dalek::PublicKey);
+
+/// X25519 secret key, encoded as `AGE-SECRET-KEY-1...` string.
+#[derive(Clone)]
+pub struct SecretKey(x25519_dalek::StaticSecret);
+
+/// Who will be able to decrypt the archive.
+#[derive(Clone)]
+pub enum Recipient {
+    Key(PublicKey),
+    /// Passphrase-protected archives can't have other recipients.
+    Passphrase {
+        passphrase: String,
+        work_factor: u8,
+    },
+}
+
+/// Secret that is used to decrypt the archive.
+#[derive(Clone)]
+pub enum Identity {
+    Key(SecretKey),
+    Passphrase(String),
+}
+
+/// Fills array with cryptographically secure random bytes.
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut result = [0; N];
+    getrandom::getrandom(&mut result)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Ok(result)
+}
+
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut result = [0; 32];
+    #[allow(clippy::unwrap_used)]
+    // UNWRAP: Output is always shorter than 255 blocks.
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut result)
+        .unwrap();
+    result
+}
+
+fn header_mac(file_key: &[u8], header: &[u8]) -> Hmac<Sha256> {
+    let key = hkdf(&[], file_key, b"header");
+    #[allow(clippy::unwrap_used)]
+    // UNWRAP: HMAC accepts keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+    mac.update(header);
+    mac
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::STANDARD_NO_PAD)
+}
+
+/// Decodes base64 without padding. Only canonical encoding is accepted, as required by age.
+fn base64_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let decoded = base64::decode_config(data, base64::STANDARD_NO_PAD).ok()?;
+    if base64_encode(&decoded).as_bytes() == data {
+        Some(decoded)
+    } else {
+        None
+    }
+}
+
+fn aead(key: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(AeadKey::from_slice(key))
+}
+
+/// Every key derived from recipient is used to wrap only one file key, so nonce is always zero.
+const WRAP_NONCE: [u8; 12] = [0; 12];
+
+/// Encrypts file key using key derived from recipient.
+fn wrap_file_key(key: &[u8; 32], file_key: &[u8]) -> Vec<u8> {
+    #[allow(clippy::unwrap_used)]
+    // UNWRAP: Encryption fails only for messages larger than 256GB.
+    aead(key)
+        .encrypt(Nonce::from_slice(&WRAP_NONCE), file_key)
+        .unwrap()
+}
+
+/// Decrypts file key, returns None when recipient does not match.
+fn unwrap_file_key(key: &[u8; 32], body: &[u8]) -> Result<Option<[u8; FILE_KEY_SIZE]>, Error> {
+    ensure!(body.len() == FILE_KEY_SIZE + TAG_SIZE, InvalidHeader);
+    let file_key = aead(key).decrypt(Nonce::from_slice(&WRAP_NONCE), body).ok();
+    Ok(file_key.and_then(|x| x.try_into().ok()))
+}
+
+impl PublicKey {
+    fn x25519_wrap(&self, file_key: &[u8]) -> io::Result<Stanza> {
+        let ephemeral = x25519_dalek::StaticSecret::from(random::<32>()?);
+        let share = x25519_dalek::PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&self.0);
+        if shared.as_bytes() == &[0; 32] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "public key has low order",
+            ));
+        }
+
+        let mut salt = share.as_bytes().to_vec();
+        salt.extend_from_slice(self.0.as_bytes());
+        let key = hkdf(&salt, shared.as_bytes(), X25519_INFO);
+        Ok(Stanza {
+            kind: X25519_TYPE.to_string(),
+            args: vec![base64_encode(share.as_bytes())],
+            body: wrap_file_key(&key, file_key),
+        })
+    }
+}
+
+impl SecretKey {
+    /// Generates a new random key.
+    pub fn generate() -> io::Result<Self> {
+        Ok(SecretKey(x25519_dalek::StaticSecret::from(random::<32>()?)))
+    }
+
+    #[must_use]
+    pub fn public(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.0))
+    }
+
+    fn x25519_unwrap(&self, stanza: &Stanza) -> Result<Option<[u8; FILE_KEY_SIZE]>, Error> {
+        let share = match stanza.args.as_slice() {
+            [share] => base64_decode(share.as_bytes()).context(InvalidHeader)?,
+            _ => return InvalidHeader.fail(),
+        };
+        let share: [u8; 32] = share.try_into().ok().context(InvalidHeader)?;
+        let share = x25519_dalek::PublicKey::from(share);
+        let shared = self.0.diffie_hellman(&share);
+        // Share is a low order point, so the stanza could be decrypted by anyone.
+        ensure!(shared.as_bytes() != &[0; 32], InvalidHeader);
+
+        let mut salt = share.as_bytes().to_vec();
+        salt.extend_from_slice(self.public().0.as_bytes());
+        let key = hkdf(&salt, shared.as_bytes(), X25519_INFO);
+        unwrap_file_key(&key, &stanza.body)
+    }
+}
+
+/// Derives key from the passphrase on the blocking pool, since it takes about a second.
+async fn scrypt_key(passphrase: &str, salt: &[u8], log_n: u8) -> Result<[u8; 32], Error> {
+    ensure!(log_n <= MAX_WORK_FACTOR, WorkFactorTooLarge { log_n });
+    let params = scrypt::Params::new(log_n, 8, 1)
+        .ok()
+        .context(InvalidHeader)?;
+    let mut full_salt = SCRYPT_SALT.to_vec();
+    full_salt.extend_from_slice(salt);
+    let passphrase = passphrase.to_owned();
+    let derive = move || {
+        let mut key = [0; 32];
+        #[allow(clippy::unwrap_used)]
+        // UNWRAP: Output length is valid.
+        scrypt::scrypt(passphrase.as_bytes(), &full_salt, &params, &mut key).unwrap();
+        key
+    };
+    tokio::task::spawn_blocking(derive)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .context(IoFailed)
+}
+
+/// Parses work factor of scrypt stanza, which is a positive decimal number without leading zeroes.
+fn parse_work_factor(s: &str) -> Option<u8> {
+    let digits = !s.is_empty() && s.bytes().all(|x| x.is_ascii_digit());
+    if !digits || s.starts_with('0') {
+        return None;
+    }
+    s.parse().ok()
+}
+
+impl Recipient {
+    /// Passphrase with [default](DEFAULT_WORK_FACTOR) work factor.
+    #[must_use]
+    pub fn passphrase(passphrase: String) -> Self {
+        Recipient::Passphrase {
+            passphrase,
+            work_factor: DEFAULT_WORK_FACTOR,
+        }
+    }
+
+    async fn wrap(&self, file_key: &[u8]) -> Result<Stanza, Error> {
+        match self {
+            Recipient::Key(key) => key.x25519_wrap(file_key).context(IoFailed),
+            Recipient::Passphrase {
+                passphrase,
+                work_factor,
+            } => {
+                let salt = random::<SCRYPT_SALT_SIZE>().context(IoFailed)?;
+                let key = scrypt_key(passphrase, &salt, *work_factor).await?;
+                Ok(Stanza {
+                    kind: SCRYPT_TYPE.to_string(),
+                    args: vec![base64_encode(&salt), work_factor.to_string()],
+                    body: wrap_file_key(&key, file_key),
+                })
+            }
+        }
+    }
+}
+
+impl Identity {
+    /// Tries to decrypt file key from the stanza. Returns None when stanza is for someone else.
+    async fn unwrap(&self, stanza: &Stanza) -> Result<Option<[u8; FILE_KEY_SIZE]>, Error> {
+        match (self, stanza.kind.as_str()) {
+            (Identity::Key(key), X25519_TYPE) => key.x25519_unwrap(stanza),
+            (Identity::Passphrase(passphrase), SCRYPT_TYPE) => {
+                let (salt, log_n) = match stanza.args.as_slice() {
+                    [salt, log_n] => (salt, log_n),
+                    _ => return InvalidHeader.fail(),
+                };
+                let salt = base64_decode(salt.as_bytes()).context(InvalidHeader)?;
+                ensure!(salt.len() == SCRYPT_SALT_SIZE, InvalidHeader);
+                let log_n = parse_work_factor(log_n).context(InvalidHeader)?;
+                let key = scrypt_key(passphrase, &salt, log_n).await?;
+                unwrap_file_key(&key, &stanza.body)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = bech32::encode(
+            PUBLIC_KEY_HRP,
+            self.0.as_bytes().to_base32(),
+            Variant::Bech32,
+        )
+        .map_err(|_| fmt::Error)?;
+        f.write_str(&encoded)
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+/// Decodes 32-byte key from bech32 string with given prefix.
+fn decode_key(s: &str, expected_hrp: &str) -> Result<[u8; 32], Error> {
+    let (hrp, data, variant) = bech32::decode(s).ok().context(InvalidKey {
+        reason: "not a bech32 string",
+    })?;
+    ensure!(
+        hrp == expected_hrp && variant == Variant::Bech32,
+        InvalidKey {
+            reason: "wrong type of key",
+        }
+    );
+    let data = Vec::<u8>::from_base32(&data).ok().context(InvalidKey {
+        reason: "not a bech32 string",
+    })?;
+    data.try_into().ok().context(InvalidKey {
+        reason: "wrong length",
+    })
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = decode_key(s, PUBLIC_KEY_HRP)?;
+        Ok(PublicKey(x25519_dalek::PublicKey::from(key)))
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = bech32::encode(
+            SECRET_KEY_HRP,
+            self.0.to_bytes().to_base32(),
+            Variant::Bech32,
+        )
+        .map_err(|_| fmt::Error)?;
+        f.write_str(&encoded.to_uppercase())
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = decode_key(s, SECRET_KEY_HRP)?;
+        Ok(SecretKey(x25519_dalek::StaticSecret::from(key)))
+    }
+}
+
+/// Single recipient entry in the header.
+struct Stanza {
+    kind: String,
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Stanza {
+    fn write_to(&self, dst: &mut Vec<u8>) {
+        dst.extend_from_slice(b"-> ");
+        dst.extend_from_slice(self.kind.as_bytes());
+        for arg in &self.args {
+            dst.push(b' ');
+            dst.extend_from_slice(arg.as_bytes());
+        }
+        dst.push(b'\n');
+        let body = base64_encode(&self.body);
+        let body = body.as_bytes();
+        // Last line is always shorter than others, even if it becomes empty.
+        for line in body.chunks(STANZA_LINE) {
+            dst.extend_from_slice(line);
+            dst.push(b'\n');
+        }
+        if body.len() % STANZA_LINE == 0 {
+            dst.push(b'\n');
+        }
+    }
+}
+
+/// Generates header that allows given recipients to decrypt the file key.
+async fn encode_header(file_key: &[u8], recipients: &[Recipient]) -> Result<Vec<u8>, Error> {
+    ensure!(!recipients.is_empty(), NoRecipients);
+    let has_passphrase = recipients
+        .iter()
+        .any(|x| matches!(x, Recipient::Passphrase { .. }));
+    ensure!(!has_passphrase || recipients.len() == 1, PassphraseNotAlone);
+
+    let mut header = MAGIC.to_vec();
+    header.push(b'\n');
+    for recipient in recipients {
+        recipient.wrap(file_key).await?.write_to(&mut header);
+    }
+    header.extend_from_slice(b"---");
+    let mac = header_mac(file_key, &header).finalize().into_bytes();
+    header.push(b' ');
+    header.extend_from_slice(base64_encode(&mac).as_bytes());
+    header.push(b'\n');
+    Ok(header)
+}
+
+/// Reads header line by line, remembering all read bytes.
+struct HeaderReader<R> {
+    reader: Take<R>,
+    raw: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> HeaderReader<R> {
+    async fn next_line(&mut self) -> Result<Vec<u8>, Error> {
+        let mut line = Vec::new();
+        self.reader
+            .read_until(b'\n', &mut line)
+            .await
+            .context(IoFailed)?;
+        self.raw.extend_from_slice(&line);
+        ensure!(line.pop() == Some(b'\n'), InvalidHeader);
+        Ok(line)
+    }
+}
+
+/// Parses header, returning its raw bytes up to `---`, all stanzas and the MAC.
+async fn decode_header<R: AsyncBufRead + Unpin>(
+    reader: R,
+) -> Result<(Vec<u8>, Vec<Stanza>, Vec<u8>), Error> {
+    let mut lines = HeaderReader {
+        reader: reader.take(MAX_HEADER_SIZE),
+        raw: Vec::new(),
+    };
+    ensure!(lines.next_line().await? == MAGIC, InvalidHeader);
+
+    let mut stanzas = Vec::new();
+    loop {
+        let line = lines.next_line().await?;
+        if let Some(mac) = line.strip_prefix(b"--- ") {
+            // MAC covers everything before the space.
+            let mut raw = lines.raw;
+            raw.truncate(raw.len() - line.len() - 1 + b"---".len());
+            let mac = base64_decode(mac).context(InvalidHeader)?;
+            ensure!(mac.len() == MAC_SIZE, InvalidHeader);
+            return Ok((raw, stanzas, mac));
+        }
+        let stanza = line.strip_prefix(b"-> ").context(InvalidHeader)?;
+        let stanza = std::str::from_utf8(stanza).ok().context(InvalidHeader)?;
+        let mut args = stanza.split(' ').map(str::to_string);
+        let kind = args.next().context(InvalidHeader)?;
+        // Arguments are not empty and consist of visible ASCII characters.
+        let valid = |arg: &str| !arg.is_empty() && arg.bytes().all(|x| x.is_ascii_graphic());
+        ensure!(stanza.split(' ').all(valid), InvalidHeader);
+
+        let mut body = Vec::new();
+        loop {
+            let line = lines.next_line().await?;
+            ensure!(line.len() <= STANZA_LINE, InvalidHeader);
+            body.extend_from_slice(&line);
+            if line.len() < STANZA_LINE {
+                break;
+            }
+        }
+        stanzas.push(Stanza {
+            kind,
+            args: args.collect(),
+            body: base64_decode(&body).context(InvalidHeader)?,
+        });
+    }
+}
+
+/// Nonce of the payload chunk: 11-byte big-endian counter and a flag of the last chunk.
+fn chunk_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last.into();
+    *Nonce::from_slice(&nonce)
+}
+
+fn payload_key(file_key: &[u8], nonce: &[u8]) -> ChaCha20Poly1305 {
+    aead(&hkdf(nonce, file_key, b"payload"))
+}
+
+pin_project! {
+    /// Reader that encrypts or decrypts data from `R` chunk by chunk.
+    pub struct Stream<R> {
+        #[pin]
+        inner: R,
+        aead: ChaCha20Poly1305,
+        encrypt: bool,
+        counter: u64,
+        // Input chunk. When encrypting, it has one more byte to know whether this chunk is the last one.
+        input: Vec<u8>,
+        filled: usize,
+        output: Vec<u8>,
+        position: usize,
+        finished: bool,
+        // Full chunk was decrypted as the last one, so nothing else may follow.
+        sealed: bool,
+    }
+}
+
+/// Wraps reader of plaintext into reader of encrypted archive for given recipients.
+pub async fn encrypt<R: AsyncRead>(
+    reader: R,
+    recipients: &[Recipient],
+) -> Result<Stream<R>, Error> {
+    let file_key = random::<FILE_KEY_SIZE>().context(IoFailed)?;
+    let nonce = random::<NONCE_SIZE>().context(IoFailed)?;
+    let mut prefix = encode_header(&file_key, recipients).await?;
+    prefix.extend_from_slice(&nonce);
+    Ok(Stream {
+        inner: reader,
+        aead: payload_key(&file_key, &nonce),
+        encrypt: true,
+        counter: 0,
+        input: vec![0; CHUNK_SIZE + 1],
+        filled: 0,
+        output: prefix,
+        position: 0,
+        finished: false,
+        sealed: false,
+    })
+}
+
+/// Reads header of encrypted archive and returns reader of the plaintext.
+pub async fn decrypt<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    identities: &[Identity],
+) -> Result<Stream<R>, Error> {
+    let (raw_header, stanzas, mac) = decode_header(&mut reader).await?;
+    let has_passphrase = stanzas.iter().any(|x| x.kind == SCRYPT_TYPE);
+    ensure!(!has_passphrase || stanzas.len() == 1, InvalidHeader);
+
+    let mut file_key = None;
+    'search: for identity in identities {
+        for stanza in &stanzas {
+            if let Some(key) = identity.unwrap(stanza).await? {
+                file_key = Some(key);
+                break 'search;
+            }
+        }
+    }
+    let file_key = file_key.context(NoMatchingIdentity)?;
+    header_mac(&file_key, &raw_header)
+        .verify_slice(&mac)
+        .ok()
+        .context(HeaderMacMismatch)?;
+
+    let mut nonce = [0; NONCE_SIZE];
+    reader.read_exact(&mut nonce).await.context(IoFailed)?;
+    Ok(Stream {
+        inner: reader,
+        aead: payload_key(&file_key, &nonce),
+        encrypt: false,
+        counter: 0,
+        input: vec![0; CHUNK_SIZE + TAG_SIZE],
+        filled: 0,
+        output: Vec::new(),
+        position: 0,
+        finished: false,
+        sealed: false,
+    })
+}
+
+/// Returns true when data looks like an encrypted archive.
+///
+/// ```
+/// # use colbak_lib::cloud::encryption::is_encrypted;
+/// assert!(is_encrypted(b"age-encryption.org/v1\n-> X25519"));
+/// assert!(!is_encrypted(b"\xC7\x71"));
+/// ```
+#[must_use]
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "encrypted archive is corrupted or truncated",
+    )
+}
+
+impl<R: AsyncRead> AsyncRead for Stream<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if *this.position < this.output.len() {
+                let available = &this.output[*this.position..];
+                let len = available.len().min(buf.remaining());
+                buf.put_slice(&available[..len]);
+                *this.position += len;
+                return Poll::Ready(Ok(()));
+            }
+            if *this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut read_buf = ReadBuf::new(&mut this.input[*this.filled..]);
+            futures::ready!(this.inner.as_mut().poll_read(cx, &mut read_buf))?;
+            let len = read_buf.filled().len();
+            *this.filled += len;
+
+            let eof = len == 0;
+            let counter = *this.counter;
+            let result = if *this.encrypt {
+                let chunk_len = this.input.len() - 1;
+                if !eof && *this.filled <= chunk_len {
+                    continue;
+                }
+                let chunk = &this.input[..chunk_len.min(*this.filled)];
+                let result = this.aead.encrypt(&chunk_nonce(counter, eof), chunk);
+                if eof {
+                    *this.finished = true;
+                } else {
+                    // Keep the byte that was read in advance.
+                    this.input[0] = this.input[chunk_len];
+                    *this.filled = 1;
+                }
+                result
+            } else {
+                if *this.sealed {
+                    if !eof {
+                        return Poll::Ready(Err(corrupted()));
+                    }
+                    *this.finished = true;
+                    continue;
+                }
+                if !eof && *this.filled < this.input.len() {
+                    continue;
+                }
+                let chunk = &this.input[..*this.filled];
+                let result = if eof {
+                    // Only the first chunk may be empty, and only when it is the last one too.
+                    if chunk.len() < TAG_SIZE || (counter != 0 && chunk.len() == TAG_SIZE) {
+                        return Poll::Ready(Err(corrupted()));
+                    }
+                    *this.finished = true;
+                    this.aead.decrypt(&chunk_nonce(counter, true), chunk)
+                } else {
+                    // Full chunk may be the last one too, it is known only after decryption.
+                    let aead = &this.aead;
+                    aead.decrypt(&chunk_nonce(counter, false), chunk)
+                        .or_else(|_| {
+                            *this.sealed = true;
+                            aead.decrypt(&chunk_nonce(counter, true), chunk)
+                        })
+                };
+                *this.filled = 0;
+                result
+            };
+            *this.output = result.map_err(|_| corrupted())?;
+            *this.position = 0;
+            *this.counter += 1;
+        }
+    }
+}
+
+/// Parses identity file, as generated by `age-keygen`: one secret key per line.
+/// Empty lines and comments starting with `#` are ignored.
+pub fn parse_identities(text: &str) -> Result<Vec<Identity>, Error> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.parse().map(Identity::Key))
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+    use sha2::Digest;
+
+    // `x25519` vector of the age testkit, see tests/age-testkit.
+    const FILE_KEY: [u8; FILE_KEY_SIZE] = hex!("59454c4c4f57205355424d4152494e45");
+    const HEADER: &[u8] = b"age-encryption.org/v1
+-> X25519 TEiF0ypqr+bpvcqXNyCVJpL7OuwPdVwPL7KQEbFDOCc
+EmECAEcKN+n/Vs9SbWiV+Hu0r+E8R77DdWYyd83nw7U
+---";
+    const MAC: &str = "Vn+54jqiiUCE+WZcEVY3f1sqHjlu/z1LCQ/T7Xm7qI0";
+    const NONCE: [u8; NONCE_SIZE] = hex!("eecf62c7ce91b433274e68d4f2f9134c");
+    const CHUNK: [u8; 19] = hex!("b74c5bfef7beaa52c8f0bc0e992c1e8331fb66");
+    const PAYLOAD_SHA256: [u8; 32] =
+        hex!("013f54400c82da08037759ada907a8b864e97de81c088a182062c4b5622fd2ab");
+
+    #[test]
+    fn header_mac_known_answer() {
+        let mac = header_mac(&FILE_KEY, HEADER).finalize().into_bytes();
+        assert_eq!(base64_encode(&mac), MAC);
+    }
+
+    #[test]
+    fn payload_known_answer() {
+        assert_eq!(chunk_nonce(0, true)[..], hex!("000000000000000000000001"));
+        assert_eq!(
+            chunk_nonce(0x0102, false)[..],
+            hex!("000000000000000000010200")
+        );
+        let plaintext = payload_key(&FILE_KEY, &NONCE)
+            .decrypt(&chunk_nonce(0, true), &CHUNK[..])
+            .unwrap();
+        assert_eq!(Sha256::digest(&plaintext)[..], PAYLOAD_SHA256);
+    }
+}
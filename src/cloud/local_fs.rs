@@ -1,11 +1,13 @@
 #![cfg(feature = "local-fs")]
 
+use std::io::SeekFrom;
+use std::ops::Range;
 use std::path::PathBuf;
 use std::pin::Pin;
 
 use futures::Future;
 use snafu::{ResultExt, Snafu};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 use super::{CloudProvider, Key};
 
@@ -66,4 +68,21 @@ impl CloudProvider for LocalFs {
             Ok(file)
         })
     }
+
+    fn download_range<'a>(
+        &'a self,
+        key: Key,
+        range: Range<u64>,
+    ) -> Pin<Box<dyn 'a + Future<Output = Result<Pin<Box<dyn 'a + AsyncRead>>, Self::Error>>>> {
+        Box::pin(async move {
+            let path = self.root.join(&key.0);
+            let mut file = tokio::fs::File::open(path).await.context(IoFailed)?;
+            file.seek(SeekFrom::Start(range.start))
+                .await
+                .context(IoFailed)?;
+            let length = range.end.saturating_sub(range.start);
+            let reader: Pin<Box<dyn AsyncRead>> = Box::pin(file.take(length));
+            Ok(reader)
+        })
+    }
 }
@@ -1,9 +1,16 @@
+use std::io;
+use std::ops::Range;
 use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use futures::Future;
-use tokio::io::AsyncRead;
+use futures::{ready, Future};
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 
+pub mod compression;
+pub mod encryption;
 pub mod local_fs;
+pub mod parity;
 pub mod state;
 
 /// Key of archive in cloud.
@@ -31,6 +38,62 @@ pub trait CloudProvider {
         &'a self,
         key: Key,
     ) -> Pin<Box<dyn 'a + Future<Output = Result<Self::DownloadReader<'a>, Self::Error>>>>;
+
+    /// Downloads only given bytes of the archive.
+    ///
+    /// By default the whole archive is downloaded and unneeded bytes are thrown away,
+    /// providers that support ranged requests should override it.
+    #[allow(clippy::type_complexity)]
+    fn download_range<'a>(
+        &'a self,
+        key: Key,
+        range: Range<u64>,
+    ) -> Pin<Box<dyn 'a + Future<Output = Result<Pin<Box<dyn 'a + AsyncRead>>, Self::Error>>>> {
+        Box::pin(async move {
+            let reader = self.download(key).await?;
+            let length = range.end.saturating_sub(range.start);
+            let reader: Pin<Box<dyn AsyncRead>> =
+                Box::pin(SkipReader::new(reader, range.start).take(length));
+            Ok(reader)
+        })
+    }
+}
+
+pin_project! {
+    /// Reader that throws away given number of bytes before returning anything.
+    pub struct SkipReader<R> {
+        #[pin]
+        inner: R,
+        skip: u64,
+    }
+}
+
+impl<R> SkipReader<R> {
+    pub fn new(inner: R, skip: u64) -> Self {
+        Self { inner, skip }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for SkipReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        let mut scratch = [0; 8 * 1024];
+        while *this.skip > 0 {
+            let len = usize::try_from(*this.skip).map_or(scratch.len(), |x| x.min(scratch.len()));
+            let mut skipped = ReadBuf::new(&mut scratch[..len]);
+            ready!(this.inner.as_mut().poll_read(cx, &mut skipped))?;
+            if skipped.filled().is_empty() {
+                // Archive is shorter than expected, so there is nothing to return.
+                return Poll::Ready(Ok(()));
+            }
+            *this.skip -= skipped.filled().len() as u64;
+        }
+        this.inner.poll_read(cx, buf)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -0,0 +1,387 @@
+//! Reed-Solomon parity for archives, stored as a separate object next to them.
+//!
+//! Archive is split into blocks of [`BLOCK_SIZE`](BLOCK_SIZE) bytes, and every
+//! [`GROUP_SIZE`](GROUP_SIZE) blocks get a number of parity blocks, so redundancy is set in percents.
+//! Hashes of all blocks are stored too, so damaged blocks are known in advance
+//! and any of them can be rebuilt, as long as there are enough intact blocks in the group.
+//!
+//! Parity is computed over the bytes as they are uploaded, after compression and encryption:
+//! damaged archive can't be decrypted or decompressed before it is repaired.
+//!
+//! Parity file starts with [`MAGIC`](MAGIC), followed by parity blocks of all groups.
+//! [`Header`](Header) is known only after the whole archive is read, so it is written last,
+//! followed by its length as 8 bytes in big-endian.
+
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future::poll_fn;
+use futures::ready;
+use pin_project_lite::pin_project;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::types::Checksum;
+use crate::DefaultDigest;
+
+/// Size of a single block, both for data and parity.
+pub const BLOCK_SIZE: usize = 64 * 1024;
+/// Number of data blocks in each group. Since it is 100, redundancy percentage
+/// is exactly the number of parity blocks per group.
+pub const GROUP_SIZE: usize = 100;
+
+/// First bytes of every parity file, parity blocks start right after it.
+const MAGIC: &[u8] = b"colbak-parity\n";
+const VERSION: u32 = 2;
+/// Size of the header length at the end of parity file.
+const TRAILER_SIZE: usize = 8;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Redundancy should be from 1 to 100 percent, not {}", percent))]
+    InvalidRedundancy {
+        percent: u32,
+    },
+    IoFailed {
+        source: io::Error,
+    },
+    CodingFailed {
+        source: reed_solomon_erasure::Error,
+    },
+    /// Parity file is damaged itself or has unknown format.
+    InvalidParity,
+    #[snafu(display(
+        "Group {} has {} damaged blocks, but only {} intact parity blocks",
+        group,
+        damaged,
+        parity
+    ))]
+    Unrecoverable {
+        group: usize,
+        damaged: usize,
+        parity: usize,
+    },
+}
+
+/// Describes layout of the archive and its parity. Stored as JSON after parity blocks.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    block_size: usize,
+    group_size: usize,
+    /// Number of parity blocks for each group, including the last incomplete one.
+    parity_blocks: usize,
+    /// Length of the archive in bytes.
+    length: u64,
+    data_hashes: Vec<Checksum>,
+    parity_hashes: Vec<Checksum>,
+}
+
+/// Hash of a single block. The last block is padded with zeroes.
+fn block_hash(block: &[u8]) -> Checksum {
+    DefaultDigest::digest(block).into()
+}
+
+/// Computes parity while archive is being read, see [`wrap`](Self::wrap).
+///
+/// Parity blocks are written to the file as soon as their group is complete,
+/// so only a single group is kept in memory.
+pub struct ParityEncoder {
+    header: Header,
+    /// Data of the current group, up to `GROUP_SIZE * BLOCK_SIZE` bytes.
+    group: Vec<u8>,
+    /// Bytes that are not written to the file yet.
+    pending: Vec<u8>,
+    /// Number of bytes from `pending` that are already written.
+    written: usize,
+    file: File,
+}
+
+impl ParityEncoder {
+    /// Creates encoder that adds `percent` parity blocks to every hundred of data blocks.
+    /// Parity is written to the given file, which should be empty.
+    pub fn new(percent: u32, file: File) -> Result<Self, Error> {
+        snafu::ensure!((1..=100).contains(&percent), InvalidRedundancy { percent });
+        Ok(ParityEncoder {
+            header: Header {
+                version: VERSION,
+                block_size: BLOCK_SIZE,
+                group_size: GROUP_SIZE,
+                // Checked above.
+                parity_blocks: percent as usize,
+                length: 0,
+                data_hashes: Vec::new(),
+                parity_hashes: Vec::new(),
+            },
+            group: Vec::with_capacity(GROUP_SIZE * BLOCK_SIZE),
+            pending: MAGIC.to_vec(),
+            written: 0,
+            file,
+        })
+    }
+
+    /// Feeds next bytes of the archive.
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        self.header.length += data.len() as u64;
+        while !data.is_empty() {
+            let free = GROUP_SIZE * BLOCK_SIZE - self.group.len();
+            let (head, tail) = data.split_at(free.min(data.len()));
+            self.group.extend_from_slice(head);
+            data = tail;
+            if self.group.len() == GROUP_SIZE * BLOCK_SIZE {
+                self.flush_group()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_group(&mut self) -> Result<(), Error> {
+        if self.group.is_empty() {
+            return Ok(());
+        }
+        let blocks = (self.group.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        self.group.resize(blocks * BLOCK_SIZE, 0);
+
+        let mut shards: Vec<Vec<u8>> = self.group.chunks(BLOCK_SIZE).map(<[u8]>::to_vec).collect();
+        shards.resize(blocks + self.header.parity_blocks, vec![0; BLOCK_SIZE]);
+        let codec = ReedSolomon::new(blocks, self.header.parity_blocks).context(CodingFailed)?;
+        codec.encode(&mut shards).context(CodingFailed)?;
+
+        let (data, parity) = shards.split_at(blocks);
+        self.header
+            .data_hashes
+            .extend(data.iter().map(|x| block_hash(x)));
+        self.header
+            .parity_hashes
+            .extend(parity.iter().map(|x| block_hash(x)));
+        for block in parity {
+            self.pending.extend_from_slice(block);
+        }
+        self.group.clear();
+        Ok(())
+    }
+
+    /// Wraps reader of the archive, so parity is computed while it is read.
+    pub fn wrap<R: AsyncRead>(&mut self, reader: R) -> ParityReader<'_, R> {
+        ParityReader {
+            inner: reader,
+            encoder: self,
+        }
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let pending = &self.pending[self.written..];
+            let len = ready!(Pin::new(&mut self.file).poll_write(cx, pending))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += len;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes the rest of parity file and returns it. Should be called after the whole archive is read.
+    pub async fn finish(mut self) -> Result<File, Error> {
+        self.flush_group()?;
+        let header = serde_json::to_vec(&self.header).unwrap_or_default();
+        self.pending.extend_from_slice(&header);
+        self.pending
+            .extend_from_slice(&(header.len() as u64).to_be_bytes());
+        poll_fn(|cx| self.poll_write_pending(cx))
+            .await
+            .context(IoFailed)?;
+        self.file.flush().await.context(IoFailed)?;
+        Ok(self.file)
+    }
+}
+
+pin_project! {
+    /// Reader returned by [`ParityEncoder::wrap`](ParityEncoder::wrap).
+    pub struct ParityReader<'a, R> {
+        #[pin]
+        inner: R,
+        encoder: &'a mut ParityEncoder,
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for ParityReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        // Parity of the previous group is written before the next one is read.
+        ready!(this.encoder.poll_write_pending(cx))?;
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        let result = this.encoder.update(&buf.filled()[before..]);
+        Poll::Ready(result.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+    }
+}
+
+/// Result of [`repair`](repair).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of data blocks that were rebuilt.
+    pub repaired: usize,
+    /// Number of parity blocks that were damaged too.
+    pub damaged_parity: usize,
+}
+
+/// Reads block of the archive at given position, padding it with zeroes.
+/// Missing bytes at the end of damaged archive are treated as zeroes too.
+async fn read_block(archive: &mut File, index: usize, length: u64) -> Result<Vec<u8>, Error> {
+    let start = (index * BLOCK_SIZE) as u64;
+    // Never larger than a block.
+    #[allow(clippy::cast_possible_truncation)]
+    let valid = (length - start).min(BLOCK_SIZE as u64) as usize;
+    let mut block = vec![0; BLOCK_SIZE];
+    archive
+        .seek(SeekFrom::Start(start))
+        .await
+        .context(IoFailed)?;
+    let mut filled = 0;
+    while filled < valid {
+        let len = archive
+            .read(&mut block[filled..valid])
+            .await
+            .context(IoFailed)?;
+        if len == 0 {
+            break;
+        }
+        filled += len;
+    }
+    Ok(block)
+}
+
+/// Reads exactly `len` bytes of parity file at given position.
+async fn read_parity(parity: &mut File, start: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let mut result = vec![0; len];
+    parity
+        .seek(SeekFrom::Start(start))
+        .await
+        .context(IoFailed)?;
+    match parity.read_exact(&mut result).await {
+        Ok(_) => Ok(result),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => InvalidParity.fail(),
+        Err(e) => Err(e).context(IoFailed),
+    }
+}
+
+/// Reads header of parity file and checks that it matches the file size.
+async fn read_header(parity: &mut File) -> Result<Header, Error> {
+    let size = parity.metadata().await.context(IoFailed)?.len();
+    let overhead = (MAGIC.len() + TRAILER_SIZE) as u64;
+    snafu::ensure!(size >= overhead, InvalidParity);
+    snafu::ensure!(
+        read_parity(parity, 0, MAGIC.len()).await? == MAGIC,
+        InvalidParity
+    );
+
+    let trailer = read_parity(parity, size - TRAILER_SIZE as u64, TRAILER_SIZE).await?;
+    let mut header_len = [0; TRAILER_SIZE];
+    header_len.copy_from_slice(&trailer);
+    let header_len = u64::from_be_bytes(header_len);
+    snafu::ensure!(header_len <= size - overhead, InvalidParity);
+    let start = size - TRAILER_SIZE as u64 - header_len;
+    let len = usize::try_from(header_len).ok().context(InvalidParity)?;
+    let header: Header = serde_json::from_slice(&read_parity(parity, start, len).await?)
+        .ok()
+        .context(InvalidParity)?;
+    snafu::ensure!(
+        header.version == VERSION
+            && header.block_size == BLOCK_SIZE
+            && header.group_size == GROUP_SIZE
+            && (header.length + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64
+                == header.data_hashes.len() as u64
+            && (header.parity_hashes.len() * BLOCK_SIZE) as u64 == start - MAGIC.len() as u64,
+        InvalidParity
+    );
+    Ok(header)
+}
+
+/// Rebuilds damaged blocks of downloaded archive in place, using its parity file.
+///
+/// Archive is also truncated or extended to its original length.
+/// Parity blocks are read only for groups with damaged blocks.
+pub async fn repair(archive: &mut File, parity: &mut File) -> Result<RepairReport, Error> {
+    let header = read_header(parity).await?;
+
+    let mut report = RepairReport {
+        repaired: 0,
+        damaged_parity: 0,
+    };
+    let groups = header.data_hashes.chunks(GROUP_SIZE);
+    let parity_groups = header.parity_hashes.chunks(header.parity_blocks);
+    for (group, (data_hashes, parity_hashes)) in groups.zip(parity_groups).enumerate() {
+        let mut shards = Vec::with_capacity(data_hashes.len() + parity_hashes.len());
+        let mut damaged = Vec::new();
+        for (i, expected) in data_hashes.iter().enumerate() {
+            let index = group * GROUP_SIZE + i;
+            let block = read_block(archive, index, header.length).await?;
+            if block_hash(&block) == *expected {
+                shards.push(Some(block));
+            } else {
+                damaged.push(index);
+                shards.push(None);
+            }
+        }
+        if damaged.is_empty() {
+            continue;
+        }
+
+        let mut intact = 0;
+        for (i, expected) in parity_hashes.iter().enumerate() {
+            let index = group * header.parity_blocks + i;
+            let start = (MAGIC.len() + index * BLOCK_SIZE) as u64;
+            let block = read_parity(parity, start, BLOCK_SIZE).await?;
+            if block_hash(&block) == *expected {
+                intact += 1;
+                shards.push(Some(block));
+            } else {
+                report.damaged_parity += 1;
+                shards.push(None);
+            }
+        }
+        snafu::ensure!(
+            damaged.len() <= intact,
+            Unrecoverable {
+                group,
+                damaged: damaged.len(),
+                parity: intact
+            }
+        );
+
+        let codec =
+            ReedSolomon::new(data_hashes.len(), parity_hashes.len()).context(CodingFailed)?;
+        codec.reconstruct_data(&mut shards).context(CodingFailed)?;
+        for index in damaged {
+            let block = shards[index - group * GROUP_SIZE]
+                .as_ref()
+                .context(InvalidParity)?;
+            let start = (index * BLOCK_SIZE) as u64;
+            // Never larger than a block.
+            #[allow(clippy::cast_possible_truncation)]
+            let valid = (header.length - start).min(BLOCK_SIZE as u64) as usize;
+            archive
+                .seek(SeekFrom::Start(start))
+                .await
+                .context(IoFailed)?;
+            archive.write_all(&block[..valid]).await.context(IoFailed)?;
+            report.repaired += 1;
+        }
+    }
+
+    archive.set_len(header.length).await.context(IoFailed)?;
+    archive.flush().await.context(IoFailed)?;
+    Ok(report)
+}
@@ -1,15 +1,26 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
 use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
 
-use rusqlite::params;
-use snafu::{Backtrace, ResultExt, Snafu};
+use rusqlite::{params, OptionalExtension};
+use snafu::{Backtrace, OptionExt, ResultExt, Snafu};
+use tokio::io::{AsyncRead, AsyncSeekExt, BufReader};
 
-use crate::cpio::Archive;
+use crate::cpio::pending::{ChangePolicy, LockStrategy, ReadOptions};
+use crate::cpio::reader::{NextItem, ReadFile, ReadingError};
+use crate::cpio::{Archive, Format, ManifestEntry, Offsets, Reader, UnknownFormat};
 use crate::fileinfo::Info;
-use crate::path::{External, Local};
+use crate::path::{EncodedPath, External, Local};
+use crate::progress::Observer;
 use crate::utils::Utils;
 use crate::DateTime;
 
-use super::{CloudProvider, FakeCloud, Key};
+use super::compression::{Codec, Compression, UnknownCodec};
+use super::encryption::{self, Identity, Recipient};
+use super::parity::{self, ParityEncoder};
+use super::{CloudProvider, FakeCloud, Key, SkipReader};
 
 #[derive(Snafu)]
 pub enum Error<C: CloudProvider> {
@@ -21,6 +32,46 @@ pub enum Error<C: CloudProvider> {
         source: C::Error,
         backtrace: Backtrace,
     },
+    #[snafu(display("Archive {} is not known", key))]
+    UnknownArchive {
+        key: String,
+    },
+    InvalidCodec {
+        source: UnknownCodec,
+    },
+    InvalidFormat {
+        source: UnknownFormat,
+    },
+    EncryptionFailed {
+        source: encryption::Error,
+    },
+    /// Archive is encrypted, but no identities were given.
+    MissingIdentity,
+    ReadFailed {
+        source: ReadingError,
+    },
+    #[snafu(display("There is no file at offset {}", offset))]
+    MissingEntry {
+        offset: u64,
+    },
+    ParityFailed {
+        source: parity::Error,
+    },
+    PackIdFailed {
+        source: std::io::Error,
+    },
+    /// Temporary parity file can't be created or read.
+    TempFileFailed {
+        source: std::io::Error,
+    },
+    #[snafu(display(
+        "Database schema version {} is newer than supported {}",
+        version,
+        SCHEMA_VERSION
+    ))]
+    UnsupportedSchema {
+        version: i64,
+    },
 }
 
 impl<C: CloudProvider> std::fmt::Debug for Error<C> {
@@ -36,6 +87,46 @@ impl<C: CloudProvider> std::fmt::Debug for Error<C> {
                 .field("source", source)
                 .field("backtrace", backtrace)
                 .finish(),
+            Self::UnknownArchive { key } => {
+                f.debug_struct("UnknownArchive").field("key", key).finish()
+            }
+            Self::InvalidCodec { source } => f
+                .debug_struct("InvalidCodec")
+                .field("source", source)
+                .finish(),
+            Self::InvalidFormat { source } => f
+                .debug_struct("InvalidFormat")
+                .field("source", source)
+                .finish(),
+            Self::EncryptionFailed { source } => f
+                .debug_struct("EncryptionFailed")
+                .field("source", source)
+                .finish(),
+            Self::MissingIdentity => f.debug_struct("MissingIdentity").finish(),
+            Self::ReadFailed { source } => f
+                .debug_struct("ReadFailed")
+                .field("source", source)
+                .finish(),
+            Self::MissingEntry { offset } => f
+                .debug_struct("MissingEntry")
+                .field("offset", offset)
+                .finish(),
+            Self::ParityFailed { source } => f
+                .debug_struct("ParityFailed")
+                .field("source", source)
+                .finish(),
+            Self::TempFileFailed { source } => f
+                .debug_struct("TempFileFailed")
+                .field("source", source)
+                .finish(),
+            Self::PackIdFailed { source } => f
+                .debug_struct("PackIdFailed")
+                .field("source", source)
+                .finish(),
+            Self::UnsupportedSchema { version } => f
+                .debug_struct("UnsupportedSchema")
+                .field("version", version)
+                .finish(),
         }
     }
 }
@@ -44,14 +135,93 @@ impl<C: CloudProvider> std::fmt::Debug for Error<C> {
 pub struct State<C: CloudProvider> {
     db: rusqlite::Connection,
     cloud: C,
+    format: Format,
+    compression: Compression,
+    /// Archives are encrypted when there is at least one recipient.
+    recipients: Vec<Recipient>,
+    /// Redundancy of parity in percents, see [`parity`](super::parity).
+    parity: Option<u32>,
+    /// How files are read while they are uploaded.
+    read_options: ReadOptions,
+    progress: Option<Arc<dyn Observer>>,
 }
 
 pub struct UploadedArchive {
     pub key: Key,
-    pub files: Vec<Info<External>>,
+    pub format: Format,
+    /// Codec the archive was compressed with before uploading.
+    pub codec: Codec,
+    /// Whether the archive was encrypted after compression.
+    pub encrypted: bool,
+    /// Key of the parity file, when it was uploaded.
+    pub parity: Option<Key>,
+    /// Identifier stored in the manifest of the archive.
+    pub pack_id: Option<String>,
+    pub files: Vec<ManifestEntry<External>>,
     pub uploaded_at: DateTime,
 }
 
+/// Location of a single file in the cloud, see [`State::locate`](State::locate).
+pub struct StoredFile {
+    pub key: Key,
+    /// Offsets in the uncompressed and decrypted archive.
+    pub offsets: Offsets,
+    pub size: u64,
+}
+
+/// Version of the schema, stored as `user_version` of the database.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Columns that were added after the tables were created.
+/// Databases written before the schema was versioned may lack any of them.
+const ADDED_COLUMNS: [(&str, &str, &str); 11] = [
+    ("archives", "codec", "TEXT NOT NULL DEFAULT 'none'"),
+    ("archives", "encrypted", "INTEGER NOT NULL DEFAULT 0"),
+    ("archives", "parity", "TEXT"),
+    ("archives", "format", "TEXT NOT NULL DEFAULT 'bin'"),
+    ("archives", "pack_id", "TEXT"),
+    ("contents", "path", "BLOB"),
+    ("contents", "size", "INTEGER"),
+    ("contents", "header_offset", "INTEGER"),
+    ("contents", "data_offset", "INTEGER"),
+    ("contents", "changed", "TEXT"),
+    // Hard links have no content, so they point to the header of the entry that has it.
+    ("contents", "link_offset", "INTEGER"),
+];
+
+/// Creates tables, or adds missing columns to ones written by older versions.
+fn migrate(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let txn = db.unchecked_transaction()?;
+    txn.execute_batch(
+        r#"
+            CREATE TABLE IF NOT EXISTS archives(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                key TEXT,
+                uploaded_at TEXT
+            );
+            CREATE TABLE IF NOT EXISTS contents(
+                hash TEXT,
+                archive INTEGER REFERENCES archives(id)
+            );
+        "#,
+    )?;
+    for (table, column, definition) in ADDED_COLUMNS {
+        let exists: bool = txn.query_row(
+            "SELECT count(*) > 0 FROM pragma_table_info(?) WHERE name = ?",
+            params![table, column],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            txn.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, definition
+            ))?;
+        }
+    }
+    txn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+    txn.commit()
+}
+
 impl<C: CloudProvider> State<C> {
     pub fn fake<P: AsRef<Path>>(
         path: P,
@@ -59,24 +229,86 @@ impl<C: CloudProvider> State<C> {
         State::open(path, FakeCloud)
     }
 
-    /// Opens database file at specified path.
+    /// Opens database file at specified path, upgrading its schema when needed.
     pub fn open<P: AsRef<Path>>(path: P, cloud: C) -> Result<Self, Error<C>> {
         let db = rusqlite::Connection::open(path).context(SqliteFailed)?;
-        db.execute_batch(
-            r#"
-                CREATE TABLE IF NOT EXISTS archives(
-                    id PRIMARY KEY AUTOINCREMENT,
-                    key TEXT,
-                    uploaded_at TEXT
-                );
-                CREATE TABLE IF NOT EXISTS contents(
-                    hash TEXT,
-                    archive INTEGER FOREIGN KEY REFERENCES archives(id)
-                );
-            "#,
-        )
-        .context(SqliteFailed)?;
-        Ok(State { db, cloud })
+        let version: i64 = db
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))
+            .context(SqliteFailed)?;
+        snafu::ensure!(version <= SCHEMA_VERSION, UnsupportedSchema { version });
+        if version < SCHEMA_VERSION {
+            migrate(&db).context(SqliteFailed)?;
+        }
+        Ok(State {
+            db,
+            cloud,
+            format: Format::default(),
+            compression: Compression::default(),
+            recipients: Vec::new(),
+            parity: None,
+            read_options: ReadOptions::default(),
+            progress: None,
+        })
+    }
+
+    /// Sets format of archives uploaded later. By default it is [`Binary`](Format::Binary).
+    #[must_use]
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Encrypts archives uploaded later, so only given recipients can decrypt them.
+    /// By default they are not encrypted.
+    #[must_use]
+    pub fn with_encryption(mut self, recipients: Vec<Recipient>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+
+    /// Sets compression for archives uploaded later. By default they are not compressed.
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Uploads parity file along with every archive uploaded later,
+    /// so damaged archives can be repaired. Redundancy is set in percents, from 1 to 100.
+    #[must_use]
+    pub fn with_parity(mut self, percent: u32) -> Self {
+        self.parity = Some(percent);
+        self
+    }
+
+    /// Reports progress of archives that are uploaded, see [`Archive::with_progress`].
+    #[must_use]
+    pub fn with_progress(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.progress = Some(observer);
+        self
+    }
+
+    /// Sets what to do with files that are changed or deleted while they are uploaded.
+    /// By default the whole upload fails.
+    #[must_use]
+    pub fn with_change_policy(mut self, policy: ChangePolicy) -> Self {
+        self.read_options.policy = policy;
+        self
+    }
+
+    /// Sets how files are locked while they are uploaded. By default they are locked exclusively.
+    #[must_use]
+    pub fn with_lock(mut self, lock: LockStrategy) -> Self {
+        self.read_options.lock = lock;
+        self
+    }
+
+    /// Checks that files were not changed since the snapshot right after they are opened,
+    /// see [`Archive::with_recheck`](Archive::with_recheck).
+    #[must_use]
+    pub fn with_recheck(mut self, recheck: bool) -> Self {
+        self.read_options.recheck = recheck;
+        self
     }
 
     /// Puts information about uploaded archive to the database.
@@ -84,19 +316,66 @@ impl<C: CloudProvider> State<C> {
         let txn = self.db.transaction().context(SqliteFailed)?;
         let uploaded_at = archive.uploaded_at.format_rfc3339();
         txn.execute(
-            "INSERT INTO archives(key, uploaded_at) VALUES (?, ?)",
-            params![archive.key.0, uploaded_at],
+            "INSERT INTO archives(key, uploaded_at, codec, encrypted, parity, format, pack_id)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                archive.key.0,
+                uploaded_at,
+                archive.codec.name(),
+                archive.encrypted,
+                archive.parity.map(|x| x.0),
+                archive.format.name(),
+                archive.pack_id
+            ],
         )
         .context(SqliteFailed)?;
 
+        // First entry of every hard-linked file is the one with content.
+        let mut originals = HashMap::new();
+        for file in &archive.files {
+            if let (Some(id), Some(offsets)) = (file.info.hard_link_id(), file.offsets) {
+                originals.entry(id).or_insert(offsets.header);
+            }
+        }
+
         {
             let id = txn.last_insert_rowid();
             let mut query = txn
-                .prepare_cached("INSERT INTO contents(hash, archive) VALUES (?, ?)")
+                .prepare_cached(
+                    "INSERT INTO contents(
+                         hash, archive, path, size, header_offset, data_offset, changed, link_offset
+                     ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                )
                 .context(SqliteFailed)?;
             for file in archive.files {
-                let hash = file.hash.map(|hash| hash.to_string());
-                query.execute(params![hash, id]).context(SqliteFailed)?;
+                let original = file.info.hard_link_id().and_then(|x| originals.get(&x));
+                let link = original
+                    .copied()
+                    .filter(|x| Some(*x) != file.offsets.map(|x| x.header));
+                let hash = file.info.hash.map(|hash| hash.to_string());
+                let changed = file
+                    .changed
+                    .map(|x| serde_json::to_string(&x).unwrap_or_default());
+                // SQLite has signed integers only, but real archives are never that large.
+                #[allow(clippy::cast_possible_wrap)]
+                let (size, header, data, link) = (
+                    file.info.size().map(|x| x as i64),
+                    file.offsets.map(|x| x.header as i64),
+                    file.offsets.map(|x| x.data as i64),
+                    link.map(|x| x as i64),
+                );
+                query
+                    .execute(params![
+                        hash,
+                        id,
+                        file.info.path.as_bytes(),
+                        size,
+                        header,
+                        data,
+                        changed,
+                        link
+                    ])
+                    .context(SqliteFailed)?;
             }
         }
 
@@ -104,22 +383,249 @@ impl<C: CloudProvider> State<C> {
     }
 
     /// Uploads given files to the cloud.
-    pub async fn upload(&mut self, files: Vec<Info<Local>>) -> Result<(), Error<C>> {
-        let mut archive = Archive::new();
-        for f in files.clone() {
+    ///
+    /// Returns files that were changed or deleted meanwhile, when [change policy](Self::with_change_policy)
+    /// allows that. They are flagged in the database too.
+    pub async fn upload(
+        &mut self,
+        files: Vec<Info<Local>>,
+    ) -> Result<Vec<ManifestEntry<External>>, Error<C>> {
+        let options = self.read_options;
+        let pack_id = crate::packer::new_pack_id().context(PackIdFailed)?;
+        let mut archive = Archive::with_format(self.format)
+            .with_pack_id(pack_id.clone())
+            .with_change_policy(options.policy)
+            .with_lock(options.lock)
+            .with_recheck(options.recheck);
+        if let Some(progress) = &self.progress {
+            archive = archive.with_progress(Arc::clone(progress));
+        }
+        for f in files {
             archive.add(f);
         }
 
-        let reader = archive.read();
-        let key = self.cloud.upload(reader).await.context(CloudFailed)?;
+        let mut reader = self.compression.compress(archive.read());
+        let encrypted = !self.recipients.is_empty();
+        if encrypted {
+            let encrypted = encryption::encrypt(reader, &self.recipients).await;
+            reader = Box::pin(encrypted.context(EncryptionFailed)?);
+        }
+        // Parity is computed over the uploaded bytes, since damaged archive can't be decrypted.
+        let (key, parity) = match self.parity {
+            Some(percent) => {
+                let temp = std::env::temp_dir().join(format!("colbak-parity-{}", pack_id));
+                let (key, parity) = self.upload_with_parity(reader, percent, &temp).await?;
+                (key, Some(parity))
+            }
+            None => (self.cloud.upload(reader).await.context(CloudFailed)?, None),
+        };
 
-        let files = files.into_iter().map(Info::cast).collect();
+        // Offsets are known only after the archive is written.
+        let files: Vec<_> = archive
+            .manifest()
+            .files
+            .into_iter()
+            .map(ManifestEntry::cast)
+            .collect();
+        let changed = files.iter().filter(|x| x.changed.is_some()).cloned();
+        let changed = changed.collect();
         let uploaded = UploadedArchive {
             key,
+            format: self.format,
+            codec: self.compression.codec(),
+            encrypted,
+            parity,
+            pack_id: Some(pack_id),
             files,
             uploaded_at: DateTime::now_utc(),
         };
         self.set_uploaded(uploaded)?;
-        Ok(())
+        Ok(changed)
+    }
+
+    /// Uploads archive and then its parity, which is written to a temporary file meanwhile.
+    async fn upload_with_parity(
+        &self,
+        reader: Pin<Box<dyn AsyncRead + '_>>,
+        percent: u32,
+        temp: &Path,
+    ) -> Result<(Key, Key), Error<C>> {
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(temp)
+            .await
+            .context(TempFileFailed)?;
+        let result = async {
+            let mut encoder = ParityEncoder::new(percent, file).context(ParityFailed)?;
+            let upload = self.cloud.upload(encoder.wrap(reader));
+            let key = upload.await.context(CloudFailed)?;
+            let mut sidecar = encoder.finish().await.context(ParityFailed)?;
+            sidecar
+                .seek(SeekFrom::Start(0))
+                .await
+                .context(TempFileFailed)?;
+            let parity = self.cloud.upload(sidecar).await.context(CloudFailed)?;
+            Ok((key, parity))
+        }
+        .await;
+        // Removed even when upload failed.
+        tokio::fs::remove_file(temp).await.ok();
+        result
+    }
+
+    /// Uploads [restore script](crate::restore_script), so archives can be extracted without colbak.
+    ///
+    /// It is never compressed or encrypted, since the script can't handle that anyway.
+    pub async fn upload_restore_script(&self) -> Result<Key, Error<C>> {
+        let script = crate::restore_script::SCRIPT.as_bytes();
+        self.cloud.upload(script).await.context(CloudFailed)
+    }
+
+    /// Returns codec that was used for given archive.
+    pub fn codec(&self, key: &Key) -> Result<Codec, Error<C>> {
+        let (codec, _) = self.stored_as(key)?;
+        Ok(codec)
+    }
+
+    /// Returns whether given archive is encrypted.
+    pub fn is_encrypted(&self, key: &Key) -> Result<bool, Error<C>> {
+        let (_, encrypted) = self.stored_as(key)?;
+        Ok(encrypted)
+    }
+
+    /// Returns format of given archive.
+    pub fn format(&self, key: &Key) -> Result<Format, Error<C>> {
+        let row: Option<String> = self
+            .db
+            .query_row(
+                "SELECT format FROM archives WHERE key = ?",
+                params![key.0],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(SqliteFailed)?;
+        let format = row.context(UnknownArchive { key: &key.0 })?;
+        format.parse().context(InvalidFormat)
+    }
+
+    fn stored_as(&self, key: &Key) -> Result<(Codec, bool), Error<C>> {
+        let row: Option<(String, bool)> = self
+            .db
+            .query_row(
+                "SELECT codec, encrypted FROM archives WHERE key = ?",
+                params![key.0],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .context(SqliteFailed)?;
+        let (codec, encrypted) = row.context(UnknownArchive { key: &key.0 })?;
+        Ok((codec.parse().context(InvalidCodec)?, encrypted))
+    }
+
+    /// Downloads parity file of given archive, when it was uploaded.
+    ///
+    /// It is used to [`repair`](parity::repair) the archive downloaded as is, with
+    /// [`CloudProvider::download`](CloudProvider::download). It should be saved to a file first.
+    pub async fn download_parity(
+        &self,
+        key: &Key,
+    ) -> Result<Option<Pin<Box<dyn AsyncRead + '_>>>, Error<C>> {
+        let row: Option<Option<String>> = self
+            .db
+            .query_row(
+                "SELECT parity FROM archives WHERE key = ?",
+                params![key.0],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(SqliteFailed)?;
+        let parity = match row.context(UnknownArchive { key: &key.0 })? {
+            Some(parity) => Key(parity),
+            None => return Ok(None),
+        };
+        let reader = self.cloud.download(parity).await.context(CloudFailed)?;
+        Ok(Some(Box::pin(reader)))
+    }
+
+    /// Downloads archive from the cloud, decrypting and decompressing it when needed.
+    ///
+    /// Identities are required only for encrypted archives.
+    pub async fn download(
+        &self,
+        key: Key,
+        identities: &[Identity],
+    ) -> Result<Pin<Box<dyn AsyncRead + '_>>, Error<C>> {
+        let (codec, encrypted) = self.stored_as(&key)?;
+        snafu::ensure!(!encrypted || !identities.is_empty(), MissingIdentity);
+        let reader = self.cloud.download(key).await.context(CloudFailed)?;
+        let mut reader: Pin<Box<dyn AsyncRead>> = Box::pin(reader);
+        if encrypted {
+            let decrypted = encryption::decrypt(BufReader::new(reader), identities).await;
+            reader = Box::pin(decrypted.context(EncryptionFailed)?);
+        }
+        Ok(codec.decompress(reader))
+    }
+
+    /// Finds the most recently uploaded copy of the file with given path.
+    ///
+    /// Files uploaded before offsets were recorded are not returned.
+    /// Hard links are resolved to the entry with their content, which is stored under another path.
+    pub fn locate(&self, path: &EncodedPath<External>) -> Result<Option<StoredFile>, Error<C>> {
+        let row: Option<(String, i64, i64, Option<i64>)> = self
+            .db
+            .query_row(
+                "SELECT archives.key,
+                     coalesce(original.header_offset, contents.header_offset),
+                     coalesce(original.data_offset, contents.data_offset),
+                     contents.size
+                 FROM contents JOIN archives ON contents.archive = archives.id
+                 LEFT JOIN contents AS original ON original.archive = contents.archive
+                     AND original.header_offset = contents.link_offset
+                 WHERE contents.path = ? AND contents.header_offset IS NOT NULL
+                 ORDER BY archives.id DESC LIMIT 1",
+                params![path.as_bytes()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()
+            .context(SqliteFailed)?;
+        // Values were written from unsigned integers.
+        #[allow(clippy::cast_sign_loss)]
+        Ok(row.map(|(key, header, data, size)| StoredFile {
+            key: Key(key),
+            offsets: Offsets {
+                header: header as u64,
+                data: data as u64,
+            },
+            size: size.unwrap_or(0) as u64,
+        }))
+    }
+
+    /// Downloads a single file found by [`locate`](Self::locate).
+    ///
+    /// Only bytes of this file are requested from the cloud when the archive is stored as is.
+    /// Compressed and encrypted archives can't be read from the middle,
+    /// so everything before the file is downloaded and thrown away.
+    pub async fn download_file(
+        &self,
+        file: StoredFile,
+        identities: &[Identity],
+    ) -> Result<ReadFile<Pin<Box<dyn AsyncRead + '_>>>, Error<C>> {
+        let (codec, encrypted) = self.stored_as(&file.key)?;
+        let start = file.offsets.header;
+        let stream: Pin<Box<dyn AsyncRead>> = if codec == Codec::None && !encrypted {
+            let padding = self.format(&file.key)?.data_padding(file.size);
+            let end = file.offsets.data + file.size + padding as u64;
+            let ranged = self.cloud.download_range(file.key, start..end).await;
+            ranged.context(CloudFailed)?
+        } else {
+            let whole = self.download(file.key, identities).await?;
+            Box::pin(SkipReader::new(whole, start))
+        };
+        match Reader::new(stream).advance().await.context(ReadFailed)? {
+            NextItem::File(file) => Ok(file),
+            NextItem::End(_) => MissingEntry { offset: start }.fail(),
+        }
     }
 }
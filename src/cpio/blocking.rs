@@ -0,0 +1,174 @@
+#![cfg(feature = "blocking")]
+//! Synchronous counterparts of [`Archive::read`](Archive::read) and [`Reader`](super::Reader),
+//! for tools that use `std::io` only.
+//!
+//! Both wrap the async ones, so output is exactly the same. Writer drives them on its own
+//! single-threaded tokio runtime, since files are opened on the blocking pool. Reader does not
+//! need a runtime at all: all its I/O is done in place, so futures are never pending.
+
+use super::reader::{self, ReadError, ReadingError, UnpackedArchive};
+use super::{Archive, Offsets};
+use crate::fileinfo::Info;
+use crate::path::{EncodedPath, External};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::runtime::Runtime;
+
+/// Writer of cpio archive, created by [`Archive::read_blocking`](Archive::read_blocking).
+pub struct Writer<'a> {
+    // Dropped before the runtime it is driven by.
+    inner: Pin<Box<dyn AsyncRead + 'a>>,
+    runtime: Runtime,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(archive: &'a mut Archive) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        Ok(Writer {
+            inner: Box::pin(archive.read()),
+            runtime,
+        })
+    }
+}
+
+impl Read for Writer<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.runtime.block_on(inner.read(buf))
+    }
+}
+
+/// Makes `std::io` type usable by async code. Every call blocks, so it is never pending.
+struct SyncIo<T>(T);
+
+impl<T: Read + Unpin> AsyncRead for SyncIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let read = self.get_mut().0.read(buf.initialize_unfilled())?;
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Seek + Unpin> AsyncSeek for SyncIo<T> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.get_mut().0.seek(position).map(drop)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(self.get_mut().0.stream_position())
+    }
+}
+
+impl<T: Write + Unpin> AsyncWrite for SyncIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().0.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().0.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Extractor of cpio archive, same as the [async one](super::Reader).
+pub struct Reader<R> {
+    inner: reader::Reader<SyncIo<R>>,
+}
+
+impl<R> Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Reader {
+            inner: reader::Reader::new(SyncIo(reader)),
+        }
+    }
+}
+
+impl<R: Seek + Unpin> Reader<R> {
+    /// Starts reading from the entry at given offsets, skipping everything before it.
+    ///
+    /// Hard links to files stored earlier are not detected in this case.
+    pub fn at(reader: R, offsets: Offsets) -> io::Result<Self> {
+        let inner = futures::executor::block_on(reader::Reader::at(SyncIo(reader), offsets))?;
+        Ok(Reader { inner })
+    }
+
+    /// Same as [`advance`](Self::advance), but damaged entries are skipped,
+    /// see [`reader::Reader::recover`].
+    pub fn recover(self) -> Result<NextItem<R>, ReadingError>
+    where
+        R: Read,
+    {
+        let next = match futures::executor::block_on(self.inner.recover())? {
+            reader::NextItem::File(inner) => NextItem::File(ReadFile { inner }),
+            reader::NextItem::End(unpacked) => NextItem::End(unpacked),
+        };
+        Ok(next)
+    }
+}
+
+impl<R: Read + Unpin> Reader<R> {
+    pub fn advance(self) -> Result<NextItem<R>, ReadingError> {
+        let next = match futures::executor::block_on(self.inner.advance())? {
+            reader::NextItem::File(inner) => NextItem::File(ReadFile { inner }),
+            reader::NextItem::End(unpacked) => NextItem::End(unpacked),
+        };
+        Ok(next)
+    }
+}
+
+pub enum NextItem<R> {
+    File(ReadFile<R>),
+    End(UnpackedArchive),
+}
+
+/// Represents single file being read from archive at this moment, see [`reader::ReadFile`].
+pub struct ReadFile<R> {
+    inner: reader::ReadFile<SyncIo<R>>,
+}
+
+impl<R: Read + Unpin> ReadFile<R> {
+    /// Writes contents of file to the provided writer.
+    pub fn drain_to<W: Write>(self, dst: &mut W) -> Result<Reader<R>, ReadError> {
+        let inner = futures::executor::block_on(self.inner.drain_to(&mut SyncIo(dst)))?;
+        Ok(Reader { inner })
+    }
+
+    /// Skips file on non-seekable reader
+    pub fn to_void(self) -> Result<Reader<R>, ReadError> {
+        self.drain_to(&mut io::sink())
+    }
+
+    /// Skips this file completely
+    pub fn skip(self) -> Result<Reader<R>, ReadError>
+    where
+        R: Seek,
+    {
+        let inner = futures::executor::block_on(self.inner.skip())?;
+        Ok(Reader { inner })
+    }
+
+    /// Extracts info about this file. Hash is not set.
+    pub fn info(&self) -> Info<External> {
+        self.inner.info()
+    }
+
+    /// When this entry is a hard link to a file extracted earlier, returns path of that file.
+    pub fn hard_link(&self) -> Option<&EncodedPath<External>> {
+        self.inner.hard_link()
+    }
+}
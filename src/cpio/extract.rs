@@ -0,0 +1,680 @@
+//! Writes entries of the archive to the local filesystem.
+//!
+//! Metadata is applied only after the whole archive is read: precise timestamps and extended
+//! attributes are stored in the manifest, and directories should be changed after their content.
+//! Holes of sparse files are recreated then too, since their layout is stored in the manifest as well,
+//! or in the headers of [`Pax`](super::Format::Pax) archives.
+//!
+//! Archive is not trusted: entries are never written outside of the output directory,
+//! neither with `..` in their paths nor through symbolic links extracted earlier.
+
+use std::fmt;
+use std::io;
+use std::mem::discriminant;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use snafu::{ResultExt, Snafu};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+use super::manifest::{ManifestEntry, ManifestError};
+use super::pending::Change;
+use super::reader::{ReadError, ReadFile, Reader, UnpackedArchive};
+use super::sparse::SparseMap;
+use crate::fileext;
+use crate::fileinfo::{Info, SpecialInfo, SpecialKind, UnspecifiedInfo};
+use crate::path::{EncodedPath, EscapedString, External, Local};
+use crate::progress::{Observer, Progress, Stage};
+use crate::stream_hash::stream_hash;
+use crate::types::Checksum;
+
+#[derive(Debug, Snafu)]
+pub enum ExtractError {
+    #[snafu(display("Path {:?} can't be used on this platform", path.escaped()))]
+    InvalidPath {
+        path: EncodedPath<External>,
+        source: os_str_bytes::EncodingError,
+    },
+    #[snafu(display("Can't create {:?}: {}", path, source))]
+    CreateFailed { path: PathBuf, source: io::Error },
+    #[snafu(display("Can't read {:?} from archive: {}", path, source))]
+    ReadFailed { path: PathBuf, source: ReadError },
+}
+
+/// What to do when extracted entry already exists.
+///
+/// Existing directories are always merged with extracted ones, and never replaced by files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Keep existing file and skip the entry.
+    Skip,
+    /// Replace existing file. It is removed first, so other hard links to it are not changed.
+    Overwrite,
+    /// Replace existing file only when it is older than the stored one.
+    KeepNewer,
+    /// Extract the entry under another name, adding `.1`, `.2` and so on.
+    Rename,
+}
+
+impl Default for Overwrite {
+    fn default() -> Self {
+        Overwrite::Skip
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "Unknown overwrite policy `{}`, expected one of: skip, overwrite, keep-newer, rename",
+    name
+))]
+pub struct UnknownOverwrite {
+    name: String,
+}
+
+impl FromStr for Overwrite {
+    type Err = UnknownOverwrite;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Overwrite::Skip),
+            "overwrite" => Ok(Overwrite::Overwrite),
+            "keep-newer" => Ok(Overwrite::KeepNewer),
+            "rename" => Ok(Overwrite::Rename),
+            _ => UnknownOverwriteContext { name: s }.fail(),
+        }
+    }
+}
+
+/// Something that was not extracted exactly as stored, but did not stop the extraction.
+#[derive(Debug)]
+pub enum Warning {
+    InvalidManifest {
+        source: ManifestError,
+    },
+    /// Entry would be written outside of the output directory, so it was skipped.
+    UnsafePath {
+        path: EncodedPath<External>,
+    },
+    /// Entry already exists, so it was skipped.
+    Exists {
+        path: PathBuf,
+    },
+    /// Entry already exists, so it was extracted under another name.
+    Renamed {
+        path: PathBuf,
+        renamed: PathBuf,
+    },
+    /// Hard link points to the file that was skipped.
+    MissingOriginal {
+        path: PathBuf,
+        original: EncodedPath<External>,
+    },
+    SkippedSymlink {
+        path: PathBuf,
+    },
+    SkippedSpecial {
+        path: PathBuf,
+        kind: SpecialKind,
+        source: io::Error,
+    },
+    SkippedUnknown {
+        path: PathBuf,
+    },
+    PathMismatch {
+        expected: EncodedPath<External>,
+        found: EncodedPath<External>,
+    },
+    HashMismatch {
+        path: PathBuf,
+        expected: Option<Checksum>,
+        found: Option<Checksum>,
+    },
+    /// Sparse file was extracted, but its holes can't be recreated.
+    HolesFailed {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Permissions, owner, timestamp or extended attribute can't be set.
+    MetadataFailed {
+        path: PathBuf,
+        what: &'static str,
+        source: io::Error,
+    },
+    /// These bytes of the archive were damaged, so they were skipped.
+    Damaged {
+        range: Range<u64>,
+    },
+    /// File is listed in the manifest, but its entry was damaged.
+    Lost {
+        path: EncodedPath<External>,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidManifest { source } => write!(f, "{}", source),
+            Self::UnsafePath { path } => {
+                write!(f, "skipping {:?}: it points outside", path.escaped())
+            }
+            Self::Exists { path } => write!(f, "skipping {:?}: it already exists", path),
+            Self::Renamed { path, renamed } => {
+                write!(f, "{:?} already exists, extracted to {:?}", path, renamed)
+            }
+            Self::MissingOriginal { path, original } => write!(
+                f,
+                "skipping hard link {:?}: {:?} was not extracted",
+                path,
+                original.escaped()
+            ),
+            Self::SkippedSymlink { path } => write!(f, "skipping symbolic link {:?}", path),
+            Self::SkippedSpecial { path, kind, source } => {
+                write!(f, "skipping {:?} {:?}: {}", kind, path, source)
+            }
+            Self::SkippedUnknown { path } => write!(f, "skipping unknown file {:?}", path),
+            Self::PathMismatch { expected, found } => write!(
+                f,
+                "path mismatch. Expected {:?}, found {:?}",
+                expected, found
+            ),
+            Self::HashMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "hash mismatch at {:?}. Expected {:?}, found {:?}",
+                path, expected, found
+            ),
+            Self::HolesFailed { path, source } => {
+                write!(f, "can't recreate holes in {:?}: {}", path, source)
+            }
+            Self::MetadataFailed { path, what, source } => {
+                write!(f, "can't set {} on {:?}: {}", what, path, source)
+            }
+            Self::Damaged { range } => write!(
+                f,
+                "skipped damaged bytes {}..{} of the archive",
+                range.start, range.end
+            ),
+            Self::Lost { path } => write!(f, "{:?} was lost in damaged part", path.escaped()),
+        }
+    }
+}
+
+/// Entry that was read from the archive.
+struct Extracted {
+    /// Read from the header, with hash of the written content.
+    info: Info<External>,
+    /// Where the entry was written to. `None` when it was skipped.
+    destination: Option<PathBuf>,
+    /// Layout and size of the file with holes from the header, used when there is no manifest.
+    sparse: Option<(SparseMap, u64)>,
+}
+
+/// Extracts archive entry by entry, see [`extract`](Self::extract) and [`finish`](Self::finish).
+pub struct Extractor {
+    output: PathBuf,
+    overwrite: Overwrite,
+    extracted: Vec<Extracted>,
+    warnings: Vec<Warning>,
+    progress: Option<Arc<dyn Observer>>,
+    /// Size of the content of entries extracted so far.
+    bytes: u64,
+}
+
+impl Extractor {
+    /// Files will be extracted into `output`, which should already exist.
+    #[must_use]
+    pub fn new(output: PathBuf) -> Self {
+        Extractor {
+            output,
+            overwrite: Overwrite::default(),
+            extracted: Vec::new(),
+            warnings: Vec::new(),
+            progress: None,
+            bytes: 0,
+        }
+    }
+
+    /// Sets what to do with already existing files. By default they are skipped.
+    #[must_use]
+    pub fn with_overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Reports every entry to the observer once it is extracted or skipped.
+    #[must_use]
+    pub fn with_progress(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.progress = Some(observer);
+        self
+    }
+
+    /// Where given entry should be extracted, when it does not exist yet.
+    ///
+    /// Leading slashes are removed, so absolute paths are extracted into the output too.
+    /// Paths with `..` components are never extracted, `None` is returned for them.
+    pub fn destination(
+        &self,
+        path: &EncodedPath<External>,
+    ) -> Result<Option<PathBuf>, ExtractError> {
+        let relative = path
+            .clone()
+            .cast::<Local>()
+            .to_path()
+            .context(InvalidPath { path: path.clone() })?;
+        let mut result = self.output.clone();
+        for component in relative.components() {
+            match component {
+                Component::Normal(name) => result.push(name),
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => return Ok(None),
+            }
+        }
+        Ok(Some(result))
+    }
+
+    /// Creates missing parents of the destination.
+    ///
+    /// Returns `false` when any of them is a symbolic link: it may point anywhere, and
+    /// archive could create such link itself to write outside of the output.
+    async fn create_parents(&self, path: &Path) -> Result<bool, ExtractError> {
+        let relative = match path.parent().map(|x| x.strip_prefix(&self.output)) {
+            Some(Ok(relative)) => relative,
+            _ => return Ok(true),
+        };
+        let mut current = self.output.clone();
+        for component in relative.components() {
+            current.push(component);
+            match tokio::fs::symlink_metadata(&current).await {
+                Ok(metadata) if metadata.file_type().is_symlink() => return Ok(false),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // Archives created from a list of files may have no entries for directories.
+                    tokio::fs::create_dir(&current)
+                        .await
+                        .context(CreateFailed { path: &current })?;
+                }
+                Err(e) => return Err(e).context(CreateFailed { path: current }),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Decides what to do with already existing file, according to [`Overwrite`](Overwrite) policy.
+    /// Returns path the entry should be written to, or `None` when it should be skipped.
+    async fn resolve(
+        &mut self,
+        info: &Info<External>,
+        path: PathBuf,
+    ) -> Result<Option<PathBuf>, ExtractError> {
+        let existing = match tokio::fs::symlink_metadata(&path).await {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(path)),
+            Err(e) => return Err(e).context(CreateFailed { path }),
+        };
+        if existing.is_dir() {
+            if matches!(info.data, UnspecifiedInfo::Dir(_)) {
+                return Ok(Some(path));
+            }
+            self.warnings.push(Warning::Exists { path });
+            return Ok(None);
+        }
+
+        let replace = match self.overwrite {
+            Overwrite::Skip => false,
+            Overwrite::Overwrite => true,
+            Overwrite::KeepNewer => existing
+                .modified()
+                .map(|modified| modified < info.modified_at)
+                .unwrap_or(true),
+            Overwrite::Rename => {
+                let name = path.file_name().unwrap_or_default().to_owned();
+                let mut counter = 0_u64;
+                let renamed = loop {
+                    counter += 1;
+                    let mut candidate = name.clone();
+                    candidate.push(format!(".{}", counter));
+                    let candidate = path.with_file_name(candidate);
+                    match tokio::fs::symlink_metadata(&candidate).await {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => break candidate,
+                        Err(e) => return Err(e).context(CreateFailed { path: candidate }),
+                    }
+                };
+                self.warnings.push(Warning::Renamed {
+                    path,
+                    renamed: renamed.clone(),
+                });
+                return Ok(Some(renamed));
+            }
+        };
+        if replace {
+            tokio::fs::remove_file(&path)
+                .await
+                .context(CreateFailed { path: &path })?;
+            Ok(Some(path))
+        } else {
+            self.warnings.push(Warning::Exists { path });
+            Ok(None)
+        }
+    }
+
+    /// Finds where the entry should be written, or reports why it should be skipped.
+    async fn prepare(&mut self, info: &Info<External>) -> Result<Option<PathBuf>, ExtractError> {
+        if let Some(path) = self.destination(&info.path)? {
+            if self.create_parents(&path).await? {
+                return self.resolve(info, path).await;
+            }
+        }
+        self.warnings.push(Warning::UnsafePath {
+            path: info.path.clone(),
+        });
+        Ok(None)
+    }
+
+    /// Creates special file, returning `false` when it is not allowed or not supported.
+    fn create_special(
+        &mut self,
+        path: &Path,
+        special: &SpecialInfo,
+        mode: u32,
+    ) -> Result<bool, ExtractError> {
+        match fileext::create_special(path, special, mode) {
+            Ok(()) => Ok(true),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                ) =>
+            {
+                self.warnings.push(Warning::SkippedSpecial {
+                    path: path.to_owned(),
+                    kind: special.kind,
+                    source: e,
+                });
+                Ok(false)
+            }
+            Err(e) => Err(e).context(CreateFailed { path }),
+        }
+    }
+
+    /// Writes single entry to the disk, returning reader of the next one.
+    pub async fn extract<R: AsyncRead + Unpin>(
+        &mut self,
+        file: ReadFile<R>,
+    ) -> Result<Reader<R>, ExtractError> {
+        let mut info = file.info();
+        let sparse = file.sparse();
+        let dst = if let Some(dst) = self.prepare(&info).await? {
+            dst
+        } else {
+            let reader = file.to_void().await.context(ReadFailed {
+                path: self.output.clone(),
+            })?;
+            self.push(Extracted {
+                info,
+                destination: None,
+                sparse,
+            });
+            return Ok(reader);
+        };
+
+        let mut skipped = false;
+        let reader = match &info.data {
+            UnspecifiedInfo::Dir(_) => {
+                if !dst.is_dir() {
+                    tokio::fs::create_dir(&dst)
+                        .await
+                        .context(CreateFailed { path: &dst })?;
+                }
+                file.to_void().await.context(ReadFailed { path: &dst })?
+            }
+            UnspecifiedInfo::File(_) => {
+                if let Some(original) = file.hard_link().cloned() {
+                    // Content was stored with the first link only.
+                    let found = self.extracted.iter().find(|x| x.info.path == original);
+                    info.hash = found.and_then(|x| x.info.hash);
+                    if let Some(original) = found.and_then(|x| x.destination.clone()) {
+                        tokio::fs::hard_link(original, &dst)
+                            .await
+                            .context(CreateFailed { path: &dst })?;
+                    } else {
+                        self.warnings.push(Warning::MissingOriginal {
+                            path: dst.clone(),
+                            original,
+                        });
+                        skipped = true;
+                    }
+                    file.to_void().await.context(ReadFailed { path: &dst })?
+                } else {
+                    let output = File::create(&dst)
+                        .await
+                        .context(CreateFailed { path: &dst })?;
+                    let mut hasher = stream_hash(output);
+                    let reader = file
+                        .drain_to(&mut hasher)
+                        .await
+                        .context(ReadFailed { path: &dst })?;
+                    // Otherwise pending writes may change modification time later.
+                    hasher.flush().await.context(CreateFailed { path: &dst })?;
+                    info.hash = Some(hasher.finalize().into());
+                    reader
+                }
+            }
+            UnspecifiedInfo::Symlink(link) => {
+                #[cfg(unix)]
+                {
+                    let target: std::ffi::OsString = os_str_bytes::OsStringBytes::from_raw_vec(
+                        link.target.clone(),
+                    )
+                    .context(InvalidPath {
+                        path: info.path.clone(),
+                    })?;
+                    tokio::fs::symlink(target, &dst)
+                        .await
+                        .context(CreateFailed { path: &dst })?;
+                }
+                #[cfg(not(unix))]
+                {
+                    let _ = link;
+                    self.warnings
+                        .push(Warning::SkippedSymlink { path: dst.clone() });
+                    skipped = true;
+                }
+                file.to_void().await.context(ReadFailed { path: &dst })?
+            }
+            UnspecifiedInfo::Special(special) => {
+                skipped = !self.create_special(&dst, special, info.mode)?;
+                file.to_void().await.context(ReadFailed { path: &dst })?
+            }
+            UnspecifiedInfo::Unknown(_) => {
+                self.warnings
+                    .push(Warning::SkippedUnknown { path: dst.clone() });
+                skipped = true;
+                file.to_void().await.context(ReadFailed { path: &dst })?
+            }
+        };
+        self.push(Extracted {
+            info,
+            destination: if skipped { None } else { Some(dst) },
+            sparse,
+        });
+        Ok(reader)
+    }
+
+    /// Remembers the entry that is not extracted, so the rest still match the manifest.
+    /// Its content should be skipped by the caller.
+    pub fn skip<R: AsyncRead + Unpin>(&mut self, file: &ReadFile<R>) {
+        self.extracted.push(Extracted {
+            info: file.info(),
+            destination: None,
+            sparse: None,
+        });
+    }
+
+    /// Remembers the entry for [`finish`](Self::finish), and reports it.
+    fn push(&mut self, extracted: Extracted) {
+        if extracted.destination.is_some() {
+            self.bytes += extracted.info.size().unwrap_or(0);
+        }
+        if let Some(progress) = &self.progress {
+            let done = Progress {
+                files: self.extracted.len() as u64 + 1,
+                bytes: self.bytes,
+            };
+            progress.update(Stage::Extracting, done, extracted.info.path.as_bytes());
+        }
+        self.extracted.push(extracted);
+    }
+
+    /// Compares extracted files with the manifest and applies their metadata.
+    ///
+    /// Permissions, modification times and extended attributes are always restored,
+    /// owners only when running as superuser. Manifest is preferred, since it stores timestamps
+    /// with nanoseconds, but headers are used for archives without it.
+    #[must_use]
+    pub fn finish(mut self, end: UnpackedArchive) -> Vec<Warning> {
+        if let Some(source) = end.warning {
+            self.warnings.push(Warning::InvalidManifest { source });
+        }
+        let recovery = end.recovery.unwrap_or_default();
+        for range in &recovery.skipped {
+            self.warnings.push(Warning::Damaged {
+                range: range.clone(),
+            });
+        }
+        for path in &recovery.lost {
+            self.warnings.push(Warning::Lost { path: path.clone() });
+        }
+        let mut expected = end
+            .manifest
+            .map(|manifest| manifest.files)
+            .unwrap_or_default()
+            .into_iter()
+            // Files that vanished while the archive was written are not stored.
+            .filter(|x| x.changed != Some(Change::Vanished))
+            .filter(|x| !recovery.is_lost(x));
+
+        let mut metadata = Vec::with_capacity(self.extracted.len());
+        for found in std::mem::take(&mut self.extracted) {
+            // Skipped entries are still counted, so the rest match the manifest.
+            let expected = expected.next();
+            let destination = match found.destination {
+                Some(destination) => destination,
+                None => continue,
+            };
+            let info = match expected {
+                Some(expected) if expected.info.path != found.info.path => {
+                    self.warnings.push(Warning::PathMismatch {
+                        expected: expected.info.path,
+                        found: found.info.path.clone(),
+                    });
+                    found.info
+                }
+                Some(ManifestEntry {
+                    info: mut expected,
+                    sparse,
+                    ..
+                }) => {
+                    // Kind decides how metadata is applied, so it is taken from the header:
+                    // manifest could claim that an extracted symbolic link is a regular file.
+                    if discriminant(&expected.data) != discriminant(&found.info.data) {
+                        expected.data = found.info.data;
+                    }
+                    // Only data of sparse files was extracted so far.
+                    let expected_hash = sparse.as_ref().map_or(expected.hash, |x| x.hash);
+                    let hash_match = expected_hash
+                        .zip(found.info.hash)
+                        .map(|(x, y)| x == y)
+                        .unwrap_or(true);
+                    if !hash_match {
+                        self.warnings.push(Warning::HashMismatch {
+                            path: destination.clone(),
+                            expected: expected_hash,
+                            found: found.info.hash,
+                        });
+                    }
+                    if let (Some(sparse), Some(size)) = (sparse, expected.size()) {
+                        self.expand(&sparse, &destination, size);
+                    }
+                    expected
+                }
+                None => {
+                    if let Some((sparse, size)) = &found.sparse {
+                        self.expand(sparse, &destination, *size);
+                    }
+                    found.info
+                }
+            };
+            metadata.push((info, destination));
+        }
+
+        // Directories go last, so creating their content does not change them anymore.
+        // Children are listed after parents, so they are changed first.
+        let (dirs, others): (Vec<_>, Vec<_>) = metadata
+            .into_iter()
+            .partition(|(info, _)| matches!(info.data, UnspecifiedInfo::Dir(_)));
+        let superuser = fileext::is_superuser();
+        for (info, destination) in others.into_iter().chain(dirs.into_iter().rev()) {
+            self.apply_metadata(&info, &destination, superuser);
+        }
+        self.warnings
+    }
+
+    /// Recreates holes of the sparse file. Hard links to it share the same layout,
+    /// so it is done only when the file still has the stored size.
+    fn expand(&mut self, sparse: &SparseMap, path: &Path, size: u64) {
+        let result = std::fs::symlink_metadata(path).and_then(|metadata| {
+            if metadata.is_file() && metadata.len() == sparse.stored_size() {
+                sparse.expand(path, size)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(source) = result {
+            self.warnings.push(Warning::HolesFailed {
+                path: path.to_owned(),
+                source,
+            });
+        }
+    }
+
+    fn apply_metadata(&mut self, info: &Info<External>, path: &Path, superuser: bool) {
+        let mut check = |what, result: io::Result<()>| {
+            if let Err(source) = result {
+                self.warnings.push(Warning::MetadataFailed {
+                    path: path.to_owned(),
+                    what,
+                    source,
+                });
+            }
+        };
+
+        for xattr in &info.xattrs {
+            check("extended attribute", fileext::write_xattr(path, xattr));
+        }
+        // Changing owner may reset setuid and setgid bits, so it goes before permissions.
+        if superuser {
+            check(
+                "owner",
+                fileext::set_owner(path, info.user_id, info.group_id),
+            );
+        }
+        // Permissions of symbolic links are never used, and setting them would follow the link.
+        #[cfg(unix)]
+        {
+            if !matches!(info.data, UnspecifiedInfo::Symlink(_)) {
+                check("permissions", fileext::set_mode(path, info.mode));
+            }
+            check(
+                "modification time",
+                fileext::set_modified(path, info.modified_at),
+            );
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Selects which entries of the archive should be read, see [`Filter`](Filter).
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use snafu::{OptionExt, Snafu};
+use tokio::io::{AsyncRead, AsyncSeek};
+
+use super::reader::{NextItem, ReadError, Reader, ReadingError};
+use crate::path::{EncodedPath, External};
+
+#[derive(Debug, Snafu)]
+pub enum GlobError {
+    #[snafu(display("Pattern `{}` has unclosed `[`", pattern))]
+    UnclosedClass { pattern: String },
+    #[snafu(display("Pattern `{}` ends with `\\`", pattern))]
+    TrailingEscape { pattern: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(u8),
+    /// `?`, any byte except `/`.
+    Any,
+    /// `*`, any number of bytes except `/`.
+    Star,
+    /// `**`, any number of bytes including `/`.
+    DoubleStar,
+    /// `**/`, any number of whole directories, including none.
+    Directories,
+    /// `[a-z]` or `[!a-z]`.
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+/// Shell-like pattern, matched against raw bytes of [`EncodedPath`](EncodedPath).
+///
+/// Supports `?`, `*`, `**` (matches `/` too), classes like `[a-z]` and `[!0-9]`,
+/// and `\` to escape any of them.
+///
+/// ```
+/// # use colbak_lib::cpio::filter::Glob;
+/// let glob = Glob::new("home/*/docs/**/*.txt").unwrap();
+/// assert!(glob.matches(b"home/user/docs/a.txt"));
+/// assert!(glob.matches(b"home/user/docs/deep/inside/b.txt"));
+/// assert!(!glob.matches(b"home/user/other/docs/a.txt"));
+/// assert!(Glob::new("[ab]?c").unwrap().matches(b"bxc"));
+/// assert!(!Glob::new("[!ab]?c").unwrap().matches(b"bxc"));
+/// assert!(Glob::new("[ab").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, GlobError> {
+        let bytes = pattern.as_bytes();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'?' => tokens.push(Token::Any),
+                b'*' if bytes.get(i + 1) == Some(&b'*') => {
+                    i += 1;
+                    if bytes.get(i + 1) == Some(&b'/') {
+                        i += 1;
+                        tokens.push(Token::Directories);
+                    } else {
+                        tokens.push(Token::DoubleStar);
+                    }
+                }
+                b'*' => tokens.push(Token::Star),
+                b'\\' => {
+                    i += 1;
+                    let escaped = bytes.get(i).context(TrailingEscape { pattern })?;
+                    tokens.push(Token::Literal(*escaped));
+                }
+                b'[' => {
+                    let (token, end) =
+                        Self::parse_class(bytes, i + 1).context(UnclosedClass { pattern })?;
+                    tokens.push(token);
+                    i = end;
+                }
+                byte => tokens.push(Token::Literal(byte)),
+            }
+            i += 1;
+        }
+        Ok(Glob { tokens })
+    }
+
+    /// Parses class starting after `[`, returns it with position of the closing `]`.
+    fn parse_class(bytes: &[u8], mut i: usize) -> Option<(Token, usize)> {
+        let negated = matches!(bytes.get(i), Some(b'!' | b'^'));
+        if negated {
+            i += 1;
+        }
+        let mut ranges = Vec::new();
+        // The first `]` is a literal, like in shells.
+        let start = i;
+        loop {
+            let byte = *bytes.get(i)?;
+            if byte == b']' && i != start {
+                return Some((Token::Class { negated, ranges }, i));
+            }
+            if bytes.get(i + 1) == Some(&b'-') && !matches!(bytes.get(i + 2), None | Some(b']')) {
+                ranges.push((byte, bytes[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((byte, byte));
+                i += 1;
+            }
+        }
+    }
+
+    /// Whether the whole path matches this pattern.
+    #[must_use]
+    pub fn matches(&self, path: &[u8]) -> bool {
+        Self::matches_tokens(&self.tokens, path)
+    }
+
+    fn matches_tokens(tokens: &[Token], path: &[u8]) -> bool {
+        let (token, rest) = match tokens.split_first() {
+            Some(split) => split,
+            None => return path.is_empty(),
+        };
+        let single = |matches: bool| matches && Self::matches_tokens(rest, &path[1..]);
+        match token {
+            Token::Literal(byte) => single(path.first() == Some(byte)),
+            Token::Any => single(matches!(path.first(), Some(x) if *x != b'/')),
+            Token::Class { negated, ranges } => single(matches!(
+                path.first(),
+                Some(x) if *x != b'/'
+                    && ranges.iter().any(|(from, to)| (from..=to).contains(&x)) != *negated
+            )),
+            Token::Star => {
+                let end = path.iter().position(|x| *x == b'/').unwrap_or(path.len());
+                (0..=end).any(|i| Self::matches_tokens(rest, &path[i..]))
+            }
+            Token::DoubleStar => (0..=path.len()).any(|i| Self::matches_tokens(rest, &path[i..])),
+            Token::Directories => {
+                Self::matches_tokens(rest, path)
+                    || path
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, x)| **x == b'/')
+                        .any(|(i, _)| Self::matches_tokens(rest, &path[i + 1..]))
+            }
+        }
+    }
+}
+
+impl FromStr for Glob {
+    type Err = GlobError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Glob::new(s)
+    }
+}
+
+/// Decides which entries should be read from the archive.
+///
+/// Patterns and listed paths also select everything inside of matched directories.
+/// Entry is selected when it matches any include pattern or listed path, or when there
+/// are none of them at all, and it matches no exclude patterns.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    files: HashSet<EncodedPath<External>>,
+}
+
+impl Filter {
+    /// Filter that selects everything.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn include(mut self, glob: Glob) -> Self {
+        self.include.push(glob);
+        self
+    }
+
+    #[must_use]
+    pub fn exclude(mut self, glob: Glob) -> Self {
+        self.exclude.push(glob);
+        self
+    }
+
+    /// Selects exactly this path, without interpreting any pattern characters.
+    #[must_use]
+    pub fn file(mut self, path: EncodedPath<External>) -> Self {
+        self.files.insert(path);
+        self
+    }
+
+    /// Whether nothing is filtered out.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.files.is_empty()
+    }
+
+    /// Path itself and all its parent directories.
+    fn ancestors(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+        let parents = path
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x == b'/')
+            .map(move |(i, _)| &path[..i]);
+        parents.chain(std::iter::once(path))
+    }
+
+    #[must_use]
+    pub fn matches(&self, path: &EncodedPath<External>) -> bool {
+        let path = path.as_bytes();
+        let matches_any = |globs: &[Glob]| {
+            Self::ancestors(path).any(|ancestor| globs.iter().any(|glob| glob.matches(ancestor)))
+        };
+        let listed = || {
+            Self::ancestors(path).any(|ancestor| {
+                self.files
+                    .contains(&EncodedPath::from_vec(ancestor.to_vec()))
+            })
+        };
+        let included = (self.include.is_empty() && self.files.is_empty())
+            || matches_any(&self.include)
+            || listed();
+        included && !matches_any(&self.exclude)
+    }
+}
+
+fn skip_failed(source: ReadError) -> ReadingError {
+    ReadingError::SkipFailed { source }
+}
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    /// Same as [`advance`](Self::advance), but entries not selected by the filter are
+    /// read and thrown away. They are not reported, so extraction uses [`advance`](Self::advance)
+    /// and [`Extractor::skip`](super::extract::Extractor::skip) instead.
+    pub async fn advance_matching(mut self, filter: &Filter) -> Result<NextItem<R>, ReadingError> {
+        loop {
+            match self.advance().await? {
+                NextItem::File(file) if !filter.matches(&file.info().path) => {
+                    self = file.to_void().await.map_err(skip_failed)?;
+                }
+                item => return Ok(item),
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> Reader<R> {
+    /// Same as [`advance_matching`](Self::advance_matching), but content of entries
+    /// not selected by the filter is not read at all.
+    pub async fn seek_matching(mut self, filter: &Filter) -> Result<NextItem<R>, ReadingError> {
+        loop {
+            match self.advance().await? {
+                NextItem::File(file) if !filter.matches(&file.info().path) => {
+                    self = file.skip().await.map_err(skip_failed)?;
+                }
+                item => return Ok(item),
+            }
+        }
+    }
+}
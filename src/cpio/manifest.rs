@@ -0,0 +1,378 @@
+//! Metadata stored in the `TRAILER!!!` entry, after all files.
+//!
+//! Older versions stored a bare JSON list of files there, it is still read as version 0.
+//! Manifest is not needed to extract files, so any problem with it should be reported
+//! as a warning only.
+
+use futures::channel::{mpsc, oneshot};
+use futures::task::{waker, ArcWake};
+use futures::{SinkExt, StreamExt};
+use serde::de::{self, Deserializer as _};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::Thread;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use super::pending::Change;
+use super::sparse::SparseMap;
+use crate::fileinfo::Info;
+use crate::path::PathKind;
+use crate::types::Checksum;
+use crate::DateTime;
+
+/// Version of the manifest written by this version of colbak.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Larger manifests are not read at all. It is enough for several hundred thousand files.
+pub const MAX_MANIFEST_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Size of a chunk passed to the parser at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Position of an entry in the archive, in bytes from its beginning.
+///
+/// They allow to read a single file without unpacking everything before it,
+/// see [`Reader::at`](super::Reader::at).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Offsets {
+    /// Where the header of the entry starts.
+    pub header: u64,
+    /// Where the content of the entry starts, right after the header, name and padding.
+    pub data: u64,
+}
+
+/// Information about a single file, as it is stored in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "P:")]
+pub struct ManifestEntry<P: PathKind> {
+    #[serde(flatten)]
+    pub info: Info<P>,
+    /// Not stored by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offsets: Option<Offsets>,
+    /// Layout of the file with holes. Hard links to it have the same layout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sparse: Option<SparseMap>,
+    /// Set when the file was changed while it was archived, so its content may be inconsistent.
+    /// Files that vanished are listed, but not stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<Change>,
+}
+
+impl<P: PathKind> ManifestEntry<P> {
+    /// Same as [`Info::cast`](Info::cast).
+    #[must_use]
+    pub fn cast<T: PathKind>(self) -> ManifestEntry<T> {
+        ManifestEntry {
+            info: self.info.cast(),
+            offsets: self.offsets,
+            sparse: self.sparse,
+            changed: self.changed,
+        }
+    }
+}
+
+/// Metadata of the whole archive together with the list of its files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "P:")]
+pub struct Manifest<P: PathKind> {
+    /// Version of the format, see [`MANIFEST_VERSION`](MANIFEST_VERSION).
+    pub version: u32,
+    /// Version of colbak that created the archive. Empty for version 0.
+    #[serde(default)]
+    pub colbak_version: String,
+    #[serde(default)]
+    pub created_at: Option<DateTime>,
+    /// Identifier of the pack this archive was created for.
+    #[serde(default)]
+    pub pack_id: Option<String>,
+    /// Hash of everything before the trailer.
+    #[serde(default)]
+    pub hash: Option<Checksum>,
+    pub files: Vec<ManifestEntry<P>>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum ManifestError {
+    #[snafu(display("Manifest is larger than {} bytes", limit))]
+    TooLarge { limit: u64 },
+    #[snafu(display("Manifest version {} is not supported", version))]
+    UnsupportedVersion { version: u32 },
+    #[snafu(display("Can't parse manifest: {}", source))]
+    InvalidJson { source: serde_json::Error },
+    #[snafu(display("Can't read manifest: {}", source))]
+    ReadFailed { source: io::Error },
+}
+
+/// Reads at most [`MAX_MANIFEST_SIZE`] bytes, and fails after them.
+struct Capped<R> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> Read for Capped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "manifest is too large",
+            ));
+        }
+        // Limited by the length of the buffer.
+        #[allow(clippy::cast_possible_truncation)]
+        let len = self.left.min(buf.len() as u64) as usize;
+        let read = self.inner.read(&mut buf[..len])?;
+        self.left -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Feeds chunks sent by [`Manifest::read`] to the parser.
+struct Chunks {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            match futures::executor::block_on(self.receiver.next()) {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+            self.position = 0;
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Wakes the thread that waits for the reader.
+struct Unpark(Thread);
+
+impl ArcWake for Unpark {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark();
+    }
+}
+
+/// Reads from async reader in place, parking the thread while it is pending.
+/// Errors of the reader are kept aside, so they are not mistaken for problems with the manifest.
+struct InPlace<'a, R> {
+    inner: &'a mut R,
+    failed: Option<io::Error>,
+}
+
+impl<R: AsyncRead + Unpin> Read for InPlace<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let waker = waker(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut buf = ReadBuf::new(buf);
+        loop {
+            match Pin::new(&mut *self.inner).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(())) => return Ok(buf.filled().len()),
+                Poll::Ready(Err(e)) => {
+                    let kind = e.kind();
+                    self.failed = Some(e);
+                    return Err(kind.into());
+                }
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+}
+
+/// Parses the manifest of any version. The version is checked as soon as it is read,
+/// since the rest may be completely different.
+struct Visitor<'a, P> {
+    unsupported: &'a mut Option<u32>,
+    kind: PhantomData<P>,
+}
+
+impl<'de, P: PathKind> de::Visitor<'de> for Visitor<'_, P> {
+    type Value = Manifest<P>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("manifest")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        // Version 0 is a bare list of files.
+        let mut files = Vec::new();
+        while let Some(file) = seq.next_element()? {
+            files.push(file);
+        }
+        Ok(Manifest {
+            version: 0,
+            colbak_version: String::new(),
+            created_at: None,
+            pack_id: None,
+            hash: None,
+            files,
+        })
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut version = None;
+        let mut colbak_version = String::new();
+        let mut created_at = None;
+        let mut pack_id = None;
+        let mut hash = None;
+        let mut files = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "version" => {
+                    let value = map.next_value()?;
+                    if !(1..=MANIFEST_VERSION).contains(&value) {
+                        *self.unsupported = Some(value);
+                        return Err(de::Error::custom("unsupported version"));
+                    }
+                    version = Some(value);
+                }
+                "colbak_version" => colbak_version = map.next_value()?,
+                "created_at" => created_at = map.next_value()?,
+                "pack_id" => pack_id = map.next_value()?,
+                "hash" => hash = map.next_value()?,
+                "files" => files = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(Manifest {
+            version: version.ok_or_else(|| de::Error::missing_field("version"))?,
+            colbak_version,
+            created_at,
+            pack_id,
+            hash,
+            files: files.ok_or_else(|| de::Error::missing_field("files"))?,
+        })
+    }
+}
+
+impl<P: PathKind> Manifest<P> {
+    /// Parses content of the trailer. Returns `None` when there is no manifest at all.
+    ///
+    /// Content may be followed by padding or any other garbage, it is ignored.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Manifest;
+    /// # use colbak_lib::path::External;
+    /// type M = Manifest<External>;
+    /// assert!(M::parse(b"\0\0\0\0").unwrap().is_none());
+    /// assert_eq!(M::parse(b"[]\0\0").unwrap().unwrap().version, 0);
+    /// assert_eq!(M::parse(br#"{"version":1,"files":[]}"#).unwrap().unwrap().version, 1);
+    /// assert!(M::parse(br#"{"version":100,"files":[]}"#).is_err());
+    /// assert!(M::parse(b"{").is_err());
+    /// ```
+    pub fn parse(content: &[u8]) -> Result<Option<Self>, ManifestError> {
+        Self::from_reader(content)
+    }
+
+    /// Same as [`parse`](Self::parse), but content is parsed while it is read, so it is never
+    /// kept in memory. Nothing is read after the manifest, or after [`MAX_MANIFEST_SIZE`] bytes.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Option<Self>, ManifestError> {
+        let mut capped = Capped {
+            inner: reader,
+            left: MAX_MANIFEST_SIZE,
+        };
+        let mut reader = io::BufReader::new(&mut capped);
+        loop {
+            let buffer = match reader.fill_buf() {
+                Ok(buffer) => buffer,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(source) => return Err(ManifestError::ReadFailed { source }),
+            };
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            // Padding before the manifest is skipped.
+            let start = buffer
+                .iter()
+                .position(|x| *x != 0 && !x.is_ascii_whitespace());
+            if let Some(start) = start {
+                reader.consume(start);
+                break;
+            }
+            let len = buffer.len();
+            reader.consume(len);
+        }
+
+        let mut unsupported = None;
+        let visitor = Visitor {
+            unsupported: &mut unsupported,
+            kind: PhantomData,
+        };
+        let parsed = serde_json::Deserializer::from_reader(reader).deserialize_any(visitor);
+        match (parsed, unsupported) {
+            (Ok(manifest), _) => Ok(Some(manifest)),
+            (Err(_), _) if capped.left == 0 => TooLarge {
+                limit: MAX_MANIFEST_SIZE,
+            }
+            .fail(),
+            (Err(_), Some(version)) => UnsupportedVersion { version }.fail(),
+            (Err(source), None) => Err(ManifestError::InvalidJson { source }),
+        }
+    }
+}
+
+impl<P: PathKind + Send + 'static> Manifest<P> {
+    /// Parses the manifest while it is read from `reader`, see [`from_reader`](Self::from_reader).
+    /// Errors of the reader are returned separately from problems with the manifest itself.
+    ///
+    /// Parser needs blocking reads, so within tokio runtime it runs on the blocking pool.
+    /// Otherwise the caller is blocked anyway, like [blocking reader](super::blocking::Reader) is,
+    /// and the manifest is parsed in place.
+    pub async fn read<R: AsyncRead + Unpin>(
+        reader: &mut R,
+    ) -> io::Result<Result<Option<Self>, ManifestError>> {
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            return Self::read_on(&runtime, reader).await;
+        }
+        let mut in_place = InPlace {
+            inner: reader,
+            failed: None,
+        };
+        let parsed = Self::from_reader(&mut in_place);
+        in_place.failed.map_or(Ok(parsed), Err)
+    }
+
+    /// Parses the manifest on the blocking pool of the runtime, passing it one chunk at a time.
+    async fn read_on<R: AsyncRead + Unpin>(
+        runtime: &tokio::runtime::Handle,
+        reader: &mut R,
+    ) -> io::Result<Result<Option<Self>, ManifestError>> {
+        let (mut sender, receiver) = mpsc::channel(1);
+        let (result, parsed) = oneshot::channel();
+        runtime.spawn_blocking(move || {
+            let chunks = Chunks {
+                receiver,
+                chunk: Vec::new(),
+                position: 0,
+            };
+            let _ = result.send(Self::from_reader(chunks));
+        });
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let read = reader.read(&mut chunk).await?;
+            chunk.truncate(read);
+            // Parser stops reading once the manifest is parsed.
+            if read == 0 || sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        parsed
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "manifest parser has stopped"))
+    }
+}
@@ -1,21 +1,414 @@
 #[macro_use]
 mod state_machine;
+pub mod blocking;
+pub mod extract;
+pub mod filter;
+pub mod manifest;
+mod newc;
 pub mod pending;
+pub mod read_ahead;
 pub mod reader;
+pub mod recovery;
 mod smart_read;
+pub mod source;
+pub mod sparse;
+pub mod tar;
+pub mod verify;
 mod writer;
 
-use crate::fileinfo::{Info, UnspecifiedInfo};
+use crate::fileinfo::{Info, SpecialInfo, SpecialKind, UnspecifiedInfo};
+use crate::progress::Observer;
+use crate::types::Checksum;
 use crate::DateTime;
-use pending::Pending;
+use pending::{Change, ChangePolicy, LockStrategy, Pending, ReadOptions};
 use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use crate::path::{EncodedPath, External, Local, PathKind};
+use crate::path::{EncodedPath, EscapedString, External, Local, PathKind};
+pub use manifest::{Manifest, ManifestEntry, Offsets};
+pub use newc::{checksum_update, NewcHeader};
 pub use reader::Reader;
+pub use tar::TarHeader;
+
+/// Archive formats supported by this archiver: variants of cpio and POSIX tar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    /// Old binary format, see [`CpioHeader`](CpioHeader).
+    /// Supports files up to 256TB, but only when archive is read by colbak.
+    Binary,
+    /// SVR4 portable format without checksums, see [`NewcHeader`](NewcHeader).
+    Newc,
+    /// Same as [`Newc`](Format::Newc), but stores sum of all bytes of each file.
+    Crc,
+    /// POSIX tar with pax extended headers, see [`tar`] module.
+    /// Has no limits on names and sizes, and keeps nanoseconds and extended attributes.
+    Pax,
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "Unknown archive format `{}`, expected one of: bin, newc, crc, pax",
+    name
+))]
+pub struct UnknownFormat {
+    name: String,
+}
+
+impl Format {
+    /// Detects format by first bytes of the header.
+    /// Two bytes are enough to detect [`Binary`](Format::Binary), others need six bytes.
+    ///
+    /// Magic of [`Pax`](Format::Pax) is in the middle of the header, so it is not detected here.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Format;
+    /// assert_eq!(Format::detect(b"070701"), Some(Format::Newc));
+    /// assert_eq!(Format::detect(b"070702"), Some(Format::Crc));
+    /// assert_eq!(Format::detect(b"070707"), None);
+    /// assert_eq!(Format::detect(b"07"), None);
+    /// ```
+    #[must_use]
+    pub fn detect(magic: &[u8]) -> Option<Format> {
+        match magic {
+            [a, b, ..] if CpioHeader::is_magic([*a, *b]) => Some(Format::Binary),
+            newc::NEWC_MAGIC => Some(Format::Newc),
+            newc::CRC_MAGIC => Some(Format::Crc),
+            _ => None,
+        }
+    }
+
+    /// Length of the header without name.
+    #[must_use]
+    pub fn header_len(self) -> usize {
+        match self {
+            Format::Binary => size_of::<CpioHeader>(),
+            Format::Newc | Format::Crc => NewcHeader::LEN,
+            Format::Pax => tar::BLOCK,
+        }
+    }
+
+    /// Name of the format, as it is accepted by [`from_str`](Self::from_str).
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Format;
+    /// assert_eq!(Format::Binary.name(), "bin");
+    /// assert_eq!(Format::Pax.name().parse::<Format>().ok(), Some(Format::Pax));
+    /// ```
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Binary => "bin",
+            Format::Newc => "newc",
+            Format::Crc => "crc",
+            Format::Pax => "pax",
+        }
+    }
+
+    /// Number of NUL bytes added after the name (including its NUL byte).
+    /// In [`Pax`](Format::Pax) format the name is a part of the header.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Format;
+    /// assert_eq!(Format::Binary.name_padding(11), 1);
+    /// assert_eq!(Format::Binary.name_padding(12), 0);
+    /// // Header is 110 bytes long, so 110 + 11 + 3 is a multiple of four.
+    /// assert_eq!(Format::Newc.name_padding(11), 3);
+    /// assert_eq!(Format::Crc.name_padding(2), 0);
+    /// ```
+    #[must_use]
+    pub fn name_padding(self, namesize: usize) -> usize {
+        match self {
+            Format::Binary => namesize % 2,
+            Format::Newc | Format::Crc => (4 - (NewcHeader::LEN + namesize) % 4) % 4,
+            Format::Pax => 0,
+        }
+    }
+
+    /// Number of NUL bytes added after the file data.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Format;
+    /// assert_eq!(Format::Binary.data_padding(15), 1);
+    /// assert_eq!(Format::Newc.data_padding(15), 1);
+    /// assert_eq!(Format::Newc.data_padding(13), 3);
+    /// assert_eq!(Format::Crc.data_padding(16), 0);
+    /// assert_eq!(Format::Pax.data_padding(15), 497);
+    /// ```
+    #[must_use]
+    pub fn data_padding(self, size: u64) -> usize {
+        let align = match self {
+            Format::Binary => 2,
+            Format::Newc | Format::Crc => 4,
+            Format::Pax => return tar::padding(size),
+        };
+        // Result is always less than 4.
+        #[allow(clippy::cast_possible_truncation)]
+        let padding = ((align - size % align) % align) as usize;
+        padding
+    }
+
+    /// Maximum size of a file that can be stored in this format.
+    #[must_use]
+    pub fn max_file_size(self) -> u64 {
+        match self {
+            Format::Binary => (1 << 48) - 1,
+            Format::Newc | Format::Crc => u32::MAX.into(),
+            Format::Pax => u64::MAX,
+        }
+    }
+
+    /// Whether device number can be stored in this format.
+    /// Old binary format keeps only eight bits of both major and minor numbers.
+    #[must_use]
+    pub fn fits_rdev(self, rdev: u64) -> bool {
+        let (major, minor) = split_rdev(rdev);
+        self != Format::Binary || (major <= 0xff && minor <= 0xff)
+    }
+
+    /// Whether target of symbolic link is stored as its content, like `cpio` does.
+    /// Tar stores it in the header instead.
+    #[must_use]
+    pub fn link_in_data(self) -> bool {
+        !matches!(self, Format::Pax)
+    }
+
+    /// Whether file checksum should be written to the header.
+    #[must_use]
+    pub fn has_checksum(self) -> bool {
+        matches!(self, Format::Crc)
+    }
+
+    /// Creates header for given info, correctly attaches filename and returns everything.
+    ///
+    /// `checksum` is ignored by every format except [`Crc`](Format::Crc).
+    #[must_use]
+    pub fn encode<K: PathKind>(self, info: &Info<K>, checksum: u32) -> Vec<u8> {
+        match self {
+            Format::Binary => CpioHeader::encode(info),
+            Format::Newc => NewcHeader::encode(info, false, 0),
+            Format::Crc => NewcHeader::encode(info, true, checksum),
+            Format::Pax => TarHeader::encode(info),
+        }
+    }
+
+    /// Generates `TRAILER!!!` entry followed by `content`.
+    /// Tar archive is ended by two zero blocks instead.
+    #[must_use]
+    pub fn trailer(self, content: &[u8]) -> Vec<u8> {
+        match self {
+            Format::Binary => CpioHeader::trailer(content),
+            Format::Newc => NewcHeader::trailer(false, content),
+            Format::Crc => NewcHeader::trailer(true, content),
+            Format::Pax => tar::trailer(content),
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Binary
+    }
+}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    /// Parses format name, using the same names as `cpio --format` and `pax -x` do.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Format;
+    /// assert_eq!("bin".parse::<Format>().ok(), Some(Format::Binary));
+    /// assert_eq!("newc".parse::<Format>().ok(), Some(Format::Newc));
+    /// assert_eq!("pax".parse::<Format>().ok(), Some(Format::Pax));
+    /// assert!("tar".parse::<Format>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bin" => Ok(Format::Binary),
+            "newc" => Ok(Format::Newc),
+            "crc" => Ok(Format::Crc),
+            "pax" => Ok(Format::Pax),
+            _ => Err(UnknownFormat {
+                name: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Header of a single entry, implemented by headers of every [format](Format).
+pub trait EntryHeader {
+    fn format(&self) -> Format;
+
+    /// Size of data following the header.
+    fn size(&self) -> u64;
+
+    /// Full `mode` field, including file type bits.
+    fn mode(&self) -> u32;
+
+    /// Returns `(device, inode)` when this entry is a file with several hard links.
+    /// Only the first entry with such pair has data, others are links to it.
+    fn hard_link_id(&self) -> Option<(u64, u64)>;
+
+    /// Sum of all file bytes, stored only in [`Crc`](Format::Crc) format.
+    fn checksum(&self) -> Option<u32> {
+        None
+    }
+
+    /// Extracts info from header, using provided name.
+    ///
+    /// `info.hash` will be set to None.
+    fn info(&self, name: &[u8]) -> Info<External>;
+}
+
+/// Returns `id` when `mode` describes a file with more than one link.
+fn file_link_id(mode: u32, links: u64, id: (u64, u64)) -> Option<(u64, u64)> {
+    let is_file = mode & 0o0170000 == 0o0100000;
+    (is_file && links > 1).then(|| id)
+}
+
+impl EntryHeader for CpioHeader {
+    fn format(&self) -> Format {
+        Format::Binary
+    }
+
+    fn size(&self) -> u64 {
+        CpioHeader::size(self)
+    }
+
+    fn mode(&self) -> u32 {
+        self.mode.into()
+    }
+
+    fn hard_link_id(&self) -> Option<(u64, u64)> {
+        let id = (0, decode_u32(self.dev_ino).into());
+        file_link_id(self.mode.into(), self.nlink.into(), id)
+    }
+
+    fn info(&self, name: &[u8]) -> Info<External> {
+        CpioHeader::info(self, name)
+    }
+}
+
+impl EntryHeader for NewcHeader {
+    fn format(&self) -> Format {
+        NewcHeader::format(self)
+    }
+
+    fn size(&self) -> u64 {
+        NewcHeader::size(self)
+    }
+
+    fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    fn hard_link_id(&self) -> Option<(u64, u64)> {
+        let id = (self.device(), self.ino.into());
+        file_link_id(self.mode, self.nlink.into(), id)
+    }
+
+    fn checksum(&self) -> Option<u32> {
+        NewcHeader::checksum(self)
+    }
+
+    fn info(&self, name: &[u8]) -> Info<External> {
+        NewcHeader::info(self, name)
+    }
+}
+
+/// Header of an entry in any of supported [formats](Format).
+#[derive(Debug)]
+pub enum Header {
+    Binary(CpioHeader),
+    Newc(NewcHeader),
+    Tar(TarHeader),
+}
+
+impl Header {
+    fn inner(&self) -> &dyn EntryHeader {
+        match self {
+            Header::Binary(header) => header,
+            Header::Newc(header) => header,
+            Header::Tar(header) => header,
+        }
+    }
+
+    #[must_use]
+    pub fn format(&self) -> Format {
+        self.inner().format()
+    }
+
+    /// Length of name, including the NUL byte.
+    #[must_use]
+    pub fn namesize(&self) -> usize {
+        match self {
+            Header::Binary(header) => header.namesize.into(),
+            Header::Newc(header) => header.namesize as usize,
+            Header::Tar(header) => header.name().len() + 1,
+        }
+    }
+
+    /// Size of data following the header.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.inner().size()
+    }
+
+    /// Full `mode` field, including file type bits.
+    #[must_use]
+    pub fn mode(&self) -> u32 {
+        self.inner().mode()
+    }
+
+    /// Returns `(device, inode)` when this entry is a file with several hard links.
+    /// Only the first entry with such pair has data, others are links to it.
+    #[must_use]
+    pub fn hard_link_id(&self) -> Option<(u64, u64)> {
+        self.inner().hard_link_id()
+    }
+
+    /// Returns true when this entry is a symbolic link, and it's target is stored as the content.
+    #[must_use]
+    pub fn is_symlink(&self) -> bool {
+        self.format().link_in_data() && self.mode() & 0o0170000 == S_IFLNK
+    }
+
+    /// Sum of all file bytes, stored only in [`Crc`](Format::Crc) format.
+    #[must_use]
+    pub fn checksum(&self) -> Option<u32> {
+        self.inner().checksum()
+    }
+
+    /// Returns true when current entry is an `TRAILER!!!` entry, or the end of tar archive.
+    ///
+    /// Note: name must be NUL-ended.
+    #[must_use]
+    pub fn is_trailer(&self, name: &[u8]) -> bool {
+        match self {
+            Header::Binary(header) => header.is_trailer(name),
+            Header::Newc(header) => header.is_trailer(name),
+            Header::Tar(header) => header.is_end(),
+        }
+    }
+
+    /// Extracts info from header, using provided name.
+    ///
+    /// `info.hash` will be set to None.
+    #[must_use]
+    pub fn info(&self, name: &[u8]) -> Info<External> {
+        self.inner().info(name)
+    }
+}
 
 /// This is header of old binary format. See [`man 5 cpio`](http://man.he.net/man5/cpio) for details.
+///
+/// Each field is a 16-bit word (or two words, most significant first) in some [byte order](ByteOrder).
+/// Headers are always written in [`WRITE_ORDER`](WRITE_ORDER), but both orders can be read.
 #[derive(Debug)]
 #[repr(C)]
 pub struct CpioHeader {
@@ -24,12 +417,17 @@ pub struct CpioHeader {
     /// Stores both `dev` and `ino` fields of cpio format.
     /// But we do not need to store `dev` and more important `inode` takes 4 bytes.
     /// So in this format `inode` spans over both `dev` and `ino`
+    ///
+    /// Hard links are detected by this field only, since there is no room for the device.
+    /// That is why linked files store their [group](pending::Pending::link_group) here.
     dev_ino: [u16; 2],
     mode: u16,
     uid: u16,
     gid: u16,
-    /// Number of links. This archiver does not aware of hard links, so this field is mostly useless.
-    /// It is set to `1` for files and TRAILER, to `2` for directories and `0` for everything else.
+    /// Number of links. It is set to `2` for directories, `1` for TRAILER and `0` for unknown entries.
+    ///
+    /// For files it is the number of hard links. When it is more than one,
+    /// data is stored only in the first entry with such inode, and others have zero size.
     nlink: u16,
     /// `rdev` field is used for storing higher bits of file size.
     /// This allows us to decode files up to 2^48 = 256TB, but is not supported by normal archivers.
@@ -43,6 +441,65 @@ pub struct CpioHeader {
 }
 
 const MAGIC: u16 = 0o070707;
+/// File type bits of symbolic link in `mode` field.
+const S_IFLNK: u32 = 0o0120000;
+/// File type bits of special files in `mode` field.
+const S_IFBLK: u32 = 0o0060000;
+const S_IFCHR: u32 = 0o0020000;
+const S_IFIFO: u32 = 0o0010000;
+const S_IFSOCK: u32 = 0o0140000;
+
+/// Byte order of words in headers produced by this archiver.
+/// It matches one used by `cpio` on the most popular little-endian machines.
+pub const WRITE_ORDER: ByteOrder = ByteOrder::Little;
+
+/// Byte order of 16-bit words in old binary format.
+///
+/// Classic `cpio` writes them in the native order of the machine,
+/// so reader should detect it from the magic, like GNU cpio does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    /// Magic looks like `0o143561` when read as little-endian.
+    Big,
+}
+
+impl ByteOrder {
+    /// Detects byte order from first two bytes of the header.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::ByteOrder;
+    /// assert_eq!(ByteOrder::detect([0xC7, 0x71]), Some(ByteOrder::Little));
+    /// assert_eq!(ByteOrder::detect([0x71, 0xC7]), Some(ByteOrder::Big));
+    /// assert_eq!(ByteOrder::detect(*b"07"), None);
+    /// ```
+    #[must_use]
+    pub fn detect(magic: [u8; 2]) -> Option<ByteOrder> {
+        if u16::from_le_bytes(magic) == MAGIC {
+            Some(ByteOrder::Little)
+        } else if u16::from_be_bytes(magic) == MAGIC {
+            Some(ByteOrder::Big)
+        } else {
+            None
+        }
+    }
+
+    #[must_use]
+    pub fn encode(self, word: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::Little => word.to_le_bytes(),
+            ByteOrder::Big => word.to_be_bytes(),
+        }
+    }
+
+    #[must_use]
+    pub fn decode(self, word: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(word),
+            ByteOrder::Big => u16::from_be_bytes(word),
+        }
+    }
+}
 const TRAILER: &[u8] = b"TRAILER!!!\0";
 
 // FIXME: Isn't it better to just use TRAILER.len()? Will it hurt performance so much?
@@ -100,7 +557,86 @@ pub fn encode_timestamp(x: i64) -> [u16; 2] {
     }
 }
 
+/// Returns file type bits of `mode`, number of links and size of data stored after the header.
+fn entry_kind<K: PathKind>(info: &Info<K>) -> (u32, u32, u64) {
+    match &info.data {
+        UnspecifiedInfo::File(file) => {
+            let links = info.links.clamp(1, u16::MAX.into());
+            // Clamped above.
+            #[allow(clippy::cast_possible_truncation)]
+            (0o0100000, links as u32, file.size)
+        }
+        UnspecifiedInfo::Dir(_) => (0o0040000, 2, 0),
+        // Target of the link is stored as its content, just like `cpio` does.
+        UnspecifiedInfo::Symlink(link) => (S_IFLNK, 1, link.target.len() as u64),
+        UnspecifiedInfo::Special(special) => {
+            let kind = match special.kind {
+                SpecialKind::BlockDevice => S_IFBLK,
+                SpecialKind::CharDevice => S_IFCHR,
+                SpecialKind::Fifo => S_IFIFO,
+                SpecialKind::Socket => S_IFSOCK,
+            };
+            (kind, 1, 0)
+        }
+        // No file type at all, so it is never mistaken for something else.
+        UnspecifiedInfo::Unknown(_) => (0, 0, 0),
+    }
+}
+
+/// Returns device number of device node, zero for everything else.
+fn entry_rdev<K: PathKind>(info: &Info<K>) -> u64 {
+    match &info.data {
+        UnspecifiedInfo::Special(special) => special.rdev,
+        _ => 0,
+    }
+}
+
+/// Returns true when `mode` describes a device node, so `rdev` field contains device number.
+fn is_device(mode: u32) -> bool {
+    matches!(mode & 0o0170000, S_IFBLK | S_IFCHR)
+}
+
+/// Splits device number into major and minor parts, using encoding of Linux `makedev`.
+fn split_rdev(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    // Both parts are 32-bit after shifts above.
+    #[allow(clippy::cast_possible_truncation)]
+    (major as u32, minor as u32)
+}
+
+/// Reverse of [`split_rdev`](split_rdev).
+fn join_rdev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (u64::from(major), u64::from(minor));
+    ((major & 0xfff) << 8) | ((major & !0xfff) << 32) | (minor & 0xff) | ((minor & !0xff) << 12)
+}
+
+/// Reverse of [`entry_kind`](entry_kind): restores kind of entry from its `mode`, size and device number.
+///
+/// Target of symbolic link is stored after the header, so it is left empty here.
+fn decode_kind(mode: u32, size: u64, rdev: u64) -> UnspecifiedInfo {
+    use crate::fileinfo::{DirInfo, FileInfo, SpecialInfo, SymlinkInfo, UnknownInfo};
+
+    let special = |kind| UnspecifiedInfo::Special(SpecialInfo { kind, rdev });
+    match mode & 0o0170000 {
+        0o0100000 => UnspecifiedInfo::File(FileInfo { size }),
+        0o0040000 => UnspecifiedInfo::Dir(DirInfo {}),
+        S_IFLNK => UnspecifiedInfo::Symlink(SymlinkInfo::default()),
+        S_IFBLK => special(SpecialKind::BlockDevice),
+        S_IFCHR => special(SpecialKind::CharDevice),
+        S_IFIFO => special(SpecialKind::Fifo),
+        S_IFSOCK => special(SpecialKind::Socket),
+        _ => UnspecifiedInfo::Unknown(UnknownInfo {}),
+    }
+}
+
 impl CpioHeader {
+    /// Checks whether these two bytes are the magic of old binary format, in any byte order.
+    #[must_use]
+    pub fn is_magic(magic: [u8; 2]) -> bool {
+        ByteOrder::detect(magic).is_some()
+    }
+
     /// Generates `TRAILER!!!` entry that marks end of archive.
     /// Can be followed by any given content, most normal archivers will handle it without major issues.
     #[must_use]
@@ -152,26 +688,7 @@ impl CpioHeader {
     /// Name must not exceed 65535 bytes,
     #[must_use]
     fn from_info<K: PathKind>(info: &Info<K>, name: &[u8]) -> Self {
-        let mode;
-        let nlink;
-        let filesize;
-        match &info.data {
-            UnspecifiedInfo::File(file) => {
-                mode = 0o0100000;
-                nlink = 1;
-                filesize = file.size;
-            }
-            UnspecifiedInfo::Dir(_) => {
-                mode = 0o0040000;
-                nlink = 2;
-                filesize = 0;
-            }
-            UnspecifiedInfo::Unknown(_) => {
-                mode = 0o0020000;
-                nlink = 0;
-                filesize = 0;
-            }
-        };
+        let (mode, nlink, filesize) = entry_kind(info);
         let mode = mode | (info.mode & (!0o0170000));
 
         // Name should include NUL byte, but it is not included in `name`.
@@ -179,7 +696,15 @@ impl CpioHeader {
         debug_assert!(u16::try_from(namesize).is_ok());
 
         let max_normal_size = u64::from(u32::MAX);
-        let rdev = filesize >> 32;
+        // Maximum file size is checked by the writer.
+        #[allow(clippy::cast_possible_truncation)]
+        let rdev = if is_device(mode) {
+            // Device numbers are stored like in 16-bit `dev_t`, larger ones are rejected.
+            let (major, minor) = split_rdev(entry_rdev(info));
+            (major << 8) | (minor & 0xff)
+        } else {
+            (filesize >> 32) as u32
+        };
         let filesize = filesize & max_normal_size;
         // Now maximum file size is 2^(32 + 16) = 2^48 = 256 TB
 
@@ -192,7 +717,7 @@ impl CpioHeader {
             mode: mode as u16,
             uid: info.user_id as u16,
             gid: info.group_id as u16,
-            nlink,
+            nlink: nlink as u16,
             rdev: rdev as u16,
             mtime: encode_timestamp(info.modified_at.unix_timestamp()),
             namesize: namesize as u16,
@@ -221,25 +746,67 @@ impl CpioHeader {
     }
 
     /// Decodes header from provided array, checking for correct magic.
+    /// Byte order is detected automatically.
     #[must_use]
     pub fn decode(data: [u8; size_of::<CpioHeader>()]) -> Option<Self> {
-        let header: CpioHeader = unsafe { std::mem::transmute(data) };
-        match header.magic {
-            MAGIC => Some(header),
-            _ => None,
-        }
+        let order = ByteOrder::detect([data[0], data[1]])?;
+        let mut words = data
+            .chunks_exact(2)
+            .map(|word| order.decode([word[0], word[1]]));
+        let mut next = || words.next().unwrap_or_default();
+        Some(CpioHeader {
+            magic: next(),
+            dev_ino: [next(), next()],
+            mode: next(),
+            uid: next(),
+            gid: next(),
+            nlink: next(),
+            rdev: next(),
+            mtime: [next(), next()],
+            namesize: next(),
+            filesize: [next(), next()],
+        })
     }
 
-    /// Encodes header into byte array.
+    /// Encodes header into byte array using [`WRITE_ORDER`](WRITE_ORDER).
     #[must_use]
     pub fn into_array(self) -> [u8; size_of::<Self>()] {
-        unsafe { std::mem::transmute(self) }
+        self.to_bytes(WRITE_ORDER)
+    }
+
+    /// Encodes header into byte array using given byte order.
+    #[must_use]
+    pub fn to_bytes(&self, order: ByteOrder) -> [u8; size_of::<Self>()] {
+        let words = [
+            self.magic,
+            self.dev_ino[0],
+            self.dev_ino[1],
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.rdev,
+            self.mtime[0],
+            self.mtime[1],
+            self.namesize,
+            self.filesize[0],
+            self.filesize[1],
+        ];
+        let mut result = [0; size_of::<Self>()];
+        for (word, dst) in words.iter().zip(result.chunks_exact_mut(2)) {
+            dst.copy_from_slice(&order.encode(*word));
+        }
+        result
     }
 
     /// Decodes full size from different fields.
     #[must_use]
     pub fn size(&self) -> u64 {
-        let higher = u64::from(self.rdev);
+        let higher = if is_device(self.mode.into()) {
+            0
+        } else {
+            u64::from(self.rdev)
+        };
         let lower = u64::from(decode_u32(self.filesize));
         (higher << 32) | lower
     }
@@ -249,17 +816,15 @@ impl CpioHeader {
     /// `info.hash` will be set to None.
     #[must_use]
     pub fn info(&self, name: &[u8]) -> Info<External> {
-        use crate::fileinfo::{DirInfo, FileInfo, UnknownInfo};
-
         debug_assert_eq!(self.namesize as usize - 1, name.len());
 
-        let kind = self.mode & 0o0170000;
         let mode = self.mode & 0o0000777;
-        let data = match kind {
-            0o0100000 => UnspecifiedInfo::File(FileInfo { size: self.size() }),
-            0o0040000 => UnspecifiedInfo::Dir(DirInfo {}),
-            _ => UnspecifiedInfo::Unknown(UnknownInfo {}),
+        let rdev = if is_device(self.mode.into()) {
+            join_rdev((self.rdev >> 8).into(), (self.rdev & 0xff).into())
+        } else {
+            0
         };
+        let data = decode_kind(self.mode.into(), self.size(), rdev);
 
         #[allow(clippy::unwrap_used)]
         let (created_at, modified_at) = (
@@ -271,51 +836,247 @@ impl CpioHeader {
         Info {
             path: EncodedPath::from_vec(name.to_vec()),
             inode: decode_u32(self.dev_ino).into(),
+            device: 0,
+            links: self.nlink.into(),
             mode: mode.into(),
             user_id: self.uid.into(),
             group_id: self.gid.into(),
             created_at,
             modified_at,
             hash: None,
+            xattrs: Vec::new(),
             data,
         }
     }
 }
 
+fn default_read_ahead() -> usize {
+    read_ahead::DEFAULT_BUDGET
+}
+
 /// Pending cpio archive, waiting for be written.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Archive {
+    #[serde(default)]
+    format: Format,
     files: Vec<Pending<Local>>,
+    #[serde(default = "DateTime::now_utc")]
+    created_at: DateTime,
+    #[serde(default)]
+    pack_id: Option<String>,
+    #[serde(default)]
+    read_options: ReadOptions,
+    /// Memory for files [opened ahead](read_ahead), in bytes.
+    #[serde(default = "default_read_ahead")]
+    read_ahead: usize,
+    /// Hash of everything before the trailer, known once the archive is written.
+    #[serde(default)]
+    hash: Option<Checksum>,
+    #[serde(skip)]
+    progress: Option<Arc<dyn Observer>>,
+    /// Positions of files with content, keyed by device and inode of their hard links.
+    /// It is not stored, so files added after deserialization are not linked to earlier ones.
+    #[serde(skip)]
+    links: HashMap<(u64, u64), usize>,
 }
 
 impl Archive {
+    /// Creates empty archive in the default [`Binary`](Format::Binary) format.
     #[must_use]
     pub fn new() -> Self {
-        Archive { files: Vec::new() }
+        Self::with_format(Format::default())
+    }
+
+    #[must_use]
+    pub fn with_format(format: Format) -> Self {
+        Archive {
+            format,
+            files: Vec::new(),
+            created_at: DateTime::now_utc(),
+            pack_id: None,
+            read_options: ReadOptions {
+                // Tar stores holes in a way other archivers understand, cpio can't do that.
+                sparse: format == Format::Pax,
+                ..ReadOptions::default()
+            },
+            read_ahead: read_ahead::DEFAULT_BUDGET,
+            hash: None,
+            progress: None,
+            links: HashMap::new(),
+        }
+    }
+
+    /// Stores given pack id in the manifest, so the archive can be matched with it later.
+    #[must_use]
+    pub fn with_pack_id(mut self, pack_id: String) -> Self {
+        self.pack_id = Some(pack_id);
+        self
+    }
+
+    /// Sets what to do with files that are changed or deleted while the archive is written.
+    /// By default the whole archive fails.
+    #[must_use]
+    pub fn with_change_policy(mut self, policy: ChangePolicy) -> Self {
+        self.read_options.policy = policy;
+        self
+    }
+
+    /// Sets how files are locked while they are read. By default they are locked exclusively,
+    /// waiting at most [`DEFAULT_LOCK_TIMEOUT`](pending::DEFAULT_LOCK_TIMEOUT) for other processes.
+    #[must_use]
+    pub fn with_lock(mut self, lock: LockStrategy) -> Self {
+        self.read_options.lock = lock;
+        self
+    }
+
+    /// Checks that every file is still the same as in its info right after it is opened,
+    /// comparing their [identifiers](crate::fileinfo::FileIdentifier). Differences are handled
+    /// according to the [change policy](Self::with_change_policy).
+    #[must_use]
+    pub fn with_recheck(mut self, recheck: bool) -> Self {
+        self.read_options.recheck = recheck;
+        self
+    }
+
+    /// Stores only data extents of files with holes, see [`sparse`] module. It is the default
+    /// for [`Pax`](Format::Pax) only: other archivers extract such cpio entries without holes.
+    #[must_use]
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.read_options.sparse = sparse;
+        self
+    }
+
+    /// Limits memory taken by first blocks of the files that are [opened ahead](read_ahead)
+    /// while the current one is written. Nothing is opened ahead when it is zero.
+    #[must_use]
+    pub fn with_read_ahead(mut self, budget: usize) -> Self {
+        self.read_ahead = budget;
+        self
+    }
+
+    /// Reports progress of [`read`](Self::read) to the observer: every entry is reported
+    /// as [`Archiving`](crate::progress::Stage::Archiving), and every chunk of file content
+    /// as [`Hashing`](crate::progress::Stage::Hashing).
+    #[must_use]
+    pub fn with_progress(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.progress = Some(observer);
+        self
+    }
+
+    #[must_use]
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    #[must_use]
+    pub fn read_options(&self) -> ReadOptions {
+        self.read_options
+    }
+
+    #[must_use]
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead
     }
 
     /// Adds file to the archive by it's path.
+    ///
+    /// When it is a hard link to some file that is already added, it's content won't be stored again.
+    /// [`Pax`](Format::Pax) has no type for sockets and unknown entries, so they are skipped.
     pub fn add(&mut self, file: Info<Local>) {
-        self.files.push(Pending::new(file));
+        if self.format == Format::Pax
+            && matches!(
+                file.data,
+                UnspecifiedInfo::Unknown(_)
+                    | UnspecifiedInfo::Special(SpecialInfo {
+                        kind: SpecialKind::Socket,
+                        ..
+                    })
+            )
+        {
+            log!(warn: "Skipping `{}`: it can't be stored in pax format", path=file.path.escaped());
+            return;
+        }
+        let mut pending = Pending::new(file);
+        if let Some(id) = pending.info.hard_link_id() {
+            let position = *self.links.entry(id).or_insert(self.files.len());
+            pending.link_group = Some(position as u64);
+            pending.link_to = Some(position).filter(|x| *x != self.files.len());
+        }
+        self.files.push(pending);
     }
 
-    /// Generates trailer with custom json-serialized metadata.
+    /// Adds entry which content is read from the `source`, see [`source`] module.
+    ///
+    /// `info` declares its metadata, it should be a [file](UnspecifiedInfo::File) with exact size
+    /// of the content. It is never a hard link, even when `info` says so.
+    pub fn add_source(&mut self, mut info: Info<Local>, source: impl source::Source + 'static) {
+        info.links = 1;
+        self.files
+            .push(Pending::with_source(info, Arc::new(source)));
+    }
+
+    /// Returns metadata of this archive as it is stored in the trailer.
+    ///
+    /// Hashes and offsets are known only for files that were already written by [`read`](Self::read).
+    /// Files that vanished before that are listed too, but without a hash.
     #[must_use]
-    pub fn trailer(&self) -> Vec<u8> {
-        let mut infos = Vec::with_capacity(self.files.len());
+    pub fn manifest(&self) -> Manifest<Local> {
+        let mut entries = Vec::with_capacity(self.files.len());
         for pending in &self.files {
             let mut info = pending.info.clone();
-            info.hash = pending.calculated.or(info.hash);
-            infos.push(info);
+            let original = match pending.link_to {
+                Some(original) => self.files.get(original),
+                None => Some(pending),
+            };
+            info.hash = original.and_then(|x| x.calculated).or(info.hash);
+            // Links are stored even when the original vanished.
+            let changed = original
+                .and_then(|x| x.changed)
+                .filter(|x| *x != Change::Vanished || pending.link_to.is_none());
+            if changed == Some(Change::Vanished) {
+                info.hash = None;
+            }
+            entries.push(ManifestEntry {
+                info,
+                offsets: pending.offsets,
+                sparse: original.and_then(|x| x.sparse.clone()),
+                changed,
+            });
         }
-        let content = serde_json::to_vec(&infos).unwrap_or_default();
-        CpioHeader::trailer(&content)
+        Manifest {
+            version: manifest::MANIFEST_VERSION,
+            colbak_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: Some(self.created_at),
+            pack_id: self.pack_id.clone(),
+            hash: self.hash,
+            files: entries,
+        }
+    }
+
+    /// Generates trailer with custom json-serialized metadata.
+    #[must_use]
+    pub fn trailer(&self) -> Vec<u8> {
+        let content = serde_json::to_vec(&self.manifest()).unwrap_or_default();
+        self.format.trailer(&content)
+    }
+
+    /// Files that were changed or deleted while the archive was written, see [`ChangePolicy`](ChangePolicy).
+    pub fn changed(&self) -> impl Iterator<Item = (&Info<Local>, Change)> {
+        self.files
+            .iter()
+            .filter_map(|x| x.changed.map(|change| (&x.info, change)))
     }
 
     /// Returns `AsyncRead` over contents of this archive.
     pub fn read(&mut self) -> impl tokio::io::AsyncRead + '_ {
         writer::Reader::new(self)
     }
+
+    /// Returns `std::io::Read` over contents of this archive, same as [`read`](Self::read) returns.
+    #[cfg(feature = "blocking")]
+    pub fn read_blocking(&mut self) -> std::io::Result<blocking::Writer<'_>> {
+        blocking::Writer::new(self)
+    }
 }
 
 impl Default for Archive {
@@ -0,0 +1,266 @@
+//! SVR4 portable format, also known as `newc`, and its `crc` variant.
+//! See [`man 5 cpio`](http://man.he.net/man5/cpio) for details.
+//!
+//! Unlike old binary format, every number here is stored as eight hexadecimal ASCII digits,
+//! so archive does not depend on byte order and can be unpacked by any `cpio -i`.
+
+use super::{
+    decode_kind, entry_kind, entry_rdev, join_rdev, split_rdev, Format, TRAILER, TRAILER_LEN,
+};
+use crate::fileinfo::{Info, UnspecifiedInfo};
+use crate::path::{EncodedPath, External, PathKind};
+use crate::DateTime;
+
+pub(super) const NEWC_MAGIC: &[u8] = b"070701";
+pub(super) const CRC_MAGIC: &[u8] = b"070702";
+
+/// Header of SVR4 portable format.
+#[derive(Debug, Clone)]
+pub struct NewcHeader {
+    /// When set, magic is `070702` and `check` contains sum of all file bytes.
+    /// Otherwise magic is `070701` and `check` is zero.
+    with_checksum: bool,
+    pub(super) ino: u32,
+    pub(super) mode: u32,
+    uid: u32,
+    gid: u32,
+    pub(super) nlink: u32,
+    mtime: u32,
+    /// Files larger than 4GB can't be stored in this format.
+    filesize: u32,
+    /// Device is used only to detect hard links, so it is simply split into two halves.
+    devmajor: u32,
+    devminor: u32,
+    rdevmajor: u32,
+    rdevminor: u32,
+    /// Length of name, including the NUL byte.
+    pub(super) namesize: u32,
+    check: u32,
+}
+
+/// Adds bytes to the checksum used by `crc` format. It is a simple sum of all bytes of the file.
+///
+/// ```
+/// # use colbak_lib::cpio::checksum_update;
+/// assert_eq!(checksum_update(0, b"\x01\x02\xFF"), 0x102);
+/// assert_eq!(checksum_update(u32::MAX, b"\x02"), 1);
+/// ```
+#[must_use]
+pub fn checksum_update(sum: u32, data: &[u8]) -> u32 {
+    data.iter()
+        .fold(sum, |sum, byte| sum.wrapping_add(u32::from(*byte)))
+}
+
+/// Parses eight hexadecimal digits.
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    let digits = std::str::from_utf8(digits).ok()?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+impl NewcHeader {
+    /// Length of encoded header: magic and 13 fields, eight bytes each.
+    pub const LEN: usize = 6 + 13 * 8;
+
+    #[must_use]
+    pub fn format(&self) -> Format {
+        if self.with_checksum {
+            Format::Crc
+        } else {
+            Format::Newc
+        }
+    }
+
+    /// Generates `TRAILER!!!` entry that marks end of archive, followed by `content`.
+    #[must_use]
+    pub fn trailer(with_checksum: bool, content: &[u8]) -> Vec<u8> {
+        let header = NewcHeader {
+            with_checksum,
+            ino: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            mtime: 0,
+            filesize: 0,
+            devmajor: 0,
+            devminor: 0,
+            rdevmajor: 0,
+            rdevminor: 0,
+            namesize: TRAILER_LEN.into(),
+            check: 0,
+        };
+        let padding = header.format().name_padding(TRAILER.len());
+
+        let mut res = Vec::with_capacity(Self::LEN + TRAILER.len() + padding + content.len());
+        res.extend_from_slice(&header.into_array());
+        res.extend_from_slice(TRAILER);
+        res.resize(res.len() + padding, 0);
+        res.extend_from_slice(content);
+        res
+    }
+
+    /// Returns true when current entry is an `TRAILER!!!` entry.
+    ///
+    /// Note: name must be NUL-ended.
+    #[must_use]
+    pub fn is_trailer(&self, name: &[u8]) -> bool {
+        self.filesize == 0 && name == TRAILER
+    }
+
+    /// Creates header for given info, correctly attaches filename and returns everything.
+    ///
+    /// Sizes over 4GB are truncated, caller should check them before.
+    #[must_use]
+    pub fn encode<K: PathKind>(info: &Info<K>, with_checksum: bool, checksum: u32) -> Vec<u8> {
+        let name = info.path.crop_name_to(u16::MAX - 1);
+        let (mode, nlink, filesize) = entry_kind(info);
+        let (rdevmajor, rdevminor) = split_rdev(entry_rdev(info));
+        debug_assert!(filesize <= u32::MAX.into());
+
+        // Name should include NUL byte, but it is not included in `name`.
+        let namesize = name.len() + 1;
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let header = NewcHeader {
+            with_checksum,
+            ino: info.inode as u32,
+            mode: mode | (info.mode & (!0o0170000)),
+            uid: info.user_id,
+            gid: info.group_id,
+            nlink,
+            mtime: info.modified_at.unix_timestamp().clamp(0, u32::MAX.into()) as u32,
+            filesize: filesize as u32,
+            devmajor: (info.device >> 32) as u32,
+            devminor: info.device as u32,
+            rdevmajor,
+            rdevminor,
+            namesize: namesize as u32,
+            check: match &info.data {
+                _ if !with_checksum => 0,
+                // Link target is not read by the writer, so checksum is computed here.
+                UnspecifiedInfo::Symlink(symlink) => checksum_update(0, &symlink.target),
+                _ => checksum,
+            },
+        };
+        let padding = header.format().name_padding(namesize);
+
+        let mut result = Vec::with_capacity(Self::LEN + namesize + padding);
+        result.extend_from_slice(&header.into_array());
+        result.extend_from_slice(&name);
+        result.push(0);
+        result.resize(result.len() + padding, 0);
+        result
+    }
+
+    /// Decodes header from provided array, checking for correct magic.
+    #[must_use]
+    pub fn decode(data: &[u8; Self::LEN]) -> Option<Self> {
+        let (magic, fields) = data.split_at(6);
+        let with_checksum = match magic {
+            NEWC_MAGIC => false,
+            CRC_MAGIC => true,
+            _ => return None,
+        };
+        let mut fields = fields.chunks_exact(8).map(parse_hex);
+        let mut next = || fields.next().flatten();
+        Some(NewcHeader {
+            with_checksum,
+            ino: next()?,
+            mode: next()?,
+            uid: next()?,
+            gid: next()?,
+            nlink: next()?,
+            mtime: next()?,
+            filesize: next()?,
+            devmajor: next()?,
+            devminor: next()?,
+            rdevmajor: next()?,
+            rdevminor: next()?,
+            namesize: next()?,
+            check: next()?,
+        })
+    }
+
+    /// Encodes header into byte array.
+    #[must_use]
+    pub fn into_array(self) -> [u8; Self::LEN] {
+        let magic = if self.with_checksum {
+            CRC_MAGIC
+        } else {
+            NEWC_MAGIC
+        };
+        let fields = [
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.mtime,
+            self.filesize,
+            self.devmajor,
+            self.devminor,
+            self.rdevmajor,
+            self.rdevminor,
+            self.namesize,
+            self.check,
+        ];
+
+        let mut result = [0; Self::LEN];
+        result[..6].copy_from_slice(magic);
+        for (field, dst) in fields.iter().zip(result[6..].chunks_exact_mut(8)) {
+            dst.copy_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        result
+    }
+
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.filesize.into()
+    }
+
+    /// Device containing the file, as it was stored by [`encode`](Self::encode).
+    #[must_use]
+    pub fn device(&self) -> u64 {
+        (u64::from(self.devmajor) << 32) | u64::from(self.devminor)
+    }
+
+    /// Sum of all file bytes, available only in `crc` format.
+    #[must_use]
+    pub fn checksum(&self) -> Option<u32> {
+        self.with_checksum.then(|| self.check)
+    }
+
+    /// Extracts info from header, using provided name.
+    ///
+    /// `info.hash` will be set to None.
+    #[must_use]
+    pub fn info(&self, name: &[u8]) -> Info<External> {
+        debug_assert_eq!(self.namesize as usize - 1, name.len());
+
+        #[allow(clippy::unwrap_used)]
+        let (created_at, modified_at) = (
+            DateTime::from_unix_timestamp(0).unwrap(),
+            // UNWRAP: Any u32 timestamp can be safely converted
+            DateTime::from_unix_timestamp(self.mtime.into()).unwrap(),
+        );
+
+        Info {
+            path: EncodedPath::from_vec(name.to_vec()),
+            inode: self.ino.into(),
+            device: self.device(),
+            links: self.nlink.into(),
+            mode: self.mode & 0o0000777,
+            user_id: self.uid,
+            group_id: self.gid,
+            created_at,
+            modified_at,
+            hash: None,
+            xattrs: Vec::new(),
+            data: decode_kind(
+                self.mode,
+                self.size(),
+                join_rdev(self.rdevmajor, self.rdevminor),
+            ),
+        }
+    }
+}
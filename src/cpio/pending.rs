@@ -1,19 +1,29 @@
+use crate::cpio::read_ahead::Prefetched;
 use crate::cpio::smart_read::{SmartBuf, SmartRead, SmartReader};
+use crate::cpio::source::{Limited, Source, SourceReader};
+use crate::cpio::sparse::{skip_extents, Extent, ExtentReader, HoleHasher, SparseMap};
 use crate::cpio::state_machine::{AdvanceResult, Advanceable};
-use crate::cpio::CpioHeader;
-use crate::fileinfo::{Info, UnspecifiedInfo};
-use crate::path::{Local, PathKind};
+use crate::cpio::tar::TarHeader;
+use crate::cpio::{checksum_update, Format, Offsets};
+use crate::fileinfo::{systime_to_datetime, FileIdentifier, FileInfo, Info, UnspecifiedInfo};
+use crate::path::{EscapedString, Local, PathKind};
+use crate::progress::{Observer, Progress, Stage};
 use crate::types::Checksum;
-use crate::DefaultDigest;
+use crate::{DateTime, DefaultDigest};
 use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use snafu::{ResultExt, Snafu};
+use std::borrow::Cow;
+use std::fmt;
 use std::io;
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs::File;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// File in archive that is not archived yet.
 ///
@@ -24,14 +34,296 @@ pub struct Pending<P: PathKind> {
     pub info: Info<P>,
     /// Checksum computed when reading this file. May differ from one in info.
     pub calculated: Option<Checksum>,
+    /// Position of another file in the archive that is a hard link to the same data.
+    /// When set, content of this file is not written, since it is already stored there.
+    #[serde(default)]
+    pub link_to: Option<usize>,
+    /// Position of the first file among all hard links to the same data, this one included.
+    /// Cpio headers have no room for the whole device and inode, so it is stored instead.
+    #[serde(default)]
+    pub link_group: Option<u64>,
+    /// Position of this file in the archive, known once its header is written.
+    #[serde(default)]
+    pub offsets: Option<Offsets>,
+    /// Layout of the file when it has holes, see [`detect_holes_fut`](Self::detect_holes_fut).
+    #[serde(default)]
+    pub sparse: Option<SparseMap>,
+    /// Set when the file was changed while the archive was written, see [`ChangePolicy`](ChangePolicy).
+    #[serde(default)]
+    pub changed: Option<Change>,
+    /// Where the content comes from, when it is not the file at `info.path`.
+    #[serde(skip)]
+    pub source: Option<Arc<dyn Source>>,
 }
 
 #[derive(Debug, Snafu)]
 pub enum CantOpen {
-    IoFailed { source: io::Error },
-    InvalidPath { source: os_str_bytes::EncodingError },
+    IoFailed {
+        source: io::Error,
+    },
+    InvalidPath {
+        source: os_str_bytes::EncodingError,
+    },
+    #[snafu(display("File is still locked by another process after {:?}", timeout))]
+    LockTimedOut {
+        timeout: Duration,
+    },
+    /// File is not the one from the info, see [`ReadOptions::recheck`](ReadOptions::recheck).
+    #[snafu(display("{}", source))]
+    Replaced {
+        source: Box<Mismatch<Local>>,
+    },
+}
+
+impl CantOpen {
+    /// Whether the file does not exist anymore.
+    #[must_use]
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, CantOpen::IoFailed { source } if source.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+/// What to do with a file that is changed or deleted while the archive is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangePolicy {
+    /// Fail the whole archive.
+    Abort,
+    /// Read the file until it stays the same from start to end, and write that content.
+    /// Changes made after that are flagged, just like with [`Flag`](ChangePolicy::Flag).
+    Retry,
+    /// Write what was read, truncated or padded with zeroes to the size from the header,
+    /// and flag the file in the manifest. Deleted files are skipped.
+    Flag,
+}
+
+impl Default for ChangePolicy {
+    fn default() -> Self {
+        ChangePolicy::Abort
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "Unknown change policy `{}`, expected one of: abort, retry, flag",
+    name
+))]
+pub struct UnknownChangePolicy {
+    name: String,
+}
+
+impl FromStr for ChangePolicy {
+    type Err = UnknownChangePolicy;
+
+    /// ```
+    /// # use colbak_lib::cpio::pending::ChangePolicy;
+    /// assert_eq!("retry".parse::<ChangePolicy>().ok(), Some(ChangePolicy::Retry));
+    /// assert!("ignore".parse::<ChangePolicy>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(ChangePolicy::Abort),
+            "retry" => Ok(ChangePolicy::Retry),
+            "flag" => Ok(ChangePolicy::Flag),
+            _ => UnknownChangePolicyContext { name: s }.fail(),
+        }
+    }
+}
+
+/// How source files are locked while they are read.
+///
+/// Locks are advisory, so they only matter for processes that lock these files too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockStrategy {
+    /// Files are not locked at all.
+    None,
+    /// Other processes may read and share-lock the file too, but can't lock it exclusively.
+    Shared { timeout: Option<Duration> },
+    /// No other process may lock the file while it is read.
+    Exclusive { timeout: Option<Duration> },
+}
+
+/// Interval between attempts to take a lock held by another process.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long the [default](LockStrategy::default) strategy waits for other processes.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl LockStrategy {
+    /// Sets how long to wait for other processes to release the file.
+    /// After that reading fails. Strategies without timeout wait forever.
+    #[must_use]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        match self {
+            LockStrategy::None => LockStrategy::None,
+            LockStrategy::Shared { .. } => LockStrategy::Shared {
+                timeout: Some(timeout),
+            },
+            LockStrategy::Exclusive { .. } => LockStrategy::Exclusive {
+                timeout: Some(timeout),
+            },
+        }
+    }
+
+    /// Returns whether the lock is exclusive and how long to wait for it, `None` when there is no lock.
+    fn parts(self) -> Option<(bool, Option<Duration>)> {
+        match self {
+            LockStrategy::None => None,
+            LockStrategy::Shared { timeout } => Some((false, timeout)),
+            LockStrategy::Exclusive { timeout } => Some((true, timeout)),
+        }
+    }
+
+    /// Takes the lock without waiting. Returns false when another process holds it.
+    fn try_lock(exclusive: bool, file: &std::fs::File) -> Result<bool, CantOpen> {
+        let locked = if exclusive {
+            file.try_lock_exclusive()
+        } else {
+            file.try_lock_shared()
+        };
+        match locked {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(false),
+            Err(source) => Err(CantOpen::IoFailed { source }),
+        }
+    }
+
+    /// Waits for the lock forever.
+    fn lock_forever(exclusive: bool, file: &std::fs::File) -> Result<(), CantOpen> {
+        if exclusive {
+            file.lock_exclusive().context(IoFailed {})
+        } else {
+            file.lock_shared().context(IoFailed {})
+        }
+    }
+
+    /// Takes the lock, retrying until the timeout. Waiting lock would block the runtime thread,
+    /// so it is never used here, even without a timeout.
+    async fn lock(self, file: &std::fs::File) -> Result<(), CantOpen> {
+        let (exclusive, timeout) = match self.parts() {
+            Some(parts) => parts,
+            None => return Ok(()),
+        };
+        let started = Instant::now();
+        while !Self::try_lock(exclusive, file)? {
+            if let Some(timeout) = timeout.filter(|x| started.elapsed() >= *x) {
+                return LockTimedOut { timeout }.fail();
+            }
+            tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Same as [`lock`](Self::lock), but blocks the thread, so it is used on the blocking pool only.
+    pub(crate) fn lock_blocking(self, file: &std::fs::File) -> Result<(), CantOpen> {
+        let (exclusive, timeout) = match self.parts() {
+            Some((exclusive, Some(timeout))) => (exclusive, timeout),
+            Some((exclusive, None)) => return Self::lock_forever(exclusive, file),
+            None => return Ok(()),
+        };
+        let started = Instant::now();
+        while !Self::try_lock(exclusive, file)? {
+            if started.elapsed() >= timeout {
+                return LockTimedOut { timeout }.fail();
+            }
+            std::thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+impl Default for LockStrategy {
+    fn default() -> Self {
+        LockStrategy::Exclusive {
+            timeout: Some(DEFAULT_LOCK_TIMEOUT),
+        }
+    }
 }
 
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "Unknown lock strategy `{}`, expected one of: none, shared, exclusive",
+    name
+))]
+pub struct UnknownLockStrategy {
+    name: String,
+}
+
+impl FromStr for LockStrategy {
+    type Err = UnknownLockStrategy;
+
+    /// Parses strategy without timeout, see [`with_timeout`](Self::with_timeout).
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::pending::LockStrategy;
+    /// assert_eq!("shared".parse::<LockStrategy>().ok(), Some(LockStrategy::Shared { timeout: None }));
+    /// assert!("read".parse::<LockStrategy>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(LockStrategy::None),
+            "shared" => Ok(LockStrategy::Shared { timeout: None }),
+            "exclusive" => Ok(LockStrategy::Exclusive { timeout: None }),
+            _ => UnknownLockStrategyContext { name: s }.fail(),
+        }
+    }
+}
+
+/// How files are opened and checked by [`Pending::read`](Pending::read).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadOptions {
+    pub policy: ChangePolicy,
+    pub lock: LockStrategy,
+    /// Compare [identifier](FileIdentifier) of the opened file with one of the info,
+    /// so files changed since the snapshot are detected before their content is read.
+    pub recheck: bool,
+    /// Store only data extents of files with holes, see [`sparse`](super::sparse).
+    #[serde(default)]
+    pub sparse: bool,
+}
+
+/// How the file turned out to be inconsistent, stored in the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Change {
+    /// File was deleted before it was opened, so it is not stored at all.
+    Vanished,
+    /// Size on disk differs from the one in the header.
+    /// Stored content is truncated or padded with zeroes to match the header.
+    Size { expected: u64, found: u64 },
+    /// Content differs from the expected hash.
+    Hash,
+    /// File was changed or replaced after the snapshot, before it was opened.
+    Identifier,
+    /// File was modified while it was read, so stored content may be torn.
+    Modified,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Vanished => write!(f, "deleted before it was read"),
+            Change::Size { expected, found } => {
+                write!(f, "size changed from {} to {} bytes", expected, found)
+            }
+            Change::Hash => write!(f, "content does not match its hash"),
+            Change::Identifier => write!(f, "changed since the snapshot"),
+            Change::Modified => write!(f, "modified while it was read"),
+        }
+    }
+}
+
+/// Content of a file that did not change while it was read, see [`Pending::settle_fut`](Pending::settle_fut).
+#[derive(Debug)]
+pub struct Settled {
+    pub size: u64,
+    pub modified_at: DateTime,
+    pub hash: Checksum,
+    /// Sum of all bytes, as required by [`Crc`](Format::Crc) format.
+    pub checksum: u32,
+}
+
+/// Number of times [`Pending::settle_fut`](Pending::settle_fut) reads the file before giving up.
+pub const SETTLE_ATTEMPTS: usize = 3;
+
 /// Result of [`Pending::read`](Pending::read) function.
 pub type PendingReader<'a> = impl AsyncRead + 'a;
 
@@ -39,12 +331,36 @@ pub type PendingReader<'a> = impl AsyncRead + 'a;
 pub type OpeningReadFuture<'a> =
     impl std::future::Future<Output = Result<PendingReader<'a>, CantOpen>>;
 
+/// Future that is returned by [`Pending::checksum_fut`](Pending::checksum_fut)
+pub type ChecksumFuture = impl std::future::Future<Output = Result<u32, CantOpen>>;
+
+/// Future that is returned by [`Pending::settle_fut`](Pending::settle_fut)
+pub type SettleFuture = impl std::future::Future<Output = Result<Option<Settled>, CantOpen>>;
+
+/// Future that is returned by [`Pending::detect_holes_fut`](Pending::detect_holes_fut)
+pub type HolesFuture = impl std::future::Future<Output = Result<Option<SparseMap>, CantOpen>>;
+
 impl<P: PathKind> Pending<P> {
     #[must_use]
     pub fn new(info: Info<P>) -> Self {
         Self {
             info,
             calculated: None,
+            link_to: None,
+            link_group: None,
+            offsets: None,
+            sparse: None,
+            changed: None,
+            source: None,
+        }
+    }
+
+    /// Creates entry which content is read from the `source` instead of the file.
+    #[must_use]
+    pub fn with_source(info: Info<P>, source: Arc<dyn Source>) -> Self {
+        Self {
+            source: Some(source),
+            ..Self::new(info)
         }
     }
 }
@@ -53,31 +369,290 @@ impl Pending<Local> {
     /// Opens file for reading and returns it.
     /// After file is completely read, [`self.calculated`] will be updated
     ///
+    /// Exactly [`stored_size`](Self::stored_size) bytes are returned. When the file turns out
+    /// to be changed, it is either reported as error or flagged, depending on the policy.
+    ///
+    /// File that was [opened ahead](crate::cpio::read_ahead) is used as is,
+    /// its [holes](Self::detect_holes_fut) should be taken from there too.
+    /// Entries with a [source](Self::source) are read from it instead.
+    /// Every chunk that is read is reported to `progress` as [`Hashing`](Stage::Hashing).
+    ///
     /// [`self.calculated`]: Self::calculated
-    pub async fn read(&mut self) -> Result<impl AsyncRead + '_, CantOpen> {
-        let path = self.info.path.to_path().context(InvalidPath)?;
-        let file = std::fs::File::open(path).context(IoFailed {})?;
-        file.lock_exclusive().context(IoFailed {})?;
-        let file = File::from_std(file);
+    pub async fn read(
+        &mut self,
+        options: ReadOptions,
+        prefetched: Option<Prefetched>,
+        progress: Option<Arc<dyn Observer>>,
+    ) -> Result<impl AsyncRead + '_, CantOpen> {
+        let (opened, head) = match prefetched {
+            Some(prefetched) => {
+                if options.recheck {
+                    self.recheck(&prefetched.file, options.policy)?;
+                }
+                let extents = skip_extents(&self.extents(), prefetched.head.len() as u64);
+                let opened = states::Opened::new(prefetched.file, extents, prefetched.modified)?;
+                (Some(opened), prefetched.head)
+            }
+            None => match &self.source {
+                Some(source) => {
+                    let reader = source.open().context(IoFailed {})?;
+                    let limit = self.stored_size().unwrap_or(0);
+                    let opened = states::Opened::Source(Box::pin(Limited::new(reader, limit)));
+                    (Some(opened), Vec::new())
+                }
+                None => (self.open(options).await?, Vec::new()),
+            },
+        };
 
         let reading = Reading::File(states::File {
+            holes: self.sparse.as_ref().map(HoleHasher::new),
             pending: self,
-            opened: Box::pin(file),
+            opened,
+            policy: options.policy,
             hasher: DefaultDigest::default(),
+            head,
             length: 0,
+            padding: 0,
+            progress,
         });
         Ok(SmartReader::new(reading))
     }
 
+    /// Opens and locks the file. Returns `None` when it was deleted and the policy allows that.
+    async fn open(&mut self, options: ReadOptions) -> Result<Option<states::Opened>, CantOpen> {
+        let path = self.info.path.to_path().context(InvalidPath)?;
+        let file = match File::open(path).await {
+            Ok(file) => file.into_std().await,
+            // Header is already written, so the entry is filled with zeroes.
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound && options.policy != ChangePolicy::Abort =>
+            {
+                return Ok(None)
+            }
+            Err(source) => return Err(CantOpen::IoFailed { source }),
+        };
+        options.lock.lock(&file).await?;
+        if options.recheck {
+            self.recheck(&file, options.policy)?;
+        }
+        let modified = file.metadata().context(IoFailed {})?.modified().ok();
+        states::Opened::new(file, self.extents(), modified).map(Some)
+    }
+
+    /// Compares identifier of the opened file with one of the info.
+    fn recheck(&mut self, file: &std::fs::File, policy: ChangePolicy) -> Result<(), CantOpen> {
+        let metadata = file.metadata().context(IoFailed {})?;
+        if self.info.identifier() == Some(FileIdentifier::from_metadata(&metadata)) {
+            return Ok(());
+        }
+        if policy == ChangePolicy::Abort {
+            let source = Box::new(Mismatch::IdentifierMismatch {
+                pending: self.clone(),
+            });
+            return Err(CantOpen::Replaced { source });
+        }
+        self.changed = Some(Change::Identifier);
+        Ok(())
+    }
+
+    /// Parts of the file that are stored in the archive.
+    fn extents(&self) -> Vec<Extent> {
+        match &self.sparse {
+            Some(sparse) => sparse.extents.clone(),
+            None => vec![Extent {
+                offset: 0,
+                length: self.stored_size().unwrap_or(0),
+            }],
+        }
+    }
+
+    /// Checks that the file still exists and finds holes in it when `sparse` is set, so only
+    /// its data is read and written to the archive. It is done on the blocking pool.
+    /// Returns `None` for anything else than a regular file with its own content.
+    ///
+    /// Result should be stored in [`sparse`](Self::sparse) before the [header](Self::header) is created,
+    /// since it stores size of that data.
+    #[must_use]
+    pub fn detect_holes_fut(&self, sparse: bool) -> HolesFuture {
+        let path = self.info.path.to_path();
+        let size = match &self.info.data {
+            UnspecifiedInfo::File(file) if self.link_to.is_none() && self.source.is_none() => {
+                Some(file.size)
+            }
+            _ => None,
+        };
+        async move {
+            let size = match size {
+                Some(size) => size,
+                None => return Ok(None),
+            };
+            let path = path.context(InvalidPath)?;
+            let detect = move || {
+                let file = std::fs::File::open(path).context(IoFailed {})?;
+                if !sparse || file.metadata().context(IoFailed {})?.len() != size {
+                    // Changed file is read as is, and that is reported then.
+                    return Ok(None);
+                }
+                SparseMap::detect(&file, size).context(IoFailed {})
+            };
+            tokio::task::spawn_blocking(detect)
+                .await
+                .map_err(|e| CantOpen::IoFailed {
+                    source: io::Error::new(io::ErrorKind::Other, e),
+                })?
+        }
+    }
+
     /// Same as [`Self::read`](Self::read), but returns named type.
-    pub fn read_fut(&mut self) -> OpeningReadFuture<'_> {
-        self.read()
+    pub fn read_fut(
+        &mut self,
+        options: ReadOptions,
+        prefetched: Option<Prefetched>,
+        progress: Option<Arc<dyn Observer>>,
+    ) -> OpeningReadFuture<'_> {
+        self.read(options, prefetched, progress)
+    }
+
+    /// Reads the whole file until it stays the same from start to end,
+    /// but at most [`SETTLE_ATTEMPTS`](SETTLE_ATTEMPTS) times. Returns `None` when the file is deleted.
+    ///
+    /// Result should be passed to [`settle`](Self::settle) before the header is created.
+    pub fn settle_fut(&self) -> SettleFuture {
+        let path = self.info.path.to_path();
+        async move {
+            let path = path.context(InvalidPath)?;
+            let mut buf = vec![0; 64 * 1024];
+            let mut settled = None;
+            for _ in 0..SETTLE_ATTEMPTS {
+                let mut file = match File::open(&path).await {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(source) => return Err(CantOpen::IoFailed { source }),
+                };
+                let before = file.metadata().await.context(IoFailed {})?;
+                let (mut hasher, mut size, mut checksum) = (DefaultDigest::default(), 0, 0);
+                loop {
+                    let len = file.read(&mut buf).await.context(IoFailed {})?;
+                    if len == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..len]);
+                    checksum = checksum_update(checksum, &buf[..len]);
+                    size += len as u64;
+                }
+                let after = file.metadata().await.context(IoFailed {})?;
+                let stable = before.len() == size
+                    && after.len() == size
+                    && before.modified().ok() == after.modified().ok();
+                settled = Some(Settled {
+                    size,
+                    modified_at: systime_to_datetime(after.modified()),
+                    hash: hasher.finalize().into(),
+                    checksum,
+                });
+                if stable {
+                    break;
+                }
+            }
+            Ok(settled)
+        }
+    }
+
+    /// Replaces size, modification time and hash in the info with ones found by
+    /// [`settle_fut`](Self::settle_fut), or marks the file as deleted.
+    pub fn settle(&mut self, settled: Option<&Settled>) {
+        match settled {
+            Some(settled) => {
+                self.info.data = UnspecifiedInfo::File(FileInfo { size: settled.size });
+                self.info.modified_at = settled.modified_at;
+                self.info.hash = Some(settled.hash);
+            }
+            None => self.changed = Some(Change::Vanished),
+        }
     }
 
-    /// Returns [cpio header](CpioHeader) for this file.
+    /// Whether the file was deleted before anything was written, so it is not in the archive.
     #[must_use]
-    pub fn header(&self) -> Vec<u8> {
-        CpioHeader::encode(&self.info)
+    pub fn is_vanished(&self) -> bool {
+        self.changed == Some(Change::Vanished)
+    }
+
+    /// Computes sum of all bytes in the file, as required by [`Crc`](Format::Crc) format.
+    ///
+    /// File is opened separately from [`Self::read`](Self::read), but any change in between
+    /// will be detected there anyway, since size and hash are checked.
+    pub fn checksum_fut(&self) -> ChecksumFuture {
+        let path = self.info.path.to_path();
+        let source = self.source.clone();
+        async move {
+            let mut file: SourceReader = match source {
+                Some(source) => source.open().context(IoFailed {})?,
+                None => {
+                    let path = path.context(InvalidPath)?;
+                    Box::new(File::open(path).await.context(IoFailed {})?)
+                }
+            };
+            let mut buf = vec![0; 64 * 1024];
+            let mut sum = 0;
+            loop {
+                let len = file.read(&mut buf).await.context(IoFailed {})?;
+                if len == 0 {
+                    break Ok(sum);
+                }
+                sum = checksum_update(sum, &buf[..len]);
+            }
+        }
+    }
+
+    /// Returns [cpio header](crate::cpio::Header) for this file in the given format.
+    ///
+    /// `checksum` is used by [`Crc`](Format::Crc) format only. `original` is the path of the
+    /// entry [`link_to`](Self::link_to) points at, when it was written.
+    /// In [`Pax`](Format::Pax) format the map of extents follows the header of a sparse file.
+    #[must_use]
+    pub fn header(&self, format: Format, checksum: u32, original: Option<&[u8]>) -> Vec<u8> {
+        let mut info = Cow::Borrowed(&self.info);
+        if let Some(group) = self.link_group.filter(|_| format != Format::Pax) {
+            // Truncated inodes of different files may be equal, but groups never are.
+            let info = info.to_mut();
+            info.device = 0;
+            info.inode = group;
+        }
+        if self.link_to.is_some() {
+            // Data is stored only in the first link, so this one is empty.
+            info.to_mut().data = UnspecifiedInfo::File(FileInfo { size: 0 });
+            if let (Format::Pax, Some(original)) = (format, original) {
+                // Tar has a type for that, so other archivers restore the link too.
+                return TarHeader::encode_hard_link(&info, original);
+            }
+            return format.encode(&info, 0);
+        }
+        if let (Format::Pax, Some(sparse)) = (format, &self.sparse) {
+            return TarHeader::encode_sparse(&info, &sparse.extents);
+        }
+        if let Some(sparse) = &self.sparse {
+            // Holes are skipped, and they add nothing to the checksum.
+            info.to_mut().data = UnspecifiedInfo::File(FileInfo {
+                size: sparse.stored_size(),
+            });
+        }
+        format.encode(&info, checksum)
+    }
+
+    /// Number of bytes of this file stored in the archive.
+    #[must_use]
+    pub fn stored_size(&self) -> Option<u64> {
+        match (&self.sparse, self.link_to) {
+            (_, Some(_)) => Some(0),
+            (Some(sparse), None) => Some(sparse.stored_size()),
+            (None, None) => self.info.size(),
+        }
+    }
+
+    /// Returns true when content of this file should be written to the archive.
+    #[must_use]
+    pub fn has_content(&self) -> bool {
+        self.info.size().is_some() && self.link_to.is_none()
     }
 }
 
@@ -85,8 +660,12 @@ impl Pending<Local> {
 /// ```text
 /// ↙--        ↙--↖
 /// File --> Done-/
-///      \-> Mismatch -> !
 /// ```
+///
+/// Changes of the file are either reported as [`Mismatch`](Mismatch) errors right away,
+/// or recorded in [`Pending::changed`](Pending::changed), see [`ChangePolicy`](ChangePolicy).
+// Done is the final state, so boxing File would not save any memory.
+#[allow(clippy::large_enum_variant)]
 pub enum Reading<'a> {
     /// Should be unreachable.
     Poisoned,
@@ -94,8 +673,6 @@ pub enum Reading<'a> {
     File(states::File<'a>),
     /// Fle successfully was read.
     Done(states::Done<'a>),
-    /// Computed information differs from expected (stored in info).
-    Mismatch(Mismatch<Local>),
 }
 
 impl SmartRead for Reading<'_> {
@@ -111,7 +688,6 @@ impl SmartRead for Reading<'_> {
                 Reading::Poisoned => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, "State is poisoned"))),
                 Reading::File => |x| x,
                 Reading::Done => Reading::Done,
-                Reading::Mismatch => Reading::Mismatch,
             }
         };
         *this = new_state;
@@ -119,29 +695,102 @@ impl SmartRead for Reading<'_> {
     }
 }
 
+/// Computed information differs from expected (stored in info).
 #[derive(Debug, Snafu)]
 pub enum Mismatch<P: PathKind> {
+    #[snafu(display(
+        "Hash of {:?} is {}, expected {}",
+        pending.info.path.escaped(),
+        found,
+        expected
+    ))]
     HashMismatch {
         pending: Pending<P>,
         expected: Box<Checksum>,
         found: Box<Checksum>,
     },
+    #[snafu(display(
+        "Size of {:?} is {}, expected {}",
+        pending.info.path.escaped(),
+        found,
+        expected
+    ))]
     SizeMismatch {
         pending: Pending<P>,
         expected: u64,
         found: u64,
     },
+    #[snafu(display("{:?} was modified while it was read", pending.info.path.escaped()))]
+    ModifiedMismatch { pending: Pending<P> },
+    #[snafu(display("{:?} was changed since the snapshot", pending.info.path.escaped()))]
+    IdentifierMismatch { pending: Pending<P> },
+}
+
+impl<P: PathKind> Mismatch<P> {
+    /// Same problem, as it is stored in the manifest.
+    #[must_use]
+    pub fn change(&self) -> Change {
+        match self {
+            Mismatch::HashMismatch { .. } => Change::Hash,
+            Mismatch::SizeMismatch {
+                expected, found, ..
+            } => Change::Size {
+                expected: *expected,
+                found: *found,
+            },
+            Mismatch::ModifiedMismatch { .. } => Change::Modified,
+            Mismatch::IdentifierMismatch { .. } => Change::Identifier,
+        }
+    }
 }
 
 /// Stores variants of [`Reading`](Reading) state machine.
 mod states {
     use super::*;
 
+    /// Content that is being read.
+    pub enum Opened {
+        File {
+            reader: Pin<Box<ExtentReader<tokio::fs::File>>>,
+            /// Same file, used to check whether it was changed while it was read.
+            handle: std::fs::File,
+            /// Modification time when the file was opened.
+            modified: Option<SystemTime>,
+        },
+        /// Content of the [source](super::Pending::source), limited to the declared size.
+        Source(Pin<Box<Limited>>),
+    }
+
+    impl Opened {
+        pub fn new(
+            file: std::fs::File,
+            extents: Vec<Extent>,
+            modified: Option<SystemTime>,
+        ) -> Result<Self, CantOpen> {
+            let handle = file.try_clone().context(IoFailed {})?;
+            Ok(Opened::File {
+                reader: Box::pin(ExtentReader::new(tokio::fs::File::from_std(file), extents)),
+                handle,
+                modified,
+            })
+        }
+    }
+
     pub struct File<'a> {
         pub pending: &'a mut Pending<Local>,
-        pub opened: Pin<Box<tokio::fs::File>>,
+        /// `None` when the file was deleted after its header was written.
+        pub opened: Option<Opened>,
+        pub policy: ChangePolicy,
+        /// Hashes holes too, when the file is sparse.
+        pub holes: Option<HoleHasher>,
         pub hasher: DefaultDigest,
+        /// First bytes of the data that were [read ahead](crate::cpio::read_ahead).
+        pub head: Vec<u8>,
+        /// Number of bytes read, without holes.
         pub length: u64,
+        /// Number of zeroes left to write in place of data that was truncated.
+        pub padding: u64,
+        pub progress: Option<Arc<dyn Observer>>,
     }
 
     pub struct Done<'a> {
@@ -150,68 +799,173 @@ mod states {
     }
 }
 
-impl<'a> Advanceable for states::File<'a> {
-    type Next = Reading<'a>;
+impl<'a> states::File<'a> {
+    fn update(&mut self, data: &[u8]) {
+        self.length += data.len() as u64;
+        match &mut self.holes {
+            Some(holes) => holes.update(&mut self.hasher, data),
+            None => self.hasher.update(data),
+        }
+        self.report(0);
+    }
 
-    fn advance(
-        mut self,
-        cx: &mut Context<'_>,
-        buf: &mut SmartBuf<'_, '_, '_>,
-    ) -> AdvanceResult<Self, Self::Next> {
-        match buf.fill_using(self.opened.as_mut(), cx) {
-            Poll::Pending => AdvanceResult::Pending(self),
-            Poll::Ready(Err(err)) => AdvanceResult::Failed(err),
-            Poll::Ready(Ok(Some(written))) => {
-                self.length += written.len() as u64;
-                self.hasher.update(written);
-                AdvanceResult::Ready(Reading::File(self))
+    fn report(&self, files: u64) {
+        if let Some(progress) = &self.progress {
+            let done = Progress {
+                files,
+                bytes: self.length,
+            };
+            progress.update(Stage::Hashing, done, self.pending.info.path.as_bytes());
+        }
+    }
+
+    /// Reports the mismatch, or flags the file when the policy allows that.
+    fn tolerate(&mut self, mismatch: Mismatch<Local>) -> io::Result<()> {
+        if self.policy == ChangePolicy::Abort {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, mismatch));
+        }
+        // The first problem is the most telling one.
+        self.pending.changed.get_or_insert(mismatch.change());
+        Ok(())
+    }
+
+    /// Compares the file on disk with the info, after everything was read.
+    fn check_metadata(&self) -> io::Result<Option<Mismatch<Local>>> {
+        let expected = self.pending.info.size().unwrap_or_default();
+        let (handle, modified) = match &self.opened {
+            Some(states::Opened::File {
+                handle, modified, ..
+            }) => (handle, modified),
+            Some(states::Opened::Source(reader)) if reader.found() == expected => return Ok(None),
+            Some(states::Opened::Source(reader)) => {
+                return Ok(Some(Mismatch::SizeMismatch {
+                    pending: self.pending.clone(),
+                    expected,
+                    found: reader.found(),
+                }))
             }
-            Poll::Ready(Ok(None)) => {
-                // EOF
-                buf.eof();
-
-                if let UnspecifiedInfo::File(file) = &self.pending.info.data {
-                    if file.size != self.length {
-                        return AdvanceResult::Ready(Reading::Mismatch(Mismatch::SizeMismatch {
-                            expected: file.size,
-                            found: self.length,
-                            pending: self.pending.clone(),
-                        }));
-                    }
-                }
+            None => {
+                return Ok(Some(Mismatch::SizeMismatch {
+                    pending: self.pending.clone(),
+                    expected,
+                    found: 0,
+                }))
+            }
+        };
+        let metadata = handle.metadata()?;
+        if metadata.len() != expected {
+            return Ok(Some(Mismatch::SizeMismatch {
+                pending: self.pending.clone(),
+                expected,
+                found: metadata.len(),
+            }));
+        }
+        if metadata.modified().ok() != *modified {
+            return Ok(Some(Mismatch::ModifiedMismatch {
+                pending: self.pending.clone(),
+            }));
+        }
+        Ok(None)
+    }
 
-                let checksum = self.hasher.finalize().into();
+    fn finish(mut self, buf: &mut SmartBuf<'_, '_, '_>) -> AdvanceResult<Self, Reading<'a>> {
+        let expected = self.pending.stored_size().unwrap_or_default();
+        if self.length < expected {
+            // File was truncated while it was read.
+            let mismatch = Mismatch::SizeMismatch {
+                pending: self.pending.clone(),
+                expected,
+                found: self.length,
+            };
+            if self.policy == ChangePolicy::Abort {
+                return AdvanceResult::Failed(io::Error::new(io::ErrorKind::InvalidData, mismatch));
+            }
+            self.padding = expected - self.length;
+            return AdvanceResult::Ready(Reading::File(self));
+        }
 
-                if let Some(expected) = self.pending.info.hash {
-                    if checksum != expected {
-                        return AdvanceResult::Ready(Reading::Mismatch(Mismatch::HashMismatch {
-                            expected: Box::new(expected),
-                            found: Box::new(checksum),
-                            pending: self.pending.clone(),
-                        }));
-                    }
+        match self.check_metadata() {
+            Ok(None) => {}
+            Ok(Some(mismatch)) => {
+                if let Err(err) = self.tolerate(mismatch) {
+                    return AdvanceResult::Failed(err);
                 }
+            }
+            Err(err) => return AdvanceResult::Failed(err),
+        }
 
-                self.pending.calculated = Some(checksum);
-                AdvanceResult::Ready(Reading::Done(states::Done {
-                    pending: self.pending,
-                    checksum,
-                }))
+        if let (Some(holes), Some(sparse), Some(size)) = (
+            self.holes.take(),
+            self.pending.sparse.as_mut(),
+            self.pending.info.size(),
+        ) {
+            sparse.hash = Some(holes.finish(&mut self.hasher, size));
+        }
+        let checksum = std::mem::take(&mut self.hasher).finalize().into();
+
+        if let Some(expected) = self.pending.info.hash {
+            if checksum != expected {
+                let mismatch = Mismatch::HashMismatch {
+                    expected: Box::new(expected),
+                    found: Box::new(checksum),
+                    pending: self.pending.clone(),
+                };
+                if let Err(err) = self.tolerate(mismatch) {
+                    return AdvanceResult::Failed(err);
+                }
             }
         }
+
+        buf.eof();
+        self.report(1);
+        self.pending.calculated = Some(checksum);
+        AdvanceResult::Ready(Reading::Done(states::Done {
+            pending: self.pending,
+            checksum,
+        }))
     }
 }
 
-// TODO: This impl does not look good
-impl Advanceable for Mismatch<Local> {
-    type Next = Self;
+impl<'a> Advanceable for states::File<'a> {
+    type Next = Reading<'a>;
 
     fn advance(
-        self,
-        _cx: &mut Context<'_>,
-        _buf: &mut SmartBuf<'_, '_, '_>,
+        mut self,
+        cx: &mut Context<'_>,
+        buf: &mut SmartBuf<'_, '_, '_>,
     ) -> AdvanceResult<Self, Self::Next> {
-        AdvanceResult::Failed(io::Error::new(io::ErrorKind::InvalidData, self))
+        const ZEROES: [u8; 8 * 1024] = [0; 8 * 1024];
+
+        if !self.head.is_empty() {
+            let head = std::mem::take(&mut self.head);
+            buf.put_slice(&head);
+            self.update(&head);
+            return AdvanceResult::Ready(Reading::File(self));
+        }
+        if self.padding > 0 {
+            // Never larger than the buffer.
+            #[allow(clippy::cast_possible_truncation)]
+            let len = self.padding.min(ZEROES.len() as u64) as usize;
+            buf.put_slice(&ZEROES[..len]);
+            self.update(&ZEROES[..len]);
+            self.padding -= len as u64;
+            return AdvanceResult::Ready(Reading::File(self));
+        }
+
+        let polled = match &mut self.opened {
+            Some(states::Opened::File { reader, .. }) => buf.fill_using(reader.as_mut(), cx),
+            Some(states::Opened::Source(reader)) => buf.fill_using(reader.as_mut(), cx),
+            None => Poll::Ready(Ok(None)),
+        };
+        match polled {
+            Poll::Pending => AdvanceResult::Pending(self),
+            Poll::Ready(Err(err)) => AdvanceResult::Failed(err),
+            Poll::Ready(Ok(Some(written))) => {
+                self.update(written);
+                AdvanceResult::Ready(Reading::File(self))
+            }
+            Poll::Ready(Ok(None)) => self.finish(buf),
+        }
     }
 }
 
@@ -0,0 +1,181 @@
+//! Opens next files of the archive while the current one is written.
+//!
+//! Each file is opened, locked and checked for holes on the blocking pool, and the first block
+//! of its data is read right away, so a small file costs a single trip there instead of several.
+//! Both the number of files opened ahead and the memory taken by their blocks are limited.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use futures::ready;
+use tokio::task::JoinHandle;
+
+use super::pending::{CantOpen, LockStrategy, Pending, ReadOptions};
+use super::sparse::{Extent, SparseMap};
+use crate::path::Local;
+
+/// Maximum number of files opened ahead, so file descriptors are not exhausted.
+pub const MAX_FILES: usize = 32;
+
+/// Size of the first block read from every file.
+pub const HEAD_SIZE: usize = 64 * 1024;
+
+/// Default limit for memory taken by first blocks of files opened ahead.
+pub const DEFAULT_BUDGET: usize = 4 * 1024 * 1024;
+
+/// File that is already opened and locked, together with its first block.
+#[derive(Debug)]
+pub struct Prefetched {
+    pub file: std::fs::File,
+    /// Modification time right after the file was opened.
+    pub modified: Option<SystemTime>,
+    /// Layout of the file, same as [`Pending::detect_holes_fut`](Pending::detect_holes_fut) finds.
+    pub sparse: Option<SparseMap>,
+    /// First bytes of the data stored in the archive. Shorter when the file was truncated.
+    pub head: Vec<u8>,
+}
+
+struct Task {
+    position: usize,
+    /// Memory reserved for the first block.
+    reserved: usize,
+    handle: JoinHandle<Result<Prefetched, CantOpen>>,
+}
+
+/// Queue of files opened ahead, in the order of the archive.
+pub struct ReadAhead {
+    budget: usize,
+    used: usize,
+    /// Position of the next file to consider.
+    next: usize,
+    tasks: VecDeque<Task>,
+}
+
+impl ReadAhead {
+    /// Creates queue that keeps at most `budget` bytes in memory. Nothing is read ahead when it is zero.
+    #[must_use]
+    pub fn new(budget: usize) -> Self {
+        ReadAhead {
+            budget,
+            used: 0,
+            next: 0,
+            tasks: VecDeque::new(),
+        }
+    }
+
+    /// Starts opening files from `position` onwards, while limits allow that.
+    pub fn fill(&mut self, files: &[Pending<Local>], position: usize, options: ReadOptions) {
+        self.next = self.next.max(position);
+        if self.budget == 0 {
+            return;
+        }
+        while self.tasks.len() < MAX_FILES {
+            let file = match files.get(self.next) {
+                Some(file) => file,
+                None => break,
+            };
+            let (size, path) = match (file.info.size(), file.info.path.to_path()) {
+                (Some(size), Ok(path)) if file.has_content() && file.source.is_none() => {
+                    (size, path)
+                }
+                // Nothing to read, not a local file, or an error that is reported when it is written.
+                _ => {
+                    self.next += 1;
+                    continue;
+                }
+            };
+            // Never larger than `HEAD_SIZE`.
+            #[allow(clippy::cast_possible_truncation)]
+            let reserved = size.min(HEAD_SIZE as u64) as usize;
+            if self.used + reserved > self.budget {
+                break;
+            }
+            let handle = tokio::task::spawn_blocking(move || {
+                prefetch(&path, size, reserved, options.lock, options.sparse)
+            });
+            self.tasks.push_back(Task {
+                position: self.next,
+                reserved,
+                handle,
+            });
+            self.used += reserved;
+            self.next += 1;
+        }
+    }
+
+    /// Waits for the file at `position`. Returns `None` when it was not opened ahead.
+    pub fn poll_take(&mut self, position: usize, cx: &mut Context<'_>) -> Poll<Option<Prefetched>> {
+        while let Some(task) = self.tasks.front_mut() {
+            if task.position > position {
+                break;
+            }
+            let result = ready!(Pin::new(&mut task.handle).poll(cx));
+            self.used -= task.reserved;
+            let found = task.position == position;
+            self.tasks.pop_front();
+            if found {
+                // Failed task is simply ignored, so the file is opened again and the error is reported then.
+                return Poll::Ready(result.ok().and_then(Result::ok));
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+/// Runs on the blocking pool.
+fn prefetch(
+    path: &Path,
+    size: u64,
+    limit: usize,
+    lock: LockStrategy,
+    sparse: bool,
+) -> Result<Prefetched, CantOpen> {
+    let failed = |source| CantOpen::IoFailed { source };
+    let file = std::fs::File::open(path).map_err(failed)?;
+    lock.lock_blocking(&file)?;
+    let metadata = file.metadata().map_err(failed)?;
+    // Changed file is read as is, and the change is detected later.
+    let sparse = if sparse && metadata.len() == size {
+        SparseMap::detect(&file, size).map_err(failed)?
+    } else {
+        None
+    };
+    let extents = match &sparse {
+        Some(sparse) => sparse.extents.clone(),
+        None => vec![Extent {
+            offset: 0,
+            length: size,
+        }],
+    };
+    let head = read_head(&file, &extents, limit).map_err(failed)?;
+    Ok(Prefetched {
+        file,
+        modified: metadata.modified().ok(),
+        sparse,
+        head,
+    })
+}
+
+/// Reads up to `limit` bytes of the given extents.
+fn read_head(mut file: &std::fs::File, extents: &[Extent], limit: usize) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(limit);
+    for extent in extents {
+        let left = (limit - head.len()) as u64;
+        if left == 0 {
+            break;
+        }
+        file.seek(SeekFrom::Start(extent.offset))?;
+        let length = extent.length.min(left);
+        let read = file.take(length).read_to_end(&mut head)?;
+        if (read as u64) < length {
+            // File was truncated.
+            break;
+        }
+    }
+    Ok(head)
+}
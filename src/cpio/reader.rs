@@ -1,19 +1,100 @@
-use super::CpioHeader;
-use crate::fileinfo::Info;
-use crate::path::External;
+use super::manifest::ManifestError;
+use super::recovery::{Recovery, RecoveryReport};
+use super::sparse::SparseMap;
+use super::tar::{self, TarHeader};
+use super::{
+    checksum_update, CpioHeader, EntryHeader, Format, Header, Manifest, NewcHeader, Offsets,
+};
+use crate::fileinfo::{Info, UnspecifiedInfo};
+use crate::path::{EncodedPath, External};
 use snafu::{OptionExt, ResultExt, Snafu};
-use std::io::SeekFrom;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, SeekFrom};
 use std::mem::size_of;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
-/// Extractor of cpio archive.
+/// Symbolic links with longer targets are considered broken.
+const MAX_LINK_SIZE: u64 = 64 * 1024;
+
+/// Names are never longer than this when written, so anything bigger is a corrupted header.
+const MAX_NAME_SIZE: usize = 64 * 1024;
+
+/// Extractor of cpio and tar archives.
 pub struct Reader<R> {
     reader: R,
+    links: Links,
+    /// Set once damaged entries are skipped, see [`recover`](Self::recover).
+    recovery: Option<Recovery>,
 }
 
 impl<R> Reader<R> {
     pub fn new(reader: R) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            links: Links::default(),
+            recovery: None,
+        }
+    }
+}
+
+impl<R: AsyncSeek + Unpin> Reader<R> {
+    /// Starts reading from the entry at given offsets, skipping everything before it.
+    ///
+    /// Hard links to files stored earlier are not detected in this case.
+    pub async fn at(mut reader: R, offsets: Offsets) -> tokio::io::Result<Self> {
+        reader.seek(SeekFrom::Start(offsets.header)).await?;
+        let mut result = Self::new(reader);
+        result.links.partial = true;
+        Ok(result)
+    }
+}
+
+/// Hard-linked files seen so far.
+///
+/// `cpio -H newc` stores the content only with the last link of a file, while earlier ones are empty.
+/// So empty entries of newc archives are deferred until the content arrives,
+/// and then returned right after it as links to it.
+#[derive(Default)]
+struct Links {
+    /// Paths of already seen hard-linked files, keyed by device and inode.
+    paths: HashMap<(u64, u64), EncodedPath<External>>,
+    /// Empty entries that wait for the content of their file.
+    deferred: Vec<((u64, u64), Entry)>,
+    /// Entries that are returned before reading the next one.
+    ready: VecDeque<Entry>,
+    /// Reading started in the middle of the archive, so nothing is deferred.
+    partial: bool,
+}
+
+impl Links {
+    /// Returns entry that should be returned now, or None when it is deferred.
+    fn arrange(&mut self, entry: Entry) -> Option<Entry> {
+        if entry.header.is_trailer(&entry.filename) {
+            if self.deferred.is_empty() {
+                return Some(entry);
+            }
+            // Content never arrived, so they are links to an empty file.
+            self.ready.extend(self.deferred.drain(..).map(|(_, x)| x));
+            self.ready.push_back(entry);
+            return self.ready.pop_front();
+        }
+        let id = match entry.header.hard_link_id() {
+            Some(id) if matches!(entry.header, Header::Newc(_)) && !self.partial => id,
+            _ => return Some(entry),
+        };
+        if self.paths.contains_key(&id) {
+            return Some(entry);
+        }
+        if entry.header.size() == 0 {
+            self.deferred.push((id, entry));
+            return None;
+        }
+        let (linked, rest) = std::mem::take(&mut self.deferred)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(x, _)| *x == id);
+        self.deferred = rest;
+        self.ready.extend(linked.into_iter().map(|(_, x)| x));
+        Some(entry)
     }
 }
 
@@ -22,14 +103,24 @@ impl<R> Reader<R> {
 pub struct ReadFile<R> {
     filename: Vec<u8>,
     reader: R,
-    header: CpioHeader,
+    header: Header,
+    /// Content of entry that was read in advance. Currently it is a target of symbolic link.
+    body: Option<Vec<u8>>,
+    /// Path of earlier entry this file is hard link to.
+    hard_link: Option<EncodedPath<External>>,
+    links: Links,
+    recovery: Option<Recovery>,
 }
 
 #[derive(Debug, Snafu)]
-#[snafu(context(false))]
-pub struct ReadError {
-    source: tokio::io::Error,
-    backtrace: snafu::Backtrace,
+pub enum ReadError {
+    #[snafu(context(false))]
+    ReadFailed {
+        source: tokio::io::Error,
+        backtrace: snafu::Backtrace,
+    },
+    #[snafu(display("Checksum mismatch: expected {:08X}, found {:08X}", expected, found))]
+    ChecksumMismatch { expected: u32, found: u32 },
 }
 
 impl<R: AsyncRead + Unpin> ReadFile<R> {
@@ -38,24 +129,65 @@ impl<R: AsyncRead + Unpin> ReadFile<R> {
     where
         W: AsyncWrite + Unpin,
     {
-        let size = self.header.size();
-        let mut file = self.reader.take(size);
+        let (reader, mismatch) = self.drain_unchecked(dst).await?;
+        mismatch.map_or(Ok(reader), Err)
+    }
 
-        let mut buf = vec![0; 1024 * 1024];
-        loop {
-            let len = file.read(&mut buf).await?;
-            if len == 0 {
-                break;
+    /// Same as [`drain_to`](Self::drain_to), but checksum mismatch is returned together with
+    /// the reader instead of failing, so following entries can still be read.
+    pub async fn drain_unchecked<W>(
+        self,
+        dst: &mut W,
+    ) -> Result<(Reader<R>, Option<ReadError>), ReadError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let expected = self.header.checksum();
+        let mut checksum = 0;
+        let mut reader = self.reader;
+
+        if let Some(body) = self.body {
+            // Padding is already consumed too.
+            checksum = checksum_update(checksum, &body);
+            dst.write_all(&body).await?;
+        } else {
+            let size = self.header.size();
+            let mut file = reader.take(size);
+
+            let mut buf = vec![0; 1024 * 1024];
+            loop {
+                let len = file.read(&mut buf).await?;
+                if len == 0 {
+                    break;
+                }
+                if expected.is_some() {
+                    checksum = checksum_update(checksum, &buf[..len]);
+                }
+                dst.write_all(&buf[..len]).await?;
             }
-            dst.write_all(&buf[..len]).await?;
-        }
 
-        let mut reader = file.into_inner();
-        if size % 2 != 0 {
-            reader.read_u8().await?;
+            // Archive ends in the middle of the content.
+            if file.limit() > 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            reader = file.into_inner();
+            let padding = self.header.format().data_padding(size);
+            reader.read_exact(&mut [0; tar::BLOCK][..padding]).await?;
         }
 
-        Ok(Reader { reader })
+        let mismatch =
+            expected
+                .filter(|x| *x != checksum)
+                .map(|expected| ReadError::ChecksumMismatch {
+                    expected,
+                    found: checksum,
+                });
+        let reader = Reader {
+            reader,
+            links: self.links,
+            recovery: self.recovery,
+        };
+        Ok((reader, mismatch))
     }
 
     /// Skips file on non-seekable reader
@@ -68,14 +200,21 @@ impl<R: AsyncRead + Unpin> ReadFile<R> {
     where
         R: AsyncSeek,
     {
-        let mut size = self.header.size();
-        if size % 2 != 0 {
-            size += 1;
+        if self.body.is_some() {
+            return Ok(Reader {
+                reader: self.reader,
+                links: self.links,
+                recovery: self.recovery,
+            });
         }
+        let size = self.header.size();
+        let size = size + self.header.format().data_padding(size) as u64;
         #[allow(clippy::cast_possible_wrap)] // size can't be moore than 2^48
         self.reader.seek(SeekFrom::Current(size as i64)).await?;
         Ok(Reader {
             reader: self.reader,
+            links: self.links,
+            recovery: self.recovery,
         })
     }
 
@@ -83,14 +222,37 @@ impl<R: AsyncRead + Unpin> ReadFile<R> {
     pub fn info(&self) -> Info<External> {
         // We should skip NUL byte in the end.
         let name = &self.filename[..self.filename.len() - 1];
-        self.header.info(name)
+        let mut info = self.header.info(name);
+        if let (UnspecifiedInfo::Symlink(link), Some(body)) = (&mut info.data, &self.body) {
+            link.target = body.clone();
+        }
+        info
+    }
+
+    /// Layout and the whole size of the file with holes, when it is stored in the header,
+    /// see [`TarHeader::sparse`]. Layout from the manifest is used by colbak instead.
+    pub fn sparse(&self) -> Option<(SparseMap, u64)> {
+        match &self.header {
+            Header::Tar(tar) => tar.sparse(),
+            _ => None,
+        }
+    }
+
+    /// When this entry is a hard link to a file extracted earlier, returns path of that file.
+    /// Such entries have no content, so the link should be created instead.
+    pub fn hard_link(&self) -> Option<&EncodedPath<External>> {
+        self.hard_link.as_ref()
     }
 }
 
 /// Optional metadata that is stored in the `TRAILER!!!` entry.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct UnpackedArchive {
-    pub files: Option<Vec<Info<External>>>,
+    pub manifest: Option<Manifest<External>>,
+    /// Manifest could not be read. It is not critical, since all files are already extracted.
+    pub warning: Option<ManifestError>,
+    /// Damaged parts of the archive, when it was read by [`Reader::recover`].
+    pub recovery: Option<RecoveryReport>,
 }
 
 pub enum NextItem<R> {
@@ -108,50 +270,358 @@ pub enum ReadingError {
     InvalidHeader,
     /// Filename does not ends with zero byte
     InvalidName,
-    CantDeserializeArchive {
-        source: serde_json::Error,
-    },
+    #[snafu(display("Symbolic link target is too long ({} bytes)", size))]
+    LinkTooLong { size: u64 },
+    #[snafu(display("Extended tar header is too large ({} bytes)", size))]
+    ExtendedTooLarge { size: u64 },
+    /// Entry that was not selected can't be skipped.
+    SkipFailed { source: ReadError },
 }
 
 impl<R: AsyncRead + Unpin> Reader<R> {
-    pub async fn advance(mut self) -> Result<NextItem<R>, ReadingError> {
-        let mut header = [0; size_of::<CpioHeader>()];
+    /// Reads header in any of supported formats, detecting it by magic.
+    /// Anything that is not cpio is expected to be tar.
+    async fn read_header(&mut self) -> Result<Header, ReadingError> {
+        let mut magic = [0; 6];
         self.reader
-            .read_exact(&mut header)
+            .read_exact(&mut magic[..2])
             .await
             .context(IoFailed {})?;
-        let header = CpioHeader::decode(header).context(InvalidHeader)?;
+        if Format::detect(&magic[..2]) != Some(Format::Binary) {
+            self.reader
+                .read_exact(&mut magic[2..])
+                .await
+                .context(IoFailed {})?;
+        }
 
-        let mut filename = vec![0; header.namesize as usize];
-        self.reader
-            .read_exact(&mut filename)
-            .await
-            .context(IoFailed {})?;
-        if filename.last().copied() != Some(0) {
-            return InvalidName.fail();
+        let header = match Format::detect(&magic).unwrap_or(Format::Pax) {
+            Format::Binary => {
+                let mut header = [0; size_of::<CpioHeader>()];
+                header[..2].copy_from_slice(&magic[..2]);
+                self.reader
+                    .read_exact(&mut header[2..])
+                    .await
+                    .context(IoFailed {})?;
+                Header::Binary(CpioHeader::decode(header).context(InvalidHeader)?)
+            }
+            Format::Newc | Format::Crc => {
+                let mut header = [0; NewcHeader::LEN];
+                header[..6].copy_from_slice(&magic);
+                self.reader
+                    .read_exact(&mut header[6..])
+                    .await
+                    .context(IoFailed {})?;
+                Header::Newc(NewcHeader::decode(&header).context(InvalidHeader)?)
+            }
+            Format::Pax => {
+                let mut block = [0; tar::BLOCK];
+                block[..6].copy_from_slice(&magic);
+                self.reader
+                    .read_exact(&mut block[6..])
+                    .await
+                    .context(IoFailed {})?;
+                Header::Tar(self.read_tar(block).await?)
+            }
+        };
+        Ok(header)
+    }
+
+    /// Reads tar header starting with given block, applying all extended headers before it.
+    async fn read_tar(&mut self, mut block: [u8; tar::BLOCK]) -> Result<TarHeader, ReadingError> {
+        let mut extended = tar::Extended::default();
+        loop {
+            if tar::is_end(&block) {
+                // Archive is ended by two zero blocks.
+                self.reader
+                    .read_exact(&mut block)
+                    .await
+                    .context(IoFailed {})?;
+                return Ok(TarHeader::end());
+            }
+            if !matches!(block[156], b'x' | b'g' | b'L' | b'K') {
+                return TarHeader::decode(&block, extended).context(InvalidHeader);
+            }
+
+            let header =
+                TarHeader::decode(&block, tar::Extended::default()).context(InvalidHeader)?;
+            let size = header.size();
+            snafu::ensure!(size <= tar::MAX_EXTENDED_SIZE, ExtendedTooLarge { size });
+            // Size is limited above.
+            #[allow(clippy::cast_possible_truncation)]
+            let mut content = vec![0; size as usize + tar::padding(size)];
+            self.reader
+                .read_exact(&mut content)
+                .await
+                .context(IoFailed {})?;
+            if !extended.update(header.typeflag(), &content) {
+                return InvalidHeader.fail();
+            }
+            self.reader
+                .read_exact(&mut block)
+                .await
+                .context(IoFailed {})?;
+        }
+    }
+
+    /// Reads the manifest stored after the end of archive.
+    async fn read_end(mut self) -> Result<NextItem<R>, ReadingError> {
+        let parsed = Manifest::read(&mut self.reader).await.context(IoFailed)?;
+        let (manifest, warning) = match parsed {
+            Ok(manifest) => (manifest, None),
+            Err(e) => (None, Some(e)),
+        };
+        Ok(NextItem::End(UnpackedArchive {
+            manifest,
+            warning,
+            recovery: self.recovery.map(Recovery::report),
+        }))
+    }
+
+    pub async fn advance(mut self) -> Result<NextItem<R>, ReadingError> {
+        loop {
+            if let Some(entry) = self.links.ready.pop_front() {
+                return self.next_item(entry).await;
+            }
+            let entry = self.read_entry().await?;
+            if let Some(entry) = self.links.arrange(entry) {
+                return self.next_item(entry).await;
+            }
         }
+    }
+
+    /// Reads header of the next entry with everything stored before its content.
+    async fn read_entry(&mut self) -> Result<Entry, ReadingError> {
+        let header = self.read_header().await?;
+        self.read_name(header).await
+    }
+
+    /// Reads the map of sparse file extents that precedes its data, see [`TarHeader::set_map`].
+    async fn read_map(&mut self, tar: &mut TarHeader) -> Result<(), ReadingError> {
+        let mut content = Vec::new();
+        let extents = loop {
+            let start = content.len();
+            let size = start as u64;
+            snafu::ensure!(size < tar::MAX_EXTENDED_SIZE, ExtendedTooLarge { size });
+            // Map is a part of the data, it can't be longer.
+            snafu::ensure!(size + tar::BLOCK as u64 <= tar.size(), InvalidHeader);
+            content.resize(start + tar::BLOCK, 0);
+            self.reader
+                .read_exact(&mut content[start..])
+                .await
+                .context(IoFailed {})?;
+            if let Some(extents) = tar::parse_map(&content) {
+                break extents;
+            }
+        };
+        snafu::ensure!(tar.set_map(extents, content.len() as u64), InvalidHeader);
+        Ok(())
+    }
 
-        if header.namesize % 2 != 0 {
-            self.reader.read_u8().await.context(IoFailed {})?;
+    /// Reads name of the entry and everything else stored after the header, before the content.
+    async fn read_name(&mut self, mut header: Header) -> Result<Entry, ReadingError> {
+        if let Header::Tar(tar) = &mut header {
+            if tar.has_map() {
+                self.read_map(tar).await?;
+            }
         }
+        let filename = if let Header::Tar(tar) = &header {
+            // Name is a part of tar header.
+            [tar.name(), &b"\0"[..]].concat()
+        } else {
+            snafu::ensure!(header.namesize() <= MAX_NAME_SIZE, InvalidHeader);
+            let mut filename = vec![0; header.namesize()];
+            self.reader
+                .read_exact(&mut filename)
+                .await
+                .context(IoFailed {})?;
+            if filename.last().copied() != Some(0) {
+                return InvalidName.fail();
+            }
 
+            let padding = header.format().name_padding(header.namesize());
+            self.reader
+                .read_exact(&mut [0; tar::BLOCK][..padding])
+                .await
+                .context(IoFailed {})?;
+            filename
+        };
+
+        let body = if let Header::Tar(tar) = &header {
+            tar.link_target().map(<[u8]>::to_vec)
+        } else if header.is_symlink() {
+            let size = header.size();
+            snafu::ensure!(size <= MAX_LINK_SIZE, LinkTooLong { size });
+            // Size is limited above.
+            #[allow(clippy::cast_possible_truncation)]
+            let mut body = vec![0; size as usize];
+            self.reader
+                .read_exact(&mut body)
+                .await
+                .context(IoFailed {})?;
+            let padding = header.format().data_padding(size);
+            self.reader
+                .read_exact(&mut [0; tar::BLOCK][..padding])
+                .await
+                .context(IoFailed {})?;
+            Some(body)
+        } else {
+            None
+        };
+        Ok(Entry {
+            header,
+            filename,
+            body,
+        })
+    }
+
+    async fn next_item(mut self, entry: Entry) -> Result<NextItem<R>, ReadingError> {
+        let Entry {
+            header,
+            filename,
+            body,
+        } = entry;
         if header.is_trailer(&filename) {
-            // FIXME: Limit size of json.
-            let mut json = Vec::new();
-            self.reader.read_to_end(&mut json).await.context(IoFailed)?;
-            let files = if json.iter().all(|x| *x == 0) {
-                None
-            } else {
-                // FIXME: This error is not critical.
-                Some(serde_json::from_slice(&json).context(CantDeserializeArchive {})?)
-            };
-            return Ok(NextItem::End(UnpackedArchive { files }));
+            return self.read_end().await;
+        }
+
+        let mut hard_link = match &header {
+            Header::Tar(tar) => tar.hard_link().map(|x| EncodedPath::from_vec(x.to_vec())),
+            _ => None,
+        };
+        if let Some(id) = header.hard_link_id() {
+            match self.links.paths.get(&id) {
+                Some(original) if header.size() == 0 => hard_link = Some(original.clone()),
+                Some(_) => {}
+                None => {
+                    let name = filename[..filename.len() - 1].to_vec();
+                    self.links.paths.insert(id, EncodedPath::from_vec(name));
+                }
+            }
         }
 
         Ok(NextItem::File(ReadFile {
             filename,
             reader: self.reader,
             header,
+            body,
+            hard_link,
+            links: self.links,
+            recovery: self.recovery,
         }))
     }
 }
+
+impl<R: AsyncRead + AsyncSeek + Unpin> Reader<R> {
+    /// Same as [`advance`](Self::advance), but damaged entries are skipped instead of failing.
+    ///
+    /// Bytes after them are scanned for the next header, and the manifest at the end of
+    /// the archive is used to check it when it can be found. Skipped parts are reported in
+    /// [`UnpackedArchive::recovery`]. Each call may skip damaged entries, so once it is used
+    /// the whole archive should be read by it.
+    pub async fn recover(mut self) -> Result<NextItem<R>, ReadingError> {
+        if let Some(entry) = self.links.ready.pop_front() {
+            return self.next_item(entry).await;
+        }
+        let mut recovery = match self.recovery.take() {
+            Some(recovery) => recovery,
+            None => Recovery::new(&mut self.reader).await.context(IoFailed)?,
+        };
+        let mut offset = self.position().await?;
+        loop {
+            if let Some(entry) = self.read_plausible(&recovery, offset).await? {
+                if let Some(entry) = self.links.arrange(entry) {
+                    self.recovery = Some(recovery);
+                    return self.next_item(entry).await;
+                }
+                offset = self.position().await?;
+                continue;
+            }
+            let next = recovery
+                .next_candidate(&mut self.reader, offset + 1)
+                .await
+                .context(IoFailed)?;
+            match next {
+                Some(next) => {
+                    recovery.skip(offset..next);
+                    offset = next;
+                }
+                None => {
+                    recovery.skip(offset..recovery.data_end());
+                    return Ok(NextItem::End(UnpackedArchive {
+                        manifest: recovery.take_manifest(),
+                        warning: None,
+                        recovery: Some(recovery.report()),
+                    }));
+                }
+            }
+        }
+    }
+
+    async fn position(&mut self) -> Result<u64, ReadingError> {
+        self.reader
+            .seek(SeekFrom::Current(0))
+            .await
+            .context(IoFailed)
+    }
+
+    /// Reads entry at `offset`, returning `None` when it is damaged.
+    async fn read_plausible(
+        &mut self,
+        recovery: &Recovery,
+        offset: u64,
+    ) -> Result<Option<Entry>, ReadingError> {
+        self.reader
+            .seek(SeekFrom::Start(offset))
+            .await
+            .context(IoFailed)?;
+        let header = match self.read_header().await {
+            Ok(header) => header,
+            Err(e) if is_damage(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // Damaged sizes may be huge, so nothing is read when the entry ends after the data.
+        let mut end = self.position().await?;
+        if !matches!(header, Header::Tar(_)) {
+            let namesize = header.namesize();
+            end = end.saturating_add((namesize + header.format().name_padding(namesize)) as u64);
+        }
+        let size = header.size();
+        let end = end
+            .saturating_add(size)
+            .saturating_add(header.format().data_padding(size) as u64);
+        if end > recovery.data_end() {
+            return Ok(None);
+        }
+        let entry = match self.read_name(header).await {
+            Ok(entry) => entry,
+            Err(e) if is_damage(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if entry.header.is_trailer(&entry.filename) {
+            let position = self.position().await?;
+            return Ok(recovery.is_plausible_end(offset, position).then(|| entry));
+        }
+
+        let name = &entry.filename[..entry.filename.len() - 1];
+        let known_kind = !matches!(entry.header.info(name).data, UnspecifiedInfo::Unknown(_));
+        Ok(recovery
+            .is_plausible(offset, end, name, known_kind)
+            .then(|| entry))
+    }
+}
+
+/// Header of an entry with its name and body, see [`ReadFile`].
+struct Entry {
+    header: Header,
+    filename: Vec<u8>,
+    body: Option<Vec<u8>>,
+}
+
+/// Whether the error is caused by damaged bytes, rather than by failed reading.
+fn is_damage(error: &ReadingError) -> bool {
+    match error {
+        ReadingError::IoFailed { source, .. } => source.kind() == ErrorKind::UnexpectedEof,
+        ReadingError::SkipFailed { .. } => false,
+        _ => true,
+    }
+}
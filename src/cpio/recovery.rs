@@ -0,0 +1,203 @@
+//! Reading of damaged archives, see [`Reader::recover`](super::Reader::recover).
+//!
+//! When an entry can't be read, following bytes are scanned for the next magic of any format,
+//! and the header found there is used only when it looks plausible. Manifest is found at the
+//! end of the archive before that, so headers are looked up at offsets it lists instead,
+//! and recovered entries are checked against it: they should have the same path and end
+//! exactly where the next one starts. Everything that was skipped is [reported](RecoveryReport).
+
+use super::manifest::{ManifestEntry, MAX_MANIFEST_SIZE};
+use super::{tar, CpioHeader, Format, Manifest};
+use crate::path::{EncodedPath, External, PathKind};
+use std::collections::BTreeMap;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+/// Size of a chunk read at once while looking for the next header.
+const WINDOW: usize = 64 * 1024;
+
+/// Parts of damaged archive that could not be read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Byte ranges that were skipped, in order.
+    pub skipped: Vec<Range<u64>>,
+    /// Files listed in the manifest, which headers are inside skipped ranges.
+    pub lost: Vec<EncodedPath<External>>,
+    /// Whether the manifest with offsets was found, so recovered entries were checked against it.
+    pub verified: bool,
+}
+
+impl RecoveryReport {
+    /// Whether the entry of the manifest was lost.
+    #[must_use]
+    pub fn is_lost<P: PathKind>(&self, entry: &ManifestEntry<P>) -> bool {
+        entry.offsets.map_or(false, |offsets| {
+            self.skipped.iter().any(|x| x.contains(&offsets.header))
+        })
+    }
+}
+
+/// State of [`Reader`](super::Reader) that skips damaged entries.
+pub(super) struct Recovery {
+    /// Length of the whole archive.
+    length: u64,
+    manifest: Option<Manifest<External>>,
+    /// Where the manifest starts, right after the end of archive.
+    manifest_start: Option<u64>,
+    /// Paths of entries listed in the manifest, by offsets of their headers.
+    known: BTreeMap<u64, EncodedPath<External>>,
+    skipped: Vec<Range<u64>>,
+}
+
+impl Recovery {
+    /// Looks for the manifest at the end of the archive, and returns to the current position.
+    pub(super) async fn new<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let position = reader.seek(SeekFrom::Current(0)).await?;
+        let length = reader.seek(SeekFrom::End(0)).await?;
+        let limit = length.saturating_sub(MAX_MANIFEST_SIZE);
+
+        // Manifest is JSON, so it never has NUL bytes, but the end of archive always does.
+        let mut start = length;
+        let mut buffer = vec![0; WINDOW];
+        while start > limit {
+            // Limited by the size of the buffer.
+            #[allow(clippy::cast_possible_truncation)]
+            let len = (start - limit).min(WINDOW as u64) as usize;
+            let window = &mut buffer[..len];
+            reader.seek(SeekFrom::Start(start - len as u64)).await?;
+            reader.read_exact(window).await?;
+            if let Some(x) = window.iter().rposition(|x| *x == 0) {
+                start = start - len as u64 + x as u64 + 1;
+                break;
+            }
+            start -= len as u64;
+        }
+        reader.seek(SeekFrom::Start(start)).await?;
+        let manifest = Manifest::<External>::read(reader).await?.ok().flatten();
+        reader.seek(SeekFrom::Start(position)).await?;
+
+        let manifest_start = manifest.as_ref().map(|_| start);
+        let known = manifest
+            .iter()
+            .flat_map(|x| &x.files)
+            .filter_map(|x| Some((x.offsets?.header, x.info.path.clone())))
+            .collect();
+        Ok(Recovery {
+            length,
+            manifest,
+            manifest_start,
+            known,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Manifest found at the end of the archive, it is returned only once.
+    pub(super) fn take_manifest(&mut self) -> Option<Manifest<External>> {
+        self.manifest.take()
+    }
+
+    /// Where entries of the archive end, so nothing after it is scanned.
+    pub(super) fn data_end(&self) -> u64 {
+        self.manifest_start.unwrap_or(self.length)
+    }
+
+    /// Marks bytes as skipped, merging them with the previous range when they touch.
+    pub(super) fn skip(&mut self, range: Range<u64>) {
+        match self.skipped.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.skipped.push(range),
+        }
+    }
+
+    /// Checks that entry with the header at `offset`, which ends at `end`, is a real one.
+    /// `name` does not include the NUL byte.
+    pub(super) fn is_plausible(
+        &self,
+        offset: u64,
+        end: u64,
+        name: &[u8],
+        known_kind: bool,
+    ) -> bool {
+        if end > self.data_end() {
+            return false;
+        }
+        if !self.known.is_empty() {
+            let next = self.known.range(offset + 1..).next().map(|(x, _)| *x);
+            let path = self.known.get(&offset).map(EncodedPath::as_bytes);
+            return path == Some(name) && next.map_or(true, |x| x == end);
+        }
+        !name.is_empty() && !name.contains(&0) && known_kind
+    }
+
+    /// Checks that the end of archive at `offset`, with everything up to `end`, is a real one.
+    pub(super) fn is_plausible_end(&self, offset: u64, end: u64) -> bool {
+        self.known.range(offset..).next().is_none()
+            && self.manifest_start.map_or(true, |x| x == end)
+    }
+
+    /// Finds offset of the next header after `from`, it may still be damaged.
+    pub(super) async fn next_candidate<R: AsyncRead + AsyncSeek + Unpin>(
+        &self,
+        reader: &mut R,
+        from: u64,
+    ) -> io::Result<Option<u64>> {
+        if let Some((offset, _)) = self.known.range(from..).next() {
+            return Ok(Some(*offset));
+        }
+        let tar_end = self
+            .manifest_start
+            .and_then(|x| x.checked_sub(2 * tar::BLOCK as u64));
+
+        let end = self.data_end();
+        let mut position = from;
+        while position < end {
+            reader.seek(SeekFrom::Start(position)).await?;
+            let mut window = Vec::with_capacity(WINDOW);
+            // Nothing after the data is read, it is never scanned anyway.
+            (&mut *reader)
+                .take((end - position).min(WINDOW as u64))
+                .read_to_end(&mut window)
+                .await?;
+            let candidates = (position..end).take(window.len()).enumerate();
+            for (n, offset) in candidates {
+                if is_magic(&window[n..], offset) || Some(offset) == tar_end {
+                    return Ok(Some(offset));
+                }
+            }
+            if window.len() < WINDOW {
+                break;
+            }
+            // Tar magic is in the middle of the header, so windows overlap.
+            position += (WINDOW - tar::BLOCK) as u64;
+        }
+        Ok(None)
+    }
+
+    pub(super) fn report(self) -> RecoveryReport {
+        let mut report = RecoveryReport {
+            skipped: self.skipped,
+            lost: Vec::new(),
+            verified: !self.known.is_empty(),
+        };
+        report.lost = self
+            .known
+            .into_iter()
+            .filter(|(offset, _)| report.skipped.iter().any(|x| x.contains(offset)))
+            .map(|(_, path)| path)
+            .collect();
+        report
+    }
+}
+
+/// Whether a header of any format may start here. Headers are always aligned.
+fn is_magic(bytes: &[u8], offset: u64) -> bool {
+    let binary = offset % 2 == 0 && bytes.len() >= 2 && CpioHeader::is_magic([bytes[0], bytes[1]]);
+    let newc = offset % 4 == 0
+        && matches!(
+            bytes.get(..6).and_then(Format::detect),
+            Some(Format::Newc | Format::Crc)
+        );
+    let tar = offset % tar::BLOCK as u64 == 0 && bytes.get(257..262) == Some(&b"ustar"[..]);
+    binary || newc || tar
+}
@@ -0,0 +1,155 @@
+//! Content of entries that are not local files, such as database dumps or command output.
+//!
+//! Such entry is added by [`Archive::add_source`](super::Archive::add_source) together with
+//! its declared [info](crate::fileinfo::Info), and its content is checked just like the one of
+//! a local file: size and hash are compared with the info, see [`ChangePolicy`](super::pending::ChangePolicy).
+//! Holes, locks and [identifiers](crate::fileinfo::FileIdentifier) are not applicable to them.
+
+use futures::ready;
+use pin_project_lite::pin_project;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Reader returned by [`Source::open`](Source::open).
+pub type SourceReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Anything that can provide content of an entry.
+pub trait Source: fmt::Debug + Send + Sync {
+    /// Starts reading the content from the beginning.
+    ///
+    /// Usually it is called once, but [`Crc`](super::Format::Crc) format needs the content twice:
+    /// to compute checksum before the header and to write it after.
+    fn open(&self) -> io::Result<SourceReader>;
+}
+
+/// Content that is already in memory.
+#[derive(Clone)]
+pub struct Memory {
+    data: Arc<[u8]>,
+}
+
+impl From<Vec<u8>> for Memory {
+    fn from(data: Vec<u8>) -> Self {
+        Memory { data: data.into() }
+    }
+}
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Memory({} bytes)", self.data.len())
+    }
+}
+
+impl Source for Memory {
+    fn open(&self) -> io::Result<SourceReader> {
+        Ok(Box::new(io::Cursor::new(Arc::clone(&self.data))))
+    }
+}
+
+/// Stream that can be read only once, such as output of a command.
+///
+/// It can't be stored in [`Crc`](super::Format::Crc) archives, since it is needed twice there.
+pub struct Once {
+    reader: Mutex<Option<SourceReader>>,
+}
+
+impl Once {
+    pub fn new(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Once {
+            reader: Mutex::new(Some(Box::new(reader))),
+        }
+    }
+}
+
+impl fmt::Debug for Once {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Once")
+    }
+}
+
+impl Source for Once {
+    fn open(&self) -> io::Result<SourceReader> {
+        let taken = match self.reader.lock() {
+            Ok(mut reader) => reader.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        taken.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Source can be read only once"))
+    }
+}
+
+pin_project! {
+    /// Reads at most `limit` bytes of the source. Everything after that is counted,
+    /// so a source that is longer than declared is detected, but never written.
+    pub struct Limited {
+        #[pin]
+        inner: SourceReader,
+        limit: u64,
+        // Number of bytes read from the source, including ones after the limit.
+        found: u64,
+        // Set when the source ended before the limit.
+        ended: bool,
+    }
+}
+
+impl Limited {
+    #[must_use]
+    pub fn new(inner: SourceReader, limit: u64) -> Self {
+        Limited {
+            inner,
+            limit,
+            found: 0,
+            ended: false,
+        }
+    }
+
+    /// Real size of the source, known once everything was read.
+    #[must_use]
+    pub fn found(&self) -> u64 {
+        self.found
+    }
+}
+
+impl AsyncRead for Limited {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if *this.ended {
+            return Poll::Ready(Ok(()));
+        }
+        if *this.found >= *this.limit {
+            let mut buffer = [0; 8 * 1024];
+            loop {
+                let mut scratch = ReadBuf::new(&mut buffer);
+                ready!(this.inner.as_mut().poll_read(cx, &mut scratch))?;
+                if scratch.filled().is_empty() {
+                    *this.ended = true;
+                    return Poll::Ready(Ok(()));
+                }
+                *this.found += scratch.filled().len() as u64;
+            }
+        }
+
+        // Never larger than `buf.remaining()`.
+        #[allow(clippy::cast_possible_truncation)]
+        let len = (*this.limit - *this.found).min(buf.remaining() as u64) as usize;
+        let mut limited = buf.take(len);
+        ready!(this.inner.poll_read(cx, &mut limited))?;
+        let filled = limited.filled().len();
+        // Bytes were written into the same memory.
+        unsafe {
+            buf.assume_init(filled);
+        }
+        buf.advance(filled);
+        // Source is shorter than declared when nothing is read, size check will fail.
+        *this.ended = filled == 0;
+        *this.found += filled as u64;
+        Poll::Ready(Ok(()))
+    }
+}
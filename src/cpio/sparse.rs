@@ -0,0 +1,265 @@
+//! Files with holes, mostly virtual machine images and databases.
+//!
+//! Only data extents of such files are written to the archive, one right after another.
+//! Layout of the file is stored in the manifest, see [`SparseMap`](SparseMap), so holes are
+//! recreated when the whole archive is extracted.
+//!
+//! [`Pax`](super::Format::Pax) archives store the layout before the data too, in the format
+//! other archivers understand, see [`tar`](super::tar). Cpio has nothing like that, so tools
+//! that ignore the manifest extract extents glued together, and such files are stored whole
+//! unless [asked otherwise](super::Archive::with_sparse).
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::fileext;
+use crate::types::Checksum;
+use crate::DefaultDigest;
+
+/// Part of the file that contains data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Layout of a sparse file, stored in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseMap {
+    /// Sorted and never overlapping. Everything between them is a hole.
+    pub extents: Vec<Extent>,
+    /// Hash of the data as it is stored in the archive, without holes.
+    /// Hash in the info is computed over the whole file, as for any other file.
+    pub hash: Option<Checksum>,
+}
+
+impl SparseMap {
+    /// Finds holes in the file. Returns `None` when there are none, or they can't be detected.
+    pub fn detect(file: &std::fs::File, size: u64) -> io::Result<Option<Self>> {
+        let ranges = fileext::data_ranges(file, size)?;
+        let stored: u64 = ranges.iter().map(|x| x.end - x.start).sum();
+        if stored == size {
+            return Ok(None);
+        }
+        Ok(Some(SparseMap {
+            extents: ranges
+                .into_iter()
+                .map(|x| Extent {
+                    offset: x.start,
+                    length: x.end - x.start,
+                })
+                .collect(),
+            hash: None,
+        }))
+    }
+
+    /// Number of bytes stored in the archive.
+    #[must_use]
+    pub fn stored_size(&self) -> u64 {
+        self.extents.iter().map(|x| x.length).sum()
+    }
+
+    /// Turns extracted file with glued extents into a sparse file of given size.
+    ///
+    /// File is rewritten in place, so hard links to it are kept.
+    /// Stored data is copied aside first, since holes may only be created by truncating the file.
+    pub fn expand(&self, path: &Path, size: u64) -> io::Result<()> {
+        let (temp, mut stored) = tempfile_near(path)?;
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        io::copy(&mut file, &mut stored)?;
+        stored.seek(SeekFrom::Start(0))?;
+
+        file.set_len(0)?;
+        for extent in &self.extents {
+            file.seek(SeekFrom::Start(extent.offset))?;
+            let copied = io::copy(&mut (&mut stored).take(extent.length), &mut file)?;
+            if copied != extent.length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        file.set_len(size)?;
+        file.flush()?;
+        drop(stored);
+        std::fs::remove_file(temp)
+    }
+}
+
+/// Removes first `length` bytes of data from the extents, when they are already read.
+#[must_use]
+pub fn skip_extents(extents: &[Extent], mut length: u64) -> Vec<Extent> {
+    let mut result = Vec::with_capacity(extents.len());
+    for extent in extents {
+        if length >= extent.length {
+            length -= extent.length;
+            continue;
+        }
+        result.push(Extent {
+            offset: extent.offset + length,
+            length: extent.length - length,
+        });
+        length = 0;
+    }
+    result
+}
+
+/// Creates a new file next to `path` and returns both.
+fn tempfile_near(path: &Path) -> io::Result<(std::path::PathBuf, std::fs::File)> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".colbak-sparse");
+    let temp = path.with_file_name(name);
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&temp)?;
+    Ok((temp, file))
+}
+
+/// Hashes the whole file, while only its data extents are read.
+pub struct HoleHasher {
+    extents: Vec<Extent>,
+    index: usize,
+    /// Position in the original file.
+    position: u64,
+    stored: DefaultDigest,
+}
+
+impl HoleHasher {
+    #[must_use]
+    pub fn new(map: &SparseMap) -> Self {
+        HoleHasher {
+            extents: map.extents.clone(),
+            index: 0,
+            position: 0,
+            stored: DefaultDigest::default(),
+        }
+    }
+
+    /// Feeds next stored bytes to the `hasher`, preceded by zeroes of skipped holes.
+    pub fn update(&mut self, hasher: &mut DefaultDigest, mut data: &[u8]) {
+        self.stored.update(data);
+        while !data.is_empty() {
+            let extent = if let Some(extent) = self.extents.get(self.index) {
+                *extent
+            } else {
+                // More data than expected, size check will fail anyway.
+                hasher.update(data);
+                return;
+            };
+            if self.position < extent.offset {
+                hash_zeroes(hasher, extent.offset - self.position);
+                self.position = extent.offset;
+            }
+            let left = extent.offset + extent.length - self.position;
+            // Never larger than `data.len()`.
+            #[allow(clippy::cast_possible_truncation)]
+            let len = left.min(data.len() as u64) as usize;
+            hasher.update(&data[..len]);
+            data = &data[len..];
+            self.position += len as u64;
+            if self.position == extent.offset + extent.length {
+                self.index += 1;
+            }
+        }
+    }
+
+    /// Hashes the final hole, returns hash of stored data.
+    pub fn finish(self, hasher: &mut DefaultDigest, size: u64) -> Checksum {
+        if self.position < size {
+            hash_zeroes(hasher, size - self.position);
+        }
+        self.stored.finalize().into()
+    }
+}
+
+fn hash_zeroes(hasher: &mut DefaultDigest, mut length: u64) {
+    const ZEROES: [u8; 64 * 1024] = [0; 64 * 1024];
+    while length > 0 {
+        // Never larger than the buffer.
+        #[allow(clippy::cast_possible_truncation)]
+        let len = length.min(ZEROES.len() as u64) as usize;
+        hasher.update(&ZEROES[..len]);
+        length -= len as u64;
+    }
+}
+
+pin_project! {
+    /// Reads only given extents of the file, one after another.
+    /// Anything after the last extent is never read, even when the file grows.
+    ///
+    /// `left` is the number of bytes left in the current extent.
+    pub struct ExtentReader<R> {
+        #[pin]
+        inner: R,
+        extents: std::vec::IntoIter<Extent>,
+        left: u64,
+        seeking: bool,
+    }
+}
+
+impl<R> ExtentReader<R> {
+    pub fn new(inner: R, extents: Vec<Extent>) -> Self {
+        ExtentReader {
+            inner,
+            extents: extents.into_iter(),
+            left: 0,
+            seeking: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek> AsyncRead for ExtentReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        while *this.left == 0 {
+            if !*this.seeking {
+                let extent = match this.extents.as_slice().first() {
+                    Some(extent) => *extent,
+                    None => return Poll::Ready(Ok(())),
+                };
+                this.inner
+                    .as_mut()
+                    .start_seek(SeekFrom::Start(extent.offset))?;
+                *this.seeking = true;
+            }
+            ready!(this.inner.as_mut().poll_complete(cx))?;
+            *this.seeking = false;
+            *this.left = this.extents.next().map_or(0, |x| x.length);
+        }
+
+        // Never larger than `buf.remaining()`.
+        #[allow(clippy::cast_possible_truncation)]
+        let len = (*this.left).min(buf.remaining() as u64) as usize;
+        let mut limited = buf.take(len);
+        ready!(this.inner.poll_read(cx, &mut limited))?;
+        let filled = limited.filled().len();
+        // Bytes were written into the same memory.
+        unsafe {
+            buf.assume_init(filled);
+        }
+        buf.advance(filled);
+        if filled == 0 {
+            // File was truncated, size check will fail.
+            *this.left = 0;
+            this.extents.for_each(drop);
+        } else {
+            *this.left -= filled as u64;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,717 @@
+//! POSIX tar archives in pax format, see
+//! [`pax(1)`](https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html).
+//!
+//! Every entry is an ustar header, preceded by an extended header when something does not fit:
+//! long names and link targets, big files and ids, fractional times and extended attributes.
+//!
+//! Only the first of hard links to the same data has it, and others are link entries pointing
+//! to its path, so other archivers extract them as links too. Sockets have no ustar type,
+//! so they are not archived in this format at all.
+//!
+//! Files with holes are stored in GNU sparse 1.0 format, which GNU tar and libarchive extract
+//! with holes: the map of extents precedes the data, and only the data of extents is stored.
+//!
+//! Archive ends with two zero blocks, and the manifest follows them, so `tar` never sees it.
+
+use super::sparse::{Extent, SparseMap};
+use super::{
+    decode_kind, entry_rdev, file_link_id, join_rdev, split_rdev, EntryHeader, Format, S_IFLNK,
+};
+use crate::fileinfo::{Info, SpecialInfo, SpecialKind, SymlinkInfo, UnspecifiedInfo, Xattr};
+use crate::path::{EncodedPath, External, PathKind};
+use crate::DateTime;
+use std::borrow::Cow;
+
+/// Size of every header and alignment of data.
+pub const BLOCK: usize = 512;
+
+/// Extended headers larger than that are considered broken.
+pub const MAX_EXTENDED_SIZE: u64 = 1024 * 1024;
+
+const MAGIC: &[u8] = b"ustar\0";
+const VERSION: &[u8] = b"00";
+const XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+/// Directory of files with holes for readers that don't know GNU sparse format.
+const SPARSE_DIR: &[u8] = b"GNUSparseFile.0/";
+
+/// Largest value of octal field with given width, one byte is taken by NUL.
+fn octal_max(width: usize) -> u64 {
+    (1 << (3 * (width as u64 - 1))) - 1
+}
+
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+/// Parses octal number, or base-256 one used by GNU tar for large values.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    if field.first().map_or(false, |x| x & 0x80 != 0) {
+        // Wide fields start with zero bytes, values that do not fit are rejected.
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7f), |acc, x| {
+                (acc >> 56 == 0).then(|| (acc << 8) | u64::from(*x))
+            });
+    }
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|x: char| x == '\0' || x == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Bytes of the field until the first NUL.
+fn until_nul(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|x| *x == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+/// Appends single `"<length> <key>=<value>\n"` record of extended header.
+fn put_record(records: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let content = key.len() + value.len() + 3;
+    // Length includes its own digits.
+    let mut length = content + 1;
+    while length != content + length.to_string().len() {
+        length = content + length.to_string().len();
+    }
+    records.extend_from_slice(length.to_string().as_bytes());
+    records.push(b' ');
+    records.extend_from_slice(key);
+    records.push(b'=');
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// Fields of ustar header, before the checksum is computed.
+struct Block<'a> {
+    name: &'a [u8],
+    mode: u64,
+    uid: u64,
+    gid: u64,
+    size: u64,
+    mtime: u64,
+    typeflag: u8,
+    linkname: &'a [u8],
+    devmajor: u64,
+    devminor: u64,
+}
+
+impl Block<'_> {
+    /// Encodes the block. Names and numbers that do not fit are truncated,
+    /// they should be stored in the extended header then.
+    fn encode(&self) -> [u8; BLOCK] {
+        let mut block = [0; BLOCK];
+        let name = &self.name[..self.name.len().min(100)];
+        block[..name.len()].copy_from_slice(name);
+        put_octal(&mut block[100..108], self.mode.min(octal_max(8)));
+        put_octal(&mut block[108..116], self.uid.min(octal_max(8)));
+        put_octal(&mut block[116..124], self.gid.min(octal_max(8)));
+        put_octal(&mut block[124..136], self.size.min(octal_max(12)));
+        put_octal(&mut block[136..148], self.mtime.min(octal_max(12)));
+        block[156] = self.typeflag;
+        let linkname = &self.linkname[..self.linkname.len().min(100)];
+        block[157..157 + linkname.len()].copy_from_slice(linkname);
+        block[257..263].copy_from_slice(MAGIC);
+        block[263..265].copy_from_slice(VERSION);
+        put_octal(&mut block[329..337], self.devmajor.min(octal_max(8)));
+        put_octal(&mut block[337..345], self.devminor.min(octal_max(8)));
+
+        // Checksum is computed as if its own field is filled with spaces.
+        block[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = block.iter().map(|x| u64::from(*x)).sum();
+        put_octal(&mut block[148..155], checksum);
+        block[154] = 0;
+        block
+    }
+}
+
+/// Number of NUL bytes added after `size` bytes of data.
+///
+/// ```
+/// # use colbak_lib::cpio::tar::padding;
+/// assert_eq!(padding(0), 0);
+/// assert_eq!(padding(1), 511);
+/// assert_eq!(padding(1024), 0);
+/// ```
+#[must_use]
+pub fn padding(size: u64) -> usize {
+    let block = BLOCK as u64;
+    // Result is always less than a block.
+    #[allow(clippy::cast_possible_truncation)]
+    let padding = ((block - size % block) % block) as usize;
+    padding
+}
+
+/// Whether this block marks the end of archive.
+#[must_use]
+pub fn is_end(block: &[u8]) -> bool {
+    block.iter().all(|x| *x == 0)
+}
+
+/// Generates end of archive followed by `content`.
+#[must_use]
+pub fn trailer(content: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; 2 * BLOCK];
+    result.extend_from_slice(content);
+    result
+}
+
+/// Records of extended headers that apply to the next entry.
+#[derive(Debug, Default)]
+pub struct Extended {
+    path: Option<Vec<u8>>,
+    linkpath: Option<Vec<u8>>,
+    size: Option<u64>,
+    mtime: Option<DateTime>,
+    uid: Option<u64>,
+    gid: Option<u64>,
+    device: Option<u64>,
+    inode: Option<u64>,
+    nlink: Option<u64>,
+    xattrs: Vec<Xattr>,
+    sparse_major: Option<u64>,
+    sparse_name: Option<Vec<u8>>,
+    sparse_size: Option<u64>,
+}
+
+impl Extended {
+    /// Applies content of an extended header with given type, returns false when it is broken.
+    ///
+    /// Besides pax headers, long names of GNU tar are supported too.
+    pub fn update(&mut self, typeflag: u8, content: &[u8]) -> bool {
+        match typeflag {
+            b'x' => self.parse(content).is_some(),
+            b'L' => {
+                self.path = Some(until_nul(content).to_vec());
+                true
+            }
+            b'K' => {
+                self.linkpath = Some(until_nul(content).to_vec());
+                true
+            }
+            // Global headers and anything else are ignored.
+            _ => true,
+        }
+    }
+
+    fn parse(&mut self, mut content: &[u8]) -> Option<()> {
+        while !content.is_empty() && content[0] != 0 {
+            let space = content.iter().position(|x| *x == b' ')?;
+            let length: usize = std::str::from_utf8(&content[..space]).ok()?.parse().ok()?;
+            if length <= space || length > content.len() || content[length - 1] != b'\n' {
+                return None;
+            }
+            let record = &content[space + 1..length - 1];
+            let equals = record.iter().position(|x| *x == b'=')?;
+            self.apply(&record[..equals], &record[equals + 1..]);
+            content = &content[length..];
+        }
+        Some(())
+    }
+
+    fn apply(&mut self, key: &[u8], value: &[u8]) {
+        let number = || std::str::from_utf8(value).ok()?.parse::<u64>().ok();
+        match key {
+            b"path" => self.path = Some(value.to_vec()),
+            b"linkpath" => self.linkpath = Some(value.to_vec()),
+            b"size" => self.size = number(),
+            b"uid" => self.uid = number(),
+            b"gid" => self.gid = number(),
+            b"mtime" => self.mtime = parse_time(value),
+            b"SCHILY.dev" => self.device = number(),
+            b"SCHILY.ino" => self.inode = number(),
+            b"SCHILY.nlink" => self.nlink = number(),
+            b"GNU.sparse.major" => self.sparse_major = number(),
+            b"GNU.sparse.name" => self.sparse_name = Some(value.to_vec()),
+            b"GNU.sparse.realsize" => self.sparse_size = number(),
+            _ if key.starts_with(XATTR_PREFIX) => self.xattrs.push(Xattr {
+                name: key[XATTR_PREFIX.len()..].to_vec(),
+                value: value.to_vec(),
+            }),
+            _ => {}
+        }
+    }
+}
+
+/// Creates map of GNU sparse 1.0 format: number of extents, then offset and length of each,
+/// one number per line, padded to the block. File that ends with a hole gets an empty extent
+/// at its end, so archivers extend it to the whole `size`.
+fn sparse_map(extents: &[Extent], size: u64) -> Vec<u8> {
+    let end = extents.last().map_or(0, |x| x.offset + x.length);
+    let last = if end < size {
+        Some(Extent {
+            offset: size,
+            length: 0,
+        })
+    } else {
+        None
+    };
+    let count = extents.len() + usize::from(last.is_some());
+    let mut map = format!("{}\n", count).into_bytes();
+    for extent in extents.iter().chain(&last) {
+        map.extend_from_slice(format!("{}\n{}\n", extent.offset, extent.length).as_bytes());
+    }
+    map.resize(map.len() + padding(map.len() as u64), 0);
+    map
+}
+
+/// Parses map of GNU sparse 1.0 format, see [`TarHeader::set_map`].
+/// Returns `None` when it is broken or not complete yet.
+#[must_use]
+pub fn parse_map(content: &[u8]) -> Option<Vec<Extent>> {
+    let mut rest = content;
+    let mut number = || {
+        let end = rest.iter().position(|x| *x == b'\n')?;
+        let value = std::str::from_utf8(&rest[..end]).ok()?.parse::<u64>().ok();
+        rest = &rest[end + 1..];
+        value
+    };
+    let count = number()?;
+    let mut extents = Vec::new();
+    for _ in 0..count {
+        let offset = number()?;
+        let length = number()?;
+        extents.push(Extent { offset, length });
+    }
+    Some(extents)
+}
+
+/// Formats time as it is stored in `mtime` record. Fraction of negative time is negative too,
+/// so it is written as a signed decimal.
+fn format_time(time: DateTime) -> String {
+    let nanos = time.unix_timestamp_nanos();
+    let sign = if nanos < 0 { "-" } else { "" };
+    let nanos = nanos.unsigned_abs();
+    format!(
+        "{}{}.{:09}",
+        sign,
+        nanos / 1_000_000_000,
+        nanos % 1_000_000_000
+    )
+}
+
+/// Parses `seconds[.fraction]` as it is stored in `mtime` record.
+fn parse_time(value: &[u8]) -> Option<DateTime> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let seconds: i64 = seconds.parse().ok()?;
+    let mut nanos = 0;
+    for (n, digit) in fraction.bytes().take(9).enumerate() {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let scale = 10_i128.pow(8 - n as u32);
+        nanos += i128::from(digit - b'0') * scale;
+    }
+    // Seconds of times between -1 and 0 are written as `-0`.
+    let nanos = if value.starts_with('-') {
+        -nanos
+    } else {
+        nanos
+    };
+    DateTime::from_unix_timestamp_nanos(i128::from(seconds) * 1_000_000_000 + nanos).ok()
+}
+
+/// Name for the ustar header, when the whole one is stored in the extended header.
+fn short_name<K: PathKind>(path: &EncodedPath<K>, sparse: bool) -> Cow<[u8]> {
+    if sparse {
+        // Readers without sparse support extract the map and the data under this name.
+        let base = path.as_bytes().rsplit(|x| *x == b'/').next();
+        let fake = EncodedPath::from_vec([SPARSE_DIR, base.unwrap_or_default()].concat());
+        Cow::Owned(fake.crop_name_to(100_usize).into_owned())
+    } else {
+        // Readers without pax support get a unique name at least.
+        path.crop_name_to(100_usize)
+    }
+}
+
+/// Type flag, size and link name of the ustar header for given entry.
+fn entry_type<'a, K: PathKind>(
+    info: &'a Info<K>,
+    original: Option<&'a [u8]>,
+) -> (u8, u64, &'a [u8]) {
+    match (original, &info.data) {
+        (Some(original), _) => (b'1', 0, original),
+        (None, UnspecifiedInfo::File(file)) => (b'0', file.size, b""),
+        (None, UnspecifiedInfo::Dir(_)) => (b'5', 0, b""),
+        (None, UnspecifiedInfo::Symlink(link)) => (b'2', 0, &link.target),
+        (None, UnspecifiedInfo::Special(special)) => match special.kind {
+            SpecialKind::CharDevice => (b'3', 0, b""),
+            SpecialKind::BlockDevice => (b'4', 0, b""),
+            SpecialKind::Fifo => (b'6', 0, b""),
+            // Archive skips sockets in this format, the mode still tells them apart.
+            SpecialKind::Socket => (b'0', 0, b""),
+        },
+        (None, UnspecifiedInfo::Unknown(_)) => (b'0', 0, b""),
+    }
+}
+
+/// Header of an entry in pax format.
+#[derive(Debug, Clone)]
+pub struct TarHeader {
+    /// Set for the end of archive, all other fields are empty then.
+    end: bool,
+    name: Vec<u8>,
+    /// Permission bits. Type bits are stored here too for sockets, which have no type in tar.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: DateTime,
+    typeflag: u8,
+    linkname: Vec<u8>,
+    devmajor: u32,
+    devminor: u32,
+    device: u64,
+    inode: u64,
+    nlink: u64,
+    xattrs: Vec<Xattr>,
+    /// Set for files with holes in GNU sparse 1.0 format. Boxed, since they are rare.
+    sparse: Option<Box<Sparse>>,
+}
+
+/// Layout of the file with holes in GNU sparse 1.0 format.
+#[derive(Debug, Clone)]
+struct Sparse {
+    /// Whole size of the file.
+    size: u64,
+    /// Read from the start of the data, see [`TarHeader::set_map`].
+    map: Option<Vec<Extent>>,
+}
+
+impl TarHeader {
+    /// Header that marks the end of archive.
+    #[must_use]
+    pub fn end() -> Self {
+        TarHeader {
+            end: true,
+            name: Vec::new(),
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            mtime: DateTime::UNIX_EPOCH,
+            typeflag: 0,
+            linkname: Vec::new(),
+            devmajor: 0,
+            devminor: 0,
+            device: 0,
+            inode: 0,
+            nlink: 0,
+            xattrs: Vec::new(),
+            sparse: None,
+        }
+    }
+
+    /// Creates headers for given info, preceded by an extended header when needed.
+    #[must_use]
+    pub fn encode<K: PathKind>(info: &Info<K>) -> Vec<u8> {
+        Self::encode_entry(info, None, None)
+    }
+
+    /// Creates headers of a hard link to the entry at `original`, which holds the data.
+    #[must_use]
+    pub fn encode_hard_link<K: PathKind>(info: &Info<K>, original: &[u8]) -> Vec<u8> {
+        Self::encode_entry(info, Some(original), None)
+    }
+
+    /// Creates headers of a file with holes in GNU sparse 1.0 format, followed by the map
+    /// of its `extents`. Only data of the extents is stored after that.
+    #[must_use]
+    pub fn encode_sparse<K: PathKind>(info: &Info<K>, extents: &[Extent]) -> Vec<u8> {
+        Self::encode_entry(info, None, Some(extents))
+    }
+
+    fn encode_entry<K: PathKind>(
+        info: &Info<K>,
+        original: Option<&[u8]>,
+        extents: Option<&[Extent]>,
+    ) -> Vec<u8> {
+        let name = info.path.as_bytes();
+        let (typeflag, mut size, linkname) = entry_type(info, original);
+        let map = extents.map(|extents| sparse_map(extents, size));
+        let short_name = short_name(&info.path, map.is_some());
+        let mode = match &info.data {
+            UnspecifiedInfo::Special(SpecialInfo {
+                kind: SpecialKind::Socket,
+                ..
+            }) => super::S_IFSOCK | (info.mode & 0o7777),
+            _ => info.mode & 0o7777,
+        };
+        let (devmajor, devminor) = split_rdev(entry_rdev(info));
+        let seconds = info.modified_at.unix_timestamp();
+        #[allow(clippy::cast_sign_loss)] // Negative time is stored in the extended header only.
+        let mtime = seconds.max(0) as u64;
+
+        let mut records = Vec::new();
+        if let (Some(map), Some(extents)) = (&map, extents) {
+            put_record(&mut records, b"GNU.sparse.major", b"1");
+            put_record(&mut records, b"GNU.sparse.minor", b"0");
+            put_record(&mut records, b"GNU.sparse.name", name);
+            put_record(
+                &mut records,
+                b"GNU.sparse.realsize",
+                size.to_string().as_bytes(),
+            );
+            size = map.len() as u64 + extents.iter().map(|x| x.length).sum::<u64>();
+        } else if name.len() > 100 {
+            put_record(&mut records, b"path", name);
+        }
+        if linkname.len() > 100 {
+            put_record(&mut records, b"linkpath", linkname);
+        }
+        if size > octal_max(12) {
+            put_record(&mut records, b"size", size.to_string().as_bytes());
+        }
+        if info.modified_at.nanosecond() != 0 || seconds < 0 || mtime > octal_max(12) {
+            put_record(
+                &mut records,
+                b"mtime",
+                format_time(info.modified_at).as_bytes(),
+            );
+        }
+        for (key, id) in [(&b"uid"[..], info.user_id), (&b"gid"[..], info.group_id)] {
+            if u64::from(id) > octal_max(8) {
+                put_record(&mut records, key, id.to_string().as_bytes());
+            }
+        }
+        if info.hard_link_id().is_some() {
+            let id = [
+                (&b"SCHILY.dev"[..], info.device),
+                (&b"SCHILY.ino"[..], info.inode),
+                (&b"SCHILY.nlink"[..], info.links),
+            ];
+            for (key, value) in id {
+                put_record(&mut records, key, value.to_string().as_bytes());
+            }
+        }
+        for xattr in &info.xattrs {
+            let key = [XATTR_PREFIX, &xattr.name[..]].concat();
+            put_record(&mut records, &key, &xattr.value);
+        }
+
+        let mut result = Vec::with_capacity(2 * BLOCK + records.len());
+        if !records.is_empty() {
+            let base = name.rsplit(|x| *x == b'/').next().unwrap_or_default();
+            let extended_name = [&b"PaxHeaders/"[..], base].concat();
+            let extended = Block {
+                name: &extended_name,
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                size: records.len() as u64,
+                mtime,
+                typeflag: b'x',
+                linkname: b"",
+                devmajor: 0,
+                devminor: 0,
+            };
+            result.extend_from_slice(&extended.encode());
+            result.extend_from_slice(&records);
+            result.resize(result.len() + padding(records.len() as u64), 0);
+        }
+        let block = Block {
+            name: &short_name,
+            mode: mode.into(),
+            uid: info.user_id.into(),
+            gid: info.group_id.into(),
+            size,
+            mtime,
+            typeflag,
+            linkname,
+            devmajor: devmajor.into(),
+            devminor: devminor.into(),
+        };
+        result.extend_from_slice(&block.encode());
+        result.extend_from_slice(&map.unwrap_or_default());
+        result
+    }
+
+    /// Decodes ustar header, checking its magic and checksum. Values from `extended` take precedence.
+    #[must_use]
+    pub fn decode(block: &[u8; BLOCK], extended: Extended) -> Option<Self> {
+        if &block[257..262] != b"ustar" {
+            return None;
+        }
+        let expected = parse_octal(&block[148..156])?;
+        let sum = |bytes: &[u8]| bytes.iter().map(|x| u64::from(*x)).sum::<u64>();
+        let found = sum(&block[..148]) + 8 * u64::from(b' ') + sum(&block[156..]);
+        if expected != found {
+            return None;
+        }
+
+        let mut name = until_nul(&block[..100]).to_vec();
+        let prefix = until_nul(&block[345..500]);
+        if !prefix.is_empty() {
+            name = [prefix, &b"/"[..], &name[..]].concat();
+        }
+        // Fields of the header are not parsed at all when extended header overrides them.
+        let mtime = match extended.mtime {
+            Some(mtime) => mtime,
+            None => {
+                let seconds = i64::try_from(parse_octal(&block[136..148])?).ok()?;
+                DateTime::from_unix_timestamp(seconds).ok()?
+            }
+        };
+        // Ids that are too large are stored in the extended header.
+        #[allow(clippy::cast_possible_truncation)]
+        let id = |field: &[u8], extended: Option<u64>| match extended {
+            Some(id) => Some(id as u32),
+            None => Some(parse_octal(field)? as u32),
+        };
+        #[allow(clippy::cast_possible_truncation)]
+        Some(TarHeader {
+            end: false,
+            name: extended.sparse_name.or(extended.path).unwrap_or(name),
+            mode: parse_octal(&block[100..108])? as u32,
+            uid: id(&block[108..116], extended.uid)?,
+            gid: id(&block[116..124], extended.gid)?,
+            size: match extended.size {
+                Some(size) => size,
+                None => parse_octal(&block[124..136])?,
+            },
+            mtime,
+            typeflag: block[156],
+            linkname: extended
+                .linkpath
+                .unwrap_or_else(|| until_nul(&block[157..257]).to_vec()),
+            devmajor: parse_octal(&block[329..337])? as u32,
+            devminor: parse_octal(&block[337..345])? as u32,
+            device: extended.device.unwrap_or(0),
+            inode: extended.inode.unwrap_or(0),
+            nlink: extended.nlink.unwrap_or(1),
+            xattrs: extended.xattrs,
+            // Older versions of the sparse format are not supported, their entries are read as is.
+            sparse: extended
+                .sparse_size
+                .filter(|_| extended.sparse_major == Some(1))
+                .map(|size| Box::new(Sparse { size, map: None })),
+        })
+    }
+
+    /// Whether data of this entry starts with the map of extents that is not read yet.
+    #[must_use]
+    pub fn has_map(&self) -> bool {
+        self.sparse.as_ref().map_or(false, |x| x.map.is_none())
+    }
+
+    /// Stores the map of extents, read from the first `length` bytes of data,
+    /// so only data of the extents is left. Returns false when it is longer than the data.
+    pub fn set_map(&mut self, extents: Vec<Extent>, length: u64) -> bool {
+        match &mut self.sparse {
+            Some(sparse) if length <= self.size => {
+                self.size -= length;
+                sparse.map = Some(extents);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Layout and the whole size of the file with holes, when it is stored in GNU sparse format.
+    #[must_use]
+    pub fn sparse(&self) -> Option<(SparseMap, u64)> {
+        let sparse = self.sparse.as_ref()?;
+        let extents = sparse.map.as_ref()?;
+        let map = SparseMap {
+            // The last extent is empty when the file ends with a hole.
+            extents: extents.iter().filter(|x| x.length > 0).copied().collect(),
+            hash: None,
+        };
+        Some((map, sparse.size))
+    }
+
+    #[must_use]
+    pub fn is_end(&self) -> bool {
+        self.end
+    }
+
+    /// Type of the entry, extended headers have `x`, `g`, `L` or `K` types.
+    #[must_use]
+    pub fn typeflag(&self) -> u8 {
+        self.typeflag
+    }
+
+    /// Name of the entry, without the NUL byte.
+    #[must_use]
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Target of a symbolic link, it is stored in the header instead of data.
+    #[must_use]
+    pub fn link_target(&self) -> Option<&[u8]> {
+        (self.typeflag == b'2').then(|| &self.linkname[..])
+    }
+
+    /// Entry this one is a hard link to, when it is written by another archiver.
+    #[must_use]
+    pub fn hard_link(&self) -> Option<&[u8]> {
+        (self.typeflag == b'1').then(|| &self.linkname[..])
+    }
+}
+
+impl EntryHeader for TarHeader {
+    fn format(&self) -> Format {
+        Format::Pax
+    }
+
+    /// Size of data following the header. Links and special files have none.
+    fn size(&self) -> u64 {
+        match self.typeflag {
+            b'0' | b'\0' | b'7' | b'x' | b'g' | b'L' | b'K' => self.size,
+            _ => 0,
+        }
+    }
+
+    fn mode(&self) -> u32 {
+        let kind = match self.typeflag {
+            b'2' => S_IFLNK,
+            b'3' => super::S_IFCHR,
+            b'4' => super::S_IFBLK,
+            b'5' => 0o0040000,
+            b'6' => super::S_IFIFO,
+            _ if self.mode & 0o0170000 != 0 => self.mode & 0o0170000,
+            _ => 0o0100000,
+        };
+        kind | (self.mode & 0o7777)
+    }
+
+    /// Hard links written by other archivers have their own type, see [`TarHeader::hard_link`].
+    fn hard_link_id(&self) -> Option<(u64, u64)> {
+        let links = if self.typeflag == b'1' { 1 } else { self.nlink };
+        file_link_id(self.mode(), links, (self.device, self.inode))
+    }
+
+    fn info(&self, name: &[u8]) -> Info<External> {
+        let rdev = join_rdev(self.devmajor, self.devminor);
+        let data = match self.link_target() {
+            Some(target) => UnspecifiedInfo::Symlink(SymlinkInfo {
+                target: target.to_vec(),
+            }),
+            None => decode_kind(self.mode(), self.size(), rdev),
+        };
+
+        #[allow(clippy::unwrap_used)]
+        // UNWRAP: Zero timestamp can be safely converted
+        let created_at = DateTime::from_unix_timestamp(0).unwrap();
+
+        Info {
+            path: EncodedPath::from_vec(name.to_vec()),
+            inode: self.inode,
+            device: self.device,
+            links: self.nlink,
+            mode: self.mode & 0o0000777,
+            user_id: self.uid,
+            group_id: self.gid,
+            created_at,
+            modified_at: self.mtime,
+            hash: None,
+            xattrs: self.xattrs.clone(),
+            data,
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Checks archive against its manifest without extracting anything.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use snafu::{ResultExt, Snafu};
+use tokio::io::AsyncRead;
+
+use super::manifest::ManifestError;
+use super::pending::Change;
+use super::reader::{NextItem, ReadError, ReadingError};
+use super::Reader;
+use crate::fileinfo::UnspecifiedInfo;
+use crate::path::{EncodedPath, EscapedString, External};
+use crate::stream_hash::stream_hash;
+use crate::types::Checksum;
+
+#[derive(Debug, Snafu)]
+pub enum VerifyError {
+    #[snafu(display("Can't read entry: {}", source))]
+    AdvanceFailed { source: ReadingError },
+}
+
+/// Single difference between the archive and its manifest.
+#[derive(Debug)]
+pub enum Problem {
+    /// Trailer has no manifest, so nothing can be compared.
+    NoManifest,
+    /// Manifest is present, but can't be read.
+    InvalidManifest { source: ManifestError },
+    /// File is listed in the manifest, but not stored in the archive.
+    Missing { path: EncodedPath<External> },
+    /// File is stored in the archive, but not listed in the manifest.
+    Unexpected { path: EncodedPath<External> },
+    SizeMismatch {
+        path: EncodedPath<External>,
+        expected: Option<u64>,
+        found: Option<u64>,
+    },
+    HashMismatch {
+        path: EncodedPath<External>,
+        expected: Checksum,
+        found: Option<Checksum>,
+    },
+    /// Content of the file does not match its checksum, or the archive ends in the middle of it.
+    ContentCorrupted {
+        path: EncodedPath<External>,
+        source: ReadError,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoManifest => write!(f, "archive has no manifest"),
+            Self::InvalidManifest { source } => write!(f, "{}", source),
+            Self::Missing { path } => write!(f, "{:?} is missing", path.escaped()),
+            Self::Unexpected { path } => {
+                write!(f, "{:?} is not listed in the manifest", path.escaped())
+            }
+            Self::SizeMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{:?} has size {:?}, expected {:?}",
+                path.escaped(),
+                found,
+                expected
+            ),
+            Self::HashMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{:?} has hash {:?}, expected {}",
+                path.escaped(),
+                found,
+                expected
+            ),
+            Self::ContentCorrupted { path, source } => {
+                write!(f, "{:?} is corrupted: {}", path.escaped(), source)
+            }
+        }
+    }
+}
+
+/// Result of [`Reader::verify`](Reader::verify).
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// Number of entries read from the archive.
+    pub entries: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    /// Whether everything matches the manifest.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What was actually found in the archive.
+struct Found {
+    size: Option<u64>,
+    hash: Option<Checksum>,
+}
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    /// Reads the whole archive, hashing content of every file, and compares it with the manifest.
+    ///
+    /// Only broken archive structure is reported as error, any difference is put into the report.
+    pub async fn verify(mut self) -> Result<VerifyReport, VerifyError> {
+        let mut found: HashMap<EncodedPath<External>, Found> = HashMap::new();
+        let mut order = Vec::new();
+        let mut problems = Vec::new();
+        let end = loop {
+            let file = match self.advance().await.context(AdvanceFailed)? {
+                NextItem::File(file) => file,
+                NextItem::End(end) => break Some(end),
+            };
+            let info = file.info();
+            let (drained, entry) = if let Some(original) = file.hard_link() {
+                // Content was stored with the first link only.
+                let original = found.get(original);
+                let entry = Found {
+                    size: original.and_then(|x| x.size),
+                    hash: original.and_then(|x| x.hash),
+                };
+                (file.drain_unchecked(&mut tokio::io::sink()).await, entry)
+            } else if let UnspecifiedInfo::File(_) = info.data {
+                let mut hasher = stream_hash(tokio::io::sink());
+                let drained = file.drain_unchecked(&mut hasher).await;
+                let entry = Found {
+                    size: info.size(),
+                    hash: Some(hasher.finalize().into()),
+                };
+                (drained, entry)
+            } else {
+                let entry = Found {
+                    size: info.size(),
+                    hash: None,
+                };
+                (file.drain_unchecked(&mut tokio::io::sink()).await, entry)
+            };
+            let path = info.path;
+            match drained {
+                Ok((reader, mismatch)) => {
+                    self = reader;
+                    if let Some(source) = mismatch {
+                        let path = path.clone();
+                        problems.push(Problem::ContentCorrupted { path, source });
+                    }
+                }
+                Err(source) => {
+                    // Archive is truncated, so nothing can be read after it.
+                    problems.push(Problem::ContentCorrupted { path, source });
+                    break None;
+                }
+            }
+            order.push(path.clone());
+            found.insert(path, entry);
+        };
+
+        let mut report = VerifyReport {
+            entries: order.len(),
+            problems,
+        };
+        let end = match end {
+            Some(end) => end,
+            None => {
+                report.problems.push(Problem::NoManifest);
+                return Ok(report);
+            }
+        };
+        let manifest = match (end.manifest, end.warning) {
+            (Some(manifest), _) => manifest,
+            (None, Some(source)) => {
+                report.problems.push(Problem::InvalidManifest { source });
+                return Ok(report);
+            }
+            (None, None) => {
+                report.problems.push(Problem::NoManifest);
+                return Ok(report);
+            }
+        };
+
+        for entry in manifest.files {
+            if entry.changed == Some(Change::Vanished) {
+                // Listed, but never stored.
+                continue;
+            }
+            let path = entry.info.path.clone();
+            let actual = if let Some(actual) = found.remove(&path) {
+                actual
+            } else {
+                report.problems.push(Problem::Missing { path });
+                continue;
+            };
+            // Only data of sparse files is stored, so it is compared instead of the whole file.
+            let (size, hash) = match entry.sparse {
+                Some(sparse) => (Some(sparse.stored_size()), sparse.hash),
+                None => (entry.info.size(), entry.info.hash),
+            };
+            if size != actual.size {
+                report.problems.push(Problem::SizeMismatch {
+                    path: path.clone(),
+                    expected: size,
+                    found: actual.size,
+                });
+            }
+            if let Some(hash) = hash {
+                if actual.hash != Some(hash) {
+                    report.problems.push(Problem::HashMismatch {
+                        path,
+                        expected: hash,
+                        found: actual.hash,
+                    });
+                }
+            }
+        }
+        for path in order {
+            if found.remove(&path).is_some() {
+                report.problems.push(Problem::Unexpected { path });
+            }
+        }
+        Ok(report)
+    }
+}
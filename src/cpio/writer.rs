@@ -1,19 +1,32 @@
-use super::pending::{OpeningReadFuture, Pending, PendingReader};
+use super::pending::{
+    CantOpen, Change, ChangePolicy, ChecksumFuture, HolesFuture, OpeningReadFuture, Pending,
+    PendingReader, ReadOptions, SettleFuture,
+};
+use super::read_ahead::{Prefetched, ReadAhead};
 use super::smart_read::SmartReadExt;
 use super::smart_read::SmartWrap;
+use super::sparse::SparseMap;
 use super::state_machine::{AdvanceResult, Advanceable};
 use crate::cpio::smart_read::{SmartBuf, SmartRead};
-use crate::cpio::Archive;
+use crate::cpio::{tar, Archive, Format, Offsets};
+use crate::fileinfo::UnspecifiedInfo;
+use crate::path::Local;
+use crate::progress::{Observer, Progress, Stage};
+use crate::types::Checksum;
 use crate::utils::Either;
+use crate::DefaultDigest;
+use futures::ready;
 use pin_project_lite::pin_project;
+use sha2::Digest;
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, ReadBuf};
 
 pin_project! {
-    /// Writer of cpio archive, created by [`Archive`](Archive)
+    /// Writer of cpio or tar archive, created by [`Archive`](Archive)
     pub struct Reader<'a> {
         #[pin]
         inner: SmartWrap<State<'a>>
@@ -32,11 +45,23 @@ impl<'a> AsyncRead for Reader<'a> {
 
 impl Reader<'_> {
     pub fn new(archive: &mut Archive) -> Reader {
+        let options = archive.read_options();
+        // Retried files are read whole before their header anyway.
+        let budget = match options.policy {
+            ChangePolicy::Retry => 0,
+            _ => archive.read_ahead(),
+        };
         Reader {
             inner: State::None(states::None {
+                format: archive.format(),
+                options,
+                read_ahead: ReadAhead::new(budget),
                 archive: archive as *mut _,
                 phantom: std::marker::PhantomData::default(),
                 position: 0,
+                offset: 0,
+                hasher: DefaultDigest::default(),
+                progress: archive.progress.clone(),
             })
             .wrap(),
         }
@@ -50,10 +75,17 @@ impl Reader<'_> {
 ///     \-> Trailer -> EOF-⸜
 ///                     ↖--/
 /// ```
+///
+/// For [`Crc`](Format::Crc) format `Header` state reads the whole file once to compute checksum.
+/// With [`ChangePolicy::Retry`](ChangePolicy::Retry) it reads the file until it stays the same,
+/// and computes checksum at the same time. Files that vanished before that have no header at all.
+///
+/// Next files are [opened ahead](super::read_ahead) in `None` state, so `OpeningFile` usually
+/// finishes at once for them. Holes of other files are found in `Header` state, on the blocking pool.
 enum State<'a> {
     /// «Neutral» state
     None(states::None<'a>),
-    /// Writing a header for chosen file from archive
+    /// Writing a header for chosen file from archive, computing checksum before when needed
     Header(states::Header<'a>),
     /// Waiting for tokio opening the file
     OpeningFile(states::OpeningFile<'a>),
@@ -102,19 +134,35 @@ impl SmartRead for State<'_> {
 
 /// See [`State`](State) enum
 mod states {
-    use crate::path::Local;
-
     use super::*;
 
     pub struct None<'a> {
+        pub format: Format,
+        pub options: ReadOptions,
+        pub read_ahead: ReadAhead,
         pub archive: *mut Archive,
         pub phantom: std::marker::PhantomData<&'a mut Archive>,
         pub position: usize,
+        /// Number of bytes written so far, used to fill [`Pending::offsets`](super::Pending::offsets).
+        pub offset: u64,
+        /// Hash of everything written so far, stored in the manifest.
+        pub hasher: DefaultDigest,
+        pub progress: Option<Arc<dyn Observer>>,
     }
 
     pub struct Header<'a> {
         pub none: None<'a>,
         pub file: &'a mut super::Pending<Local>,
+        /// Path of the entry that holds data of this hard link.
+        pub original: Option<Vec<u8>>,
+        pub settle: Option<Pin<Box<super::SettleFuture>>>,
+        /// Checks that the file still exists and finds its holes.
+        pub holes: Option<Pin<Box<super::HolesFuture>>>,
+        pub checksum: Option<Pin<Box<super::ChecksumFuture>>>,
+        /// Checksum that is already known from [`settle`](Self::settle).
+        pub sum: Option<u32>,
+        /// File opened ahead, if it was.
+        pub prefetched: Option<Prefetched>,
     }
 
     pub struct OpeningFile<'a> {
@@ -130,6 +178,9 @@ mod states {
 
     pub struct Trailer<'a> {
         pub archive: &'a mut Archive,
+        pub hash: Checksum,
+        pub offset: u64,
+        pub progress: Option<Arc<dyn Observer>>,
     }
 
     pub struct Eof;
@@ -138,8 +189,8 @@ mod states {
 impl<'a> Advanceable for states::None<'a> {
     type Next = Either<states::Header<'a>, states::Trailer<'a>>;
     fn advance(
-        self,
-        _cx: &mut Context<'_>,
+        mut self,
+        cx: &mut Context<'_>,
         _buf: &mut SmartBuf<'_, '_, '_>,
     ) -> AdvanceResult<Self, Self::Next> {
         // Switch to the next file
@@ -150,35 +201,191 @@ impl<'a> Advanceable for states::None<'a> {
         };
 
         let res = if self.position < archive.files.len() {
+            self.read_ahead
+                .fill(&archive.files, self.position, self.options);
+            let mut prefetched = match self.read_ahead.poll_take(self.position, cx) {
+                Poll::Pending => return AdvanceResult::Pending(self),
+                Poll::Ready(prefetched) => prefetched,
+            };
+            // Tar links point to the path of the original, it is stored unless it vanished.
+            let original = archive.files[self.position]
+                .link_to
+                .and_then(|x| archive.files.get(x))
+                .filter(|x| x.offsets.is_some())
+                .map(|x| x.info.path.as_bytes().to_vec());
             let file = &mut archive.files[self.position];
+            let retry = self.options.policy == ChangePolicy::Retry
+                && file.has_content()
+                && file.source.is_none();
+            let settle = retry.then(|| Box::pin(file.settle_fut()));
+            file.sparse = prefetched.as_mut().and_then(|x| x.sparse.take());
+            // Retried files are checked once they settle.
+            let holes = (prefetched.is_none() && !retry)
+                .then(|| Box::pin(file.detect_holes_fut(self.options.sparse)));
+            let checksum = (self.format.has_checksum() && file.has_content() && !retry)
+                .then(|| Box::pin(file.checksum_fut()));
             // None -> Header
-            Either::Left(states::Header { none: self, file })
+            Either::Left(states::Header {
+                none: self,
+                file,
+                original,
+                settle,
+                holes,
+                checksum,
+                sum: None,
+                prefetched,
+            })
         } else {
-            Either::Right(states::Trailer { archive })
+            Either::Right(states::Trailer {
+                archive,
+                hash: self.hasher.finalize().into(),
+                offset: self.offset,
+                progress: self.progress,
+            })
         };
         AdvanceResult::Ready(res)
     }
 }
 
+/// Stores holes found in the file. When it was deleted, marks it so, unless `policy` is to abort.
+fn set_holes(
+    file: &mut Pending<Local>,
+    found: Result<Option<SparseMap>, CantOpen>,
+    policy: ChangePolicy,
+) -> io::Result<()> {
+    match found {
+        Ok(sparse) => {
+            file.sparse = sparse;
+            Ok(())
+        }
+        Err(err) if err.is_not_found() && policy != ChangePolicy::Abort => {
+            file.changed = Some(Change::Vanished);
+            Ok(())
+        }
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+    }
+}
+
+impl states::Header<'_> {
+    /// Waits until the file settles and its holes are found, when needed.
+    fn poll_settle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let policy = self.none.options.policy;
+        if let Some(future) = self.settle.as_mut() {
+            let settled = ready!(future.as_mut().poll(cx))
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            self.settle = None;
+            self.file.settle(settled.as_ref());
+            self.sum = settled.map(|x| x.checksum);
+            if self.none.options.sparse && !self.file.is_vanished() {
+                self.holes = Some(Box::pin(self.file.detect_holes_fut(true)));
+            }
+        }
+        if let Some(future) = self.holes.as_mut() {
+            let found = ready!(future.as_mut().poll(cx));
+            self.holes = None;
+            set_holes(self.file, found, policy)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
 impl<'a> Advanceable for states::Header<'a> {
     type Next = Either<states::OpeningFile<'a>, states::None<'a>>;
     fn advance(
         mut self,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &mut SmartBuf<'_, '_, '_>,
     ) -> AdvanceResult<Self, Self::Next> {
-        let header = self.file.header();
+        let format = self.none.format;
+        match self.poll_settle(cx) {
+            Poll::Pending => return AdvanceResult::Pending(self),
+            Poll::Ready(Err(err)) => return AdvanceResult::Failed(err),
+            Poll::Ready(Ok(())) => {}
+        }
+        if self.file.is_vanished() {
+            // Nothing to write, the file is only flagged in the manifest.
+            self.none.position += 1;
+            return AdvanceResult::Ready(Either::Right(self.none));
+        }
+
+        if let Some(size) = self.file.stored_size() {
+            if size > format.max_file_size() {
+                return AdvanceResult::Failed(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "File of {} bytes does not fit into {:?} format",
+                        size, format
+                    ),
+                ));
+            }
+        }
+        let rdev = super::entry_rdev(&self.file.info);
+        if !format.fits_rdev(rdev) {
+            return AdvanceResult::Failed(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Device number {:#x} does not fit into {:?} format",
+                    rdev, format
+                ),
+            ));
+        }
+
+        let checksum = match self.checksum.as_mut() {
+            None => self.sum.filter(|_| format.has_checksum()).unwrap_or(0),
+            Some(future) => match future.as_mut().poll(cx) {
+                Poll::Pending => return AdvanceResult::Pending(self),
+                Poll::Ready(Err(err)) => {
+                    return AdvanceResult::Failed(io::Error::new(io::ErrorKind::Other, err))
+                }
+                Poll::Ready(Ok(checksum)) => checksum,
+            },
+        };
+
+        let header = self.file.header(format, checksum, self.original.as_deref());
         buf.put_slice(&header);
+        self.none.hasher.update(&header);
+        let offsets = Offsets {
+            header: self.none.offset,
+            data: self.none.offset + header.len() as u64,
+        };
+        self.file.offsets = Some(offsets);
+        self.none.offset = offsets.data;
+        if let Some(progress) = &self.none.progress {
+            let done = Progress {
+                files: self.none.position as u64,
+                bytes: self.none.offset,
+            };
+            progress.update(Stage::Archiving, done, self.file.info.path.as_bytes());
+        }
 
-        let next = if self.file.info.size().is_some() {
-            let future = self.file.read_fut();
+        if let UnspecifiedInfo::Symlink(link) = &self.file.info.data {
+            // Link target is already known, so there is no need to open anything.
+            let target = if format.link_in_data() {
+                &link.target[..]
+            } else {
+                &[]
+            };
+            buf.put_slice(target);
+            let padding = format.data_padding(target.len() as u64);
+            buf.put_slice(&[0; tar::BLOCK][..padding]);
+            self.none.hasher.update(target);
+            self.none.hasher.update(&[0; tar::BLOCK][..padding]);
+            self.none.offset += (target.len() + padding) as u64;
+        }
+
+        let next = if self.file.has_content() {
+            let future = self.file.read_fut(
+                self.none.options,
+                self.prefetched.take(),
+                self.none.progress.clone(),
+            );
 
             Either::Left(states::OpeningFile {
                 none: self.none,
                 future: Box::pin(future),
             })
         } else {
-            // Move to the next file, nothing to write here: there is no size.
+            // Move to the next file, nothing to write here: there is no size or it is a hard link.
             self.none.position += 1;
 
             Either::Right(self.none)
@@ -223,15 +430,17 @@ impl<'a> Advanceable for states::File<'a> {
             Poll::Ready(Err(e)) => AdvanceResult::Failed(e),
             Poll::Ready(Ok(None)) => {
                 // EOF
-                if self.length % 2 != 0 {
-                    buf.put_slice(&[0]);
-                }
+                let padding = self.none.format.data_padding(self.length);
+                buf.put_slice(&[0; tar::BLOCK][..padding]);
+                self.none.hasher.update(&[0; tar::BLOCK][..padding]);
+                self.none.offset += self.length + padding as u64;
                 // Switch to next file
                 self.none.position += 1;
                 AdvanceResult::Ready(Either::Right(self.none))
             }
             Poll::Ready(Ok(Some(written))) => {
                 self.length += written.len() as u64;
+                self.none.hasher.update(written);
                 AdvanceResult::Ready(Either::Left(self))
             }
         }
@@ -245,8 +454,16 @@ impl<'a> Advanceable for states::Trailer<'a> {
         _cx: &mut Context<'_>,
         buf: &mut SmartBuf<'_, '_, '_>,
     ) -> AdvanceResult<Self, Self::Next> {
+        self.archive.hash = Some(self.hash);
         let trailer = self.archive.trailer();
         buf.put_slice(&trailer);
+        if let Some(progress) = &self.progress {
+            let done = Progress {
+                files: self.archive.files.len() as u64,
+                bytes: self.offset + trailer.len() as u64,
+            };
+            progress.update(Stage::Archiving, done, b"");
+        }
         AdvanceResult::Ready(states::Eof)
     }
 }
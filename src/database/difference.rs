@@ -55,6 +55,8 @@ impl DiffType {
     }
 }
 
+// Rows are processed one at a time, so boxing `Changed` would not save any memory.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum DiffRow {
     Deleted {
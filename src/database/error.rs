@@ -16,6 +16,10 @@ pub enum Error {
     CantWalkdir {
         source: walkdir::Error,
     },
+    CantReadInfo {
+        source: std::io::Error,
+        backtrace: snafu::Backtrace,
+    },
     CantBuildDiffName {
         source: NotAValidSqlName,
         before: SqlName,
@@ -12,7 +12,7 @@ pub use {
     difference::{Diff, DiffRow, DiffType},
     error::Error,
     index::Database,
-    snapshot::Snapshot,
+    snapshot::{Snapshot, SnapshotFiller},
 };
 
 use snafu::{ensure, Snafu};
@@ -1,6 +1,7 @@
 use std::borrow::Borrow;
 use std::borrow::BorrowMut;
 use std::path::Path;
+use std::sync::Arc;
 
 use rusqlite::named_params;
 use rusqlite::params;
@@ -8,7 +9,8 @@ use snafu::ResultExt;
 
 use crate::fileinfo::FileIdentifier;
 use crate::fileinfo::Info;
-use crate::path::EncodedPath;
+use crate::path::Local;
+use crate::progress::{Observer, Progress, Stage};
 use crate::utils::Utils;
 
 use super::error::*;
@@ -30,6 +32,7 @@ pub struct Snapshot<D: Borrow<Database>> {
 pub struct SnapshotFiller<'a> {
     snap_name: &'a SqlName,
     transaction: rusqlite::Transaction<'a>,
+    progress: Option<Arc<dyn Observer>>,
 }
 
 impl<'a> SnapshotFiller<'a> {
@@ -44,9 +47,17 @@ impl<'a> SnapshotFiller<'a> {
         Ok(SnapshotFiller {
             snap_name: &snapshot.name,
             transaction: txn,
+            progress: None,
         })
     }
 
+    /// Reports every file added by [`fill`](Self::fill) to the observer.
+    #[must_use]
+    pub fn with_progress(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.progress = Some(observer);
+        self
+    }
+
     fn get_statement(&self) -> Result<rusqlite::CachedStatement, Error> {
         let sql = fmt_sql!(
             "INSERT INTO {0}.snap(path, identifier, info, size)
@@ -58,9 +69,14 @@ impl<'a> SnapshotFiller<'a> {
 
     /// Adds new entry to snapshot directly from [`walkdir::DirEntry`](walkdir::DirEntry).
     pub fn add(&self, entry: walkdir::DirEntry) -> Result<(), Error> {
+        self.insert(entry).map(|_| ())
+    }
+
+    /// Same as [`add`](Self::add), but returns info that was stored.
+    fn insert(&self, entry: walkdir::DirEntry) -> Result<Info<Local>, Error> {
+        // Walkdir does not follow links, so this is metadata of the link itself.
         let metadata = entry.metadata().context(CantWalkdir)?;
-        let path = EncodedPath::from_path(entry.into_path());
-        let info = Info::with_metadata(path, &metadata);
+        let info = Info::with_metadata(entry.into_path(), &metadata).context(CantReadInfo)?;
         self.get_statement()?.execute(named_params![
             ":path": info.path.as_bytes(),
             ":identifier": info.identifier().as_ref().map(FileIdentifier::as_bytes).unwrap_or_default(),
@@ -68,7 +84,7 @@ impl<'a> SnapshotFiller<'a> {
             ":size": info.size()
         ])
         .context(SqliteFailed)?;
-        Ok(())
+        Ok(info)
     }
 
     /// Must be called after snapshot is filled.
@@ -90,9 +106,15 @@ impl<'a> SnapshotFiller<'a> {
     pub fn fill(self, root: &Path) -> Result<Self, Error> {
         log!(time: "Walking over {}", root = root.to_string_lossy());
         let walk = walkdir::WalkDir::new(root).into_iter();
+        let mut done = Progress::default();
         for entry in walk {
             let entry = entry.context(CantWalkdir)?;
-            self.add(entry)?;
+            let info = self.insert(entry)?;
+            if let Some(progress) = &self.progress {
+                done.files += 1;
+                done.bytes += info.size().unwrap_or(0);
+                progress.update(Stage::Walking, done, info.path.as_bytes());
+            }
         }
         log!(time: "Done walking ({})", root = root.to_string_lossy());
         Ok(self)
@@ -1,10 +1,22 @@
+use crate::fileinfo::{SpecialInfo, SpecialKind, Xattr};
+use crate::DateTime;
 use std::fs::Metadata;
+use std::ops::Range;
+use std::path::Path;
 
 pub(crate) trait FileExtensions {
     fn inode(&self) -> u64;
+    /// Id of device containing the file.
+    fn device(&self) -> u64;
+    /// Number of hard links pointing to the file.
+    fn links(&self) -> u64;
     fn mode(&self) -> u32;
     fn user_id(&self) -> u32;
     fn group_id(&self) -> u32;
+    /// Type of special file, or None when it is a regular file, directory or symbolic link.
+    fn special_kind(&self) -> Option<SpecialKind>;
+    /// Device number, when this is a device node.
+    fn rdev(&self) -> u64;
 }
 
 #[cfg(unix)]
@@ -13,6 +25,14 @@ impl FileExtensions for Metadata {
         std::os::unix::fs::MetadataExt::ino(self)
     }
 
+    fn device(&self) -> u64 {
+        std::os::unix::fs::MetadataExt::dev(self)
+    }
+
+    fn links(&self) -> u64 {
+        std::os::unix::fs::MetadataExt::nlink(self)
+    }
+
     fn mode(&self) -> u32 {
         std::os::unix::fs::MetadataExt::mode(self)
     }
@@ -24,6 +44,27 @@ impl FileExtensions for Metadata {
     fn group_id(&self) -> u32 {
         std::os::unix::fs::MetadataExt::gid(self)
     }
+
+    fn special_kind(&self) -> Option<SpecialKind> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let file_type = self.file_type();
+        if file_type.is_block_device() {
+            Some(SpecialKind::BlockDevice)
+        } else if file_type.is_char_device() {
+            Some(SpecialKind::CharDevice)
+        } else if file_type.is_fifo() {
+            Some(SpecialKind::Fifo)
+        } else if file_type.is_socket() {
+            Some(SpecialKind::Socket)
+        } else {
+            None
+        }
+    }
+
+    fn rdev(&self) -> u64 {
+        std::os::unix::fs::MetadataExt::rdev(self)
+    }
 }
 
 #[cfg(windows)]
@@ -32,6 +73,18 @@ impl FileExtensions for Metadata {
         std::os::windows::fs::MetadataExt::file_index(self).unwrap_or_default()
     }
 
+    fn device(&self) -> u64 {
+        std::os::windows::fs::MetadataExt::volume_serial_number(self)
+            .unwrap_or_default()
+            .into()
+    }
+
+    fn links(&self) -> u64 {
+        std::os::windows::fs::MetadataExt::number_of_links(self)
+            .unwrap_or(1)
+            .into()
+    }
+
     fn mode(&self) -> u32 {
         u32::MAX
     }
@@ -43,4 +96,319 @@ impl FileExtensions for Metadata {
     fn group_id(&self) -> u32 {
         u32::MAX
     }
+
+    fn special_kind(&self) -> Option<SpecialKind> {
+        None
+    }
+
+    fn rdev(&self) -> u64 {
+        0
+    }
+}
+
+/// Creates special file at `path`, like `mknod` and `mkfifo` do.
+///
+/// Creating device nodes usually requires superuser, so caller should be ready for `PermissionDenied`.
+#[cfg(unix)]
+pub fn create_special(path: &Path, info: &SpecialInfo, mode: u32) -> std::io::Result<()> {
+    let path = c_string(path.as_os_str())?;
+    let kind = match info.kind {
+        SpecialKind::BlockDevice => libc::S_IFBLK,
+        SpecialKind::CharDevice => libc::S_IFCHR,
+        SpecialKind::Fifo => libc::S_IFIFO,
+        SpecialKind::Socket => libc::S_IFSOCK,
+    };
+    // Types differ between platforms, and values outside of their ranges can't be created anyway.
+    #[allow(clippy::cast_possible_truncation, clippy::useless_conversion)]
+    let (mode, rdev) = (
+        kind | (mode & 0o7777) as libc::mode_t,
+        info.rdev as libc::dev_t,
+    );
+    let result = unsafe {
+        // This is safe: path is a valid NUL-terminated string that outlives the call.
+        libc::mknod(path.as_ptr(), mode, rdev)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Special files can't be created on this platform.
+#[cfg(not(unix))]
+pub fn create_special(_path: &Path, _info: &SpecialInfo, _mode: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "special files are not supported on this platform",
+    ))
+}
+
+/// Sets permission bits of the file. Symbolic links are never followed, since they may point
+/// anywhere, and their own permissions are not used: error is returned for them instead.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if std::fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "permissions of symbolic links can't be changed",
+        ));
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))
+}
+
+/// Modes are not stored on this platform.
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "permissions are not supported on this platform",
+    ))
+}
+
+/// Changes owner and group of the file, without following symbolic links.
+///
+/// Usually only superuser is allowed to do it, see [`is_superuser`](is_superuser).
+#[cfg(unix)]
+pub fn set_owner(path: &Path, user_id: u32, group_id: u32) -> std::io::Result<()> {
+    let path = c_string(path.as_os_str())?;
+    let result = unsafe {
+        // This is safe: path is a valid NUL-terminated string that outlives the call.
+        libc::lchown(path.as_ptr(), user_id, group_id)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Owners are not stored on this platform.
+#[cfg(not(unix))]
+pub fn set_owner(_path: &Path, _user_id: u32, _group_id: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "owners are not supported on this platform",
+    ))
+}
+
+/// Whether current process is allowed to change owners of files.
+#[cfg(unix)]
+#[must_use]
+pub fn is_superuser() -> bool {
+    // This is safe: `geteuid` never fails and has no side effects.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+#[must_use]
+pub fn is_superuser() -> bool {
+    false
+}
+
+/// Sets modification time of the file with nanosecond precision, without following symbolic links.
+/// Access time is left as is.
+#[cfg(unix)]
+pub fn set_modified(path: &Path, modified: DateTime) -> std::io::Result<()> {
+    let path = c_string(path.as_os_str())?;
+    // Types differ between platforms, and nanoseconds are always less than a second.
+    #[allow(clippy::cast_possible_truncation, clippy::useless_conversion)]
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: modified.unix_timestamp() as libc::time_t,
+            tv_nsec: modified.nanosecond().into(),
+        },
+    ];
+    let result = unsafe {
+        // This is safe: path is a valid NUL-terminated string and `times` has exactly two items.
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Timestamps can't be set on this platform yet.
+#[cfg(not(unix))]
+pub fn set_modified(_path: &Path, _modified: DateTime) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "timestamps are not supported on this platform",
+    ))
+}
+
+/// Returns ranges of the file that contain data, everything between them is a hole.
+///
+/// Filesystems without hole support report the whole file as a single range.
+/// Data written after `size` is ignored.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn data_ranges(file: &std::fs::File, size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let seek = |offset: u64, whence| {
+        // Offsets never exceed file size, which fits into `off_t`.
+        #[allow(clippy::cast_possible_wrap)]
+        let result = unsafe {
+            // This is safe: descriptor is valid while the file is borrowed.
+            libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence)
+        };
+        // Negative result is handled right here.
+        #[allow(clippy::cast_sign_loss)]
+        if result < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(result as u64)
+        }
+    };
+
+    let mut ranges = Vec::new();
+    let mut position = 0;
+    while position < size {
+        let data = match seek(position, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // Only a hole is left.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(vec![0..size]),
+            Err(e) => return Err(e),
+        };
+        if data >= size {
+            break;
+        }
+        let hole = seek(data, libc::SEEK_HOLE)?.min(size);
+        ranges.push(data..hole);
+        position = hole;
+    }
+    Ok(ranges)
+}
+
+/// Holes can't be detected on this platform, so the whole file is data.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn data_ranges(_file: &std::fs::File, size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    Ok(vec![0..size])
+}
+
+/// Converts path or name to the form accepted by libc.
+#[cfg(unix)]
+fn c_string(s: &std::ffi::OsStr) -> std::io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::CString::new(s.as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+/// Calls `f` with buffers of growing size until the value fits, like all `*xattr` functions require.
+#[cfg(all(feature = "xattrs", target_os = "linux"))]
+fn read_growing(mut f: impl FnMut(&mut [u8]) -> libc::ssize_t) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    loop {
+        // Empty buffer only queries the size.
+        let size = f(&mut []);
+        let size = usize::try_from(size).map_err(|_| std::io::Error::last_os_error())?;
+        buffer.resize(size, 0);
+        match usize::try_from(f(&mut buffer)) {
+            Ok(len) => {
+                buffer.truncate(len);
+                return Ok(buffer);
+            }
+            // Attribute was changed between calls.
+            Err(_) if std::io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => {}
+            Err(_) => return Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Reads all extended attributes of the file, without following symbolic links.
+///
+/// Filesystems without xattrs support simply have none.
+#[cfg(all(feature = "xattrs", target_os = "linux"))]
+pub fn read_xattrs(path: &Path) -> std::io::Result<Vec<Xattr>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = c_string(path.as_os_str())?;
+    let names = read_growing(|buf| unsafe {
+        // This is safe: both pointers are valid for the given sizes.
+        libc::llistxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    });
+    let names = match names {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut result = Vec::new();
+    for name in names.split(|x| *x == 0).filter(|x| !x.is_empty()) {
+        let c_name = c_string(std::ffi::OsStr::from_bytes(name))?;
+        let value = read_growing(|buf| unsafe {
+            // This is safe: all pointers are valid for the given sizes.
+            libc::lgetxattr(
+                path.as_ptr(),
+                c_name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        });
+        match value {
+            Ok(value) => result.push(Xattr {
+                name: name.to_vec(),
+                value,
+            }),
+            // Attribute was removed after listing.
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(result)
+}
+
+/// Extended attributes are not collected without `xattrs` feature.
+#[cfg(not(all(feature = "xattrs", target_os = "linux")))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn read_xattrs(_path: &Path) -> std::io::Result<Vec<Xattr>> {
+    Ok(Vec::new())
+}
+
+/// Sets extended attribute on the file, without following symbolic links.
+#[cfg(all(feature = "xattrs", target_os = "linux"))]
+pub fn write_xattr(path: &Path, xattr: &Xattr) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = c_string(path.as_os_str())?;
+    let name = c_string(std::ffi::OsStr::from_bytes(&xattr.name))?;
+    let result = unsafe {
+        // This is safe: all pointers are valid for the given sizes.
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            xattr.value.as_ptr().cast(),
+            xattr.value.len(),
+            0,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Extended attributes can't be set without `xattrs` feature.
+#[cfg(not(all(feature = "xattrs", target_os = "linux")))]
+pub fn write_xattr(_path: &Path, _xattr: &Xattr) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "extended attributes are not supported by this build",
+    ))
 }
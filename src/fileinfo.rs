@@ -2,13 +2,13 @@
 
 use crate::fileext::FileExtensions;
 use crate::path::{EncodedPath, Local, PathKind};
+use crate::serde_b64;
 use crate::types::Checksum;
 use crate::DateTime;
 use serde::{Deserialize, Serialize};
 use std::fs::Metadata;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
-use tokio::fs::File;
 
 /// Stores generic information about some object in filesystem: file or directory or whatever.
 /// More specific information is stored in `data` field.
@@ -17,6 +17,8 @@ use tokio::fs::File;
 /// - [`UnspecifiedInfo`](UnspecifiedInfo) (default)
 /// - [`FileInfo`](FileInfo)
 /// - [`DirInfo`](DirInfo)
+/// - [`SymlinkInfo`](SymlinkInfo)
+/// - [`SpecialInfo`](SpecialInfo)
 /// - [`UnknownInfo`](UnknownInfo)
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(bound(serialize = "Kind: Serialize", deserialize = "Kind: Deserialize<'de>"))]
@@ -24,6 +26,12 @@ pub struct Info<P: PathKind, Kind = UnspecifiedInfo> {
     pub path: EncodedPath<P>,
     /// Somewhat unique file id. Used when computing [identifier](FileIdentifier)
     pub inode: u64,
+    /// Id of device containing the file. Together with `inode` it identifies hard links.
+    #[serde(default)]
+    pub device: u64,
+    /// Number of hard links to the file. Zero when unknown.
+    #[serde(default)]
+    pub links: u64,
     /// Unix-like access mode.
     pub mode: u32,
     pub user_id: u32,
@@ -31,10 +39,23 @@ pub struct Info<P: PathKind, Kind = UnspecifiedInfo> {
     pub created_at: DateTime,
     pub modified_at: DateTime,
     pub hash: Option<Checksum>,
+    /// Extended attributes, including ACLs and security labels.
+    /// Collected only when `xattrs` feature is enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<Xattr>,
     #[serde(flatten)]
     pub data: Kind,
 }
 
+/// Single extended attribute. POSIX ACLs are stored as `system.posix_acl_*` attributes too.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Xattr {
+    #[serde(with = "serde_b64")]
+    pub name: Vec<u8>,
+    #[serde(with = "serde_b64")]
+    pub value: Vec<u8>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FileInfo {
     pub size: u64,
@@ -43,6 +64,30 @@ pub struct FileInfo {
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct DirInfo {}
 
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct SymlinkInfo {
+    /// Raw path the link points to, exactly as returned by OS. It is never followed.
+    #[serde(with = "serde_b64")]
+    pub target: Vec<u8>,
+}
+
+/// Type of special file, that has no content.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SpecialKind {
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SpecialInfo {
+    pub kind: SpecialKind,
+    /// Device number of block and character devices. Always zero for other kinds.
+    #[serde(default)]
+    pub rdev: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct UnknownInfo {}
 
@@ -51,6 +96,8 @@ pub struct UnknownInfo {}
 pub enum UnspecifiedInfo {
     File(FileInfo),
     Dir(DirInfo),
+    Symlink(SymlinkInfo),
+    Special(SpecialInfo),
     Unknown(UnknownInfo),
 }
 
@@ -59,6 +106,8 @@ pub enum UnspecifiedInfo {
 pub enum InfoKind<P: PathKind> {
     File(Info<P, FileInfo>) = 1,
     Dir(Info<P, DirInfo>) = 2,
+    Symlink(Info<P, SymlinkInfo>) = 3,
+    Special(Info<P, SpecialInfo>) = 4,
     Unknown(Info<P, UnknownInfo>) = u8::MAX,
 }
 
@@ -67,6 +116,7 @@ pub enum InfoKind<P: PathKind> {
 ///
 /// This identifier is used to find what files are really changed, it is good enough to do it reliably.
 /// (at least it's not worse than looking at `modified_at`, and many popular are doing just that)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct FileIdentifier {
     inode: u64,
@@ -76,6 +126,17 @@ pub struct FileIdentifier {
 }
 
 impl FileIdentifier {
+    /// Creates identifier of the file right from its metadata, same as [`Info::identifier`] would.
+    #[must_use]
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        FileIdentifier {
+            inode: metadata.inode(),
+            ctime: systime_to_datetime(metadata.created()).unix_timestamp_nanos(),
+            size: metadata.len(),
+            mtime: systime_to_datetime(metadata.modified()).unix_timestamp_nanos(),
+        }
+    }
+
     #[must_use]
     pub fn as_bytes(&self) -> &[u8] {
         let ptr = (self as *const Self).cast::<u8>();
@@ -93,6 +154,8 @@ impl<P: PathKind> Info<P, UnspecifiedInfo> {
         match self.clone().turn() {
             InfoKind::File(f) => Some(f.identifier()),
             InfoKind::Dir(_) => None,
+            InfoKind::Symlink(_) => None,
+            InfoKind::Special(_) => None,
             InfoKind::Unknown(_) => None,
         }
     }
@@ -112,12 +175,24 @@ impl<P: PathKind> Info<P, FileInfo> {
 }
 
 impl<P: PathKind> Info<P, UnspecifiedInfo> {
+    /// Returns `(device, inode)` pair when this is a file with other hard links pointing to it.
+    /// Such files share their content, so it should be stored only once.
+    #[must_use]
+    pub fn hard_link_id(&self) -> Option<(u64, u64)> {
+        match &self.data {
+            UnspecifiedInfo::File(_) if self.links > 1 => Some((self.device, self.inode)),
+            _ => None,
+        }
+    }
+
     /// Returns size of file, or None when it is not a file.
     #[must_use]
     pub fn size(&self) -> Option<u64> {
         match &self.data {
             UnspecifiedInfo::File(file) => Some(file.size),
             UnspecifiedInfo::Dir(_) => None,
+            UnspecifiedInfo::Symlink(_) => None,
+            UnspecifiedInfo::Special(_) => None,
             UnspecifiedInfo::Unknown(_) => None,
         }
     }
@@ -130,6 +205,8 @@ impl<P: PathKind> Info<P, UnspecifiedInfo> {
         match &self.data {
             UnspecifiedInfo::File(_) => InfoKind::File(self.into_file().unwrap()),
             UnspecifiedInfo::Dir(_) => InfoKind::Dir(self.into_dir().unwrap()),
+            UnspecifiedInfo::Symlink(_) => InfoKind::Symlink(self.into_symlink().unwrap()),
+            UnspecifiedInfo::Special(_) => InfoKind::Special(self.into_special().unwrap()),
             UnspecifiedInfo::Unknown(_) => InfoKind::Unknown(self.into_unknown().unwrap()),
         }
     }
@@ -143,12 +220,15 @@ impl<P: PathKind, K> Info<P, K> {
         Info {
             path: self.path.cast(),
             inode: self.inode,
+            device: self.device,
+            links: self.links,
             mode: self.mode,
             user_id: self.user_id,
             group_id: self.group_id,
             created_at: self.created_at,
             modified_at: self.modified_at,
             hash: self.hash,
+            xattrs: self.xattrs,
             data: self.data,
         }
     }
@@ -161,12 +241,15 @@ macro_rules! conversion {
                 Self {
                     path: x.path,
                     inode: x.inode,
+                    device: x.device,
+                    links: x.links,
                     mode: x.mode,
                     user_id: x.user_id,
                     group_id: x.group_id,
                     created_at: x.created_at,
                     modified_at: x.modified_at,
                     hash: x.hash,
+                    xattrs: x.xattrs,
                     data: UnspecifiedInfo::$i(x.data),
                 }
             }
@@ -179,12 +262,15 @@ macro_rules! conversion {
                         data,
                         path: self.path,
                         inode: self.inode,
+                        device: self.device,
+                        links: self.links,
                         mode: self.mode,
                         user_id: self.user_id,
                         group_id: self.group_id,
                         created_at: self.created_at,
                         modified_at: self.modified_at,
                         hash: self.hash,
+                        xattrs: self.xattrs,
                     }),
                     _ => Err(self),
                 }
@@ -194,6 +280,8 @@ macro_rules! conversion {
 }
 conversion!(using Dir (into_dir) from DirInfo);
 conversion!(using File (into_file) from FileInfo);
+conversion!(using Symlink (into_symlink) from SymlinkInfo);
+conversion!(using Special (into_special) from SpecialInfo);
 conversion!(using Unknown (into_unknown) from UnknownInfo);
 
 /// Converts `SystemTime` to normal `DateTime`, falling back to
@@ -224,39 +312,84 @@ pub fn systime_to_datetime<E>(x: Result<SystemTime, E>) -> DateTime {
 }
 
 /// Extracts specific information about the file from metadata given by OS.
-fn extract_kind(metadata: &Metadata) -> UnspecifiedInfo {
-    if metadata.is_file() {
+///
+/// Metadata must not follow symbolic links, their target is read from `path` instead.
+fn extract_kind(path: &Path, metadata: &Metadata) -> std::io::Result<UnspecifiedInfo> {
+    let kind = if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(path)?.into_os_string();
+        UnspecifiedInfo::Symlink(SymlinkInfo {
+            target: os_str_bytes::OsStringBytes::into_raw_vec(target),
+        })
+    } else if metadata.is_file() {
         UnspecifiedInfo::File(FileInfo {
             size: metadata.len(),
         })
     } else if metadata.is_dir() {
         UnspecifiedInfo::Dir(DirInfo {})
+    } else if let Some(kind) = metadata.special_kind() {
+        UnspecifiedInfo::Special(SpecialInfo {
+            kind,
+            rdev: match kind {
+                SpecialKind::BlockDevice | SpecialKind::CharDevice => metadata.rdev(),
+                SpecialKind::Fifo | SpecialKind::Socket => 0,
+            },
+        })
     } else {
         UnspecifiedInfo::Unknown(UnknownInfo {})
-    }
+    };
+    Ok(kind)
 }
 
 impl Info<Local> {
+    /// Reads info about the given path. Symbolic links are not followed.
     pub async fn new(local_path: PathBuf) -> Result<Self, tokio::io::Error> {
-        let file = File::open(&local_path).await?;
-        let metadata = file.metadata().await?;
-
-        let path = EncodedPath::from_path(local_path);
-        Ok(Info::with_metadata(path, &metadata))
+        let metadata = tokio::fs::symlink_metadata(&local_path).await?;
+        Info::with_metadata(local_path, &metadata)
     }
 
+    /// Creates info of a regular file that does not exist on disk, such as [source](crate::cpio::source)
+    /// of an archive entry. It is owned by root and readable by everyone.
     #[must_use]
-    pub fn with_metadata(path: EncodedPath<Local>, metadata: &Metadata) -> Self {
+    pub fn generated(path: PathBuf, size: u64) -> Self {
+        let now = DateTime::now_utc();
         Self {
-            path,
+            path: EncodedPath::from_path(path),
+            inode: 0,
+            device: 0,
+            links: 1,
+            mode: 0o100644,
+            user_id: 0,
+            group_id: 0,
+            created_at: now,
+            modified_at: now,
+            data: UnspecifiedInfo::File(FileInfo { size }),
+            hash: None,
+            xattrs: Vec::new(),
+        }
+    }
+
+    /// Creates info from already known metadata, that should be obtained without following symbolic links.
+    pub fn with_metadata(local_path: PathBuf, metadata: &Metadata) -> Result<Self, std::io::Error> {
+        let data = extract_kind(&local_path, metadata)?;
+        let xattrs = crate::fileext::read_xattrs(&local_path).unwrap_or_else(|e| {
+            // Attributes are not essential, so the file is still stored without them.
+            log!(warn: "Can't read extended attributes of `{}`: {}",
+                path = local_path.to_string_lossy(), error = e.to_string());
+            Vec::new()
+        });
+        Ok(Self {
+            path: EncodedPath::from_path(local_path),
             inode: metadata.inode(),
+            device: metadata.device(),
+            links: metadata.links(),
             mode: metadata.mode(),
             user_id: metadata.user_id(),
             group_id: metadata.group_id(),
             created_at: systime_to_datetime(metadata.created()),
             modified_at: systime_to_datetime(metadata.modified()),
-            data: extract_kind(metadata),
+            data,
             hash: None,
-        }
+            xattrs,
+        })
     }
 }
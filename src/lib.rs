@@ -48,6 +48,8 @@ pub mod fileext;
 pub mod fileinfo;
 pub mod packer;
 pub mod path;
+pub mod progress;
+pub mod restore_script;
 pub mod serde_b64;
 pub mod stream_hash;
 pub mod types;
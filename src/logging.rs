@@ -30,6 +30,7 @@ pub mod groups {
     pub static cli: SyncOnceCell<Mutex<Logging>> = SyncOnceCell::new();
     pub static fmt_sql: SyncOnceCell<Mutex<Logging>> = SyncOnceCell::new();
     pub static time: SyncOnceCell<Mutex<Logging>> = SyncOnceCell::new();
+    pub static progress: SyncOnceCell<Mutex<Logging>> = SyncOnceCell::new();
 }
 
 #[allow(clippy::unwrap_used)]
@@ -1,18 +1,25 @@
 #![feature(backtrace)]
 
+use colbak_lib::cloud::compression::{Codec, Compression};
+use colbak_lib::cloud::encryption::{self, Identity, PublicKey, Recipient, SecretKey};
+use colbak_lib::cloud::parity::{self, ParityEncoder};
+use colbak_lib::cpio::extract::{Extractor, Overwrite};
+use colbak_lib::cpio::filter::{Filter, Glob};
+use colbak_lib::cpio::pending::{ChangePolicy, LockStrategy};
 use colbak_lib::cpio::reader::NextItem;
-use colbak_lib::cpio::Archive;
+use colbak_lib::cpio::{Archive, Format, Reader};
 use colbak_lib::database::{Database, SqlName};
-use colbak_lib::fileinfo::{Info, UnspecifiedInfo};
-use colbak_lib::path::{EscapedString, Local};
-use colbak_lib::stream_hash::stream_hash;
-use colbak_lib::types::Checksum;
+use colbak_lib::fileinfo::Info;
+use colbak_lib::path::{EncodedPath, EscapedString};
+use colbak_lib::progress::{Logged, Observer, Progress, Stage, Throttle};
 use std::convert::Infallible;
 use std::error::Error as StdError;
 use std::io::Cursor;
 use std::path::PathBuf;
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncWriteExt};
 
 use structopt::StructOpt;
 
@@ -20,16 +27,102 @@ use structopt::StructOpt;
 #[structopt(name = "colbak")]
 enum Opt {
     /// Reads list of files from stdin and output archive into stdout.
-    CreateCpio,
-    /// Reads archive from stdin and extracts files
+    CreateCpio {
+        /// Archive format: `bin`, `newc`, `crc` or `pax`.
+        /// Only `bin` and `pax` support files larger than 4GB,
+        /// but `bin` ones can be extracted by colbak only.
+        #[structopt(long, default_value = "bin")]
+        format: Format,
+        /// Compress archive with zstd using given level, from 1 to 22.
+        #[structopt(long)]
+        zstd: Option<u32>,
+        /// Encrypt archive to the given public key, generated by `keygen`. Can be repeated.
+        #[structopt(long)]
+        recipient: Vec<PublicKey>,
+        /// Encrypt archive with passphrase, read from the given file.
+        #[structopt(long, conflicts_with = "recipient")]
+        passphrase_file: Option<PathBuf>,
+        /// Write Reed-Solomon parity of the output into the given file, see `repair-archive`.
+        #[structopt(long)]
+        parity_file: Option<PathBuf>,
+        /// Size of parity in percents of the archive size, from 1 to 100.
+        #[structopt(long, default_value = "10")]
+        redundancy: u32,
+        /// What to do with files changed or deleted while they are archived: `abort`, `retry` or `flag`.
+        /// Changed files are listed at the end.
+        #[structopt(long, default_value = "abort")]
+        on_change: ChangePolicy,
+        /// How files are locked while they are read: `none`, `shared` or `exclusive`.
+        #[structopt(long, default_value = "exclusive")]
+        lock: LockStrategy,
+        /// Give up on files locked by other processes after this many seconds.
+        #[structopt(long, default_value = "60")]
+        lock_timeout: u64,
+        /// Check that files were not changed since they were listed, right after opening them.
+        #[structopt(long)]
+        recheck: bool,
+        /// Memory for files opened ahead of the current one, in KiB. Zero disables that.
+        #[structopt(long, default_value = "4096")]
+        read_ahead: usize,
+        /// Store only data of files with holes in cpio formats too, `pax` always does that.
+        /// Such files are extracted with holes by colbak only, other tools glue their data together.
+        #[structopt(long)]
+        sparse: bool,
+        /// Show progress in the last line of stderr.
+        #[structopt(long)]
+        progress: bool,
+    },
+    /// Reads archive from stdin or file and extracts files.
+    /// Encrypted and compressed archives are detected automatically.
     UnpackCpio {
         /// Where extracted files will be located.
         output: PathBuf,
+        /// Read archive from this file instead of stdin.
+        /// When it is not compressed or encrypted, skipped entries are not read at all.
+        #[structopt(long)]
+        archive: Option<PathBuf>,
+        /// What to do with existing files: `skip`, `overwrite`, `keep-newer` or `rename`.
+        #[structopt(long, default_value = "skip")]
+        overwrite: Overwrite,
+        /// Skip damaged entries instead of failing. Works only with plain `--archive` files.
+        #[structopt(long)]
+        recover: bool,
+        /// Show progress in the last line of stderr.
+        #[structopt(long)]
+        progress: bool,
+        #[structopt(flatten)]
+        selection: Selection,
+        #[structopt(flatten)]
+        secrets: Secrets,
+    },
+    /// Reads archive from stdin and lists files.
+    /// Encrypted and compressed archives are detected automatically.
+    ListCpio {
+        #[structopt(flatten)]
+        secrets: Secrets,
     },
-    /// Reads archive from stdin and lists files
-    ListCpio,
+    /// Rebuilds damaged blocks of the archive in place, using parity file written along with it.
+    /// It should be done before decrypting or decompressing the archive.
+    RepairArchive { archive: PathBuf, parity: PathBuf },
+    /// Reads archive from stdin and checks every file against the manifest, without extracting.
+    /// Exits with status 1 when anything does not match.
+    VerifyCpio {
+        #[structopt(flatten)]
+        secrets: Secrets,
+    },
+    /// Generates a new key pair for encryption. Public key is printed in the comment.
+    Keygen,
+    /// Writes `restore.sh` into the given directory.
+    /// It extracts unencrypted and uncompressed archives using only POSIX tools.
+    RestoreScript { output: PathBuf },
     /// Creates a snapshot of specified directory
-    CreateSnapshot { database: PathBuf, root: PathBuf },
+    CreateSnapshot {
+        database: PathBuf,
+        root: PathBuf,
+        /// Show progress in the last line of stderr.
+        #[structopt(long)]
+        progress: bool,
+    },
     /// Computes difference between snapshots
     DiffSnapshot {
         database: PathBuf,
@@ -41,33 +134,320 @@ enum Opt {
         database: PathBuf,
         directory: PathBuf,
         min_size: u64,
+        /// Format of the archives, see `create-cpio`.
+        #[structopt(long, default_value = "bin")]
+        format: Format,
+        /// Show progress in the last line of stderr.
+        #[structopt(long)]
+        progress: bool,
     },
 }
 
+/// Shows progress in the last line of stderr, rewriting it in place.
+/// It is written into the `progress` log as well, but less often.
+#[derive(Debug)]
+struct ProgressLine {
+    throttle: Throttle,
+    log: Logged,
+    /// Hashing reports the current file only, so it is added to the archive progress.
+    archived: Mutex<Progress>,
+}
+
+impl ProgressLine {
+    fn new(enabled: bool) -> Option<Arc<Self>> {
+        enabled.then(|| {
+            Arc::new(ProgressLine {
+                throttle: Throttle::new(Duration::from_millis(200)),
+                log: Logged::new(Duration::from_secs(10)),
+                archived: Mutex::new(Progress::default()),
+            })
+        })
+    }
+
+    /// Ends the line, so following output is not mixed with it.
+    fn finish(line: Option<Arc<Self>>) {
+        if line.is_some() {
+            eprintln!();
+        }
+    }
+}
+
+impl Observer for ProgressLine {
+    fn update(&self, stage: Stage, mut progress: Progress, path: &[u8]) {
+        let mut archived = match self.archived.lock() {
+            Ok(archived) => archived,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let stage = match stage {
+            Stage::Archiving => {
+                *archived = progress;
+                stage
+            }
+            Stage::Hashing => {
+                progress.files += archived.files;
+                progress.bytes += archived.bytes;
+                Stage::Archiving
+            }
+            _ => stage,
+        };
+        drop(archived);
+        self.log.update(stage, progress, path);
+        if self.throttle.is_due() {
+            eprint!(
+                "\r\x1b[K{} {} files, {} MiB: {}",
+                stage,
+                progress.files,
+                progress.bytes / (1024 * 1024),
+                path.escaped()
+            );
+        }
+    }
+}
+
+/// Secrets that are used to decrypt archives.
+#[derive(Debug, StructOpt)]
+struct Secrets {
+    /// File with secret keys generated by `keygen`.
+    #[structopt(long)]
+    identity: Option<PathBuf>,
+    /// File with passphrase the archive was encrypted with.
+    #[structopt(long)]
+    passphrase_file: Option<PathBuf>,
+}
+
+async fn read_passphrase(path: PathBuf) -> std::io::Result<String> {
+    let passphrase = tokio::fs::read_to_string(path).await?;
+    Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+impl Secrets {
+    async fn identities(self) -> Result<Vec<Identity>, Box<dyn StdError>> {
+        let mut identities = Vec::new();
+        if let Some(path) = self.identity {
+            let text = tokio::fs::read_to_string(path).await?;
+            identities.extend(encryption::parse_identities(&text)?);
+        }
+        if let Some(path) = self.passphrase_file {
+            identities.push(Identity::Passphrase(read_passphrase(path).await?));
+        }
+        Ok(identities)
+    }
+}
+
+/// Opens archive, detecting whether it is encrypted or compressed.
+async fn open_input<R: AsyncRead + Unpin + 'static>(
+    input: R,
+    secrets: Secrets,
+) -> Result<Pin<Box<dyn AsyncRead>>, Box<dyn StdError>> {
+    let mut input = tokio::io::BufReader::new(input);
+    let decrypted: Pin<Box<dyn AsyncRead>> = if encryption::is_encrypted(input.fill_buf().await?) {
+        let identities = secrets.identities().await?;
+        Box::pin(encryption::decrypt(input, &identities).await?)
+    } else {
+        Box::pin(input)
+    };
+    let mut decrypted = tokio::io::BufReader::new(decrypted);
+    let codec = Codec::detect(decrypted.fill_buf().await?);
+    Ok(codec.decompress(decrypted))
+}
+
+/// Opens stdin, detecting whether archive is encrypted or compressed.
+async fn open_stdin(secrets: Secrets) -> Result<Pin<Box<dyn AsyncRead>>, Box<dyn StdError>> {
+    open_input(tokio::io::stdin(), secrets).await
+}
+
+/// Chooses which entries to extract.
+#[derive(Debug, StructOpt)]
+struct Selection {
+    /// Extract only entries matching this pattern, can be repeated.
+    /// Directories are extracted with everything inside.
+    #[structopt(long)]
+    include: Vec<Glob>,
+    /// Do not extract entries matching this pattern, can be repeated.
+    #[structopt(long)]
+    exclude: Vec<Glob>,
+    /// Extract only paths listed in this file, one per line, as shown by `list-cpio`.
+    #[structopt(long)]
+    files_from: Option<PathBuf>,
+}
+
+impl Selection {
+    async fn filter(self) -> std::io::Result<Filter> {
+        let mut filter = Filter::new();
+        for glob in self.include {
+            filter = filter.include(glob);
+        }
+        for glob in self.exclude {
+            filter = filter.exclude(glob);
+        }
+        if let Some(path) = self.files_from {
+            let list = tokio::fs::read(path).await?;
+            for line in list.split(|x| *x == b'\n').filter(|x| !x.is_empty()) {
+                filter = filter.file(EncodedPath::from_vec(line.to_vec()));
+            }
+        }
+        Ok(filter)
+    }
+}
+
+async fn unpack<R: AsyncRead + Unpin>(
+    mut archive: Reader<R>,
+    mut extractor: Extractor,
+    filter: &Filter,
+) -> Result<(), Box<dyn StdError>> {
+    loop {
+        match archive.advance().await? {
+            NextItem::File(file) if !filter.matches(&file.info().path) => {
+                extractor.skip(&file);
+                archive = file.to_void().await?;
+            }
+            NextItem::File(file) => {
+                println!("Extracting {:?}...", file.info().path.escaped());
+                archive = extractor.extract(file).await?;
+            }
+            NextItem::End(end) => {
+                for warning in extractor.finish(end) {
+                    eprintln!("Warning: {}", warning);
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Same as [`unpack`], but skipped entries are not read at all.
+async fn unpack_seekable<R: AsyncRead + AsyncSeek + Unpin>(
+    mut archive: Reader<R>,
+    mut extractor: Extractor,
+    filter: &Filter,
+) -> Result<(), Box<dyn StdError>> {
+    loop {
+        match archive.advance().await? {
+            NextItem::File(file) if !filter.matches(&file.info().path) => {
+                extractor.skip(&file);
+                archive = file.skip().await?;
+            }
+            NextItem::File(file) => {
+                println!("Extracting {:?}...", file.info().path.escaped());
+                archive = extractor.extract(file).await?;
+            }
+            NextItem::End(end) => {
+                for warning in extractor.finish(end) {
+                    eprintln!("Warning: {}", warning);
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Same as [`unpack_seekable`], but damaged entries are skipped.
+async fn unpack_recovering<R: AsyncRead + AsyncSeek + Unpin>(
+    mut archive: Reader<R>,
+    mut extractor: Extractor,
+    filter: &Filter,
+) -> Result<(), Box<dyn StdError>> {
+    loop {
+        match archive.recover().await? {
+            NextItem::File(file) if !filter.matches(&file.info().path) => {
+                extractor.skip(&file);
+                archive = file.skip().await?;
+            }
+            NextItem::File(file) => {
+                println!("Extracting {:?}...", file.info().path.escaped());
+                archive = extractor.extract(file).await?;
+            }
+            NextItem::End(end) => {
+                for warning in extractor.finish(end) {
+                    eprintln!("Warning: {}", warning);
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
 async fn entry_point(opt: Opt) -> Result<(), Box<dyn StdError>> {
     match opt {
-        Opt::CreateCpio => {
+        Opt::CreateCpio {
+            format,
+            zstd,
+            recipient,
+            passphrase_file,
+            parity_file,
+            redundancy,
+            on_change,
+            lock,
+            lock_timeout,
+            recheck,
+            read_ahead,
+            sparse,
+            progress,
+        } => {
             let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
-            let mut archive = Archive::new();
+            let lock = lock.with_timeout(Duration::from_secs(lock_timeout));
+            let mut archive = Archive::with_format(format)
+                .with_change_policy(on_change)
+                .with_lock(lock)
+                .with_recheck(recheck)
+                .with_read_ahead(read_ahead * 1024);
+            if sparse {
+                archive = archive.with_sparse(true);
+            }
+            let line = ProgressLine::new(progress);
+            if let Some(line) = &line {
+                archive = archive.with_progress(line.clone());
+            }
             while let Some(line) = stdin.next_line().await? {
                 let path = PathBuf::from(line);
                 let info = Info::new(path).await?;
                 archive.add(info);
             }
             let mut stdout = tokio::io::stdout();
-            let mut reader = archive.read();
+            let compression = match zstd {
+                Some(level) => Compression::Zstd { level },
+                None => Compression::None,
+            };
+            let mut reader = compression.compress(archive.read());
+            let mut recipients: Vec<_> = recipient.into_iter().map(Recipient::Key).collect();
+            if let Some(path) = passphrase_file {
+                recipients.push(Recipient::passphrase(read_passphrase(path).await?));
+            }
+            if !recipients.is_empty() {
+                reader = Box::pin(encryption::encrypt(reader, &recipients).await?);
+            }
+            let mut encoder = match parity_file {
+                Some(path) => {
+                    let file = tokio::fs::File::create(path).await?;
+                    Some(ParityEncoder::new(redundancy, file)?)
+                }
+                None => None,
+            };
+            let mut reader: Pin<Box<dyn AsyncRead + '_>> = match &mut encoder {
+                Some(encoder) => Box::pin(encoder.wrap(reader)),
+                None => reader,
+            };
             let mut buffer = vec![0; 8 * 1024];
             loop {
                 buffer.clear();
                 let len = reader.read_buf(&mut buffer).await?;
                 if len == 0 {
-                    break Ok(());
+                    break;
                 }
                 stdout.write_all_buf(&mut Cursor::new(&mut buffer)).await?;
             }
+            drop(reader);
+            ProgressLine::finish(line);
+            if let Some(encoder) = encoder {
+                encoder.finish().await?;
+            }
+            for (info, change) in archive.changed() {
+                eprintln!("Changed: {:?} {}", info.path.escaped(), change);
+            }
+            Ok(())
         }
-        Opt::ListCpio => {
-            let stdin = tokio::io::stdin();
+        Opt::ListCpio { secrets } => {
+            let stdin = open_stdin(secrets).await?;
             let mut sink = tokio::io::sink(); // We can't seek stdin.
             let mut archive = colbak_lib::cpio::Reader::new(stdin);
             loop {
@@ -78,82 +458,114 @@ async fn entry_point(opt: Opt) -> Result<(), Box<dyn StdError>> {
                         archive = file.drain_to(&mut sink).await?;
                     }
                     NextItem::End(end) => {
-                        if let Some(files) = end.files {
-                            println!("{:#?}", files)
+                        if let Some(warning) = end.warning {
+                            eprintln!("Warning: {}", warning);
+                        }
+                        if let Some(manifest) = end.manifest {
+                            println!("{:#?}", manifest)
                         }
                         break Ok(());
                     }
                 }
             }
         }
-        Opt::UnpackCpio { output } => {
-            let stdin = tokio::io::stdin();
-            let mut archive = colbak_lib::cpio::Reader::new(stdin);
-            let mut hashes = Vec::new();
-            loop {
-                match archive.advance().await? {
-                    NextItem::File(file) => {
-                        let mut info = file.info();
-                        let path = info.path.clone().cast::<Local>().to_path()?;
-                        let dst = output.join(path);
-                        println!("Extracting {:?}...", dst);
-                        match info.data {
-                            UnspecifiedInfo::Dir(_) => {
-                                tokio::fs::create_dir(&dst).await?;
-                                archive = file.to_void().await?;
-                            }
-                            UnspecifiedInfo::File(_) => {
-                                let output = File::create(dst).await?;
-                                let mut hasher = stream_hash(output);
-                                archive = file.drain_to(&mut hasher).await?;
-                                let hash: Checksum = hasher.finalize().into();
-                                info.hash = Some(hash);
-                            }
-                            UnspecifiedInfo::Unknown(_) => {
-                                println!("\tSkipping unknown file.");
-                                archive = file.to_void().await?;
-                            }
-                        }
-                        hashes.push(info);
-                    }
-                    NextItem::End(end) => {
-                        let files = match end.files {
-                            Some(files) => files,
-                            None => break Ok(()),
-                        };
-                        for (expected, found) in files.into_iter().zip(hashes.into_iter()) {
-                            let total_match = expected == found;
-                            if total_match {
-                                continue;
-                            }
-                            if expected.path != found.path {
-                                eprintln!(
-                                    "Warning: path mismatch. Expected {:?}, found {:?}",
-                                    expected.path, found.path
-                                );
-                            }
-                            let hash_match = expected
-                                .hash
-                                .zip(found.hash)
-                                .map(|(x, y)| x == y)
-                                .unwrap_or(true);
-                            if !hash_match {
-                                eprintln!(
-                                    "Warning: hash mismatch at {:?}. Expected {:?}, found {:?}",
-                                    found.path, expected.hash, found.hash
-                                );
-                            }
-                        }
-                        break Ok(());
+        Opt::VerifyCpio { secrets } => {
+            let stdin = open_stdin(secrets).await?;
+            let report = colbak_lib::cpio::Reader::new(stdin).verify().await?;
+            for problem in &report.problems {
+                eprintln!("Mismatch: {}", problem);
+            }
+            println!(
+                "Checked {} entries, found {} problems",
+                report.entries,
+                report.problems.len()
+            );
+            if !report.is_ok() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Opt::UnpackCpio {
+            output,
+            archive,
+            overwrite,
+            recover,
+            progress,
+            selection,
+            secrets,
+        } => {
+            let filter = selection.filter().await?;
+            let mut extractor = Extractor::new(output).with_overwrite(overwrite);
+            let line = ProgressLine::new(progress);
+            if let Some(line) = &line {
+                extractor = extractor.with_progress(line.clone());
+            }
+            let result = match archive {
+                Some(path) => {
+                    let mut file = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+                    let head = file.fill_buf().await?;
+                    let plain =
+                        Codec::detect(head) == Codec::None && !encryption::is_encrypted(head);
+                    if recover && plain {
+                        unpack_recovering(Reader::new(file), extractor, &filter).await
+                    } else if recover {
+                        Err("Only plain archive can be recovered".into())
+                    } else if plain {
+                        unpack_seekable(Reader::new(file), extractor, &filter).await
+                    } else {
+                        let input = open_input(file, secrets).await?;
+                        unpack(Reader::new(input), extractor, &filter).await
                     }
                 }
-            }
+                None if recover => Err("Only plain archive can be recovered".into()),
+                None => {
+                    let input = open_stdin(secrets).await?;
+                    unpack(Reader::new(input), extractor, &filter).await
+                }
+            };
+            ProgressLine::finish(line);
+            result
+        }
+        Opt::RepairArchive { archive, parity } => {
+            let mut parity = tokio::fs::File::open(parity).await?;
+            let mut archive = tokio::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(archive)
+                .await?;
+            let report = parity::repair(&mut archive, &mut parity).await?;
+            println!(
+                "Repaired {} blocks, {} parity blocks were damaged",
+                report.repaired, report.damaged_parity
+            );
+            Ok(())
+        }
+        Opt::Keygen => {
+            let secret = SecretKey::generate()?;
+            println!("# public key: {}", secret.public());
+            println!("{}", secret);
+            Ok(())
         }
-        Opt::CreateSnapshot { database, root } => {
+        Opt::RestoreScript { output } => {
+            let path = colbak_lib::restore_script::write_to(&output).await?;
+            println!("Written {:?}", path);
+            Ok(())
+        }
+        Opt::CreateSnapshot {
+            database,
+            root,
+            progress,
+        } => {
             let mut database = colbak_lib::database::Database::open(database)?;
             let name = SqlName::now();
             let mut snapshot = database.open_snapshot(name)?;
-            snapshot.filler()?.fill(&root)?.save()?;
+            let line = ProgressLine::new(progress);
+            let mut filler = snapshot.filler()?;
+            if let Some(line) = &line {
+                filler = filler.with_progress(line.clone());
+            }
+            filler.fill(&root)?.save()?;
+            ProgressLine::finish(line);
             println!("Created snapshot {}", snapshot.name());
             Ok(())
         }
@@ -176,22 +588,30 @@ async fn entry_point(opt: Opt) -> Result<(), Box<dyn StdError>> {
             database,
             directory,
             min_size,
+            format,
+            progress,
         } => {
             let mut database = Database::open(database)?;
 
+            let line = ProgressLine::new(progress);
             let after = {
                 let mut after = database.open_snapshot(SqlName::now())?;
-                after.filler()?.fill(&directory)?.save()?;
+                let mut filler = after.filler()?;
+                if let Some(line) = &line {
+                    filler = filler.with_progress(line.clone());
+                }
+                filler.fill(&directory)?.save()?;
                 after.into_name()
             };
+            ProgressLine::finish(line);
             let after = database.readonly_snapshot(after)?;
 
             let before = database.empty_snapshot()?;
             let diff = database.compare_snapshots(&before, &after)?;
 
-            let packed = colbak_lib::packer::pack(&diff, min_size)?;
-            for (n, pack) in packed.0.iter().enumerate() {
-                println!("PACK {}:", n + 1);
+            let packed = colbak_lib::packer::pack(&diff, min_size, format)?;
+            for (n, pack) in packed.packs.iter().enumerate() {
+                println!("PACK {} ({}):", n + 1, packed.format.name());
                 for file in pack {
                     let file = diff
                         .query()
@@ -224,5 +644,6 @@ async fn main() {
     if let Err(e) = entry_point(opt).await {
         eprintln!("ERROR!");
         show_bt(e.as_ref());
+        std::process::exit(1);
     }
 }
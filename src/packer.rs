@@ -1,10 +1,27 @@
 use smallvec::SmallVec;
+use std::io;
 
+use crate::cpio::Format;
 use crate::database::{Diff, DiffType, RowId};
-pub struct Packed(pub Vec<SmallVec<[RowId; 4]>>);
+pub struct Packed {
+    pub packs: Vec<SmallVec<[RowId; 4]>>,
+    /// Format every pack is archived in.
+    pub format: Format,
+}
+
+/// Generates random identifier of a new pack, it is stored in the manifest of its archive.
+///
+/// # Errors
+/// Fails when system random number generator is not available.
+pub fn new_pack_id() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Ok(bytes.iter().map(|x| format!("{:02x}", x)).collect())
+}
 
 #[allow(clippy::missing_panics_doc)]
-pub fn pack(diff: &Diff, min_size: u64) -> Result<Packed, crate::database::Error> {
+pub fn pack(diff: &Diff, min_size: u64, format: Format) -> Result<Packed, crate::database::Error> {
     let mut result = Vec::new();
     let mut last_pack = SmallVec::new();
     let mut pack_size = 0;
@@ -22,5 +39,8 @@ pub fn pack(diff: &Diff, min_size: u64) -> Result<Packed, crate::database::Error
             }
             Ok(())
         })?;
-    Ok(Packed(result))
+    Ok(Packed {
+        packs: result,
+        format,
+    })
 }
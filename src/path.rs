@@ -293,6 +293,12 @@ impl EscapedString for [u8] {
     }
 }
 
+impl<P: PathKind> std::hash::Hash for EncodedPath<P> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
 impl<P: PathKind> std::fmt::Debug for EncodedPath<P> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let escaped = self.escaped();
@@ -0,0 +1,111 @@
+//! Progress of long operations: walking over files, archiving, hashing and extraction.
+//!
+//! Each operation counts files and bytes done so far and passes them to an [`Observer`]
+//! together with the path it is busy with. It is called for every chunk of data,
+//! so slow observers should skip most of the calls, see [`Throttle`].
+
+use crate::path::EscapedString;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Operation that reports its progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Files are added to the snapshot, see [`SnapshotFiller::fill`](crate::database::SnapshotFiller::fill).
+    Walking,
+    /// Content of a single file is read and hashed while it is archived.
+    /// Counts are of that file only, and it is done once the file is.
+    Hashing,
+    /// Archive is written, see [`Archive::read`](crate::cpio::Archive::read).
+    /// Counts are updated once per entry, and bytes are the size of the archive so far.
+    Archiving,
+    /// Archive is extracted, see [`Extractor`](crate::cpio::extract::Extractor).
+    Extracting,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Walking => "walking",
+            Stage::Hashing => "hashing",
+            Stage::Archiving => "archiving",
+            Stage::Extracting => "extracting",
+        })
+    }
+}
+
+/// How much of the operation is done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Receives progress of long operations.
+pub trait Observer: fmt::Debug + Send + Sync {
+    /// Called when the operation moves on, `path` is the file it works on now.
+    fn update(&self, stage: Stage, progress: Progress, path: &[u8]);
+}
+
+/// Lets through one call per interval.
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    #[must_use]
+    pub fn new(interval: Duration) -> Self {
+        Throttle {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Whether the interval passed since the last time it returned `true`.
+    pub fn is_due(&self) -> bool {
+        let now = Instant::now();
+        let mut last = match self.last.lock() {
+            Ok(last) => last,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let due = last.map_or(true, |x| now.duration_since(x) >= self.interval);
+        if due {
+            *last = Some(now);
+        }
+        due
+    }
+}
+
+/// Writes progress into the `progress` [logging group](crate::logging::groups)
+/// at most once per interval.
+#[derive(Debug)]
+pub struct Logged {
+    throttle: Throttle,
+}
+
+impl Logged {
+    #[must_use]
+    pub fn new(interval: Duration) -> Self {
+        Logged {
+            throttle: Throttle::new(interval),
+        }
+    }
+}
+
+impl Observer for Logged {
+    fn update(&self, stage: Stage, progress: Progress, path: &[u8]) {
+        if !self.throttle.is_due() {
+            return;
+        }
+        log!(progress: "{} {} files, {} bytes: {}",
+            stage,
+            files = progress.files,
+            bytes = progress.bytes,
+            path = path.escaped());
+    }
+}
@@ -0,0 +1,416 @@
+#!/bin/sh
+# Extracts an archive created by colbak without colbak itself, using POSIX tools only.
+#
+# Usage: sh restore.sh ARCHIVE [DESTINATION]
+#
+# ARCHIVE must be already decrypted (`age -d`) and decompressed (`zstd -d`).
+# Old binary format is read in both byte orders, including files larger than 4GB
+# (colbak stores higher bits of their size in the `rdev` field), as well as `newc` and `crc`.
+# Leading slashes are removed from paths, entries with `..` are skipped, and so are entries
+# inside symbolic links extracted earlier, since they could point outside of destination.
+# Pax format is not supported, such archives can be extracted by `pax -r` or `tar -x`.
+# Permissions are restored, owners and modification times are not.
+# Holes of sparse files are recreated using their layout from the manifest.
+#
+# When sha256sum, shasum or openssl is available, every file and the whole archive
+# are checked against the manifest stored after `TRAILER!!!`.
+# Exit code is 1 when anything does not match, and 2 on usage errors.
+
+set -eu
+
+if [ $# -lt 1 ] || [ $# -gt 2 ]; then
+    echo "Usage: $0 ARCHIVE [DESTINATION]" >&2
+    exit 2
+fi
+archive=$1
+dest=${2:-.}
+status=0
+
+tmp="${TMPDIR:-/tmp}/colbak-restore.$$"
+mkdir -m 700 "$tmp"
+trap 'rm -rf "$tmp"' EXIT
+: >"$tmp/hashes"
+: >"$tmp/dirs"
+
+warn() {
+    echo "restore.sh: $*" >&2
+}
+
+fail() {
+    warn "$*"
+    exit 1
+}
+
+# Prints $2 bytes of the archive starting at offset $1 as decimal numbers.
+bytes() {
+    if [ "$2" -gt 0 ]; then
+        od -An -tu1 -v -j "$1" -N "$2" "$archive"
+    fi
+}
+
+# Copies $1 bytes from stdin to stdout.
+# All `dd` calls share the same file descriptors, so each one continues where previous stopped.
+pass() {
+    dd bs=65536 count=$(($1 / 65536)) 2>/dev/null
+    dd bs=512 count=$(($1 % 65536 / 512)) 2>/dev/null
+    dd bs=1 count=$(($1 % 512)) 2>/dev/null
+}
+
+# Copies $2 bytes of the archive starting at offset $1 to stdout.
+copy() {
+    {
+        dd bs=1 skip="$1" count=0 2>/dev/null
+        pass "$2"
+    } <"$archive"
+}
+
+# Converts decimal bytes into octal escapes understood by printf.
+escape() {
+    for byte in "$@"; do
+        printf '\\%03o' "$byte"
+    done
+}
+
+# Prints octal escapes produced by `escape` as raw bytes.
+# Trailing newlines are preserved, unlike plain command substitution.
+unescape() {
+    printf "$1"
+    printf x
+}
+
+if command -v sha256sum >/dev/null 2>&1; then
+    sha256() { sha256sum | cut -d ' ' -f 1; }
+elif command -v shasum >/dev/null 2>&1; then
+    sha256() { shasum -a 256 | cut -d ' ' -f 1; }
+elif command -v openssl >/dev/null 2>&1; then
+    sha256() { openssl dgst -sha256 -r | cut -d ' ' -f 1; }
+else
+    warn "sha256sum, shasum and openssl are not found, hashes will not be checked"
+    sha256() { return 1; }
+fi
+
+# Loads $2 header bytes at offset $1 into variables b0, b1, ...
+load_header() {
+    i=0
+    for byte in $(bytes "$1" "$2"); do
+        eval "b$i=$byte"
+        i=$((i + 1))
+    done
+    [ "$i" -eq "$2" ] || fail "unexpected end of archive at $1"
+}
+
+# Succeeds when no existing parent of path $1 inside destination is a symbolic link,
+# so nothing is written through links extracted earlier.
+safe_parents() {
+    parent=$dest
+    rest=$1
+    while :; do
+        case $rest in
+        */*) ;;
+        *) return 0 ;;
+        esac
+        parent="$parent/${rest%%/*}"
+        rest=${rest#*/}
+        [ ! -h "$parent" ] || return 1
+    done
+}
+
+# Decodes N-th 16-bit word of binary header.
+word() {
+    eval "first=\$b$(($1 * 2)) second=\$b$(($1 * 2 + 1))"
+    if [ "$order" = little ]; then
+        echo $((second * 256 + first))
+    else
+        echo $((first * 256 + second))
+    fi
+}
+
+# Decodes N-th eight-digit hexadecimal field of newc header.
+field() {
+    value=0
+    i=$((6 + $1 * 8))
+    end=$((i + 8))
+    while [ "$i" -lt "$end" ]; do
+        eval "digit=\$b$i"
+        if [ "$digit" -le 57 ]; then
+            digit=$((digit - 48))
+        else
+            digit=$(((digit | 32) - 87))
+        fi
+        value=$((value * 16 + digit))
+        i=$((i + 1))
+    done
+    echo "$value"
+}
+
+pos=0
+while :; do
+    set -- $(bytes "$pos" 6)
+    [ $# -ge 2 ] || fail "unexpected end of archive at $pos"
+    if [ "$1" -eq 199 ] && [ "$2" -eq 113 ]; then
+        format=binary order=little
+    elif [ "$1" -eq 113 ] && [ "$2" -eq 199 ]; then
+        format=binary order=big
+    elif [ "$*" = "48 55 48 55 48 49" ] || [ "$*" = "48 55 48 55 48 50" ]; then
+        format=newc
+    elif [ "$(echo $(bytes $((pos + 257)) 5))" = "117 115 116 97 114" ]; then
+        fail "pax archives are not supported, extract them with \`pax -r\` or \`tar -x\`"
+    else
+        fail "invalid header at $pos"
+    fi
+
+    if [ "$format" = binary ]; then
+        load_header "$pos" 26
+        ino=$(($(word 1) * 65536 + $(word 2)))
+        mode=$(word 3)
+        nlink=$(word 6)
+        rdev=$(word 7)
+        namesize=$(word 10)
+        size=$(($(word 11) * 65536 + $(word 12)))
+        case $((mode & 61440)) in
+        8192 | 24576)
+            major=$((rdev / 256)) minor=$((rdev % 256))
+            ;;
+        *)
+            size=$((rdev * 4294967296 + size))
+            ;;
+        esac
+        link_id=$ino
+        name=$((pos + 26))
+        data=$((name + namesize + namesize % 2))
+        data_end=$((data + size + size % 2))
+    else
+        load_header "$pos" 110
+        ino=$(field 0)
+        mode=$(field 1)
+        nlink=$(field 4)
+        size=$(field 6)
+        major=$(field 9) minor=$(field 10)
+        namesize=$(field 11)
+        link_id="$(field 7)_$(field 8)_$ino"
+        name=$((pos + 110))
+        data=$((name + (110 + namesize + 3) / 4 * 4 - 110))
+        data_end=$((data + (size + 3) / 4 * 4))
+    fi
+
+    # Name is stored with a NUL byte at the end.
+    raw_name=$(bytes "$name" $((namesize - 1)))
+    if [ "$size" -eq 0 ] && [ "$(echo $raw_name)" = "84 82 65 73 76 69 82 33 33 33" ]; then
+        trailer=$pos
+        manifest=$data
+        break
+    fi
+    esc=$(escape $raw_name)
+    path=$(unescape "$esc")
+    path=${path%x}
+    # Strip leading slashes.
+    path=${path#"${path%%[!/]*}"}
+    case "/$path/" in
+    */../*)
+        warn "skipping $path: it points outside of destination"
+        pos=$data_end
+        continue
+        ;;
+    esac
+    [ -n "$path" ] || path=.
+    if ! safe_parents "$path"; then
+        warn "skipping $path: its parent is a symbolic link"
+        pos=$data_end
+        continue
+    fi
+    target="$dest/$path"
+    case "$path" in
+    */*) mkdir -p "$dest/${path%/*}" ;;
+    *) mkdir -p "$dest" ;;
+    esac
+    perm=$(printf '%o' $((mode & 4095)))
+
+    case $((mode & 61440)) in
+    32768)
+        eval "original=\${link_$link_id-}"
+        if [ "$nlink" -gt 1 ] && [ "$size" -eq 0 ] && [ -n "$original" ]; then
+            rm -f "$target"
+            ln "$original" "$target"
+        else
+            # Existing symbolic link is replaced, not written through.
+            rm -f "$target"
+            copy "$data" "$size" >"$target"
+            chmod "$perm" "$target"
+            if [ "$nlink" -gt 1 ]; then
+                eval "link_$link_id=\$target"
+            fi
+        fi
+        if hash=$(sha256 <"$target"); then
+            printf '%s %s\n' "$esc" "$hash" >>"$tmp/hashes"
+        fi
+        ;;
+    16384)
+        mkdir -p "$target"
+        # Applied in the end, since directory may be read-only.
+        printf '%s %s\n' "$perm" "$esc" >>"$tmp/dirs"
+        ;;
+    40960)
+        link=$(unescape "$(escape $(bytes "$data" "$size"))")
+        rm -f "$target"
+        ln -s "${link%x}" "$target"
+        ;;
+    4096)
+        mkfifo -m "$perm" "$target"
+        ;;
+    8192 | 24576)
+        kind=c
+        [ $((mode & 61440)) -eq 8192 ] || kind=b
+        if ! mknod -m "$perm" "$target" "$kind" "$major" "$minor" 2>/dev/null; then
+            warn "skipping device $path"
+        fi
+        ;;
+    *)
+        warn "skipping $path of unsupported type"
+        ;;
+    esac
+    pos=$data_end
+done
+
+while read -r perm esc; do
+    path=$(unescape "$esc")
+    path=${path%x}
+    path=${path#"${path%%[!/]*}"}
+    path=${path:-.}
+    # Directory could be replaced by a symbolic link later.
+    if safe_parents "$path" && [ ! -h "$dest/$path" ]; then
+        chmod "$perm" "$dest/$path"
+    fi
+done <"$tmp/dirs"
+
+# Manifest is a single JSON value. Both paths and hashes are stored in base64,
+# so they never contain quotes and can be found without a real JSON parser.
+: >"$tmp/sparse"
+tail -c +$((manifest + 1)) "$archive" | awk -v sparse="$tmp/sparse" '
+function decode(s, format,   out, bits, count, i, value, byte) {
+    out = ""
+    bits = 0
+    count = 0
+    for (i = 1; i <= length(s); i++) {
+        value = index(B64, substr(s, i, 1)) - 1
+        if (value < 0) break
+        bits = bits * 64 + value
+        count += 6
+        if (count >= 8) {
+            count -= 8
+            byte = int(bits / 2 ^ count)
+            bits -= byte * 2 ^ count
+            out = out sprintf(format, byte)
+        }
+    }
+    return out
+}
+function hash(s) {
+    if (!match(s, /"hash":"[^"]*"/)) return ""
+    # Hashes are padded with zeroes to 64 bytes, sha256 is only 32 of them.
+    return substr(decode(substr(s, RSTART + 8, RLENGTH - 9), "%02x"), 1, 64)
+}
+BEGIN { B64 = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/" }
+{ text = text $0 "\n" }
+END {
+    start = index(text, "\"files\":[")
+    if (start > 0) {
+        whole = hash(substr(text, 1, start - 1))
+        if (whole != "") print "archive", whole
+        text = substr(text, start)
+    }
+    # Path is stored as a single-element array.
+    count = split(text, entries, "\"path\":")
+    for (i = 2; i <= count; i++) {
+        entry = entries[i]
+        sub(/^\[?"/, "", entry)
+        layout = ""
+        if (match(entry, /"sparse":\{/)) {
+            layout = substr(entry, RSTART)
+            entry = substr(entry, 1, RSTART - 1)
+        }
+        expected = hash(entry)
+        path = substr(entry, 1, index(entry, "\"") - 1)
+        if (expected != "") print decode(path, "\\%03o"), expected
+        if (layout != "" && match(entry, /"size":[0-9]+/)) {
+            size = substr(entry, RSTART + 7, RLENGTH - 7)
+            match(entry, /"mode":[0-9]+/)
+            line = decode(path, "\\%03o") " " substr(entry, RSTART + 7, RLENGTH - 7) " " size
+            stored = 0
+            extents = ""
+            while (match(layout, /"offset":[0-9]+,"length":[0-9]+/)) {
+                extent = substr(layout, RSTART + 9, RLENGTH - 9)
+                sub(/,"length":/, " ", extent)
+                split(extent, parts, " ")
+                stored += parts[2]
+                extents = extents " " extent
+                layout = substr(layout, RSTART + RLENGTH)
+            }
+            print line, stored extents >sparse
+        }
+    }
+}' >"$tmp/expected"
+
+# Moves extents of sparse file $target to their places, using variables set by the loop below.
+expand() {
+    cp "$target" "$tmp/data"
+    chmod u+w "$target"
+    : >"$target"
+    set -- $extents
+    {
+        at=0
+        while [ $# -gt 0 ]; do
+            # Seeking is relative to the current position of the shared descriptor.
+            dd bs=1 seek=$(($1 - at)) count=0 conv=notrunc 2>/dev/null
+            pass "$2"
+            at=$(($1 + $2))
+            shift 2
+        done
+    } <"$tmp/data" 1<>"$target"
+    # Truncates or extends the file, so the last hole is recreated too.
+    dd of="$target" bs=1 seek="$size" count=0 2>/dev/null
+    chmod "$(printf '%o' $((mode & 4095)))" "$target"
+    rm -f "$tmp/data"
+}
+
+# Sparse files were extracted with their extents glued together, now they are moved into place.
+# Hard links share the same file, so it is done only while the file has the stored size.
+while read -r esc mode size stored extents; do
+    path=$(unescape "$esc")
+    path=${path%x}
+    path=${path#"${path%%[!/]*}"}
+    path=${path:-.}
+    target="$dest/$path"
+    if [ ! -f "$target" ] || [ -h "$target" ] || ! safe_parents "$path"; then
+        continue
+    fi
+    if [ "$(wc -c <"$target")" -eq "$stored" ]; then
+        expand
+    fi
+    if hash=$(sha256 <"$target"); then
+        printf '%s %s\n' "$esc" "$hash" >>"$tmp/hashes"
+    fi
+done <"$tmp/sparse"
+
+if [ ! -s "$tmp/expected" ]; then
+    warn "manifest is missing or has no hashes"
+elif sha256 </dev/null >/dev/null; then
+    while read -r esc expected; do
+        [ "$esc" = archive ] || continue
+        if [ "$(copy 0 "$trailer" | sha256)" != "$expected" ]; then
+            warn "archive hash does not match the manifest"
+            status=1
+        fi
+    done <"$tmp/expected"
+
+    awk 'NR == FNR { found[$1] = $2; next }
+        $1 == "archive" { next }
+        !($1 in found) { print "missing", $1; next }
+        found[$1] != $2 { print "mismatch", $1 }' "$tmp/hashes" "$tmp/expected" >"$tmp/problems"
+    while read -r problem esc; do
+        path=$(unescape "$esc")
+        warn "hash $problem: ${path%x}"
+        status=1
+    done <"$tmp/problems"
+fi
+
+exit "$status"
@@ -0,0 +1,26 @@
+//! Standalone POSIX shell script that extracts colbak archives,
+//! so they can be restored even when colbak itself is not available.
+//!
+//! See the comment at the beginning of [`SCRIPT`](SCRIPT) for its usage and limitations.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Content of the script.
+pub const SCRIPT: &str = include_str!("restore.sh");
+
+/// Name of the script when it is stored next to the archives.
+pub const FILE_NAME: &str = "restore.sh";
+
+/// Writes the script into given directory and makes it executable. Returns path to the script.
+pub async fn write_to(dir: &Path) -> io::Result<PathBuf> {
+    let path = dir.join(FILE_NAME);
+    tokio::fs::write(&path, SCRIPT).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(0o755);
+        tokio::fs::set_permissions(&path, permissions).await?;
+    }
+    Ok(path)
+}
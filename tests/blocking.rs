@@ -0,0 +1,105 @@
+#![cfg(feature = "blocking")]
+
+mod common;
+
+use colbak_lib::cpio::blocking::{NextItem, Reader};
+use colbak_lib::cpio::{Archive, Format};
+use common::{add, read};
+use std::io::Read;
+use std::path::Path;
+
+async fn archive(format: Format) -> Archive {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("blocking");
+    std::fs::create_dir_all(&dir).unwrap();
+    let files = [
+        ("a", &b"Hello world"[..]),
+        ("b", b""),
+        ("c", &[42; 100_000]),
+    ];
+    for (name, content) in files {
+        std::fs::write(dir.join(name), content).unwrap();
+    }
+    let mut archive = Archive::with_format(format);
+    add(&mut archive, &files.map(|(name, _)| dir.join(name))).await;
+    archive
+}
+
+#[tokio::test]
+async fn same_as_async() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let mut expected = archive(format).await;
+        let mut archive = expected.clone();
+
+        let async_content = read(&mut expected).await;
+
+        let content = tokio::task::spawn_blocking(move || {
+            let mut content = Vec::new();
+            archive
+                .read_blocking()
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            content
+        })
+        .await
+        .unwrap();
+        assert_eq!(content, async_content, "{:?}", format);
+    }
+}
+
+#[tokio::test]
+async fn read_back() {
+    let mut archive = archive(Format::Newc).await;
+    let files = tokio::task::spawn_blocking(move || {
+        let mut content = Vec::new();
+        archive
+            .read_blocking()
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+
+        let mut files = Vec::new();
+        let mut reader = Reader::new(&content[..]);
+        loop {
+            match reader.advance().unwrap() {
+                NextItem::File(file) => {
+                    let name = file.info().path;
+                    let mut data = Vec::new();
+                    reader = file.drain_to(&mut data).unwrap();
+                    files.push((name, data));
+                }
+                NextItem::End(unpacked) => {
+                    assert_eq!(unpacked.manifest.unwrap().files.len(), 3);
+                    break;
+                }
+            }
+        }
+        files
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[0].1, b"Hello world");
+    assert!(files[1].1.is_empty());
+    assert_eq!(files[2].1, vec![42; 100_000]);
+}
+
+#[test]
+fn read_without_runtime() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let content = runtime.block_on(async { read(&mut archive(Format::Crc).await).await });
+    drop(runtime);
+
+    // Manifest is parsed in place, since there is no runtime to run the parser on.
+    let mut reader = Reader::new(&content[..]);
+    loop {
+        match reader.advance().unwrap() {
+            NextItem::File(file) => reader = file.to_void().unwrap(),
+            NextItem::End(unpacked) => {
+                assert_eq!(unpacked.manifest.unwrap().files.len(), 3);
+                break;
+            }
+        }
+    }
+}
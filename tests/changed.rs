@@ -0,0 +1,168 @@
+mod common;
+
+use colbak_lib::cpio::extract::Extractor;
+use colbak_lib::cpio::pending::{Change, ChangePolicy};
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::{Archive, Reader};
+use common::{add, root};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Adds files to the archive, then lets `change` modify them before anything is read.
+async fn archive(
+    policy: ChangePolicy,
+    files: &[PathBuf],
+    change: impl FnOnce(),
+) -> (Archive, std::io::Result<Vec<u8>>) {
+    let mut archive = Archive::new().with_change_policy(policy);
+    add(&mut archive, files).await;
+    change();
+    let mut buffer = Vec::new();
+    let result = archive.read().read_to_end(&mut buffer).await;
+    (archive, result.map(|_| buffer))
+}
+
+/// Extracts everything, checking there are no warnings, and returns the output directory.
+async fn extract(root: &Path, data: Vec<u8>) -> PathBuf {
+    let mut extractor = Extractor::new(root.join("dest"));
+    let mut reader = Reader::new(Cursor::new(data));
+    let warnings = loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(file) => reader = extractor.extract(file).await.unwrap(),
+            NextItem::End(end) => break extractor.finish(end),
+        }
+    };
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    root.join("dest")
+        .join(root.join("src").strip_prefix("/").unwrap())
+}
+
+#[tokio::test]
+async fn abort_on_truncated_file() {
+    let root = root("changed_abort");
+    let file = root.join("src/file");
+    std::fs::write(&file, b"Hello world").unwrap();
+
+    let (_, result) = archive(ChangePolicy::Abort, &[file.clone()], || {
+        std::fs::write(&file, b"Hello").unwrap();
+    })
+    .await;
+    let err = result.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}", err);
+}
+
+#[tokio::test]
+async fn abort_on_vanished_file() {
+    let root = root("changed_abort_vanished");
+    let file = root.join("src/file");
+    std::fs::write(&file, b"Hello world").unwrap();
+
+    let (_, result) = archive(ChangePolicy::Abort, &[file.clone()], || {
+        std::fs::remove_file(&file).unwrap();
+    })
+    .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn flag_truncated_and_grown_files() {
+    let root = root("changed_flag");
+    let (shrunk, grown) = (root.join("src/shrunk"), root.join("src/grown"));
+    std::fs::write(&shrunk, b"Hello world").unwrap();
+    std::fs::write(&grown, b"Hello").unwrap();
+
+    let (archive, result) = archive(ChangePolicy::Flag, &[shrunk.clone(), grown.clone()], || {
+        std::fs::write(&shrunk, b"Hello").unwrap();
+        std::fs::write(&grown, b"Hello world").unwrap();
+    })
+    .await;
+    let data = result.unwrap();
+
+    let manifest = archive.manifest();
+    assert_eq!(
+        manifest.files[0].changed,
+        Some(Change::Size {
+            expected: 11,
+            found: 5
+        })
+    );
+    assert_eq!(
+        manifest.files[1].changed,
+        Some(Change::Size {
+            expected: 5,
+            found: 11
+        })
+    );
+    assert_eq!(archive.changed().count(), 2);
+
+    let report = Reader::new(Cursor::new(data.clone()))
+        .verify()
+        .await
+        .unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    // Content matches the size from the header.
+    let restored = extract(&root, data).await;
+    assert_eq!(
+        std::fs::read(restored.join("shrunk")).unwrap(),
+        b"Hello\0\0\0\0\0\0"
+    );
+    assert_eq!(std::fs::read(restored.join("grown")).unwrap(), b"Hello");
+}
+
+#[tokio::test]
+async fn flag_vanished_file() {
+    let root = root("changed_vanished");
+    let (vanished, kept) = (root.join("src/vanished"), root.join("src/kept"));
+    std::fs::write(&vanished, b"Hello").unwrap();
+    std::fs::write(&kept, b"world").unwrap();
+
+    let files = [vanished.clone(), kept.clone()];
+    let (archive, result) = archive(ChangePolicy::Flag, &files, || {
+        std::fs::remove_file(&vanished).unwrap();
+    })
+    .await;
+    let data = result.unwrap();
+
+    let manifest = archive.manifest();
+    assert_eq!(manifest.files.len(), 2);
+    assert_eq!(manifest.files[0].changed, Some(Change::Vanished));
+    assert_eq!(manifest.files[0].info.hash, None);
+    assert_eq!(manifest.files[0].offsets, None);
+    assert_eq!(manifest.files[1].changed, None);
+
+    let report = Reader::new(Cursor::new(data.clone()))
+        .verify()
+        .await
+        .unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.entries, 1);
+
+    let restored = extract(&root, data).await;
+    assert!(!restored.join("vanished").exists());
+    assert_eq!(std::fs::read(restored.join("kept")).unwrap(), b"world");
+}
+
+#[tokio::test]
+async fn retry_reads_current_content() {
+    let root = root("changed_retry");
+    let file = root.join("src/file");
+    std::fs::write(&file, b"Hello").unwrap();
+
+    let (archive, result) = archive(ChangePolicy::Retry, &[file.clone()], || {
+        std::fs::write(&file, b"Hello world").unwrap();
+    })
+    .await;
+    let data = result.unwrap();
+
+    let manifest = archive.manifest();
+    assert_eq!(manifest.files[0].changed, None);
+    assert_eq!(manifest.files[0].info.size(), Some(11));
+
+    let restored = extract(&root, data).await;
+    assert_eq!(
+        std::fs::read(restored.join("file")).unwrap(),
+        b"Hello world"
+    );
+}
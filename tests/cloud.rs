@@ -0,0 +1,401 @@
+#![feature(backtrace, generic_associated_types, type_alias_impl_trait)]
+
+use colbak_lib::cloud::compression::{Codec, Compression};
+use colbak_lib::cloud::encryption::{self, Identity, PublicKey, Recipient, SecretKey};
+use colbak_lib::cloud::state::{State, UploadedArchive};
+use colbak_lib::cloud::{CloudProvider, FakeCloud, Key};
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::Format;
+use colbak_lib::fileinfo::Info;
+use colbak_lib::DateTime;
+use futures::Future;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Stores archives in memory and remembers which byte ranges were requested.
+#[derive(Default)]
+struct MemoryCloud {
+    archives: Mutex<Vec<Vec<u8>>>,
+    ranges: Arc<Mutex<Vec<std::ops::Range<u64>>>>,
+}
+
+#[derive(Debug, snafu::Snafu)]
+struct MemoryError;
+
+impl CloudProvider for MemoryCloud {
+    type Error = MemoryError;
+
+    fn upload<'a, A: AsyncRead + Unpin + 'a>(
+        &'a self,
+        mut archive: A,
+    ) -> Pin<Box<dyn 'a + Future<Output = Result<Key, Self::Error>>>> {
+        Box::pin(async move {
+            let mut data = Vec::new();
+            archive.read_to_end(&mut data).await.unwrap();
+            let mut archives = self.archives.lock().unwrap();
+            archives.push(data);
+            Ok(Key((archives.len() - 1).to_string()))
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        _key: Key,
+    ) -> Pin<Box<dyn 'a + Future<Output = Result<(), Self::Error>>>> {
+        Box::pin(async { Err(MemoryError) })
+    }
+
+    type DownloadReader<'a> = impl 'a + AsyncRead;
+    fn download<'a>(
+        &'a self,
+        key: Key,
+    ) -> Pin<Box<dyn 'a + Future<Output = Result<Self::DownloadReader<'a>, Self::Error>>>> {
+        Box::pin(async move {
+            let index: usize = key.0.parse().unwrap();
+            let data = self.archives.lock().unwrap()[index].clone();
+            Ok(Cursor::new(data))
+        })
+    }
+
+    fn download_range<'a>(
+        &'a self,
+        key: Key,
+        range: std::ops::Range<u64>,
+    ) -> Pin<Box<dyn 'a + Future<Output = Result<Pin<Box<dyn 'a + AsyncRead>>, Self::Error>>>> {
+        Box::pin(async move {
+            let index: usize = key.0.parse().unwrap();
+            let data = self.archives.lock().unwrap()[index].clone();
+            let end = data.len().min(range.end as usize);
+            let data = data[range.start as usize..end].to_vec();
+            self.ranges.lock().unwrap().push(range);
+            let reader: Pin<Box<dyn AsyncRead>> = Box::pin(Cursor::new(data));
+            Ok(reader)
+        })
+    }
+}
+
+#[tokio::test]
+async fn zstd_roundtrip() {
+    let mut archive = colbak_lib::cpio::Archive::new();
+    archive.add(Info::new("tests/archive/foobar".into()).await.unwrap());
+    let mut original = Vec::new();
+    archive.read().read_to_end(&mut original).await.unwrap();
+
+    let mut compressed = Vec::new();
+    Compression::Zstd { level: 19 }
+        .compress(Cursor::new(original.clone()))
+        .read_to_end(&mut compressed)
+        .await
+        .unwrap();
+    assert_eq!(Codec::detect(&compressed), Codec::Zstd);
+
+    let mut decompressed = Vec::new();
+    Codec::detect(&compressed)
+        .decompress(Cursor::new(compressed))
+        .read_to_end(&mut decompressed)
+        .await
+        .unwrap();
+    assert_eq!(decompressed, original);
+
+    let reader = colbak_lib::cpio::Reader::new(Cursor::new(decompressed));
+    match reader.advance().await.unwrap() {
+        NextItem::File(f) => {
+            let mut buffer = Vec::new();
+            f.drain_to(&mut buffer).await.unwrap();
+            assert_eq!(buffer, b"Hello world\n");
+        }
+        NextItem::End(_) => panic!(),
+    }
+}
+
+#[tokio::test]
+async fn download_by_offsets() {
+    for (compression, format) in [
+        (Compression::None, Format::Binary),
+        (Compression::None, Format::Pax),
+        (Compression::Zstd { level: 3 }, Format::Binary),
+    ] {
+        let cloud = MemoryCloud::default();
+        let ranges = cloud.ranges.clone();
+        let mut state = State::open(":memory:", cloud)
+            .unwrap()
+            .with_compression(compression)
+            .with_format(format);
+        let files = [
+            "tests/archive/even",
+            "tests/archive/foobar",
+            "tests/archive/odd",
+        ];
+        let mut infos = Vec::new();
+        for path in files {
+            infos.push(Info::new(path.into()).await.unwrap());
+        }
+        assert!(state.upload(infos).await.unwrap().is_empty());
+        assert_eq!(state.format(&Key("0".to_string())).unwrap(), format);
+
+        let path = Info::new("tests/archive/foobar".into()).await.unwrap().path;
+        let stored = state.locate(&path.cast()).unwrap().unwrap();
+        assert_eq!(stored.size, 12);
+        let file = state.download_file(stored, &[]).await.unwrap();
+        assert_eq!(file.info().path.as_bytes(), b"tests/archive/foobar");
+        let mut content = Vec::new();
+        file.drain_to(&mut content).await.unwrap();
+        assert_eq!(content, b"Hello world\n");
+
+        let missing = Info::new("tests/archive/link".into()).await.unwrap().path;
+        assert!(state.locate(&missing.cast()).unwrap().is_none());
+        let ranges = ranges.lock().unwrap().clone();
+        match compression {
+            Compression::None => {
+                assert_eq!(ranges.len(), 1);
+                assert!(ranges[0].start > 0);
+                if format == Format::Pax {
+                    assert_eq!(ranges[0].end % 512, 0);
+                }
+            }
+            _ => assert!(ranges.is_empty()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn download_hard_link() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("cloud_hard_link_{:?}", format));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("first"), b"Hello world\n").unwrap();
+        std::fs::hard_link(dir.join("first"), dir.join("second")).unwrap();
+
+        let mut state = State::open(":memory:", MemoryCloud::default())
+            .unwrap()
+            .with_format(format);
+        let first = Info::new(dir.join("first")).await.unwrap();
+        let second = Info::new(dir.join("second")).await.unwrap();
+        let path = second.path.clone();
+        assert!(state.upload(vec![first, second]).await.unwrap().is_empty());
+
+        let stored = state.locate(&path.cast()).unwrap().unwrap();
+        assert_eq!(stored.size, 12);
+        let file = state.download_file(stored, &[]).await.unwrap();
+        let mut content = Vec::new();
+        file.drain_to(&mut content).await.unwrap();
+        assert_eq!(content, b"Hello world\n", "{:?}", format);
+    }
+}
+
+#[test]
+fn schema_is_upgraded() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("old_state.db");
+    let _ = std::fs::remove_file(&path);
+    let db = rusqlite::Connection::open(&path).unwrap();
+    db.execute_batch(
+        "CREATE TABLE archives(
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             key TEXT,
+             uploaded_at TEXT,
+             codec TEXT NOT NULL DEFAULT 'none'
+         );
+         CREATE TABLE contents(
+             hash TEXT,
+             archive INTEGER REFERENCES archives(id)
+         );
+         INSERT INTO archives(key, uploaded_at, codec) VALUES ('old', '', 'zstd');",
+    )
+    .unwrap();
+    drop(db);
+
+    let state = State::open(&path, FakeCloud).unwrap();
+    let key = Key("old".to_string());
+    assert_eq!(state.codec(&key).unwrap(), Codec::Zstd);
+    assert_eq!(state.format(&key).unwrap(), Format::Binary);
+    assert!(!state.is_encrypted(&key).unwrap());
+    drop(state);
+
+    let db = rusqlite::Connection::open(&path).unwrap();
+    let version: i64 = db
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    assert!(version > 0);
+    db.execute_batch(&format!("PRAGMA user_version = {}", version + 1))
+        .unwrap();
+    drop(db);
+    assert!(State::open(&path, FakeCloud).is_err());
+}
+
+#[test]
+fn codec_is_stored() {
+    let mut state = State::<FakeCloud>::fake(":memory:").unwrap();
+    for (key, codec) in [("plain", Codec::None), ("compressed", Codec::Zstd)] {
+        state
+            .set_uploaded(UploadedArchive {
+                key: Key(key.to_string()),
+                format: Format::Binary,
+                codec,
+                encrypted: codec == Codec::Zstd,
+                parity: None,
+                pack_id: None,
+                files: Vec::new(),
+                uploaded_at: DateTime::now_utc(),
+            })
+            .unwrap();
+    }
+    let codec = |key: &str| state.codec(&Key(key.to_string())).unwrap();
+    assert_eq!(codec("plain"), Codec::None);
+    assert_eq!(codec("compressed"), Codec::Zstd);
+    assert!(state.codec(&Key("missing".to_string())).is_err());
+    assert!(!state.is_encrypted(&Key("plain".to_string())).unwrap());
+    assert!(state.is_encrypted(&Key("compressed".to_string())).unwrap());
+}
+
+async fn encrypt_to_vec(data: &[u8], recipients: &[Recipient]) -> Vec<u8> {
+    let mut encrypted = Vec::new();
+    encryption::encrypt(data, recipients)
+        .await
+        .unwrap()
+        .read_to_end(&mut encrypted)
+        .await
+        .unwrap();
+    encrypted
+}
+
+async fn decrypt_to_vec(data: &[u8], identities: &[Identity]) -> std::io::Result<Vec<u8>> {
+    let mut decrypted = Vec::new();
+    encryption::decrypt(data, identities)
+        .await
+        .unwrap()
+        .read_to_end(&mut decrypted)
+        .await?;
+    Ok(decrypted)
+}
+
+#[tokio::test]
+async fn encryption_roundtrip() {
+    let secret = SecretKey::generate().unwrap();
+    let public: PublicKey = secret.public().to_string().parse().unwrap();
+    let other = SecretKey::generate().unwrap();
+    let recipients = [Recipient::Key(other.public()), Recipient::Key(public)];
+
+    let chunk = 64 * 1024;
+    for len in [0, 1, chunk - 1, chunk, chunk + 1, 3 * chunk + 17] {
+        let data: Vec<u8> = (0..len).map(|x| (x % 251) as u8).collect();
+        let encrypted = encrypt_to_vec(&data, &recipients).await;
+        assert!(encryption::is_encrypted(&encrypted));
+        let identity = Identity::Key(secret.to_string().parse().unwrap());
+        let decrypted = decrypt_to_vec(&encrypted, &[identity]).await.unwrap();
+        assert_eq!(decrypted, data);
+    }
+}
+
+#[tokio::test]
+async fn encryption_passphrase() {
+    let recipient = Recipient::Passphrase {
+        passphrase: "correct horse".to_string(),
+        work_factor: 10,
+    };
+    let encrypted = encrypt_to_vec(b"Hello world\n", &[recipient]).await;
+
+    let identity = Identity::Passphrase("correct horse".to_string());
+    let decrypted = decrypt_to_vec(&encrypted, &[identity]).await.unwrap();
+    assert_eq!(decrypted, b"Hello world\n");
+
+    let wrong = Identity::Passphrase("battery staple".to_string());
+    assert!(matches!(
+        encryption::decrypt(&encrypted[..], &[wrong]).await,
+        Err(encryption::Error::NoMatchingIdentity)
+    ));
+}
+
+#[tokio::test]
+async fn encryption_detects_damage() {
+    let secret = SecretKey::generate().unwrap();
+    let identities = [Identity::Key(secret.clone())];
+    let data = vec![42; 100 * 1024];
+    let encrypted = encrypt_to_vec(&data, &[Recipient::Key(secret.public())]).await;
+
+    let mut flipped = encrypted.clone();
+    let last = flipped.len() - 1;
+    flipped[last] ^= 1;
+    assert!(decrypt_to_vec(&flipped, &identities).await.is_err());
+
+    // Cut exactly after the first chunk, so the rest looks like a valid archive.
+    let truncated = &encrypted[..encrypted.len() - (data.len() - 64 * 1024) - 16];
+    assert!(decrypt_to_vec(truncated, &identities).await.is_err());
+
+    let other = Identity::Key(SecretKey::generate().unwrap());
+    assert!(matches!(
+        encryption::decrypt(&encrypted[..], &[other]).await,
+        Err(encryption::Error::NoMatchingIdentity)
+    ));
+}
+
+#[tokio::test]
+async fn parity_is_uploaded() {
+    let mut state = State::open(":memory:", MemoryCloud::default())
+        .unwrap()
+        .with_parity(10);
+    let info = Info::new("tests/archive/foobar".into()).await.unwrap();
+    state.upload(vec![info]).await.unwrap();
+
+    let key = || Key("0".to_string());
+    let mut download = state.download_parity(&key()).await.unwrap().unwrap();
+    let parity_path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cloud_parity.parity");
+    let mut parity = tokio::fs::File::create(&parity_path).await.unwrap();
+    tokio::io::copy(&mut download, &mut parity).await.unwrap();
+    drop(download);
+    let mut parity = tokio::fs::File::open(&parity_path).await.unwrap();
+    let mut original = Vec::new();
+    let mut reader = state.download(key(), &[]).await.unwrap();
+    reader.read_to_end(&mut original).await.unwrap();
+
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cloud_parity.cpio");
+    let mut damaged = original.clone();
+    damaged[10] ^= 1;
+    std::fs::write(&path, &damaged).unwrap();
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .await
+        .unwrap();
+    let report = colbak_lib::cloud::parity::repair(&mut file, &mut parity)
+        .await
+        .unwrap();
+    assert_eq!(report.repaired, 1);
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+}
+
+#[tokio::test]
+async fn parity_is_optional() {
+    let mut state = State::open(":memory:", MemoryCloud::default()).unwrap();
+    let info = Info::new("tests/archive/foobar".into()).await.unwrap();
+    state.upload(vec![info]).await.unwrap();
+    let parity = state.download_parity(&Key("0".to_string())).await.unwrap();
+    assert!(parity.is_none());
+}
+
+#[tokio::test]
+async fn pack_id_is_stored() {
+    let mut state = State::open(":memory:", MemoryCloud::default()).unwrap();
+    let mut ids = Vec::new();
+    for key in ["0", "1"] {
+        let info = Info::new("tests/archive/foobar".into()).await.unwrap();
+        state.upload(vec![info]).await.unwrap();
+        let mut data = Vec::new();
+        let mut reader = state.download(Key(key.to_string()), &[]).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+
+        let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(data));
+        let end = loop {
+            match reader.advance().await.unwrap() {
+                NextItem::File(f) => reader = f.skip().await.unwrap(),
+                NextItem::End(end) => break end,
+            }
+        };
+        ids.push(end.manifest.unwrap().pack_id.unwrap());
+    }
+    assert_ne!(ids[0], ids[1]);
+}
@@ -0,0 +1,55 @@
+//! Helpers shared by integration tests. Each test uses only some of them.
+#![allow(dead_code)]
+
+use colbak_lib::cpio::source::Memory;
+use colbak_lib::cpio::{Archive, Format};
+use colbak_lib::fileinfo::Info;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Creates an empty directory for the test, with `src` and `dest` inside.
+///
+/// Working directory is shared by parallel tests, so it is never used.
+pub fn root(name: &str) -> PathBuf {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("src")).unwrap();
+    std::fs::create_dir(root.join("dest")).unwrap();
+    root
+}
+
+/// Adds given files to the archive.
+pub async fn add<P: AsRef<Path>>(archive: &mut Archive, paths: &[P]) {
+    for path in paths {
+        archive.add(Info::new(path.as_ref().to_path_buf()).await.unwrap());
+    }
+}
+
+/// Adds files generated from given names and contents to the archive.
+pub fn add_generated(archive: &mut Archive, files: &[(&str, &[u8])]) {
+    for (name, content) in files {
+        let info = Info::generated(PathBuf::from(name), content.len() as u64);
+        archive.add_source(info, Memory::from(content.to_vec()));
+    }
+}
+
+/// Reads the whole archive.
+pub async fn read(archive: &mut Archive) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    buffer
+}
+
+/// Archives given files and returns the whole archive.
+pub async fn create<P: AsRef<Path>>(format: Format, paths: &[P]) -> Vec<u8> {
+    let mut archive = Archive::with_format(format);
+    add(&mut archive, paths).await;
+    read(&mut archive).await
+}
+
+/// Archives files generated from given names and contents and returns the whole archive.
+pub async fn generate(format: Format, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = Archive::with_format(format);
+    add_generated(&mut archive, files);
+    read(&mut archive).await
+}
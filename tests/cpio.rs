@@ -2,7 +2,9 @@ extern crate colbak_lib;
 
 use std::slice::SliceIndex;
 
+use colbak_lib::cpio::{Format, Manifest};
 use colbak_lib::fileinfo::Info;
+use colbak_lib::path::External;
 use tokio::io::AsyncReadExt;
 
 // Useful cpio format inspector: https://ide.kaitai.io/#
@@ -27,9 +29,18 @@ const EMPTY: &[u8] = &[
     0, 0, 0, 0, // c_filesize[2]
     b'T', b'R', b'A', b'I', b'L', b'E', b'R', b'!', b'!', b'!', b'\0',
     b'\0', // Additional NUL byte, since filename length is odd
-    b'[', b']', // Empty json array
 ];
 
+/// Checks that manifest of an empty archive follows the trailer.
+fn assert_empty_manifest(content: &[u8]) {
+    let manifest = Manifest::<External>::parse(content).unwrap().unwrap();
+    assert_eq!(
+        manifest.version,
+        colbak_lib::cpio::manifest::MANIFEST_VERSION
+    );
+    assert!(manifest.files.is_empty());
+}
+
 fn normalize_header_at<P>(buffer: &mut [u8], expected: &[u8], position: P)
 where
     P: SliceIndex<[u8], Output = [u8]> + Clone,
@@ -46,10 +57,31 @@ where
 
 #[tokio::test]
 async fn empty() {
-    let mut archive = colbak_lib::cpio::Archive::new();
-    let mut buffer = Vec::new();
-    archive.read().read_to_end(&mut buffer).await.unwrap();
-    assert_eq!(buffer, EMPTY);
+    let mut newc = Vec::new();
+    newc.extend_from_slice(b"070701");
+    newc.extend_from_slice(b"00000000"); // c_ino
+    newc.extend_from_slice(b"00000000"); // c_mode
+    newc.extend_from_slice(b"00000000"); // c_uid
+    newc.extend_from_slice(b"00000000"); // c_gid
+    newc.extend_from_slice(b"00000001"); // c_nlink
+    newc.extend_from_slice(b"00000000"); // c_mtime
+    newc.extend_from_slice(b"00000000"); // c_filesize
+    newc.extend_from_slice(b"00000000"); // c_devmajor
+    newc.extend_from_slice(b"00000000"); // c_devminor
+    newc.extend_from_slice(b"00000000"); // c_rdevmajor
+    newc.extend_from_slice(b"00000000"); // c_rdevminor
+    newc.extend_from_slice(b"0000000B"); // c_namesize
+    newc.extend_from_slice(b"00000000"); // c_check
+    newc.extend_from_slice(b"TRAILER!!!\0");
+    newc.extend_from_slice(b"\0\0\0"); // 110 + 11 + 3 is a multiple of four
+
+    for (format, expected) in [(Format::Binary, EMPTY), (Format::Newc, &newc[..])] {
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..expected.len()], expected);
+        assert_empty_manifest(&buffer[expected.len()..]);
+    }
 }
 
 #[tokio::test]
@@ -107,3 +139,25 @@ async fn big_archive() {
 
 // FIXME: Add tests for directory creation
 // FIXME: Add tests for cropped filenames
+
+#[tokio::test]
+async fn crc_file() {
+    let mut archive = colbak_lib::cpio::Archive::with_format(Format::Crc);
+    archive.add(Info::new("tests/archive/odd".into()).await.unwrap());
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+
+    let content = b"odd_named_file\n";
+    assert_eq!(&buffer[..6], b"070702");
+    // c_filesize
+    assert_eq!(&buffer[54..62], format!("{:08X}", content.len()).as_bytes());
+    // c_check
+    let sum: u32 = content.iter().copied().map(u32::from).sum();
+    assert_eq!(&buffer[102..110], format!("{:08X}", sum).as_bytes());
+    // Name is "tests/archive/odd\0", 110 + 18 is a multiple of four.
+    assert_eq!(&buffer[110..128], b"tests/archive/odd\0");
+    assert_eq!(&buffer[128..143], content);
+    // Data is padded to four bytes too.
+    assert_eq!(buffer[143], 0);
+    assert_eq!(&buffer[144..150], b"070702");
+}
@@ -1,5 +1,9 @@
-use colbak_lib::cpio::reader::NextItem;
-use colbak_lib::fileinfo::Info;
+use colbak_lib::cpio::manifest::ManifestError;
+use colbak_lib::cpio::reader::{NextItem, ReadError, ReadingError};
+use colbak_lib::cpio::Format;
+use colbak_lib::fileinfo::{Info, UnspecifiedInfo};
+use colbak_lib::types::Checksum;
+use sha2::Digest;
 use std::io::Cursor;
 use tokio::io::AsyncReadExt;
 
@@ -51,7 +55,7 @@ async fn extract_normal() {
 
     match reader.advance().await.unwrap() {
         NextItem::End(end) => {
-            assert!(end.files.is_none());
+            assert!(end.manifest.is_none());
         }
         NextItem::File(_) => panic!(),
     }
@@ -74,15 +78,556 @@ async fn extract_json() {
             NextItem::End(end) => break end,
         }
     };
-    let files = end.files.unwrap();
+    let files = end.manifest.unwrap().files;
     assert_eq!(files.len(), 3);
 
-    assert_eq!(files[0].size(), Some(16));
-    assert_eq!(files[0].path.as_bytes(), b"tests/archive/even");
+    assert_eq!(files[0].info.size(), Some(16));
+    assert_eq!(files[0].info.path.as_bytes(), b"tests/archive/even");
 
-    assert_eq!(files[1].size(), Some(12));
-    assert_eq!(files[1].path.as_bytes(), b"tests/archive/foobar");
+    assert_eq!(files[1].info.size(), Some(12));
+    assert_eq!(files[1].info.path.as_bytes(), b"tests/archive/foobar");
 
-    assert_eq!(files[2].size(), Some(15));
-    assert_eq!(files[2].path.as_bytes(), b"tests/archive/odd");
+    assert_eq!(files[2].info.size(), Some(15));
+    assert_eq!(files[2].info.path.as_bytes(), b"tests/archive/odd");
+}
+
+#[tokio::test]
+async fn extract_manifest_metadata() {
+    let mut archive =
+        colbak_lib::cpio::Archive::with_format(Format::Newc).with_pack_id("pack-1".to_string());
+    archive.add(Info::new("tests/archive/foobar".into()).await.unwrap());
+    archive.add(Info::new("tests/archive/link".into()).await.unwrap());
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer.clone()));
+    let end = loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => reader = f.skip().await.unwrap(),
+            NextItem::End(end) => break end,
+        }
+    };
+    assert!(end.warning.is_none());
+    let manifest = end.manifest.unwrap();
+    assert_eq!(
+        manifest.version,
+        colbak_lib::cpio::manifest::MANIFEST_VERSION
+    );
+    assert_eq!(manifest.colbak_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(manifest.pack_id.as_deref(), Some("pack-1"));
+    assert!(manifest.created_at.is_some());
+
+    let content = &buffer[..buffer.len() - archive.trailer().len()];
+    let expected: Checksum = colbak_lib::DefaultDigest::digest(content).into();
+    assert_eq!(manifest.hash, Some(expected));
+}
+
+async fn read_trailer(content: &[u8]) -> colbak_lib::cpio::reader::UnpackedArchive {
+    let reader = colbak_lib::cpio::Reader::new(Cursor::new(Format::Newc.trailer(content)));
+    match reader.advance().await.unwrap() {
+        NextItem::End(end) => end,
+        NextItem::File(_) => panic!(),
+    }
+}
+
+#[tokio::test]
+async fn extract_broken_manifest() {
+    let end = read_trailer(br#"{"version":1,"files":[{"path":"#).await;
+    assert!(end.manifest.is_none());
+    assert!(end.warning.is_some());
+
+    let end = read_trailer(br#"{"version":2,"files":[]}"#).await;
+    assert!(end.manifest.is_none());
+    assert!(end.warning.is_some());
+
+    // Never ends, so it is parsed only while it is read.
+    let trailer = Format::Newc.trailer(b"[");
+    let reader = colbak_lib::cpio::Reader::new(Cursor::new(trailer).chain(tokio::io::repeat(b' ')));
+    let end = match reader.advance().await.unwrap() {
+        NextItem::End(end) => end,
+        NextItem::File(_) => panic!(),
+    };
+    assert!(matches!(end.warning, Some(ManifestError::TooLarge { .. })));
+
+    // Written by older versions.
+    let end = read_trailer(b"[]\0\0\0").await;
+    assert!(end.warning.is_none());
+    assert_eq!(end.manifest.unwrap().version, 0);
+}
+
+#[tokio::test]
+async fn extract_formats() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        archive.add(Info::new("tests/archive/even".into()).await.unwrap());
+        archive.add(Info::new("tests/archive/foobar".into()).await.unwrap());
+        archive.add(Info::new("tests/archive/odd".into()).await.unwrap());
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let expected: [(&[u8], &[u8]); 3] = [
+            (b"tests/archive/even", b"even_named_file\n"),
+            (b"tests/archive/foobar", b"Hello world\n"),
+            (b"tests/archive/odd", b"odd_named_file\n"),
+        ];
+        let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+        for (path, content) in expected {
+            match reader.advance().await.unwrap() {
+                NextItem::File(f) => {
+                    let info = f.info();
+                    let mut buffer = Vec::new();
+                    reader = f.drain_to(&mut buffer).await.unwrap();
+                    assert_eq!(info.path.as_bytes(), path);
+                    assert_eq!(info.size(), Some(content.len() as u64));
+                    assert_eq!(buffer, content);
+                }
+                NextItem::End(_) => panic!(),
+            }
+        }
+        match reader.advance().await.unwrap() {
+            NextItem::End(end) => assert_eq!(end.manifest.unwrap().files.len(), 3),
+            NextItem::File(_) => panic!(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn extract_crc_corrupted() {
+    let mut archive = colbak_lib::cpio::Archive::with_format(Format::Crc);
+    archive.add(Info::new("tests/archive/foobar".into()).await.unwrap());
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+
+    // Header is 110 bytes, name "tests/archive/foobar\0" is padded to 132 bytes.
+    assert_eq!(&buffer[132..137], b"Hello");
+    buffer[132] = b'J';
+
+    let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    match reader.advance().await.unwrap() {
+        NextItem::File(f) => {
+            let res = f.to_void().await;
+            assert!(matches!(res, Err(ReadError::ChecksumMismatch { .. })));
+        }
+        NextItem::End(_) => panic!(),
+    }
+}
+
+#[tokio::test]
+async fn extract_huge_name() {
+    let mut archive = colbak_lib::cpio::Archive::with_format(Format::Newc);
+    archive.add(Info::new("tests/archive/foobar".into()).await.unwrap());
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+
+    // c_namesize follows magic and eleven other fields.
+    assert_eq!(&buffer[94..102], b"00000015");
+    buffer[94..102].copy_from_slice(b"FFFFFFFF");
+
+    let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    let res = reader.advance().await;
+    assert!(matches!(res, Err(ReadingError::InvalidHeader)));
+}
+
+/// Builds archive in old binary format with single file `a` containing `hello`.
+/// Header words are encoded by `word`, so any byte order can be tested.
+fn binary_archive(word: fn(u16) -> [u8; 2]) -> Vec<u8> {
+    let file: [u16; 13] = [
+        0o070707, 0, 1, 0o100644, 1000, 100, 1, 0, 0x61F5, 0x3EE1, 2, 0, 5,
+    ];
+    let trailer: [u16; 13] = [0o070707, 0, 0, 0, 0, 0, 1, 0, 0, 0, 11, 0, 0];
+
+    let mut result = Vec::new();
+    result.extend(file.iter().flat_map(|x| word(*x)));
+    result.extend_from_slice(b"a\0hello\0");
+    result.extend(trailer.iter().flat_map(|x| word(*x)));
+    result.extend_from_slice(b"TRAILER!!!\0\0");
+    result
+}
+
+#[tokio::test]
+async fn extract_binary() {
+    for (word, magic) in [
+        (u16::to_le_bytes as fn(u16) -> [u8; 2], [0xC7, 0x71]),
+        (u16::to_be_bytes, [0x71, 0xC7]),
+    ] {
+        let archive = binary_archive(word);
+        assert_eq!(archive[..2], magic);
+        let reader = colbak_lib::cpio::Reader::new(Cursor::new(archive));
+        let reader = match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let info = f.info();
+                let mut buffer = Vec::new();
+                let reader = f.drain_to(&mut buffer).await.unwrap();
+                assert_eq!(info.path.as_bytes(), b"a");
+                assert_eq!(info.size(), Some(5));
+                assert_eq!(info.mode, 0o644);
+                assert_eq!(info.user_id, 1000);
+                assert_eq!(info.group_id, 100);
+                assert_eq!(info.modified_at.unix_timestamp(), 0x61F5_3EE1);
+                assert_eq!(buffer, b"hello");
+                reader
+            }
+            NextItem::End(_) => panic!(),
+        };
+        match reader.advance().await.unwrap() {
+            NextItem::End(end) => assert!(end.manifest.is_none()),
+            NextItem::File(_) => panic!(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn extract_symlink() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let info = Info::new("tests/archive/link".into()).await.unwrap();
+        match &info.data {
+            UnspecifiedInfo::Symlink(link) => assert_eq!(link.target, b"foobar"),
+            _ => panic!("Link was followed: {:?}", info),
+        }
+
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        archive.add(info);
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+        let reader = match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let info = f.info();
+                let mut buffer = Vec::new();
+                let reader = f.drain_to(&mut buffer).await.unwrap();
+                assert_eq!(info.path.as_bytes(), b"tests/archive/link");
+                match info.data {
+                    UnspecifiedInfo::Symlink(link) => assert_eq!(link.target, b"foobar"),
+                    _ => panic!("Not a link: {:?}", info),
+                }
+                assert_eq!(buffer, b"foobar");
+                reader
+            }
+            NextItem::End(_) => panic!(),
+        };
+        match reader.advance().await.unwrap() {
+            NextItem::End(end) => assert_eq!(end.manifest.unwrap().files.len(), 1),
+            NextItem::File(_) => panic!(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn extract_hard_link() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("hard_link_{:?}", format));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("first"), b"Hello world\n").unwrap();
+        std::fs::hard_link(dir.join("first"), dir.join("second")).unwrap();
+
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        archive.add(Info::new(dir.join("first")).await.unwrap());
+        archive.add(Info::new(dir.join("second")).await.unwrap());
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+        let (reader, first) = match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let info = f.info();
+                assert!(f.hard_link().is_none());
+                let mut buffer = Vec::new();
+                let reader = f.drain_to(&mut buffer).await.unwrap();
+                assert_eq!(buffer, b"Hello world\n");
+                (reader, info)
+            }
+            NextItem::End(_) => panic!(),
+        };
+        let reader = match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                assert_eq!(f.info().size(), Some(0));
+                assert_eq!(f.hard_link(), Some(&first.path));
+                let mut buffer = Vec::new();
+                let reader = f.drain_to(&mut buffer).await.unwrap();
+                assert!(buffer.is_empty());
+                reader
+            }
+            NextItem::End(_) => panic!(),
+        };
+        match reader.advance().await.unwrap() {
+            NextItem::End(end) => {
+                let files = end.manifest.unwrap().files;
+                assert_eq!(files.len(), 2);
+                assert_eq!(files[0].info.hash, files[1].info.hash);
+                assert_eq!(files[1].info.size(), Some(12));
+            }
+            NextItem::File(_) => panic!(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn extract_hard_link_groups() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("hard_link_groups_{:?}", format));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut infos = Vec::new();
+        for (name, content) in [("a", b"first\n"), ("b", b"other\n")] {
+            std::fs::write(dir.join(name), content).unwrap();
+            std::fs::hard_link(dir.join(name), dir.join(format!("{}_link", name))).unwrap();
+            infos.push(Info::new(dir.join(name)).await.unwrap());
+            infos.push(Info::new(dir.join(format!("{}_link", name))).await.unwrap());
+        }
+        // Inodes differ only in bits that are not stored by cpio headers.
+        let inode = infos[0].inode + (1 << 32);
+        for info in &mut infos[2..] {
+            info.device += 1;
+            info.inode = inode;
+        }
+
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        for info in infos {
+            archive.add(info);
+        }
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+        let mut found = Vec::new();
+        loop {
+            match reader.advance().await.unwrap() {
+                NextItem::File(f) => {
+                    let link = f.hard_link().map(|x| x.as_bytes().to_vec());
+                    let mut content = Vec::new();
+                    reader = f.drain_to(&mut content).await.unwrap();
+                    found.push((link, content));
+                }
+                NextItem::End(_) => break,
+            }
+        }
+        let a = dir.join("a").to_str().unwrap().as_bytes().to_vec();
+        let b = dir.join("b").to_str().unwrap().as_bytes().to_vec();
+        assert_eq!(found[0], (None, b"first\n".to_vec()), "{:?}", format);
+        assert_eq!(found[1], (Some(a), Vec::new()), "{:?}", format);
+        assert_eq!(found[2], (None, b"other\n".to_vec()), "{:?}", format);
+        assert_eq!(found[3], (Some(b), Vec::new()), "{:?}", format);
+    }
+}
+
+#[tokio::test]
+async fn extract_newc_hard_links() {
+    // Made by `cpio -o -H newc` (bsdcpio 3.8.2), which stores the content with the last link only:
+    // `first`, `second` and `third` are links, `empty` and `empty_link` are links to an empty file.
+    let file: &[u8] = include_bytes!("newc_links.cpio");
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(file));
+    let mut found = Vec::new();
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let name = f.info().path.as_bytes().to_vec();
+                let link = f.hard_link().map(|x| x.as_bytes().to_vec());
+                let mut content = Vec::new();
+                reader = f.drain_to(&mut content).await.unwrap();
+                found.push((name, link, content));
+            }
+            NextItem::End(_) => break,
+        }
+    }
+    let entry = |name: &str, link: Option<&str>, content: &[u8]| {
+        let link = link.map(|x| x.as_bytes().to_vec());
+        (name.as_bytes().to_vec(), link, content.to_vec())
+    };
+    assert_eq!(
+        found,
+        [
+            entry("plain", None, b"plain\n"),
+            entry("third", None, b"linked\n"),
+            entry("first", Some("third"), b""),
+            entry("second", Some("third"), b""),
+            entry("empty", None, b""),
+            entry("empty_link", Some("empty"), b""),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn extract_special() {
+    use colbak_lib::fileinfo::{SpecialInfo, SpecialKind};
+
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let dir =
+            std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("special_{:?}", format));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let fifo = SpecialInfo {
+            kind: SpecialKind::Fifo,
+            rdev: 0,
+        };
+        colbak_lib::fileext::create_special(&dir.join("fifo"), &fifo, 0o644).unwrap();
+
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        archive.add(Info::new(dir.join("fifo")).await.unwrap());
+        archive.add(Info::new("/dev/null".into()).await.unwrap());
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+        let mut found = Vec::new();
+        let end = loop {
+            match reader.advance().await.unwrap() {
+                NextItem::File(f) => {
+                    found.push(f.info().data);
+                    reader = f.to_void().await.unwrap();
+                }
+                NextItem::End(end) => break end,
+            }
+        };
+        assert_eq!(found[0], UnspecifiedInfo::Special(fifo));
+        // `/dev/null` is 1:3 on Linux.
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            found[1],
+            UnspecifiedInfo::Special(SpecialInfo {
+                kind: SpecialKind::CharDevice,
+                rdev: 0x103,
+            })
+        );
+        assert_eq!(end.manifest.unwrap().files.len(), 2);
+    }
+}
+
+#[tokio::test]
+async fn extract_unknown() {
+    use colbak_lib::fileinfo::UnknownInfo;
+
+    for format in [Format::Binary, Format::Newc, Format::Crc] {
+        let mut info = Info::generated("unknown".into(), 0);
+        info.data = UnspecifiedInfo::Unknown(UnknownInfo {});
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        archive.add(info);
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let info = f.info();
+                assert_eq!(
+                    info.data,
+                    UnspecifiedInfo::Unknown(UnknownInfo {}),
+                    "{:?}",
+                    format
+                );
+            }
+            NextItem::End(_) => panic!(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn extract_large_device() {
+    use colbak_lib::fileinfo::{SpecialInfo, SpecialKind};
+
+    // Major number is 300, which does not fit into old binary headers.
+    let device = SpecialInfo {
+        kind: SpecialKind::CharDevice,
+        rdev: 0x12C03,
+    };
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let mut info = Info::generated("device".into(), 0);
+        info.data = UnspecifiedInfo::Special(device.clone());
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        archive.add(info);
+        let mut buffer = Vec::new();
+        let res = archive.read().read_to_end(&mut buffer).await;
+        if format == Format::Binary {
+            assert!(res.is_err());
+            continue;
+        }
+        res.unwrap();
+
+        let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let info = f.info();
+                assert_eq!(
+                    info.data,
+                    UnspecifiedInfo::Special(device.clone()),
+                    "{:?}",
+                    format
+                );
+            }
+            NextItem::End(_) => panic!(),
+        }
+    }
+}
+
+#[cfg(all(feature = "xattrs", target_os = "linux"))]
+#[tokio::test]
+async fn extract_xattrs() {
+    use colbak_lib::fileinfo::Xattr;
+
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("xattrs");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("tagged"), b"Hello world\n").unwrap();
+    let xattr = Xattr {
+        name: b"user.colbak.test".to_vec(),
+        value: b"some value".to_vec(),
+    };
+    colbak_lib::fileext::write_xattr(&dir.join("tagged"), &xattr).unwrap();
+
+    let info = Info::new(dir.join("tagged")).await.unwrap();
+    assert_eq!(info.xattrs, vec![xattr.clone()]);
+
+    let mut archive = colbak_lib::cpio::Archive::new();
+    archive.add(info);
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    let end = loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => reader = f.to_void().await.unwrap(),
+            NextItem::End(end) => break end,
+        }
+    };
+    assert_eq!(end.manifest.unwrap().files[0].info.xattrs, vec![xattr]);
+}
+
+#[tokio::test]
+async fn extract_at_offsets() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let mut archive = colbak_lib::cpio::Archive::with_format(format);
+        archive.add(Info::new("tests/archive/even".into()).await.unwrap());
+        archive.add(Info::new("tests/archive/link".into()).await.unwrap());
+        archive.add(Info::new("tests/archive/odd".into()).await.unwrap());
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let files = archive.manifest().files;
+        assert_eq!(files[0].offsets.unwrap().header, 0);
+        for entry in files {
+            let offsets = entry.offsets.unwrap();
+            let reader = colbak_lib::cpio::Reader::at(Cursor::new(buffer.clone()), offsets)
+                .await
+                .unwrap();
+            match reader.advance().await.unwrap() {
+                NextItem::File(f) => {
+                    assert_eq!(f.info().path, entry.info.path.cast());
+                    let mut content = Vec::new();
+                    f.drain_to(&mut content).await.unwrap();
+                    // Tar keeps targets of symbolic links in the header.
+                    let in_header = format == Format::Pax
+                        && matches!(entry.info.data, UnspecifiedInfo::Symlink(_));
+                    let data = offsets.data as usize;
+                    if !in_header {
+                        assert_eq!(&buffer[data..data + content.len()], content);
+                    }
+                }
+                NextItem::End(_) => panic!(),
+            }
+        }
+    }
 }
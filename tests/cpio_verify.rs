@@ -0,0 +1,110 @@
+mod common;
+
+use colbak_lib::cpio::verify::{Problem, VerifyReport};
+use colbak_lib::cpio::{Format, Reader};
+use common::create;
+use std::io::Cursor;
+
+const FILES: [&str; 3] = [
+    "tests/archive/even",
+    "tests/archive/foobar",
+    "tests/archive/odd",
+];
+
+async fn verify(content: Vec<u8>) -> VerifyReport {
+    Reader::new(Cursor::new(content)).verify().await.unwrap()
+}
+
+#[tokio::test]
+async fn verify_intact() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let report = verify(create(format, &FILES).await).await;
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.entries, 3);
+    }
+}
+
+#[tokio::test]
+async fn verify_changed_content() {
+    let mut content = create(Format::Newc, &FILES).await;
+    let position = content
+        .windows(b"Hello world".len())
+        .position(|x| x == b"Hello world")
+        .unwrap();
+    content[position] = b'J';
+    let report = verify(content).await;
+    assert_eq!(report.problems.len(), 1, "{:?}", report);
+    match &report.problems[0] {
+        Problem::HashMismatch { path, .. } => {
+            assert_eq!(path.as_bytes(), b"tests/archive/foobar");
+        }
+        problem => panic!("{:?}", problem),
+    }
+}
+
+#[tokio::test]
+async fn verify_corrupted_crc() {
+    let mut content = create(Format::Crc, &FILES).await;
+    let position = content
+        .windows(b"Hello world".len())
+        .position(|x| x == b"Hello world")
+        .unwrap();
+    content[position] = b'J';
+    let report = verify(content).await;
+    // Entries after the corrupted one are still read.
+    assert_eq!(report.entries, 3);
+    assert_eq!(report.problems.len(), 2, "{:?}", report);
+    assert!(matches!(
+        &report.problems[0],
+        Problem::ContentCorrupted { path, .. } if path.as_bytes() == b"tests/archive/foobar"
+    ));
+    assert!(matches!(
+        &report.problems[1],
+        Problem::HashMismatch { path, .. } if path.as_bytes() == b"tests/archive/foobar"
+    ));
+}
+
+#[tokio::test]
+async fn verify_truncated() {
+    let mut content = create(Format::Newc, &FILES).await;
+    let position = content
+        .windows(b"Hello world".len())
+        .position(|x| x == b"Hello world")
+        .unwrap();
+    content.truncate(position + 5);
+    let report = verify(content).await;
+    assert_eq!(report.entries, 1);
+    assert!(matches!(
+        &report.problems[..],
+        [Problem::ContentCorrupted { path, .. }, Problem::NoManifest]
+            if path.as_bytes() == b"tests/archive/foobar"
+    ));
+}
+
+#[tokio::test]
+async fn verify_renamed_file() {
+    let mut content = create(Format::Newc, &FILES).await;
+    let position = content
+        .windows(b"tests/archive/odd\0".len())
+        .position(|x| x == b"tests/archive/odd\0")
+        .unwrap();
+    content[position + b"tests/archive/".len()] = b'a';
+    let report = verify(content).await;
+    assert_eq!(report.problems.len(), 2, "{:?}", report);
+    assert!(matches!(
+        &report.problems[0],
+        Problem::Missing { path } if path.as_bytes() == b"tests/archive/odd"
+    ));
+    assert!(matches!(
+        &report.problems[1],
+        Problem::Unexpected { path } if path.as_bytes() == b"tests/archive/add"
+    ));
+}
+
+#[tokio::test]
+async fn verify_without_manifest() {
+    let content: &[u8] = include_bytes!("big_archive.cpio");
+    let report = verify(content.to_vec()).await;
+    assert_eq!(report.entries, 3);
+    assert!(matches!(report.problems[..], [Problem::NoManifest]));
+}
@@ -0,0 +1,78 @@
+use colbak_lib::cloud::encryption::{self, Error, Identity};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Single vector of the age testkit: `key: value` lines, an empty line and the encrypted file.
+/// Armored vectors are not included, since archives are never armored.
+struct Vector {
+    expect: String,
+    /// SHA-256 of the plaintext, or of its part that is decrypted before the failure.
+    payload: Option<String>,
+    identities: Vec<Identity>,
+    encrypted: Vec<u8>,
+}
+
+fn parse(content: &[u8]) -> Vector {
+    let split = content.windows(2).position(|x| x == b"\n\n").unwrap();
+    let mut vector = Vector {
+        expect: String::new(),
+        payload: None,
+        identities: Vec::new(),
+        encrypted: content[split + 2..].to_vec(),
+    };
+    for line in std::str::from_utf8(&content[..split]).unwrap().lines() {
+        let (key, value) = line.split_once(": ").unwrap();
+        match key {
+            "expect" => vector.expect = value.to_string(),
+            "payload" => vector.payload = Some(value.to_string()),
+            "identity" => vector
+                .identities
+                .push(Identity::Key(value.parse().unwrap())),
+            "passphrase" => vector
+                .identities
+                .push(Identity::Passphrase(value.to_string())),
+            _ => {}
+        }
+    }
+    vector
+}
+
+/// Decrypts the vector and names the result the same way as the testkit does.
+async fn outcome(vector: &Vector) -> (&'static str, Vec<u8>) {
+    let mut payload = Vec::new();
+    let mut stream = match encryption::decrypt(&vector.encrypted[..], &vector.identities).await {
+        Ok(stream) => stream,
+        Err(Error::NoMatchingIdentity) => return ("no match", payload),
+        Err(Error::HeaderMacMismatch) => return ("HMAC failure", payload),
+        Err(_) => return ("header failure", payload),
+    };
+    match stream.read_to_end(&mut payload).await {
+        Ok(_) => ("success", payload),
+        Err(_) => ("payload failure", payload),
+    }
+}
+
+#[tokio::test]
+async fn age_testkit() {
+    let mut names: Vec<_> = std::fs::read_dir("tests/age-testkit")
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.file_name().unwrap() != "README")
+        .collect();
+    names.sort();
+    let mut failed = Vec::new();
+    for path in &names {
+        let vector = parse(&std::fs::read(path).unwrap());
+        let (result, payload) = outcome(&vector).await;
+        let hash = format!("{:x}", Sha256::digest(&payload));
+        let name = Path::new(path).file_name().unwrap().to_string_lossy();
+        if result != vector.expect || vector.payload.iter().any(|x| *x != hash) {
+            failed.push(format!(
+                "{}: expected {}, got {}",
+                name, vector.expect, result
+            ));
+        }
+    }
+    assert!(failed.is_empty(), "{:#?}", failed);
+}
@@ -0,0 +1,318 @@
+#![cfg(unix)]
+
+mod common;
+
+use colbak_lib::cpio::extract::{Extractor, Overwrite, Warning};
+use colbak_lib::cpio::filter::{Filter, Glob};
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::{Archive, Format, Reader};
+use colbak_lib::fileext::set_modified;
+use colbak_lib::fileinfo::{FileInfo, Info, UnspecifiedInfo};
+use colbak_lib::DateTime;
+use common::{create, root};
+use std::io::Cursor;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Where absolute `path` is extracted to. Sources are added by absolute paths, which are
+/// extracted into the output without the leading slash.
+fn restored(dest: &Path, path: &Path) -> PathBuf {
+    dest.join(path.strip_prefix("/").unwrap())
+}
+
+async fn extract(content: Vec<u8>, output: PathBuf) -> Vec<Warning> {
+    extract_with(content, Extractor::new(output)).await
+}
+
+async fn extract_with(content: Vec<u8>, mut extractor: Extractor) -> Vec<Warning> {
+    let mut reader = Reader::new(Cursor::new(content));
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(file) => reader = extractor.extract(file).await.unwrap(),
+            NextItem::End(end) => return extractor.finish(end),
+        }
+    }
+}
+
+/// Extracts only entries selected by the filter, like `unpack-cpio` does.
+async fn extract_matching(content: Vec<u8>, output: PathBuf, filter: &Filter) -> Vec<Warning> {
+    let mut extractor = Extractor::new(output);
+    let mut reader = Reader::new(Cursor::new(content));
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(file) if !filter.matches(&file.info().path) => {
+                extractor.skip(&file);
+                reader = file.skip().await.unwrap();
+            }
+            NextItem::File(file) => reader = extractor.extract(file).await.unwrap(),
+            NextItem::End(end) => return extractor.finish(end),
+        }
+    }
+}
+
+fn time(seconds: i64, nanoseconds: u32) -> DateTime {
+    DateTime::from_unix_timestamp(seconds).unwrap()
+        + time::Duration::nanoseconds(nanoseconds.into())
+}
+
+#[tokio::test]
+async fn restore_metadata() {
+    for format in [Format::Binary, Format::Newc, Format::Crc, Format::Pax] {
+        let name = format!("extract_metadata_{:?}", format);
+        let root = root(&name);
+        let src = root.join("src");
+        std::fs::create_dir(src.join("dir")).unwrap();
+        std::fs::write(src.join("dir/file"), b"Hello world\n").unwrap();
+        std::os::unix::fs::symlink("dir/file", src.join("link")).unwrap();
+        std::fs::set_permissions(src.join("dir/file"), PermissionsExt::from_mode(0o640)).unwrap();
+        std::fs::set_permissions(src.join("dir"), PermissionsExt::from_mode(0o750)).unwrap();
+        set_modified(&src.join("dir/file"), time(1_000_000_000, 123_456_789)).unwrap();
+        set_modified(&src.join("link"), time(1_100_000_000, 5)).unwrap();
+        // Directory is changed last, otherwise creating files would change it.
+        set_modified(&src.join("dir"), time(1_200_000_000, 987_654_321)).unwrap();
+
+        let paths = ["src/dir", "src/dir/file", "src/link"].map(|x| root.join(x));
+        let content = create(format, &paths).await;
+        let warnings = extract(content, root.join("dest")).await;
+        assert!(warnings.is_empty(), "{:?}", warnings);
+
+        for path in &paths {
+            let original = std::fs::symlink_metadata(path).unwrap();
+            let extracted = std::fs::symlink_metadata(restored(&root.join("dest"), path)).unwrap();
+            assert_eq!(extracted.mode(), original.mode(), "{:?}", path);
+            assert_eq!(extracted.mtime(), original.mtime(), "{:?}", path);
+            assert_eq!(extracted.mtime_nsec(), original.mtime_nsec(), "{:?}", path);
+        }
+    }
+}
+
+#[tokio::test]
+async fn manifest_does_not_change_kind() {
+    let root = root("extract_malicious_manifest");
+    let target = root.join("target");
+    std::fs::write(&target, b"Secret").unwrap();
+    std::fs::set_permissions(&target, PermissionsExt::from_mode(0o600)).unwrap();
+    let link = root.join("src/link");
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+
+    let mut archive = Archive::with_format(Format::Newc);
+    archive.add(Info::new(link).await.unwrap());
+    let mut content = Vec::new();
+    archive.read().read_to_end(&mut content).await.unwrap();
+    // Manifest claims that the link is a regular file anyone can write to.
+    let mut manifest = archive.manifest();
+    manifest.files[0].info.data = UnspecifiedInfo::File(FileInfo { size: 0 });
+    manifest.files[0].info.mode = 0o100_777;
+    content.truncate(content.len() - archive.trailer().len());
+    content.extend(Format::Newc.trailer(&serde_json::to_vec(&manifest).unwrap()));
+
+    extract(content, root.join("dest")).await;
+    let metadata = std::fs::metadata(&target).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o600);
+}
+
+#[tokio::test]
+async fn restore_metadata_without_manifest() {
+    let root = root("extract_metadata_headers");
+    let path = root.join("src/file");
+    std::fs::write(&path, b"Hello world\n").unwrap();
+    std::fs::set_permissions(&path, PermissionsExt::from_mode(0o600)).unwrap();
+    set_modified(&path, time(1_000_000_000, 500)).unwrap();
+
+    let mut content = create(Format::Newc, &[path.clone()]).await;
+    // Manifest is replaced with spaces, so only headers are left.
+    let trailer = content
+        .windows(b"TRAILER!!!".len())
+        .position(|x| x == b"TRAILER!!!")
+        .unwrap();
+    for byte in &mut content[trailer + b"TRAILER!!!\0".len()..] {
+        *byte = b' ';
+    }
+    let warnings = extract(content, root.join("dest")).await;
+    assert!(warnings.is_empty(), "{:?}", warnings);
+
+    let extracted = std::fs::metadata(restored(&root.join("dest"), &path)).unwrap();
+    assert_eq!(extracted.mode() & 0o7777, 0o600);
+    // Headers store only seconds.
+    assert_eq!(extracted.mtime(), 1_000_000_000);
+    assert_eq!(extracted.mtime_nsec(), 0);
+}
+
+#[tokio::test]
+async fn absolute_paths_stay_inside() {
+    let root = root("extract_absolute");
+    std::fs::write(root.join("src/file"), b"Hello world\n").unwrap();
+    let absolute = root.join("src/file");
+    let content = create(Format::Newc, &[absolute.clone()]).await;
+    let warnings = extract(content, root.join("dest")).await;
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    let extracted = root.join("dest").join(absolute.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(extracted).unwrap(), b"Hello world\n");
+}
+
+#[tokio::test]
+async fn parent_components_are_rejected() {
+    let root = root("extract_parent");
+    std::fs::write(root.join("src/file"), b"Hello world\n").unwrap();
+    let escaping = format!("{}/dest/../src/file", root.display());
+    let content = create(Format::Newc, &[&escaping]).await;
+    let warnings = extract(content, root.join("dest")).await;
+    assert!(
+        matches!(&warnings[..], [Warning::UnsafePath { path }] if path.as_bytes() == escaping.as_bytes()),
+        "{:?}",
+        warnings
+    );
+    assert_eq!(std::fs::read_dir(root.join("dest")).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn symlinks_are_not_followed() {
+    let root = root("extract_symlink_escape");
+    std::fs::create_dir(root.join("outside")).unwrap();
+    std::fs::write(root.join("outside/file"), b"Hello world\n").unwrap();
+    std::os::unix::fs::symlink(root.join("outside"), root.join("src/link")).unwrap();
+
+    let link = root.join("src/link");
+    let through = root.join("src/link/file");
+    let content = create(Format::Newc, &[link, through.clone()]).await;
+    // Destination is the same directory, so file would be written over itself.
+    std::fs::remove_file(root.join("outside/file")).unwrap();
+    let warnings = extract(content, root.join("dest")).await;
+    assert!(
+        matches!(&warnings[..], [Warning::UnsafePath { path }] if path.as_bytes() == through.as_os_str().as_bytes()),
+        "{:?}",
+        warnings
+    );
+    assert!(!root.join("outside/file").exists());
+}
+
+#[tokio::test]
+async fn permissions_are_not_set_through_symlinks() {
+    for policy in [
+        Overwrite::Skip,
+        Overwrite::Overwrite,
+        Overwrite::KeepNewer,
+        Overwrite::Rename,
+    ] {
+        let root = root(&format!("extract_chmod_escape_{:?}", policy));
+        let outside = root.join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::set_permissions(&outside, PermissionsExt::from_mode(0o700)).unwrap();
+
+        // The same path is stored as a link to the outside and then as a directory.
+        let path = root.join("src/entry");
+        std::os::unix::fs::symlink(&outside, &path).unwrap();
+        let link = Info::new(path.clone()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o777)).unwrap();
+        let dir = Info::new(path.clone()).await.unwrap();
+        let mut archive = Archive::with_format(Format::Newc);
+        archive.add(link);
+        archive.add(dir);
+        let mut content = Vec::new();
+        archive.read().read_to_end(&mut content).await.unwrap();
+
+        let extractor = Extractor::new(root.join("dest")).with_overwrite(policy);
+        extract_with(content, extractor).await;
+        let mode = std::fs::metadata(&outside).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700, "{:?}", policy);
+    }
+}
+
+async fn overwrite(policy: Overwrite) -> (PathBuf, Vec<Warning>) {
+    let name = format!("extract_overwrite_{:?}", policy);
+    let root = root(&name);
+    let file = root.join("src/file");
+    std::fs::write(&file, b"Stored\n").unwrap();
+    set_modified(&file, time(1_500_000_000, 0)).unwrap();
+    let content = create(Format::Newc, &[file.clone()]).await;
+
+    let existing = restored(&root.join("dest"), &file);
+    std::fs::create_dir_all(existing.parent().unwrap()).unwrap();
+    std::fs::write(&existing, b"Existing\n").unwrap();
+    set_modified(&existing, time(1_600_000_000, 0)).unwrap();
+    let extractor = Extractor::new(root.join("dest")).with_overwrite(policy);
+    (existing, extract_with(content, extractor).await)
+}
+
+#[tokio::test]
+async fn overwrite_skip() {
+    let (existing, warnings) = overwrite(Overwrite::Skip).await;
+    assert!(matches!(&warnings[..], [Warning::Exists { path }] if *path == existing));
+    assert_eq!(std::fs::read(existing).unwrap(), b"Existing\n");
+}
+
+#[tokio::test]
+async fn overwrite_replace() {
+    let (existing, warnings) = overwrite(Overwrite::Overwrite).await;
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    assert_eq!(std::fs::read(existing).unwrap(), b"Stored\n");
+}
+
+#[tokio::test]
+async fn overwrite_keep_newer() {
+    let (existing, warnings) = overwrite(Overwrite::KeepNewer).await;
+    assert!(matches!(&warnings[..], [Warning::Exists { path }] if *path == existing));
+    assert_eq!(std::fs::read(existing).unwrap(), b"Existing\n");
+}
+
+#[tokio::test]
+async fn overwrite_rename() {
+    let (existing, warnings) = overwrite(Overwrite::Rename).await;
+    let renamed = existing.with_file_name("file.1");
+    assert!(
+        matches!(&warnings[..], [Warning::Renamed { path, renamed: to }] if *path == existing && *to == renamed),
+        "{:?}",
+        warnings
+    );
+    assert_eq!(std::fs::read(existing).unwrap(), b"Existing\n");
+    assert_eq!(std::fs::read(renamed).unwrap(), b"Stored\n");
+}
+
+#[test]
+fn overwrite_names() {
+    assert_eq!(
+        "keep-newer".parse::<Overwrite>().unwrap(),
+        Overwrite::KeepNewer
+    );
+    assert!("newer".parse::<Overwrite>().is_err());
+}
+
+#[tokio::test]
+async fn filtered_entries_keep_metadata() {
+    for format in [Format::Newc, Format::Pax] {
+        let name = format!("extract_filtered_{:?}", format);
+        let root = root(&name);
+        let src = root.join("src");
+        std::fs::write(
+            src.join("skipped"),
+            b"Not extracted
+",
+        )
+        .unwrap();
+        std::fs::write(
+            src.join("file"),
+            b"Hello world
+",
+        )
+        .unwrap();
+        std::fs::set_permissions(src.join("file"), PermissionsExt::from_mode(0o640)).unwrap();
+        set_modified(&src.join("file"), time(1_000_000_000, 123_456_789)).unwrap();
+
+        let paths = ["src/skipped", "src/file"].map(|x| root.join(x));
+        let content = create(format, &paths).await;
+        let filter = Filter::new().exclude(Glob::new("**/skipped").unwrap());
+        let warnings = extract_matching(content, root.join("dest"), &filter).await;
+        assert!(warnings.is_empty(), "{:?}: {:?}", format, warnings);
+
+        let dest = root.join("dest");
+        assert!(!restored(&dest, &src.join("skipped")).exists());
+        let metadata = std::fs::metadata(restored(&dest, &src.join("file"))).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o640, "{:?}", format);
+        assert_eq!(metadata.mtime(), 1_000_000_000, "{:?}", format);
+        assert_eq!(metadata.mtime_nsec(), 123_456_789, "{:?}", format);
+    }
+}
@@ -0,0 +1,126 @@
+use colbak_lib::cpio::filter::{Filter, Glob};
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::{Archive, Format, Reader};
+use colbak_lib::fileinfo::Info;
+use colbak_lib::path::EncodedPath;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+const FILES: &[&str] = &["dir/a.txt", "dir/b.log", "dir/sub/c.txt", "other/d.txt"];
+
+/// Creates the same tree for every test and returns archive of it.
+async fn create(name: &str) -> (PathBuf, Vec<u8>) {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("dir/sub")).unwrap();
+    std::fs::create_dir(root.join("other")).unwrap();
+    for file in FILES {
+        std::fs::write(root.join(file), file.as_bytes()).unwrap();
+    }
+
+    let mut archive = Archive::with_format(Format::Newc);
+    for path in ["dir", "dir/sub", "other"].iter().chain(FILES) {
+        archive.add(Info::new(root.join(path)).await.unwrap());
+    }
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    (root, buffer)
+}
+
+/// Returns relative paths of selected entries, checking that their content is intact.
+async fn selected(name: &str, filter: &Filter, seek: bool) -> Vec<String> {
+    let (root, content) = create(name).await;
+    let prefix = format!("{}/", root.display());
+    let mut reader = Reader::new(Cursor::new(content));
+    let mut result = Vec::new();
+    loop {
+        let item = if seek {
+            reader.seek_matching(filter).await.unwrap()
+        } else {
+            reader.advance_matching(filter).await.unwrap()
+        };
+        let file = match item {
+            NextItem::File(file) => file,
+            NextItem::End(_) => return result,
+        };
+        let path = String::from_utf8(file.info().path.as_bytes().to_vec()).unwrap();
+        let path = path.strip_prefix(&prefix).unwrap().to_owned();
+        let mut data = Vec::new();
+        reader = file.drain_to(&mut data).await.unwrap();
+        if FILES.contains(&path.as_str()) {
+            assert_eq!(data, path.as_bytes());
+        }
+        result.push(path);
+    }
+}
+
+fn glob(pattern: &str) -> Glob {
+    Glob::new(pattern).unwrap()
+}
+
+#[tokio::test]
+async fn empty_filter_selects_everything() {
+    let filter = Filter::new();
+    assert!(filter.is_empty());
+    let paths = selected("filter_everything", &filter, false).await;
+    assert_eq!(paths.len(), 7);
+}
+
+#[tokio::test]
+async fn include_and_exclude() {
+    let filter = Filter::new()
+        .include(glob("**/*.txt"))
+        .exclude(glob("**/sub"));
+    for seek in [false, true] {
+        let paths = selected("filter_include_exclude", &filter, seek).await;
+        assert_eq!(paths, ["dir/a.txt", "other/d.txt"]);
+    }
+}
+
+#[tokio::test]
+async fn directory_selects_content() {
+    let filter = Filter::new().include(glob("**/dir"));
+    for seek in [false, true] {
+        let paths = selected("filter_directory", &filter, seek).await;
+        assert_eq!(
+            paths,
+            ["dir", "dir/sub", "dir/a.txt", "dir/b.log", "dir/sub/c.txt"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn listed_files() {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join("filter_files");
+    let listed = |path: &str| {
+        let path = format!("{}/{}", root.display(), path);
+        EncodedPath::from_vec(path.into_bytes())
+    };
+    let filter = Filter::new()
+        .file(listed("dir/sub"))
+        .file(listed("other/d.txt"))
+        // Pattern characters are not interpreted in listed paths.
+        .file(listed("dir/*.log"));
+    for seek in [false, true] {
+        let paths = selected("filter_files", &filter, seek).await;
+        assert_eq!(paths, ["dir/sub", "dir/sub/c.txt", "other/d.txt"]);
+    }
+}
+
+#[test]
+fn glob_syntax() {
+    assert!(glob("*.txt").matches(b"a.txt"));
+    assert!(!glob("*.txt").matches(b"dir/a.txt"));
+    assert!(glob("**.txt").matches(b"dir/a.txt"));
+    assert!(glob("**/a.txt").matches(b"a.txt"));
+    assert!(glob("a/**/b").matches(b"a/b"));
+    assert!(glob("a/**/b").matches(b"a/x/y/b"));
+    assert!(!glob("a?b").matches(b"a/b"));
+    assert!(glob("[]]").matches(b"]"));
+    assert!(glob("[a-c]x").matches(b"bx"));
+    assert!(glob("\\*").matches(b"*"));
+    assert!(!glob("\\*").matches(b"x"));
+    assert!(Glob::new("abc\\").is_err());
+    assert!("[a-".parse::<Glob>().is_err());
+}
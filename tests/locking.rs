@@ -0,0 +1,103 @@
+use colbak_lib::cpio::pending::{Change, ChangePolicy, LockStrategy};
+use colbak_lib::cpio::Archive;
+use colbak_lib::fileinfo::Info;
+use fs2::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+
+fn create(name: &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, b"Hello world").unwrap();
+    path
+}
+
+async fn read(mut archive: Archive, path: &Path) -> (Archive, std::io::Result<Vec<u8>>) {
+    archive.add(Info::new(path.to_owned()).await.unwrap());
+    let mut buffer = Vec::new();
+    let result = archive.read().read_to_end(&mut buffer).await;
+    (archive, result.map(|_| buffer))
+}
+
+#[tokio::test]
+async fn exclusive_lock_times_out() {
+    let path = create("lock_exclusive");
+    let held = std::fs::File::open(&path).unwrap();
+    held.lock_shared().unwrap();
+
+    let lock = LockStrategy::Exclusive { timeout: None }.with_timeout(Duration::from_millis(100));
+    let (_, result) = read(Archive::new().with_lock(lock), &path).await;
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("locked"), "{}", err);
+
+    held.unlock().unwrap();
+    let (_, result) = read(Archive::new().with_lock(lock), &path).await;
+    result.unwrap();
+}
+
+/// Waiting for the lock must not block the runtime, otherwise the lock is never released here.
+#[tokio::test]
+async fn lock_without_timeout_waits() {
+    let path = create("lock_forever");
+    let held = std::fs::File::open(&path).unwrap();
+    held.lock_exclusive().unwrap();
+    let release = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        held.unlock().unwrap();
+    });
+
+    let lock = LockStrategy::Exclusive { timeout: None };
+    let archive = Archive::new().with_lock(lock).with_read_ahead(0);
+    let read = tokio::time::timeout(Duration::from_secs(10), read(archive, &path));
+    let (_, result) = read.await.unwrap();
+    result.unwrap();
+    release.await.unwrap();
+}
+
+#[tokio::test]
+async fn shared_and_no_lock() {
+    let path = create("lock_shared");
+    let held = std::fs::File::open(&path).unwrap();
+    held.lock_shared().unwrap();
+    let shared = LockStrategy::Shared { timeout: None }.with_timeout(Duration::from_millis(100));
+    let (_, result) = read(Archive::new().with_lock(shared), &path).await;
+    result.unwrap();
+
+    held.unlock().unwrap();
+    held.lock_exclusive().unwrap();
+    let (_, result) = read(Archive::new().with_lock(shared), &path).await;
+    assert!(result.is_err());
+    let (_, result) = read(Archive::new().with_lock(LockStrategy::None), &path).await;
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn recheck_identifier() {
+    let path = create("lock_recheck");
+    let info = Info::new(path.clone()).await.unwrap();
+    // Same size, but newer modification time.
+    std::thread::sleep(Duration::from_millis(10));
+    std::fs::write(&path, b"Jello world").unwrap();
+
+    for policy in [ChangePolicy::Abort, ChangePolicy::Flag] {
+        let mut archive = Archive::new().with_change_policy(policy).with_recheck(true);
+        archive.add(info.clone());
+        let mut buffer = Vec::new();
+        let result = archive.read().read_to_end(&mut buffer).await;
+        match policy {
+            ChangePolicy::Abort => assert!(result.is_err()),
+            _ => {
+                result.unwrap();
+                let manifest = archive.manifest();
+                assert_eq!(manifest.files[0].changed, Some(Change::Identifier));
+            }
+        }
+    }
+
+    // Without recheck nothing is noticed, since the size is the same.
+    let mut archive = Archive::new();
+    archive.add(info);
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    assert_eq!(archive.manifest().files[0].changed, None);
+}
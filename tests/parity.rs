@@ -0,0 +1,125 @@
+use colbak_lib::cloud::parity::{repair, Error, ParityEncoder, RepairReport, BLOCK_SIZE};
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncReadExt;
+
+/// Deterministic data, long enough for two groups of blocks.
+fn sample() -> Vec<u8> {
+    let mut state = 0x1234_5678_u32;
+    (0..BLOCK_SIZE * 130 + 1234)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state.to_le_bytes()[0]
+        })
+        .collect()
+}
+
+async fn encode(name: &str, data: &[u8], percent: u32) -> File {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let file = File::create(&path).await.unwrap();
+    let mut encoder = ParityEncoder::new(percent, file).unwrap();
+    let mut read = Vec::new();
+    encoder.wrap(data).read_to_end(&mut read).await.unwrap();
+    assert_eq!(read, data);
+    encoder.finish().await.unwrap();
+    File::open(&path).await.unwrap()
+}
+
+async fn open(name: &str, content: &[u8]) -> (PathBuf, File) {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, content).unwrap();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .await
+        .unwrap();
+    (path, file)
+}
+
+#[tokio::test]
+async fn repair_flipped_bits() {
+    let original = sample();
+    let mut parity = encode("parity_flipped.parity", &original, 5).await;
+
+    let mut damaged = original.clone();
+    // Two blocks in the first group and the last incomplete block.
+    damaged[7] ^= 0x80;
+    damaged[BLOCK_SIZE * 50 + 3] ^= 1;
+    let last = damaged.len() - 1;
+    damaged[last] ^= 4;
+    let (path, mut file) = open("parity_flipped.bin", &damaged).await;
+
+    let report = repair(&mut file, &mut parity).await.unwrap();
+    assert_eq!(
+        report,
+        RepairReport {
+            repaired: 3,
+            damaged_parity: 0
+        }
+    );
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+}
+
+#[tokio::test]
+async fn repair_truncated() {
+    let original = sample();
+    let mut parity = encode("parity_truncated.parity", &original, 10).await;
+    let (path, mut file) = open("parity_truncated.bin", &original[..original.len() - 100]).await;
+    let report = repair(&mut file, &mut parity).await.unwrap();
+    assert_eq!(report.repaired, 1);
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+}
+
+#[tokio::test]
+async fn repair_appended_garbage() {
+    let original = sample();
+    let mut parity = encode("parity_garbage.parity", &original, 1).await;
+    let mut damaged = original.clone();
+    damaged.extend_from_slice(b"garbage");
+    let (path, mut file) = open("parity_garbage.bin", &damaged).await;
+    let report = repair(&mut file, &mut parity).await.unwrap();
+    assert_eq!(report.repaired, 0);
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+}
+
+#[tokio::test]
+async fn too_much_damage() {
+    let original = sample();
+    let mut parity = encode("parity_unrecoverable.parity", &original, 1).await;
+    let mut damaged = original.clone();
+    damaged[0] ^= 1;
+    damaged[BLOCK_SIZE] ^= 1;
+    let (_, mut file) = open("parity_unrecoverable.bin", &damaged).await;
+    let result = repair(&mut file, &mut parity).await;
+    assert!(matches!(
+        result,
+        Err(Error::Unrecoverable {
+            group: 0,
+            damaged: 2,
+            parity: 1
+        })
+    ));
+}
+
+#[tokio::test]
+async fn truncated_parity() {
+    let original = sample();
+    let mut parity = encode("parity_short.parity", &original, 5).await;
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("parity_short.parity");
+    let truncated = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+    truncated.set_len(1000).unwrap();
+    let (_, mut file) = open("parity_short.bin", &original).await;
+    let result = repair(&mut file, &mut parity).await;
+    assert!(matches!(result, Err(Error::InvalidParity)));
+}
+
+#[tokio::test]
+async fn invalid_redundancy() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("parity_invalid.parity");
+    let file = || async { File::create(&path).await.unwrap() };
+    assert!(ParityEncoder::new(0, file().await).is_err());
+    assert!(ParityEncoder::new(101, file().await).is_err());
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use colbak_lib::cpio::extract::Extractor;
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::{Archive, Format, Reader};
+use colbak_lib::progress::{Observer, Progress, Stage};
+use common::{add_generated, read};
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct Recorder(Mutex<Vec<(Stage, Progress, Vec<u8>)>>);
+
+impl Observer for Recorder {
+    fn update(&self, stage: Stage, progress: Progress, path: &[u8]) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((stage, progress, path.to_vec()));
+    }
+}
+
+impl Recorder {
+    fn of(&self, stage: Stage) -> Vec<(Progress, Vec<u8>)> {
+        let updates = self.0.lock().unwrap();
+        updates
+            .iter()
+            .filter(|(x, _, _)| *x == stage)
+            .map(|(_, progress, path)| (*progress, path.clone()))
+            .collect()
+    }
+}
+
+async fn create(format: Format, recorder: Arc<Recorder>) -> Vec<u8> {
+    let mut archive = Archive::with_format(format).with_progress(recorder);
+    add_generated(
+        &mut archive,
+        &[("first", b"first file"), ("second", b"second file")],
+    );
+    read(&mut archive).await
+}
+
+#[tokio::test]
+async fn archiving() {
+    let recorder = Arc::new(Recorder::default());
+    let buffer = create(Format::Newc, recorder.clone()).await;
+
+    let archiving = recorder.of(Stage::Archiving);
+    let paths: Vec<_> = archiving.iter().map(|(_, path)| &path[..]).collect();
+    assert_eq!(paths, [&b"first"[..], b"second", b""]);
+    assert_eq!(archiving[1].0.files, 1);
+    let done = Progress {
+        files: 2,
+        bytes: buffer.len() as u64,
+    };
+    assert_eq!(archiving[2].0, done);
+
+    let hashing = recorder.of(Stage::Hashing);
+    let (last, path) = hashing.last().unwrap();
+    assert_eq!(path, b"second");
+    assert_eq!(
+        *last,
+        Progress {
+            files: 1,
+            bytes: 11
+        }
+    );
+}
+
+#[tokio::test]
+async fn extracting() {
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join("progress_extract");
+    let _ = std::fs::remove_dir_all(&output);
+    std::fs::create_dir_all(&output).unwrap();
+    let buffer = create(Format::Binary, Arc::new(Recorder::default())).await;
+
+    let recorder = Arc::new(Recorder::default());
+    let mut extractor = Extractor::new(output).with_progress(recorder.clone());
+    let mut reader = Reader::new(Cursor::new(buffer));
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(file) => reader = extractor.extract(file).await.unwrap(),
+            NextItem::End(end) => {
+                let _warnings = extractor.finish(end);
+                break;
+            }
+        }
+    }
+
+    let extracting = recorder.of(Stage::Extracting);
+    assert_eq!(extracting.len(), 2);
+    assert_eq!(
+        extracting[1].0,
+        Progress {
+            files: 2,
+            bytes: 21
+        }
+    );
+    assert_eq!(extracting[1].1, b"second");
+}
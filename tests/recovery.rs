@@ -0,0 +1,134 @@
+mod common;
+
+use colbak_lib::cpio::reader::{NextItem, UnpackedArchive};
+use colbak_lib::cpio::{Format, Manifest, Reader};
+use colbak_lib::path::External;
+use common::generate;
+use std::io::Cursor;
+
+const FILES: [(&str, &[u8]); 3] = [
+    ("first", b"first file"),
+    ("second", b"second file"),
+    ("third", b"third file"),
+];
+
+async fn manifest(buffer: &[u8]) -> Manifest<External> {
+    let mut reader = Reader::new(Cursor::new(buffer));
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => reader = f.skip().await.unwrap(),
+            NextItem::End(end) => return end.manifest.unwrap(),
+        }
+    }
+}
+
+/// Reads everything that can be recovered, returning paths with contents.
+async fn recover(buffer: Vec<u8>) -> (Vec<(Vec<u8>, Vec<u8>)>, UnpackedArchive) {
+    let mut reader = Reader::new(Cursor::new(buffer));
+    let mut files = Vec::new();
+    loop {
+        match reader.recover().await.unwrap() {
+            NextItem::File(f) => {
+                let path = f.info().path.as_bytes().to_vec();
+                let mut content = Vec::new();
+                reader = f.drain_to(&mut content).await.unwrap();
+                files.push((path, content));
+            }
+            NextItem::End(end) => return (files, end),
+        }
+    }
+}
+
+async fn damaged_header(format: Format) {
+    let mut buffer = generate(format, &FILES).await;
+    let offsets: Vec<_> = manifest(&buffer)
+        .await
+        .files
+        .iter()
+        .map(|x| x.offsets.unwrap().header)
+        .collect();
+    // Tar header is checked by its checksum, and cpio headers by their magic.
+    let damaged = offsets[1] + if format == Format::Pax { 100 } else { 0 };
+    buffer[damaged as usize] ^= 0xFF;
+
+    let (files, end) = recover(buffer).await;
+    let paths: Vec<_> = files.iter().map(|(path, _)| &path[..]).collect();
+    assert_eq!(paths, [&b"first"[..], b"third"]);
+    assert_eq!(files[1].1, b"third file");
+    assert_eq!(end.manifest.unwrap().files.len(), 3);
+    let report = end.recovery.unwrap();
+    assert!(report.verified);
+    assert_eq!(report.skipped, [offsets[1]..offsets[2]]);
+    assert_eq!(report.lost.len(), 1);
+    assert_eq!(report.lost[0].as_bytes(), b"second");
+}
+
+#[tokio::test]
+async fn damaged_header_binary() {
+    damaged_header(Format::Binary).await;
+}
+
+#[tokio::test]
+async fn damaged_header_newc() {
+    damaged_header(Format::Newc).await;
+}
+
+#[tokio::test]
+async fn damaged_header_pax() {
+    damaged_header(Format::Pax).await;
+}
+
+#[tokio::test]
+async fn damaged_without_manifest() {
+    let mut buffer = generate(Format::Newc, &FILES).await;
+    let offsets: Vec<_> = manifest(&buffer)
+        .await
+        .files
+        .iter()
+        .map(|x| x.offsets.unwrap())
+        .collect();
+    let manifest_start = buffer.iter().rposition(|x| *x == 0).unwrap() + 1;
+    buffer.truncate(manifest_start);
+    // Size of the second file is damaged, so the third header is found by its magic.
+    let size_field = offsets[1].header as usize + 54;
+    buffer[size_field] = b'F';
+
+    let (files, end) = recover(buffer).await;
+    let paths: Vec<_> = files.iter().map(|(path, _)| &path[..]).collect();
+    assert_eq!(paths, [&b"first"[..], b"third"]);
+    assert!(end.manifest.is_none());
+    let report = end.recovery.unwrap();
+    assert!(!report.verified);
+    assert_eq!(report.skipped, [offsets[1].header..offsets[2].header]);
+    assert!(report.lost.is_empty());
+}
+
+#[tokio::test]
+async fn damaged_name_size() {
+    let mut buffer = generate(Format::Newc, &FILES).await;
+    let offsets: Vec<_> = manifest(&buffer)
+        .await
+        .files
+        .iter()
+        .map(|x| x.offsets.unwrap().header)
+        .collect();
+    // Name of the second file would end inside the manifest.
+    let field = offsets[1] as usize + 94;
+    buffer[field..field + 8].copy_from_slice(b"0000FFFF");
+
+    let (files, end) = recover(buffer).await;
+    let paths: Vec<_> = files.iter().map(|(path, _)| &path[..]).collect();
+    assert_eq!(paths, [&b"first"[..], b"third"]);
+    let report = end.recovery.unwrap();
+    assert_eq!(report.skipped, [offsets[1]..offsets[2]]);
+}
+
+#[tokio::test]
+async fn intact_archive() {
+    let buffer = generate(Format::Binary, &FILES).await;
+    let (files, end) = recover(buffer).await;
+    assert_eq!(files.len(), 3);
+    let report = end.recovery.unwrap();
+    assert!(report.skipped.is_empty());
+    assert_eq!(end.manifest.unwrap().files.len(), 3);
+}
@@ -0,0 +1,219 @@
+#![cfg(unix)]
+
+use colbak_lib::cpio::{Archive, Format};
+use colbak_lib::fileinfo::Info;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tokio::io::AsyncReadExt;
+
+/// Creates a small tree with every kind of entry the script supports.
+fn create_tree(root: &Path) -> Vec<PathBuf> {
+    let _ = std::fs::remove_dir_all(root);
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::write(root.join("dir/file"), b"Hello world\n").unwrap();
+    std::fs::write(root.join("odd"), b"odd_named_file\n").unwrap();
+    std::fs::write(root.join("empty"), b"").unwrap();
+    std::fs::write(root.join("new\nline\n"), b"Name ends with newline").unwrap();
+    std::fs::hard_link(root.join("dir/file"), root.join("link")).unwrap();
+    std::os::unix::fs::symlink("dir/file", root.join("symlink")).unwrap();
+    std::fs::set_permissions(
+        root.join("odd"),
+        std::os::unix::fs::PermissionsExt::from_mode(0o600),
+    )
+    .unwrap();
+    [
+        "dir",
+        "dir/file",
+        "odd",
+        "empty",
+        "new\nline\n",
+        "link",
+        "symlink",
+    ]
+    .iter()
+    .map(|x| root.join(x))
+    .collect()
+}
+
+async fn write_archive(mut archive: Archive, files: &[PathBuf], path: &Path) {
+    for file in files {
+        archive.add(Info::new(file.clone()).await.unwrap());
+    }
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    std::fs::write(path, buffer).unwrap();
+}
+
+fn run_script(dir: &Path, archive: &Path, dest: &Path) -> Output {
+    let script = dir.join(colbak_lib::restore_script::FILE_NAME);
+    Command::new("sh")
+        .arg(script)
+        .arg(archive)
+        .arg(dest)
+        .output()
+        .unwrap()
+}
+
+async fn restore(format: Format) {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("restore_sh_{:?}", format));
+    let files = create_tree(&dir.join("src"));
+    let archive = dir.join("archive.cpio");
+    write_archive(Archive::with_format(format), &files, &archive).await;
+    colbak_lib::restore_script::write_to(&dir).await.unwrap();
+
+    let dest = dir.join("dest");
+    let _ = std::fs::remove_dir_all(&dest);
+    let output = run_script(&dir, &archive, &dest);
+    assert!(output.status.success(), "{:?}", output);
+    // Every file was checked and nothing was skipped.
+    assert!(output.stderr.is_empty(), "{:?}", output);
+
+    // Leading slash is removed from absolute paths.
+    let restored = dest.join(dir.join("src").strip_prefix("/").unwrap());
+    let read = |name: &str| std::fs::read(restored.join(name)).unwrap();
+    assert_eq!(read("dir/file"), b"Hello world\n");
+    assert_eq!(read("odd"), b"odd_named_file\n");
+    assert_eq!(read("empty"), b"");
+    assert_eq!(read("new\nline\n"), b"Name ends with newline");
+    assert_eq!(read("link"), b"Hello world\n");
+    assert_eq!(
+        std::fs::read_link(restored.join("symlink")).unwrap(),
+        Path::new("dir/file")
+    );
+
+    use std::os::unix::fs::MetadataExt;
+    let metadata = |name: &str| std::fs::metadata(restored.join(name)).unwrap();
+    assert_eq!(metadata("odd").mode() & 0o777, 0o600);
+    assert_eq!(metadata("link").ino(), metadata("dir/file").ino());
+}
+
+#[tokio::test]
+async fn restore_binary() {
+    restore(Format::Binary).await;
+}
+
+#[tokio::test]
+async fn restore_newc() {
+    restore(Format::Newc).await;
+}
+
+#[tokio::test]
+async fn restore_detects_corruption() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("restore_sh_corrupted");
+    let files = create_tree(&dir.join("src"));
+    let archive = dir.join("archive.cpio");
+    write_archive(Archive::with_format(Format::Newc), &files, &archive).await;
+    colbak_lib::restore_script::write_to(&dir).await.unwrap();
+
+    let mut content = std::fs::read(&archive).unwrap();
+    let position = content
+        .windows(b"Hello world".len())
+        .position(|x| x == b"Hello world")
+        .unwrap();
+    content[position] = b'J';
+    std::fs::write(&archive, content).unwrap();
+
+    let dest = dir.join("dest");
+    let _ = std::fs::remove_dir_all(&dest);
+    let output = run_script(&dir, &archive, &dest);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("archive hash does not match"), "{}", stderr);
+    assert!(stderr.contains("hash mismatch"), "{}", stderr);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn restore_sparse() {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("restore_sh_sparse");
+    let src = dir.join("src");
+    let _ = std::fs::remove_dir_all(&src);
+    std::fs::create_dir_all(&src).unwrap();
+    let mut file = std::fs::File::create(src.join("sparse")).unwrap();
+    file.set_len(16 * 1024 * 1024).unwrap();
+    file.seek(SeekFrom::Start(100_000)).unwrap();
+    file.write_all(b"Hello").unwrap();
+    file.seek(SeekFrom::Start(5_000_000)).unwrap();
+    file.write_all(b"world").unwrap();
+    drop(file);
+    std::fs::set_permissions(src.join("sparse"), PermissionsExt::from_mode(0o400)).unwrap();
+    std::fs::hard_link(src.join("sparse"), src.join("link")).unwrap();
+    let content = std::fs::read(src.join("sparse")).unwrap();
+
+    let archive = dir.join("archive.cpio");
+    let files = [src.join("sparse"), src.join("link")];
+    // Script recreates holes from the manifest.
+    let sparse = Archive::with_format(Format::Newc).with_sparse(true);
+    write_archive(sparse, &files, &archive).await;
+    colbak_lib::restore_script::write_to(&dir).await.unwrap();
+
+    let dest = dir.join("dest");
+    let _ = std::fs::remove_dir_all(&dest);
+    let output = run_script(&dir, &archive, &dest);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(output.stderr.is_empty(), "{:?}", output);
+
+    let restored = dest.join(src.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(restored.join("sparse")).unwrap(), content);
+    let metadata = std::fs::metadata(restored.join("sparse")).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o400);
+    assert_eq!(
+        metadata.ino(),
+        std::fs::metadata(restored.join("link")).unwrap().ino()
+    );
+    if std::fs::metadata(src.join("sparse")).unwrap().blocks() < 1024 {
+        assert!(metadata.blocks() < 1024, "{}", metadata.blocks());
+    }
+}
+
+#[tokio::test]
+async fn restore_skips_symlinked_parents() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("restore_sh_symlinked");
+    let src = dir.join("src");
+    let outside = dir.join("outside");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::create_dir_all(&outside).unwrap();
+    std::fs::write(outside.join("file"), b"Original").unwrap();
+    std::os::unix::fs::symlink(&outside, src.join("link")).unwrap();
+
+    // The second entry is stored inside the first one, which points outside of destination.
+    let archive = dir.join("archive.cpio");
+    let files = [src.join("link"), src.join("link/file")];
+    write_archive(Archive::with_format(Format::Newc), &files, &archive).await;
+    colbak_lib::restore_script::write_to(&dir).await.unwrap();
+    std::fs::write(outside.join("file"), b"Changed").unwrap();
+
+    let dest = dir.join("dest");
+    let output = run_script(&dir, &archive, &dest);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("its parent is a symbolic link"),
+        "{}",
+        stderr
+    );
+    assert_eq!(std::fs::read(outside.join("file")).unwrap(), b"Changed");
+}
+
+#[tokio::test]
+async fn restore_rejects_pax() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("restore_sh_pax");
+    let files = create_tree(&dir.join("src"));
+    let archive = dir.join("archive.tar");
+    write_archive(Archive::with_format(Format::Pax), &files, &archive).await;
+    colbak_lib::restore_script::write_to(&dir).await.unwrap();
+
+    let dest = dir.join("dest");
+    let _ = std::fs::remove_dir_all(&dest);
+    let output = run_script(&dir, &archive, &dest);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("pax archives are not supported"),
+        "{}",
+        stderr
+    );
+}
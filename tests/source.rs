@@ -0,0 +1,95 @@
+use colbak_lib::cpio::pending::{Change, ChangePolicy};
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::source::{Memory, Once};
+use colbak_lib::cpio::{Archive, Format, Reader};
+use colbak_lib::fileinfo::Info;
+use std::io::Cursor;
+use std::path::PathBuf;
+use tokio::io::AsyncReadExt;
+
+async fn unpack(data: Vec<u8>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut files = Vec::new();
+    let mut reader = Reader::new(Cursor::new(data));
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(file) => {
+                let name = file.info().path.as_bytes().to_vec();
+                let mut content = Vec::new();
+                reader = file.drain_to(&mut content).await.unwrap();
+                files.push((name, content));
+            }
+            NextItem::End(_) => break files,
+        }
+    }
+}
+
+#[tokio::test]
+async fn memory_and_stream() {
+    // Crc reads sources twice, which streams can not do.
+    for format in [Format::Binary, Format::Newc, Format::Pax] {
+        let mut archive = Archive::with_format(format);
+        archive.add_source(
+            Info::generated(PathBuf::from("dump.sql"), 11),
+            Memory::from(b"Hello world".to_vec()),
+        );
+        archive.add_source(
+            Info::generated(PathBuf::from("output"), 5),
+            Once::new(&b"Jello"[..]),
+        );
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let files = unpack(buffer).await;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], (b"dump.sql".to_vec(), b"Hello world".to_vec()));
+        assert_eq!(files[1], (b"output".to_vec(), b"Jello".to_vec()));
+        let manifest = archive.manifest();
+        assert!(manifest.files.iter().all(|x| x.info.hash.is_some()));
+    }
+}
+
+#[tokio::test]
+async fn crc_reads_twice() {
+    let mut archive = Archive::with_format(Format::Crc);
+    archive.add_source(
+        Info::generated(PathBuf::from("memory"), 11),
+        Memory::from(b"Hello world".to_vec()),
+    );
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    assert_eq!(unpack(buffer).await[0].1, b"Hello world");
+
+    let mut archive = Archive::with_format(Format::Crc);
+    archive.add_source(
+        Info::generated(PathBuf::from("stream"), 11),
+        Once::new(&b"Hello world"[..]),
+    );
+    let mut buffer = Vec::new();
+    assert!(archive.read().read_to_end(&mut buffer).await.is_err());
+}
+
+#[tokio::test]
+async fn wrong_size() {
+    for (declared, content) in [(5, &b"Hello world"[..]), (20, b"Hello world")] {
+        let info = Info::generated(PathBuf::from("wrong"), declared);
+
+        let mut archive = Archive::new();
+        archive.add_source(info.clone(), Memory::from(content.to_vec()));
+        let mut buffer = Vec::new();
+        let err = archive.read().read_to_end(&mut buffer).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}", err);
+
+        let mut archive = Archive::new().with_change_policy(ChangePolicy::Flag);
+        archive.add_source(info, Memory::from(content.to_vec()));
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+        let expected = Change::Size {
+            expected: declared,
+            found: content.len() as u64,
+        };
+        assert_eq!(archive.manifest().files[0].changed, Some(expected));
+
+        let files = unpack(buffer).await;
+        assert_eq!(files[0].1.len() as u64, declared);
+    }
+}
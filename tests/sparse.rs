@@ -0,0 +1,175 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use colbak_lib::cpio::extract::Extractor;
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::{Archive, Format, Reader};
+use common::{add, read, root};
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const SIZE: u64 = 16 * 1024 * 1024;
+
+/// Creates file with two small extents and holes everywhere else, including the end.
+fn create_sparse(path: &Path) -> Vec<u8> {
+    let mut file = std::fs::File::create(path).unwrap();
+    file.set_len(SIZE).unwrap();
+    file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+    file.write_all(b"Hello").unwrap();
+    file.seek(SeekFrom::Start(10 * 1024 * 1024 + 5)).unwrap();
+    file.write_all(b"world").unwrap();
+    drop(file);
+    std::fs::read(path).unwrap()
+}
+
+/// Bytes actually allocated on the disk.
+fn allocated(path: &Path) -> u64 {
+    std::fs::metadata(path).unwrap().blocks() * 512
+}
+
+async fn archive(format: Format, files: &[PathBuf]) -> Vec<u8> {
+    let mut archive = Archive::with_format(format).with_sparse(true);
+    add(&mut archive, files).await;
+    read(&mut archive).await
+}
+
+async fn roundtrip(format: Format) {
+    let root = root(&format!("sparse_{:?}", format));
+    let src = root.join("src");
+    let content = create_sparse(&src.join("sparse"));
+    if allocated(&src.join("sparse")) >= SIZE {
+        // Filesystem does not support holes.
+        return;
+    }
+    std::fs::hard_link(src.join("sparse"), src.join("link")).unwrap();
+    std::fs::write(src.join("plain"), b"Not sparse at all").unwrap();
+
+    let files = ["sparse", "link", "plain"].map(|x| src.join(x));
+    let data = archive(format, &files).await;
+    assert!((data.len() as u64) < SIZE / 4, "{}", data.len());
+
+    let report = Reader::new(Cursor::new(data.clone()))
+        .verify()
+        .await
+        .unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    let mut extractor = Extractor::new(root.join("dest"));
+    let mut reader = Reader::new(Cursor::new(data));
+    let warnings = loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(file) => reader = extractor.extract(file).await.unwrap(),
+            NextItem::End(end) => {
+                let manifest = end.manifest.as_ref().unwrap();
+                let sparse = manifest.files[0].sparse.as_ref().unwrap();
+                assert!(sparse.hash.is_some());
+                assert_eq!(manifest.files[1].sparse.as_ref(), Some(sparse));
+                assert!(manifest.files[2].sparse.is_none());
+                break extractor.finish(end);
+            }
+        }
+    };
+    assert!(warnings.is_empty(), "{:?}", warnings);
+
+    let restored = root.join("dest").join(src.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(restored.join("sparse")).unwrap(), content);
+    assert!(allocated(&restored.join("sparse")) < SIZE / 4);
+    let (sparse, link) = (
+        std::fs::metadata(restored.join("sparse")).unwrap(),
+        std::fs::metadata(restored.join("link")).unwrap(),
+    );
+    assert_eq!(sparse.ino(), link.ino());
+    assert_eq!(
+        std::fs::read(restored.join("plain")).unwrap(),
+        b"Not sparse at all"
+    );
+}
+
+#[tokio::test]
+async fn sparse_binary() {
+    roundtrip(Format::Binary).await;
+}
+
+#[tokio::test]
+async fn sparse_crc() {
+    roundtrip(Format::Crc).await;
+}
+
+#[tokio::test]
+async fn sparse_pax() {
+    roundtrip(Format::Pax).await;
+}
+
+#[tokio::test]
+async fn cpio_stores_whole_files_by_default() {
+    let root = root("sparse_default");
+    let src = root.join("src");
+    let content = create_sparse(&src.join("sparse"));
+
+    let mut archive = Archive::with_format(Format::Newc);
+    add(&mut archive, &[src.join("sparse")]).await;
+    let data = read(&mut archive).await;
+    assert!(archive.manifest().files[0].sparse.is_none());
+
+    let mut reader = Reader::new(Cursor::new(data));
+    let mut stored = Vec::new();
+    match reader.advance().await.unwrap() {
+        NextItem::File(file) => drop(file.drain_to(&mut stored).await.unwrap()),
+        NextItem::End(_) => panic!("Archive is empty"),
+    }
+    assert_eq!(stored, content);
+}
+
+#[tokio::test]
+async fn pax_holes_readable_by_tar() {
+    let root = root("sparse_tar");
+    let src = root.join("src");
+    let content = create_sparse(&src.join("sparse"));
+    if allocated(&src.join("sparse")) >= SIZE {
+        // Filesystem does not support holes.
+        return;
+    }
+    let data = archive(Format::Pax, &[src.join("sparse")]).await;
+    assert!((data.len() as u64) < SIZE / 4, "{}", data.len());
+    std::fs::write(root.join("archive.tar"), data).unwrap();
+
+    let output = std::process::Command::new("tar")
+        .arg("-xf")
+        .arg(root.join("archive.tar"))
+        .arg("-C")
+        .arg(root.join("dest"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let restored = root.join("dest").join(src.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(restored.join("sparse")).unwrap(), content);
+    assert!(allocated(&restored.join("sparse")) < SIZE / 4);
+}
+
+#[tokio::test]
+async fn pax_holes_without_manifest() {
+    let root = root("sparse_pax_headers");
+    let src = root.join("src");
+    let content = create_sparse(&src.join("sparse"));
+    let mut data = archive(Format::Pax, &[src.join("sparse")]).await;
+    // Manifest follows two zero blocks at the end of the tar archive.
+    let end = data
+        .windows(1024)
+        .rposition(|x| x.iter().all(|x| *x == 0))
+        .unwrap();
+    data.truncate(end + 1024);
+
+    let mut extractor = Extractor::new(root.join("dest"));
+    let mut reader = Reader::new(Cursor::new(data));
+    let warnings = loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(file) => reader = extractor.extract(file).await.unwrap(),
+            NextItem::End(end) => break extractor.finish(end),
+        }
+    };
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    let restored = root.join("dest").join(src.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(restored.join("sparse")).unwrap(), content);
+}
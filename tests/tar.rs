@@ -0,0 +1,163 @@
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::source::Memory;
+use colbak_lib::cpio::tar::{Extended, TarHeader, BLOCK};
+use colbak_lib::cpio::{Archive, EntryHeader, Format, Reader};
+use colbak_lib::fileinfo::{Info, Xattr};
+use colbak_lib::DateTime;
+use std::io::Cursor;
+use std::path::PathBuf;
+use tokio::io::AsyncReadExt;
+
+#[tokio::test]
+async fn metadata_roundtrip() {
+    let name = format!("{}/file.txt", "long_directory_name/".repeat(10));
+    let mut info = Info::generated(PathBuf::from(&name), 11);
+    info.modified_at = DateTime::from_unix_timestamp_nanos(1_600_000_000_123_456_789).unwrap();
+    info.user_id = 3_000_000;
+    info.xattrs.push(Xattr {
+        name: b"user.comment".to_vec(),
+        value: b"with = and\nnewline".to_vec(),
+    });
+
+    let mut archive = Archive::with_format(Format::Pax);
+    archive.add_source(info, Memory::from(b"Hello world".to_vec()));
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    // Extended header with its records goes first.
+    assert_eq!(&buffer[2 * BLOCK + 257..2 * BLOCK + 263], b"ustar\0");
+
+    let reader = Reader::new(Cursor::new(buffer));
+    let reader = match reader.advance().await.unwrap() {
+        NextItem::File(f) => {
+            let info = f.info();
+            assert_eq!(info.path.as_bytes(), name.as_bytes());
+            assert_eq!(info.modified_at.nanosecond(), 123_456_789);
+            assert_eq!(info.user_id, 3_000_000);
+            assert_eq!(info.xattrs.len(), 1);
+            assert_eq!(info.xattrs[0].value, b"with = and\nnewline");
+            let mut content = Vec::new();
+            let reader = f.drain_to(&mut content).await.unwrap();
+            assert_eq!(content, b"Hello world");
+            reader
+        }
+        NextItem::End(_) => panic!(),
+    };
+    match reader.advance().await.unwrap() {
+        NextItem::End(end) => assert_eq!(end.manifest.unwrap().files.len(), 1),
+        NextItem::File(_) => panic!(),
+    }
+}
+
+#[tokio::test]
+async fn negative_mtime_roundtrip() {
+    for nanos in [-1_500_000_000, -2_000_000_000, -1] {
+        let mut info = Info::generated(PathBuf::from("file"), 0);
+        info.modified_at = DateTime::from_unix_timestamp_nanos(nanos).unwrap();
+        let mut archive = Archive::with_format(Format::Pax);
+        archive.add_source(info, Memory::from(Vec::new()));
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let reader = Reader::new(Cursor::new(buffer));
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => assert_eq!(f.info().modified_at.unix_timestamp_nanos(), nanos),
+            NextItem::End(_) => panic!(),
+        }
+    }
+}
+
+/// Recomputes checksum of the header after it was changed.
+fn set_checksum(block: &mut [u8; BLOCK]) {
+    block[148..156].fill(b' ');
+    let sum: u64 = block.iter().map(|x| u64::from(*x)).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+}
+
+#[test]
+fn wide_numbers() {
+    let info = Info::generated(PathBuf::from("file"), 11);
+    let encoded = TarHeader::encode(&info);
+    let mut block: [u8; BLOCK] = encoded[encoded.len() - BLOCK..].try_into().unwrap();
+    // GNU tar writes base-256 numbers over the whole field.
+    block[124..136].copy_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+    set_checksum(&mut block);
+    let header = TarHeader::decode(&block, Extended::default()).unwrap();
+    assert_eq!(header.size(), 1 << 32);
+
+    // Does not fit into 64 bits.
+    block[124..136].copy_from_slice(&[0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    set_checksum(&mut block);
+    assert!(TarHeader::decode(&block, Extended::default()).is_none());
+
+    // Field is not needed when extended header has the value.
+    let mut extended = Extended::default();
+    assert!(extended.update(b'x', b"16 size=1000000\n"));
+    let header = TarHeader::decode(&block, extended).unwrap();
+    assert_eq!(header.size(), 1_000_000);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn readable_by_tar() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("tar_compat");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut archive = Archive::with_format(Format::Pax);
+    for path in ["tests/archive/foobar", "tests/archive/link"] {
+        archive.add(Info::new(path.into()).await.unwrap());
+    }
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    std::fs::write(dir.join("archive.tar"), buffer).unwrap();
+
+    let output = std::process::Command::new("tar")
+        .arg("-xf")
+        .arg(dir.join("archive.tar"))
+        .arg("-C")
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let content = std::fs::read(dir.join("tests/archive/foobar")).unwrap();
+    assert_eq!(content, b"Hello world\n");
+    let target = std::fs::read_link(dir.join("tests/archive/link")).unwrap();
+    assert_eq!(target, std::path::Path::new("foobar"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn hard_links_readable_by_tar() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("tar_hard_links");
+    let _ = std::fs::remove_dir_all(&dir);
+    let source = dir.join("source");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("first"), b"shared content\n").unwrap();
+    std::fs::hard_link(source.join("first"), source.join("second")).unwrap();
+    let socket = std::os::unix::net::UnixListener::bind(source.join("socket")).unwrap();
+
+    let mut archive = Archive::with_format(Format::Pax);
+    for name in ["first", "second", "socket"] {
+        archive.add(Info::new(source.join(name)).await.unwrap());
+    }
+    drop(socket);
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    std::fs::write(dir.join("archive.tar"), buffer).unwrap();
+
+    let output = std::process::Command::new("tar")
+        .arg("-xf")
+        .arg(dir.join("archive.tar"))
+        .arg("-C")
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let restored = dir.join(source.strip_prefix("/").unwrap());
+    for name in ["first", "second"] {
+        let content = std::fs::read(restored.join(name)).unwrap();
+        assert_eq!(content, b"shared content\n", "{}", name);
+    }
+    // Sockets have no type in tar, so they are not archived.
+    assert!(!restored.join("socket").exists());
+}